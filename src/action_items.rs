@@ -0,0 +1,382 @@
+use crate::core::datatypes::ParsedPage;
+use crate::error::Result;
+use crate::llm::{parse_json, JsonSchema, LlmProvider, Message};
+use chrono::NaiveDate;
+use dendron::traverse::DftEvent;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+
+const ACTION_ITEMS_INSTRUCTIONS: &str = "The retro is over. List the action items we agreed on, leaving out any that I turned down, and nothing that we didn't agree on. Today is";
+
+/// A task the user committed to in a retro, to be checked on in the next one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActionItem {
+    pub title: String,
+    /// What will be true once the task is done, i.e. the measurable part of a SMART task
+    pub outcome: String,
+    pub due: Option<NaiveDate>,
+    /// The topics of the user's notes that the task is about
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+/// An action item as the LLM wrote it, before its due date is parsed. Each date is parsed on its
+/// own, so that one the LLM got wrong doesn't lose the rest of the action items.
+#[derive(Deserialize)]
+struct ExtractedActionItem {
+    title: String,
+    outcome: String,
+    due: Option<String>,
+    #[serde(default)]
+    topics: Vec<String>,
+}
+
+impl From<ExtractedActionItem> for ActionItem {
+    fn from(item: ExtractedActionItem) -> Self {
+        let due = item.due.and_then(|due| {
+            NaiveDate::parse_from_str(due.trim(), "%Y-%m-%d")
+                .map_err(|e| {
+                    warn!(target: "intelligence", "leaving out the due date of \"{}\", as {:?} isn't a YYYY-MM-DD date: {}", item.title, due, e);
+                })
+                .ok()
+        });
+        ActionItem {
+            title: item.title,
+            outcome: item.outcome,
+            due,
+            topics: item.topics,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ActionItems {
+    action_items: Vec<ExtractedActionItem>,
+}
+
+/// The schema of the JSON that `extract_action_items` asks for. It is written to work with
+/// OpenAI's strict structured outputs, which means every property is required and nullable
+/// ones say so in their type.
+fn action_items_schema() -> JsonSchema {
+    JsonSchema {
+        name: "action_items".to_string(),
+        description: "The action items agreed on in a retro".to_string(),
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "action_items": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "title": {
+                                "type": "string",
+                                "description": "A short title for the task, starting with a verb"
+                            },
+                            "outcome": {
+                                "type": "string",
+                                "description": "The measurable outcome that means the task is done"
+                            },
+                            "due": {
+                                "type": ["string", "null"],
+                                "description": "The date the task is due by as YYYY-MM-DD, or null if no date was agreed on"
+                            },
+                            "topics": {
+                                "type": "array",
+                                "items": {"type": "string"},
+                                "description": "The topics of the notes that the task relates to"
+                            }
+                        },
+                        "required": ["title", "outcome", "due", "topics"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["action_items"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+/// Asks the LLM for the action items agreed on in the retro that `messages` is the conversation
+/// of. `today` is given to the LLM so that it can turn due dates like "by Friday" into dates.
+pub async fn extract_action_items(
+    llm: &dyn LlmProvider,
+    messages: &[Message],
+    today: NaiveDate,
+) -> Result<Vec<ActionItem>> {
+    info!(target: "intelligence", "--- Extracting the action items from the retro");
+    let mut messages = messages.to_vec();
+    messages.push(Message::user(format!(
+        "{} {}.",
+        ACTION_ITEMS_INSTRUCTIONS,
+        today.format("%A %Y-%m-%d")
+    )));
+
+    let response = llm.chat_json(&messages, &action_items_schema()).await?;
+    let action_items: ActionItems = parse_json(&response, "the action items")?;
+    Ok(action_items
+        .action_items
+        .into_iter()
+        .map(ActionItem::from)
+        .collect())
+}
+
+/// Something in the user's notes that suggests how an action item is going
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Evidence {
+    /// A to-do that reads like the action item
+    ToDo {
+        page: String,
+        text: String,
+        checked: bool,
+    },
+    /// A Page about one of the action item's topics
+    Page { title: String },
+}
+
+/// The most evidence we give the LLM for any one action item, so that a common word doesn't flood
+/// the prompt with to-dos
+const MAX_EVIDENCE: usize = 3;
+
+/// Words too common to say whether a to-do is about an action item
+const STOP_WORDS: [&str; 12] = [
+    "about", "daily", "each", "every", "from", "have", "into", "more", "that", "this", "week",
+    "with",
+];
+
+/// The words of `text` that say what it is about, lowercased
+fn keywords(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 4)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+/// Looks through the Pages for signs of progress on an action item: to-dos that share at least
+/// two keywords with its title (or its only keyword, for short titles), and Pages whose title
+/// names one of its topics. Checked off to-dos come first, since they're the best evidence.
+pub fn find_evidence(item: &ActionItem, pages: &[ParsedPage]) -> Vec<Evidence> {
+    let title_keywords = keywords(&item.title);
+    let needed = title_keywords.len().min(2);
+    let topics = item
+        .topics
+        .iter()
+        .map(|topic| topic.to_lowercase())
+        .collect::<Vec<_>>();
+
+    let mut evidence = Vec::new();
+    for page in pages {
+        let title = page.title.to_lowercase();
+        if topics
+            .iter()
+            .any(|topic| !topic.is_empty() && title.contains(topic.as_str()))
+        {
+            evidence.push(Evidence::Page {
+                title: page.title.clone(),
+            });
+        }
+
+        if needed == 0 {
+            continue;
+        }
+        for tree in &page.page_content {
+            for event in tree.root().depth_first_traverse() {
+                let DftEvent::Open(node) = event else {
+                    continue;
+                };
+                let block = node.borrow_data();
+                if block.is_to_do()
+                    && keywords(&block.text).intersection(&title_keywords).count() >= needed
+                {
+                    evidence.push(Evidence::ToDo {
+                        page: page.title.clone(),
+                        text: block.text.clone(),
+                        checked: block.is_checked(),
+                    });
+                }
+            }
+        }
+    }
+    debug!(target: "intelligence", "found {} pieces of evidence for the action item {}", evidence.len(), item.title);
+
+    // sort_by_key is stable, so otherwise the evidence stays in the order of the Pages
+    evidence.sort_by_key(|evidence| match evidence {
+        Evidence::ToDo { checked: true, .. } => 0,
+        Evidence::ToDo { checked: false, .. } => 1,
+        Evidence::Page { .. } => 2,
+    });
+    evidence.truncate(MAX_EVIDENCE);
+    evidence
+}
+
+/// The message that carries the action items of the last retro (held on `retro_date`) into this
+/// one, along with whatever the Pages say about how they went, so that Navi can open `step` (see
+/// `RetroFormat::follow_up_step`) by checking on them.
+pub fn follow_up_prompt(
+    action_items: &[ActionItem],
+    retro_date: NaiveDate,
+    pages: &[ParsedPage],
+    step: usize,
+) -> String {
+    let mut prompt = format!(
+        "These are the action items from my last retro, on {}. When we get to step {}, start by asking me how each of them went, one at a time. Where my notes suggest an item is done, say so and ask me to confirm it.\n",
+        retro_date.format("%Y-%m-%d"),
+        step
+    );
+    for item in action_items {
+        prompt.push_str(&format!("- {}: {}", item.title, item.outcome));
+        if let Some(due) = item.due {
+            prompt.push_str(&format!(" (due {})", due.format("%Y-%m-%d")));
+        }
+        prompt.push('\n');
+
+        for evidence in find_evidence(item, pages) {
+            let line = match evidence {
+                Evidence::ToDo {
+                    page,
+                    text,
+                    checked: true,
+                } => format!(
+                    "looks done: I checked off the to-do \"{}\" on {}",
+                    text, page
+                ),
+                Evidence::ToDo {
+                    page,
+                    text,
+                    checked: false,
+                } => format!(
+                    "in progress: the to-do \"{}\" on {} isn't checked off yet",
+                    text, page
+                ),
+                Evidence::Page { title } => format!("related: I edited the page {}", title),
+            };
+            prompt.push_str(&format!("  - {}\n", line));
+        }
+    }
+
+    prompt
+}
+
+/// Renders action items as a Markdown task list
+pub fn to_markdown(action_items: &[ActionItem]) -> String {
+    let mut markdown = String::from("## Action items\n");
+    if action_items.is_empty() {
+        markdown.push_str("\nNone were agreed on.\n");
+    }
+    for item in action_items {
+        markdown.push_str(&format!("\n- [ ] **{}**\n", item.title));
+        markdown.push_str(&format!("  - Outcome: {}\n", item.outcome));
+        if let Some(due) = item.due {
+            markdown.push_str(&format!("  - Due: {}\n", due.format("%Y-%m-%d")));
+        }
+        if !item.topics.is_empty() {
+            markdown.push_str(&format!("  - Topics: {}\n", item.topics.join(", ")));
+        }
+    }
+
+    markdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::datatypes::TextBlockKind;
+    use crate::fixtures::parsed_page;
+    use crate::llm::scripted::ScriptedLlm;
+    use chrono::Utc;
+
+    #[test]
+    fn test_follow_up_prompt_cross_references_the_notes() {
+        let action_items = vec![ActionItem {
+            title: "Practice coding challenges".to_string(),
+            outcome: "5 challenges solved".to_string(),
+            due: NaiveDate::from_ymd_opt(2024, 11, 15),
+            topics: vec!["Interviews".to_string()],
+        }];
+        let pages = vec![
+            parsed_page(
+                "Daily Journal",
+                &[
+                    (TextBlockKind::ToDo { checked: false }, "Read about coding"),
+                    (
+                        TextBlockKind::ToDo { checked: true },
+                        "Do 5 coding challenges",
+                    ),
+                    (TextBlockKind::Paragraph, "Practiced coding challenges"),
+                ],
+                Utc::now(),
+            ),
+            parsed_page(
+                "Interviews at Acme",
+                &[(TextBlockKind::Paragraph, "Prep")],
+                Utc::now(),
+            ),
+        ];
+
+        assert_eq!(
+            follow_up_prompt(
+                &action_items,
+                NaiveDate::from_ymd_opt(2024, 11, 11).unwrap(),
+                &pages,
+                2
+            ),
+            "These are the action items from my last retro, on 2024-11-11. When we get to step 2, start by asking me how each of them went, one at a time. Where my notes suggest an item is done, say so and ask me to confirm it.\n\
+             - Practice coding challenges: 5 challenges solved (due 2024-11-15)\n  \
+             - looks done: I checked off the to-do \"Do 5 coding challenges\" on Daily Journal\n  \
+             - related: I edited the page Interviews at Acme\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extract_action_items() {
+        // the JSON is wrapped in a code block, as models that can't be held to a schema tend to do
+        let llm = ScriptedLlm::new([
+            "Here you go:\n```json\n{\"action_items\": [{\"title\": \"Practice coding challenges\", \"outcome\": \"5 challenges solved\", \"due\": \"2024-11-15\", \"topics\": [\"Interviews\"]}, {\"title\": \"Plan Navi tasks\", \"outcome\": \"A task for each day\", \"due\": null, \"topics\": []}]}\n```",
+        ]);
+        let today = NaiveDate::from_ymd_opt(2024, 11, 11).unwrap();
+        let action_items = extract_action_items(&llm, &[Message::user("Sounds good")], today)
+            .await
+            .unwrap();
+
+        let request = &llm.requests()[0];
+        assert_eq!(request.schema, Some(action_items_schema()));
+        assert!(request
+            .messages
+            .last()
+            .unwrap()
+            .content
+            .ends_with("Today is Monday 2024-11-11."));
+        assert_eq!(action_items.len(), 2);
+        assert_eq!(
+            action_items[0].due,
+            Some(NaiveDate::from_ymd_opt(2024, 11, 15).unwrap())
+        );
+        assert_eq!(
+            to_markdown(&action_items),
+            "## Action items\n\n- [ ] **Practice coding challenges**\n  - Outcome: 5 challenges solved\n  - Due: 2024-11-15\n  - Topics: Interviews\n\n- [ ] **Plan Navi tasks**\n  - Outcome: A task for each day\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extract_action_items_leaves_out_malformed_due_dates() {
+        let llm = ScriptedLlm::new([
+            r#"{"action_items": [{"title": "Ship the launch post", "outcome": "The post is live", "due": "next Friday", "topics": []}, {"title": "Plan Navi tasks", "outcome": "A task for each day", "due": " 2024-11-15 ", "topics": []}]}"#,
+        ]);
+        let today = NaiveDate::from_ymd_opt(2024, 11, 11).unwrap();
+        let action_items = extract_action_items(&llm, &[Message::user("Sounds good")], today)
+            .await
+            .unwrap();
+
+        assert_eq!(action_items.len(), 2);
+        assert_eq!(action_items[0].title, "Ship the launch post");
+        assert_eq!(action_items[0].due, None);
+        assert_eq!(
+            action_items[1].due,
+            Some(NaiveDate::from_ymd_opt(2024, 11, 15).unwrap())
+        );
+    }
+}
@@ -1,16 +1,38 @@
+use crate::error::{NaviError, Result};
+use crate::retro_format::DEFAULT_FORMAT;
 use log::{debug, warn};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     #[serde(default)]
     pub exclusions: Exclusions,
+    #[serde(default)]
+    pub databases: Databases,
+    #[serde(default)]
+    pub sources: Sources,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub notion: NotionSettings,
+    #[serde(default)]
+    pub retry: RetrySettings,
+    #[serde(default)]
+    pub llm: LlmSettings,
+    #[serde(default)]
+    pub digest: DigestSettings,
+    #[serde(default)]
+    pub retro: RetroSettings,
+    #[serde(default)]
+    pub search: SearchSettings,
+    #[serde(default)]
+    pub review: ReviewSettings,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Exclusions {
     #[serde(default)]
     pub page_patterns: Vec<String>,
@@ -24,31 +46,482 @@ impl Default for Exclusions {
     }
 }
 
+/// Controls which Notion databases have their rows ingested. Entries are matched
+/// against a database's title (case insensitive) or its ID (with or without dashes).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Databases {
+    /// If non-empty, only rows of the databases listed here are ingested
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Rows of the databases listed here are never ingested, even if they are also included
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl Default for Databases {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// Controls which `NoteSource`s Navi ingests notes from. Notes from every enabled
+/// source are merged into a single retro.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Sources {
+    /// Ingest notes from Notion, using the NOTION_TOKEN environment variable
+    #[serde(default = "default_true")]
+    pub notion: bool,
+    /// Ingest notes from the Obsidian vault (or any directory of Markdown files) at this path
+    #[serde(default)]
+    pub obsidian_vault: Option<PathBuf>,
+    /// Ingest notes from the Logseq graph at this path
+    #[serde(default)]
+    pub logseq_graph: Option<PathBuf>,
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Self {
+            notion: true,
+            obsidian_vault: None,
+            logseq_graph: None,
+        }
+    }
+}
+
+/// Controls how hard Navi works the Notion API. Notion allows an average of 3 requests per
+/// second per integration, and answers anything faster with HTTP 429s.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NotionSettings {
+    /// The most Block children requests that may be in flight at once
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// The average number of requests per second Navi sends to Notion
+    #[serde(default = "default_requests_per_second")]
+    pub requests_per_second: f64,
+    /// The ID of the Page that retros are written under as new Pages once they end. Unless
+    /// this is set retros aren't written to Notion. The Page and its children are never ingested,
+    /// so that Navi doesn't retro its own retros.
+    #[serde(default)]
+    pub retro_parent_page_id: Option<String>,
+}
+
+impl Default for NotionSettings {
+    fn default() -> Self {
+        Self {
+            concurrency: default_concurrency(),
+            requests_per_second: default_requests_per_second(),
+            retro_parent_page_id: None,
+        }
+    }
+}
+
+fn default_concurrency() -> usize {
+    3
+}
+
+fn default_requests_per_second() -> f64 {
+    3.0
+}
+
+/// Controls how Navi retries requests that failed for reasons that may go away on their own,
+/// such as being rate limited (HTTP 429) or a server error (HTTP 5xx)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetrySettings {
+    /// The most times a request is attempted, including the first attempt
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// The backoff before the first retry, which doubles with every retry after that.
+    /// If the server sends a Retry-After header, we wait for as long as it says instead
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// The longest backoff between two attempts
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// The longest we wait when a server's Retry-After header asks us to wait longer
+    #[serde(default = "default_max_retry_after_ms")]
+    pub max_retry_after_ms: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            max_retry_after_ms: default_max_retry_after_ms(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+fn default_max_retry_after_ms() -> u64 {
+    120_000
+}
+
+/// The APIs Navi can talk to an LLM through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LlmProviderKind {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    #[serde(rename = "anthropic")]
+    Anthropic,
+    /// Any server that implements OpenAI's chat completions API, such as Ollama, a llama.cpp
+    /// server or vLLM
+    #[serde(rename = "openai_compatible")]
+    OpenAiCompatible,
+}
+
+impl LlmProviderKind {
+    /// A human readable name for the provider, used in logs and errors
+    pub fn name(&self) -> &'static str {
+        match self {
+            LlmProviderKind::OpenAi => "OpenAI",
+            LlmProviderKind::Anthropic => "Anthropic",
+            LlmProviderKind::OpenAiCompatible => "OpenAI-compatible",
+        }
+    }
+}
+
+/// Controls which LLM Navi runs retros with. API keys are read from the environment rather
+/// than from here, so that navi.toml can be shared.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LlmSettings {
+    #[serde(default)]
+    pub provider: LlmProviderKind,
+    /// The model to use. Defaults to gpt-4o-mini for OpenAI and claude-3-5-sonnet-latest for
+    /// Anthropic, and must be set for OpenAI-compatible providers
+    #[serde(default)]
+    pub model: Option<String>,
+    /// The base URL of the API. Must be set for OpenAI-compatible providers, e.g.
+    /// http://localhost:11434/v1 for Ollama
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// The environment variable that holds the API key. Defaults to OPENAI_API_KEY for OpenAI and
+    /// ANTHROPIC_API_KEY for Anthropic. OpenAI-compatible providers are sent no key unless it is set
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    /// The most tokens the model accepts, for models Navi doesn't know the limits of
+    #[serde(default)]
+    pub context_window: Option<u32>,
+    /// The most tokens the model may write in a single response, for models Navi doesn't know
+    /// the limits of
+    #[serde(default)]
+    pub max_output_tokens: Option<u32>,
+    /// The most tokens of notes to put in the retro prompt. The most important pages are kept
+    /// and the rest cut down to fit. Defaults to half of the context window left after the response
+    #[serde(default)]
+    pub notes_token_budget: Option<u32>,
+    /// Whether to have JSON responses match their schema with OpenAI's structured outputs, which
+    /// not every OpenAI-compatible server supports. Defaults to true for OpenAI and false for
+    /// OpenAI-compatible providers, which are only asked for the schema in the prompt
+    #[serde(default)]
+    pub strict_json: Option<bool>,
+}
+
+impl Default for LlmSettings {
+    fn default() -> Self {
+        Self {
+            provider: LlmProviderKind::default(),
+            model: None,
+            base_url: None,
+            api_key_env: None,
+            context_window: None,
+            max_output_tokens: None,
+            notes_token_budget: None,
+            strict_json: None,
+        }
+    }
+}
+
+/// Controls the optional digest of the notes, which Navi builds before the retro starts by
+/// summarising each page on its own and then combining those summaries. This helps with weeks
+/// that have too many notes to fit in a single prompt.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DigestSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The most page summaries requested from the LLM at once
+    #[serde(default = "default_digest_concurrency")]
+    pub concurrency: usize,
+}
+
+impl Default for DigestSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            concurrency: default_digest_concurrency(),
+        }
+    }
+}
+
+fn default_digest_concurrency() -> usize {
+    4
+}
+
+/// Controls how retros are run
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetroSettings {
+    /// The retro format to use unless --format picks another
+    #[serde(default = "default_format")]
+    pub format: String,
+    /// A directory of the user's own retro formats, which take precedence over the built in ones
+    #[serde(default)]
+    pub formats_dir: Option<PathBuf>,
+}
+
+impl Default for RetroSettings {
+    fn default() -> Self {
+        Self {
+            format: default_format(),
+            formats_dir: None,
+        }
+    }
+}
+
+fn default_format() -> String {
+    DEFAULT_FORMAT.to_string()
+}
+
+/// Controls the optional search of all of the user's notes, which Navi indexes after every sync
+/// and the LLM can use during a retro to find older notes that bear on what the user says
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SearchSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The model that embeds the notes for searching. Anthropic has no embeddings, so this is
+    /// OpenAI (text-embedding-3-small unless the model is set) or an OpenAI-compatible server,
+    /// which can be a local model so that the notes never leave the machine
+    #[serde(default)]
+    pub embeddings: LlmSettings,
+    /// How many passages of notes a search returns
+    #[serde(default = "default_search_results")]
+    pub results: usize,
+}
+
+impl Default for SearchSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            embeddings: LlmSettings::default(),
+            results: default_search_results(),
+        }
+    }
+}
+
+fn default_search_results() -> usize {
+    5
+}
+
+/// The algorithms that `navi review` can schedule flashcards with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SchedulerKind {
+    /// SuperMemo's SM-2, which Anki was originally based on
+    #[serde(rename = "sm2")]
+    Sm2,
+    /// The Free Spaced Repetition Scheduler, which models how well each card is remembered
+    #[default]
+    #[serde(rename = "fsrs")]
+    Fsrs,
+}
+
+/// Controls `navi review`, which quizzes the user on flashcards made from their notes. Cards are
+/// made from toggle blocks (the toggle is the question, what's inside it the answer), from
+/// blocks written as `Q:: question` and `A:: answer`, and by the LLM from pages that have the
+/// `highlight_tag`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReviewSettings {
+    #[serde(default)]
+    pub scheduler: SchedulerKind,
+    /// For FSRS, the chance of remembering a card that reviews are scheduled for, from 0 to 1.
+    /// Higher means more reviews
+    #[serde(default = "default_desired_retention")]
+    pub desired_retention: f64,
+    /// Pages with this tag anywhere in their text (e.g. #flashcards) have cards written for them
+    /// by the LLM. Unset, the LLM writes no cards
+    #[serde(default)]
+    pub highlight_tag: Option<String>,
+    /// The most cards reviewed in one session
+    #[serde(default = "default_cards_per_session")]
+    pub cards_per_session: usize,
+}
+
+impl Default for ReviewSettings {
+    fn default() -> Self {
+        Self {
+            scheduler: SchedulerKind::default(),
+            desired_retention: default_desired_retention(),
+            highlight_tag: None,
+            cards_per_session: default_cards_per_session(),
+        }
+    }
+}
+
+fn default_desired_retention() -> f64 {
+    0.9
+}
+
+fn default_cards_per_session() -> usize {
+    50
+}
+
+/// Controls where Navi keeps the notes it has synced from its sources
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Storage {
+    /// The directory Navi keeps its local data in, relative to the working directory
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            data_dir: default_data_dir(),
+        }
+    }
+}
+
+impl Storage {
+    /// The path of the SQLite database that synced notes are kept in
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.join("navi.db")
+    }
+
+    /// The directory the transcripts of past retros are saved in
+    pub fn transcripts_dir(&self) -> PathBuf {
+        self.data_dir.join("transcripts")
+    }
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(".navi")
+}
+
+fn default_true() -> bool {
+    true
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             exclusions: Exclusions::default(),
+            databases: Databases::default(),
+            sources: Sources::default(),
+            storage: Storage::default(),
+            notion: NotionSettings::default(),
+            retry: RetrySettings::default(),
+            llm: LlmSettings::default(),
+            digest: DigestSettings::default(),
+            retro: RetroSettings::default(),
+            search: SearchSettings::default(),
+            review: ReviewSettings::default(),
         }
     }
 }
 
+/// Where the configuration is read from unless --config says otherwise
+pub const DEFAULT_CONFIG_PATH: &str = "navi.toml";
+
 impl Config {
-    /// Loads configuration from navi.toml file
-    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_path = "navi.toml";
+    /// Loads configuration from the file at `path`, or from navi.toml in the working directory if
+    /// no path is given. Without a navi.toml the defaults are used, but a `path` that doesn't exist
+    /// is an error.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let config_path = path.unwrap_or(Path::new(DEFAULT_CONFIG_PATH));
 
-        if !Path::new(config_path).exists() {
+        if path.is_none() && !config_path.exists() {
             debug!("navi.toml not found, using default configuration");
             return Ok(Config::default());
         }
 
-        let config_content = fs::read_to_string(config_path)?;
-        let config: Config = toml::from_str(&config_content)?;
+        let config_content = fs::read_to_string(config_path)
+            .map_err(|e| NaviError::io(format!("unable to read {}", config_path.display()), e))?;
+        let config: Config = toml::from_str(&config_content).map_err(|e| {
+            NaviError::Config(format!("{} is invalid: {}", config_path.display(), e))
+        })?;
 
         debug!("Loaded configuration: {:?}", config);
         Ok(config)
     }
 
+    /// Returns what's wrong with the configuration that reading navi.toml doesn't catch, such as
+    /// patterns that aren't valid regexes and paths that don't exist, as one message each
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for pattern in &self.exclusions.page_patterns {
+            if let Err(e) = Regex::new(pattern) {
+                problems.push(format!(
+                    "[exclusions] page_patterns has an invalid regex '{}': {}",
+                    pattern, e
+                ));
+            }
+        }
+
+        if !self.sources.notion
+            && self.sources.obsidian_vault.is_none()
+            && self.sources.logseq_graph.is_none()
+        {
+            problems.push("[sources] has no sources enabled".to_string());
+        }
+        for (name, path) in [
+            ("obsidian_vault", &self.sources.obsidian_vault),
+            ("logseq_graph", &self.sources.logseq_graph),
+        ] {
+            if let Some(path) = path.as_ref().filter(|path| !path.is_dir()) {
+                problems.push(format!(
+                    "[sources] {} is {}, which isn't a directory",
+                    name,
+                    path.display()
+                ));
+            }
+        }
+
+        for (value, setting) in [
+            (self.notion.concurrency, "[notion] concurrency"),
+            (self.retry.max_attempts as usize, "[retry] max_attempts"),
+            (self.digest.concurrency, "[digest] concurrency"),
+            (self.search.results, "[search] results"),
+            (self.review.cards_per_session, "[review] cards_per_session"),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be at least 1", setting));
+            }
+        }
+        if self.notion.requests_per_second <= 0.0 {
+            problems.push("[notion] requests_per_second must be more than 0".to_string());
+        }
+        if self.retry.initial_backoff_ms > self.retry.max_backoff_ms {
+            problems
+                .push("[retry] initial_backoff_ms must be no more than max_backoff_ms".to_string());
+        }
+        if self.search.enabled && self.search.embeddings.provider == LlmProviderKind::Anthropic {
+            problems.push(
+                "[search.embeddings] provider can't be anthropic, which has no embedding models"
+                    .to_string(),
+            );
+        }
+        if !(0.5..=0.99).contains(&self.review.desired_retention) {
+            problems.push("[review] desired_retention must be between 0.5 and 0.99".to_string());
+        }
+
+        problems
+    }
+
     /// Checks if a page should be excluded based on the configured regex patterns
     pub fn should_exclude_page(&self, page_title: &str, page_url: &str) -> bool {
         for pattern in &self.exclusions.page_patterns {
@@ -69,6 +542,31 @@ impl Config {
         }
         false
     }
+
+    /// Checks if the rows of a database should be ingested based on the configured
+    /// `[databases]` include and exclude lists
+    pub fn should_include_database(&self, database_title: &str, database_id: &str) -> bool {
+        let matches = |entry: &String| {
+            entry.eq_ignore_ascii_case(database_title)
+                || normalize_id(entry) == normalize_id(database_id)
+        };
+
+        if self.databases.exclude.iter().any(matches) {
+            debug!(
+                "Database excluded: title='{}', id='{}'",
+                database_title, database_id
+            );
+            return false;
+        }
+
+        self.databases.include.is_empty() || self.databases.include.iter().any(matches)
+    }
+}
+
+/// Notion IDs show up both with and without dashes (e.g. in URLs vs the API), so we
+/// strip the dashes before comparing them
+fn normalize_id(id: &str) -> String {
+    id.replace('-', "").to_lowercase()
 }
 
 #[cfg(test)]
@@ -135,4 +633,70 @@ mod tests {
         // Should not exclude other titles
         assert!(!config.should_exclude_page("My Special Page 2", "https://example.com/anything"));
     }
+
+    #[test]
+    fn test_llm_settings() {
+        let config: Config = toml::from_str(
+            "[llm]\nprovider = \"openai_compatible\"\nmodel = \"llama3.1\"\nbase_url = \"http://localhost:11434/v1\"",
+        )
+        .unwrap();
+        assert_eq!(config.llm.provider, LlmProviderKind::OpenAiCompatible);
+        assert_eq!(config.llm.model.as_deref(), Some("llama3.1"));
+
+        // OpenAI is the default provider
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.llm.provider, LlmProviderKind::OpenAi);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Config::default().validate(), Vec::<String>::new());
+
+        let config: Config = toml::from_str(
+            "[exclusions]\npage_patterns = [\"[invalid\"]\n[sources]\nnotion = false\n[review]\ndesired_retention = 1.5\ncards_per_session = 0",
+        )
+        .unwrap();
+        let problems = config.validate();
+        assert_eq!(problems.len(), 4);
+        assert!(
+            problems[0].starts_with("[exclusions] page_patterns has an invalid regex '[invalid'")
+        );
+        assert_eq!(problems[1], "[sources] has no sources enabled");
+        assert_eq!(problems[2], "[review] cards_per_session must be at least 1");
+        assert_eq!(
+            problems[3],
+            "[review] desired_retention must be between 0.5 and 0.99"
+        );
+
+        assert!(Config::load(Some(Path::new("no-such-navi.toml"))).is_err());
+    }
+
+    #[test]
+    fn test_all_databases_included_by_default() {
+        let config = Config::default();
+        assert!(config.should_include_database("Reading List", "1234"));
+    }
+
+    #[test]
+    fn test_include_database_by_title_or_id() {
+        let mut config = Config::default();
+        config.databases.include.push("reading list".to_string());
+        config
+            .databases
+            .include
+            .push("651d530e07a14f9c97b4084614c5049b".to_string());
+
+        assert!(config.should_include_database("Reading List", "abcd"));
+        assert!(config.should_include_database("Tasks", "651d530e-07a1-4f9c-97b4-084614c5049b"));
+        assert!(!config.should_include_database("Meeting Notes", "abcd"));
+    }
+
+    #[test]
+    fn test_exclude_database_wins_over_include() {
+        let mut config = Config::default();
+        config.databases.include.push("Tasks".to_string());
+        config.databases.exclude.push("Tasks".to_string());
+
+        assert!(!config.should_include_database("Tasks", "abcd"));
+    }
 }
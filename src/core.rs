@@ -1,2 +1,3 @@
 pub mod datatypes;
 pub mod helpers;
+pub mod prompt;
@@ -1,4 +1,5 @@
 use chrono::{DateTime, Utc};
+use dendron::Tree;
 use derive_more::{Deref, Display};
 use notion_client::objects::block::{Block as NotionBlock, BlockType};
 use notion_client::objects::parent::Parent;
@@ -95,6 +96,7 @@ impl Block {
             BlockType::NumberedListItem {
                 numbered_list_item: _,
             } => format!("1. {}", self.text),
+            BlockType::ToDo { to_do: _ } if self.is_checked() => format!("- [x] {}", self.text),
             BlockType::ToDo { to_do: _ } => format!("- [ ] {}", self.text),
             BlockType::Toggle { toggle: _ } => format!("> {}", self.text),
             _ => format!("{}", self.text),
@@ -106,6 +108,109 @@ impl Block {
     pub fn is_empty(&self) -> bool {
         self.text.is_empty()
     }
+
+    /// Returns true if this Block is a to-do, whether or not it has been checked off
+    #[must_use]
+    pub fn is_to_do(&self) -> bool {
+        matches!(self.block_type, BlockType::ToDo { .. })
+    }
+
+    /// Returns true if this Block is a toggle, whose children are hidden until it is opened
+    #[must_use]
+    pub fn is_toggle(&self) -> bool {
+        matches!(self.block_type, BlockType::Toggle { .. })
+    }
+
+    /// Returns true if this Block is a to-do that has been checked off
+    #[must_use]
+    pub fn is_checked(&self) -> bool {
+        // we read the checkbox from the Notion API's JSON shape of the BlockType, see
+        // `TextBlockKind::to_block_type` for why
+        serde_json::to_value(&self.block_type)
+            .ok()
+            .and_then(|block_type| block_type.get("to_do")?.get("checked")?.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Creates a Block for notes that were parsed from plain text (such as a Markdown file)
+    /// rather than fetched from Notion. Plain text notes don't track when each Block was
+    /// edited, so the Block inherits the edit date of the file it came from.
+    #[must_use]
+    pub fn from_text(
+        id: BlockID,
+        page_id: PageID,
+        kind: TextBlockKind,
+        text: String,
+        update_date: DateTime<Utc>,
+    ) -> Self {
+        Block {
+            id,
+            page_id,
+            block_type: kind.to_block_type(),
+            text,
+            creation_date: update_date,
+            update_date,
+            parent: None,
+            has_children: false,
+        }
+    }
+}
+
+/// The kinds of Block that can be parsed out of plain text notes, such as Markdown files.
+///
+/// Navi represents every Block using Notion's `BlockType`, so that all of our data sources
+/// share one representation. Use `TextBlockKind::to_block_type` to convert to it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TextBlockKind {
+    Heading1,
+    Heading2,
+    Heading3,
+    Paragraph,
+    BulletedListItem,
+    NumberedListItem,
+    ToDo { checked: bool },
+    Toggle,
+    Quote,
+}
+
+impl TextBlockKind {
+    /// Converts to the equivalent Notion `BlockType`.
+    ///
+    /// We build the `BlockType` from the JSON the Notion API would have returned for it, rather
+    /// than from notion-client's structs, because that JSON shape is what notion-client is built to
+    /// deserialize and is documented [here](https://developers.notion.com/reference/block).
+    #[must_use]
+    pub fn to_block_type(self) -> BlockType {
+        let (name, mut value) = match self {
+            TextBlockKind::Heading1 => ("heading_1", serde_json::json!({"is_toggleable": false})),
+            TextBlockKind::Heading2 => ("heading_2", serde_json::json!({"is_toggleable": false})),
+            TextBlockKind::Heading3 => ("heading_3", serde_json::json!({"is_toggleable": false})),
+            TextBlockKind::Paragraph => ("paragraph", serde_json::json!({})),
+            TextBlockKind::BulletedListItem => ("bulleted_list_item", serde_json::json!({})),
+            TextBlockKind::NumberedListItem => ("numbered_list_item", serde_json::json!({})),
+            TextBlockKind::ToDo { checked } => ("to_do", serde_json::json!({ "checked": checked })),
+            TextBlockKind::Toggle => ("toggle", serde_json::json!({})),
+            TextBlockKind::Quote => ("quote", serde_json::json!({})),
+        };
+        value["rich_text"] = serde_json::json!([]);
+        value["color"] = serde_json::json!("default");
+
+        let mut block_type = serde_json::Map::new();
+        block_type.insert("type".to_string(), name.into());
+        block_type.insert(name.to_string(), value);
+
+        serde_json::from_value(serde_json::Value::Object(block_type))
+            .expect("the Notion API JSON for a block type should always deserialize")
+    }
+}
+
+/// A Property is a single piece of structured metadata attached to a Page, such as the
+/// "Status" or "Due Date" column of a Notion database row. The value has already been
+/// rendered to plain text, so it can go straight into a prompt.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Property {
+    pub name: String,
+    pub value: String,
 }
 
 /// A Page is a container for Blocks, and its structure is heavily borrowed
@@ -122,6 +227,23 @@ pub struct Page {
     pub creation_date: DateTime<Utc>,
     pub update_date: DateTime<Utc>,
     pub child_blocks: Vec<Block>,
+    /// The title of the database this Page is a row of, if any
+    pub database: Option<String>,
+    /// The Page's structured metadata (e.g. a database row's columns)
+    pub properties: Vec<Property>,
+}
+
+/// A ParsedPage represents a page that has been processed and contains its recently edited
+/// content as a tree of blocks. This is what every `NoteSource` ultimately produces.
+#[derive(Debug, Clone)]
+pub struct ParsedPage {
+    pub page_id: PageID,
+    pub title: String,
+    pub page_content: Vec<Tree<Block>>,
+    /// The title of the database this page is a row of, if any
+    pub database: Option<String>,
+    /// The page's structured metadata, rendered as front-matter in the prompt
+    pub properties: Vec<Property>,
 }
 
 #[cfg(test)]
@@ -207,4 +329,30 @@ mod tests {
 
         assert_eq!(result_markdown, expected_markdown);
     }
+
+    #[test]
+    fn test_text_block_kind_to_markdown() {
+        let block = |kind: TextBlockKind| {
+            Block::from_text(
+                BlockID::new("1".to_string()),
+                PageID::new("notes.md".to_string()),
+                kind,
+                "text".to_string(),
+                Utc::now(),
+            )
+        };
+
+        assert_eq!(block(TextBlockKind::Heading2).to_markdown(), "## text");
+        assert_eq!(block(TextBlockKind::BulletedListItem).to_markdown(), "- text");
+        assert_eq!(
+            block(TextBlockKind::ToDo { checked: false }).to_markdown(),
+            "- [ ] text"
+        );
+        assert_eq!(
+            block(TextBlockKind::ToDo { checked: true }).to_markdown(),
+            "- [x] text"
+        );
+        assert_eq!(block(TextBlockKind::Toggle).to_markdown(), "> text");
+        assert_eq!(block(TextBlockKind::Paragraph).to_markdown(), "text");
+    }
 }
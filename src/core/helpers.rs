@@ -1,6 +1,7 @@
-use super::datatypes::Block;
-use dendron::{traverse::DftEvent, Tree};
+use super::datatypes::{Block, ParsedPage, Property};
+use dendron::{traverse::DftEvent, HierarchyEditGrant, Node, Tree};
 use log::{debug, trace};
+use sha2::{Digest, Sha256};
 
 /// Builds Markdown text containing notes that have been edited recently
 ///
@@ -102,3 +103,146 @@ pub fn build_markdown_from_trees(trees: Vec<Tree<Block>>) -> String {
 
     markdown
 }
+
+/// An owned tree of Blocks, for sources that parse a whole Page up front (e.g. Markdown files)
+/// and so know a Block's children before the Block is added to a `Tree`.
+#[derive(Debug, Clone)]
+pub struct BlockNode {
+    pub block: Block,
+    pub children: Vec<BlockNode>,
+}
+
+impl BlockNode {
+    #[must_use]
+    pub fn new(block: Block) -> Self {
+        BlockNode {
+            block,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// Converts a `BlockNode` into a `Tree`, setting each Block's `has_children` along the way.
+pub fn build_tree(root: BlockNode) -> Tree<Block> {
+    let BlockNode {
+        mut block,
+        children,
+    } = root;
+    block.has_children = !children.is_empty();
+
+    let root = Node::new_tree(block);
+    let grant = root.tree().grant_hierarchy_edit().unwrap();
+    append_children(&root, &grant, children);
+
+    root.tree()
+}
+
+fn append_children(
+    parent: &Node<Block>,
+    grant: &HierarchyEditGrant<Block>,
+    children: Vec<BlockNode>,
+) {
+    for BlockNode {
+        mut block,
+        children,
+    } in children
+    {
+        block.has_children = !children.is_empty();
+        let child = parent.create_as_last_child(grant, block);
+        append_children(&child, grant, children);
+    }
+}
+
+/// Builds the front-matter that goes ahead of a Page's block content in the prompt, so that
+/// structured metadata (such as a database row's status, dates and tags) is not lost.
+///
+/// Returns an empty `String` if there is nothing to render. Otherwise it looks like:
+///
+/// ```markdown
+/// Database: Reading List
+/// ---
+/// Status: In progress
+/// Tags: history, economics
+/// ---
+/// ```
+pub fn build_front_matter(database: Option<&str>, properties: &[Property]) -> String {
+    let mut front_matter = String::new();
+
+    if let Some(database) = database {
+        front_matter.push_str(&format!("Database: {}\n", database));
+    }
+
+    if !properties.is_empty() {
+        front_matter.push_str("---\n");
+        for property in properties {
+            front_matter.push_str(&format!("{}: {}\n", property.name, property.value));
+        }
+        front_matter.push_str("---\n");
+    }
+
+    front_matter
+}
+
+/// Converts Navi's internal representation of a Vec of Page's content into a markdown
+/// prompt that can be used to guide Navi in reflecting on the past duration's worth of notetaking.
+///
+/// # Returns
+/// A `String` of prompt markdown.
+pub fn to_prompt_text(pages: Vec<ParsedPage>) -> String {
+    pages
+        .into_iter()
+        .map(page_to_prompt_text)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Converts a single Page to its part of the prompt, see `to_prompt_text`
+pub fn page_to_prompt_text(page: ParsedPage) -> String {
+    let front_matter = build_front_matter(page.database.as_deref(), &page.properties);
+    let single_page_prompt_markdown = build_markdown_from_trees(page.page_content);
+    format!(
+        "Page Title: {}\n{}{}",
+        page.title, front_matter, single_page_prompt_markdown
+    )
+}
+
+/// A hex encoded SHA-256 hash of `parts`, for identifying content by what it says
+pub fn hash_parts(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        // a separator, so that moving text from one part to the next changes the hash
+        hasher.update([0]);
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::datatypes::PageID;
+
+    #[test]
+    fn test_to_prompt_text_includes_front_matter() {
+        let pages = vec![ParsedPage {
+            page_id: PageID::new("1".to_string()),
+            title: "The Power Broker".to_string(),
+            page_content: Vec::new(),
+            database: Some("Reading List".to_string()),
+            properties: vec![Property {
+                name: "Status".to_string(),
+                value: "Reading".to_string(),
+            }],
+        }];
+
+        assert_eq!(
+            to_prompt_text(pages),
+            "Page Title: The Power Broker\nDatabase: Reading List\n---\nStatus: Reading\n---\n"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use super::datatypes::{Block, ParsedPage};
+use super::helpers::page_to_prompt_text;
+use chrono::{DateTime, Utc};
+use dendron::{traverse::DftEvent, Tree};
+use log::debug;
+use std::fmt;
+
+/// Below this many tokens of room left in the budget, we don't bother including the start of a
+/// Page that doesn't fit, since a few lines out of context would be more noise than signal
+const MIN_TRUNCATED_PAGE_TOKENS: usize = 100;
+
+/// What the prompt assembler did with each Page, so the user can be told what was left out
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PromptReport {
+    /// The token budget the notes had to fit in
+    pub budget: usize,
+    /// How many tokens of notes ended up in the prompt
+    pub tokens_used: usize,
+    /// The titles of the Pages that were included in full
+    pub included: Vec<String>,
+    /// The titles of the Pages that only had their start included
+    pub truncated: Vec<String>,
+    /// The titles of the Pages that were left out, except for their title
+    pub dropped: Vec<String>,
+}
+
+impl PromptReport {
+    /// Whether every Page made it into the prompt in full
+    pub fn is_complete(&self) -> bool {
+        self.truncated.is_empty() && self.dropped.is_empty()
+    }
+}
+
+impl fmt::Display for PromptReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Your notes were cut down to fit the model's {} token budget ({} pages in full):",
+            self.budget,
+            self.included.len()
+        )?;
+        for title in &self.truncated {
+            writeln!(f, "  - truncated: {}", title)?;
+        }
+        for title in &self.dropped {
+            writeln!(f, "  - left out: {}", title)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How much a Page's notes matter to the retro, relative to the other Pages
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct PageStats {
+    /// When any of the Page's Blocks was last edited
+    last_edited: Option<DateTime<Utc>>,
+    /// How many of the Page's Blocks were edited since the cutoff
+    edited_blocks: usize,
+    /// How many Blocks the Page has in the prompt
+    blocks: usize,
+}
+
+fn page_stats(trees: &[Tree<Block>], cutoff: DateTime<Utc>) -> PageStats {
+    let mut stats = PageStats::default();
+    for tree in trees {
+        for event in tree.root().depth_first_traverse() {
+            if let DftEvent::Open(node) = event {
+                let block = node.borrow_data();
+                stats.blocks += 1;
+                if block.update_date >= cutoff {
+                    stats.edited_blocks += 1;
+                }
+                stats.last_edited = stats.last_edited.max(Some(block.update_date));
+            }
+        }
+    }
+
+    stats
+}
+
+/// Scores a Page between 0 and 1. Recently edited Pages score highest, followed by the ones with
+/// the most edits, and then the biggest ones. Edit volume and size are relative to the busiest
+/// and biggest Pages of the week.
+fn score(stats: &PageStats, busiest: &PageStats, cutoff: DateTime<Utc>, now: DateTime<Utc>) -> f64 {
+    let window = (now - cutoff).num_seconds().max(1) as f64;
+    let recency = stats
+        .last_edited
+        .map(|last_edited| 1.0 - ((now - last_edited).num_seconds() as f64 / window))
+        .unwrap_or(0.0)
+        .clamp(0.0, 1.0);
+    let ratio = |value: usize, max: usize| {
+        if max == 0 {
+            0.0
+        } else {
+            value as f64 / max as f64
+        }
+    };
+
+    0.5 * recency
+        + 0.3 * ratio(stats.edited_blocks, busiest.edited_blocks)
+        + 0.2 * ratio(stats.blocks, busiest.blocks)
+}
+
+/// Builds the notes part of the retro prompt out of as many of the Pages as fit in `budget` tokens,
+/// as counted by `count_tokens`.
+///
+/// The Pages are ranked by `score`, and added most important first. The first Page that doesn't
+/// fit has as many of its lines included as do fit, and the Pages after it are summarised by
+/// listing their titles, so that the LLM at least knows they were worked on.
+pub fn assemble_prompt(
+    pages: Vec<ParsedPage>,
+    cutoff: DateTime<Utc>,
+    now: DateTime<Utc>,
+    budget: usize,
+    count_tokens: &dyn Fn(&str) -> usize,
+) -> (String, PromptReport) {
+    let stats = pages
+        .iter()
+        .map(|page| page_stats(&page.page_content, cutoff))
+        .collect::<Vec<_>>();
+    let busiest = PageStats {
+        last_edited: None,
+        edited_blocks: stats.iter().map(|s| s.edited_blocks).max().unwrap_or(0),
+        blocks: stats.iter().map(|s| s.blocks).max().unwrap_or(0),
+    };
+    let mut ranked = pages
+        .into_iter()
+        .zip(stats.iter().map(|s| score(s, &busiest, cutoff, now)))
+        .collect::<Vec<_>>();
+    // sort_by is stable, so equally scored Pages keep the order their sources returned them in
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let titles = ranked
+        .iter()
+        .map(|(page, _)| page.title.clone())
+        .collect::<Vec<_>>();
+    let mut report = PromptReport {
+        budget,
+        ..PromptReport::default()
+    };
+    let mut sections = Vec::new();
+    for (i, (page, page_score)) in ranked.into_iter().enumerate() {
+        let title = titles[i].clone();
+        let text = page_to_prompt_text(page);
+        let tokens = count_tokens(&text);
+        debug!(target: "intelligence", "Page {} scored {:.2} and is {} tokens", title, page_score, tokens);
+        if report.tokens_used + tokens <= budget {
+            report.tokens_used += tokens;
+            report.included.push(title);
+            sections.push(text);
+            continue;
+        }
+
+        // this Page is the first that doesn't fit, so we fit in what we can of it, while leaving
+        // room to list the titles of the Pages after it
+        let rest = &titles[i + 1..];
+        let reserved = dropped_summary(rest).map_or(0, |summary| count_tokens(&summary));
+        let room = budget.saturating_sub(report.tokens_used + reserved);
+        if room >= MIN_TRUNCATED_PAGE_TOKENS {
+            let (truncated, truncated_tokens) = truncate_lines(&text, room, count_tokens);
+            report.tokens_used += truncated_tokens;
+            report.truncated.push(title);
+            sections.push(truncated);
+        } else {
+            report.dropped.push(title);
+        }
+        report.dropped.extend_from_slice(rest);
+        break;
+    }
+
+    if let Some(summary) = dropped_summary(&report.dropped) {
+        let tokens = count_tokens(&summary);
+        if report.tokens_used + tokens <= budget {
+            report.tokens_used += tokens;
+            sections.push(summary);
+        }
+    }
+
+    (sections.join("\n\n"), report)
+}
+
+/// A line listing the titles of the Pages that were left out of the prompt, if there were any
+fn dropped_summary(titles: &[String]) -> Option<String> {
+    if titles.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "Other pages edited in this period, left out for length: {}",
+        titles.join(", ")
+    ))
+}
+
+/// Returns as many of the first lines of `text` as fit in `room` tokens, marked as truncated,
+/// along with how many tokens they are
+fn truncate_lines(
+    text: &str,
+    room: usize,
+    count_tokens: &dyn Fn(&str) -> usize,
+) -> (String, usize) {
+    const MARKER: &str = "(truncated)\n";
+    let mut truncated = String::new();
+    let mut tokens = count_tokens(MARKER);
+    for line in text.lines() {
+        // counting line by line is slightly off from counting the whole text, since tokens can
+        // span lines, so we allow a token for each newline
+        let line_tokens = count_tokens(line) + 1;
+        if tokens + line_tokens > room {
+            break;
+        }
+        tokens += line_tokens;
+        truncated.push_str(line);
+        truncated.push('\n');
+    }
+    truncated.push_str(MARKER);
+
+    (truncated, tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{days_ago, paragraphs};
+
+    fn count_words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    #[test]
+    fn test_everything_fits() {
+        let pages = vec![
+            paragraphs("Sprint 22", &["Planning notes"], days_ago(3)),
+            paragraphs("Reading", &["Finished The Power Broker"], days_ago(1)),
+        ];
+        let (prompt, report) = assemble_prompt(pages, days_ago(7), Utc::now(), 1000, &count_words);
+
+        assert!(report.is_complete());
+        // the more recently edited Page comes first
+        assert_eq!(report.included, vec!["Reading", "Sprint 22"]);
+        assert!(prompt.starts_with("Page Title: Reading\n"));
+        assert_eq!(report.tokens_used, count_words(&prompt));
+    }
+
+    #[test]
+    fn test_overflow_is_truncated_and_dropped() {
+        let long_lines = (0..100)
+            .map(|i| format!("line number {}", i))
+            .collect::<Vec<_>>();
+        let long_lines = long_lines.iter().map(String::as_str).collect::<Vec<_>>();
+        let pages = vec![
+            paragraphs("Old", &["An old note"], days_ago(6)),
+            paragraphs("Busy", &long_lines, days_ago(2)),
+            paragraphs("Recent", &["A new note"], days_ago(1)),
+        ];
+        let (prompt, report) = assemble_prompt(pages, days_ago(7), Utc::now(), 150, &count_words);
+
+        // the Page with the most edits ranks first, and is too long to fit in full
+        assert!(report.included.is_empty());
+        assert_eq!(report.truncated, vec!["Busy"]);
+        assert_eq!(report.dropped, vec!["Recent", "Old"]);
+        assert!(report.tokens_used <= 150);
+        assert!(prompt.contains("line number 0\n"));
+        assert!(!prompt.contains("line number 99"));
+        assert!(prompt.contains("(truncated)"));
+        assert!(prompt.ends_with("left out for length: Recent, Old"));
+        assert_eq!(
+            report.to_string(),
+            "Your notes were cut down to fit the model's 150 token budget (0 pages in full):\n  - truncated: Busy\n  - left out: Recent\n  - left out: Old\n"
+        );
+    }
+}
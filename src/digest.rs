@@ -0,0 +1,245 @@
+use crate::core::{
+    datatypes::ParsedPage,
+    helpers::{build_front_matter, build_markdown_from_trees, hash_parts},
+};
+use crate::error::Result;
+use crate::llm::{parse_json, LlmProvider, Message};
+use crate::store::Store;
+use futures::{stream, StreamExt, TryStreamExt};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+const PAGE_SUMMARY_INSTRUCTIONS: &str = "You will be given notes from one page of my notes. Summarise them in at most 5 bullet points. Mention every person by name, every topic, and anything I accomplished or finished. Reply with only the bullet points.";
+
+const MERGE_INSTRUCTIONS: &str = "You will be given summaries of several pages of my notes. Combine them into one shorter list of bullet points, keeping every person, topic and accomplishment. Reply with only the bullet points.";
+
+const DIGEST_INSTRUCTIONS: &str = "You will be given summaries of the pages of my notes since my last retro. Reduce them into a digest of my week, as a JSON object with these keys:
+- \"topics\": a list of the topics of my notes, each a short phrase followed by a few words of detail
+- \"people\": a list of the people I communicated with, each followed by a few words on what about
+- \"accomplishments\": a list of the things I accomplished
+Reply with only the JSON object.";
+
+/// A structured digest of a week of notes, which covers what step 1 of the retro needs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeeklyDigest {
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub people: Vec<String>,
+    #[serde(default)]
+    pub accomplishments: Vec<String>,
+}
+
+impl WeeklyDigest {
+    /// Renders the digest as Markdown, to go in the retro prompt in place of the notes
+    pub fn to_prompt_text(&self) -> String {
+        let mut text = String::from(
+            "This is a digest of my notes, made by summarising each page of them, so some details may be missing.\n",
+        );
+        for (heading, items) in [
+            ("Topics", &self.topics),
+            ("People", &self.people),
+            ("Accomplishments", &self.accomplishments),
+        ] {
+            text.push_str(&format!("\n## {}\n", heading));
+            for item in items {
+                text.push_str(&format!("- {}\n", item));
+            }
+        }
+
+        text
+    }
+}
+
+/// Builds a `WeeklyDigest` of the Pages with map-reduce: each Page (or each chunk of a Page that
+/// is longer than `chunk_tokens`) is summarised on its own, with up to `concurrency` requests in
+/// flight at once, and then the summaries are combined into the digest.
+///
+/// Every summary is cached in the `Store` under a hash of the model, the instructions and the notes
+/// that were summarised, so rerunning a retro only summarises the Pages that changed.
+pub async fn build_digest(
+    llm: &dyn LlmProvider,
+    store: &Store,
+    pages: Vec<ParsedPage>,
+    chunk_tokens: usize,
+    concurrency: usize,
+) -> Result<WeeklyDigest> {
+    let count_tokens = |text: &str| llm.count_tokens(text);
+    let chunks = pages
+        .into_iter()
+        .flat_map(|page| page_chunks(page, chunk_tokens, &count_tokens))
+        .collect::<Vec<_>>();
+    info!(target: "intelligence", "summarising {} chunks of notes for the digest...", chunks.len());
+
+    let mut summaries: Vec<String> = stream::iter(chunks)
+        .map(|chunk| summarize(llm, store, PAGE_SUMMARY_INSTRUCTIONS, chunk))
+        .buffered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    // if the summaries are still too long for one request, merge them in batches until they fit
+    while summaries.len() > 1 && count_tokens(&summaries.join("\n\n")) > chunk_tokens {
+        debug!(target: "intelligence", "merging {} summaries that are too long to reduce at once", summaries.len());
+        let batches = batch(summaries, chunk_tokens, &count_tokens);
+        summaries = stream::iter(batches)
+            .map(|batch| summarize(llm, store, MERGE_INSTRUCTIONS, batch.join("\n\n")))
+            .buffered(concurrency.max(1))
+            .try_collect()
+            .await?;
+    }
+
+    let digest = summarize(llm, store, DIGEST_INSTRUCTIONS, summaries.join("\n\n")).await?;
+    parse_json(&digest, "the weekly digest")
+}
+
+/// Asks the LLM to summarise `text` according to `instructions`, unless the `Store` already has
+/// the summary
+async fn summarize(
+    llm: &dyn LlmProvider,
+    store: &Store,
+    instructions: &str,
+    text: String,
+) -> Result<String> {
+    // the hash covers everything that determines what the summary says
+    let hash = hash_parts(&[&llm.model_info().model, instructions, &text]);
+    if let Some(summary) = store.summary(&hash)? {
+        debug!(target: "intelligence", "using the cached summary {}", hash);
+        return Ok(summary);
+    }
+
+    let summary = llm
+        .chat(&[Message::system(instructions), Message::user(text)])
+        .await?;
+    store.save_summary(&hash, &summary)?;
+    Ok(summary)
+}
+
+/// Splits a Page's notes into chunks of about `chunk_tokens` tokens, each headed by the Page's
+/// title and front-matter. A chunk holds whole `Tree`s of Blocks, so a single `Tree` that is
+/// longer than `chunk_tokens` gets a chunk to itself.
+fn page_chunks(
+    page: ParsedPage,
+    chunk_tokens: usize,
+    count_tokens: &dyn Fn(&str) -> usize,
+) -> Vec<String> {
+    let header = format!(
+        "Page Title: {}\n{}",
+        page.title,
+        build_front_matter(page.database.as_deref(), &page.properties)
+    );
+    let header_tokens = count_tokens(&header);
+
+    let mut chunks = Vec::new();
+    let mut chunk = header.clone();
+    let mut tokens = header_tokens;
+    for tree in page.page_content {
+        let markdown = build_markdown_from_trees(vec![tree]);
+        let tree_tokens = count_tokens(&markdown);
+        if tokens > header_tokens && tokens + tree_tokens > chunk_tokens {
+            chunks.push(std::mem::replace(&mut chunk, header.clone()));
+            tokens = header_tokens;
+        }
+        chunk.push_str(&markdown);
+        tokens += tree_tokens;
+    }
+    chunks.push(chunk);
+
+    chunks
+}
+
+/// Groups the summaries into batches of about `batch_tokens` tokens, keeping at least two
+/// summaries in each batch so that every round of merging makes progress
+fn batch(
+    summaries: Vec<String>,
+    batch_tokens: usize,
+    count_tokens: &dyn Fn(&str) -> usize,
+) -> Vec<Vec<String>> {
+    let mut batches: Vec<Vec<String>> = Vec::new();
+    let mut tokens = 0;
+    for summary in summaries {
+        let summary_tokens = count_tokens(&summary);
+        match batches.last_mut() {
+            Some(batch) if batch.len() < 2 || tokens + summary_tokens <= batch_tokens => {
+                tokens += summary_tokens;
+                batch.push(summary);
+            }
+            _ => {
+                tokens = summary_tokens;
+                batches.push(vec![summary]);
+            }
+        }
+    }
+
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::paragraphs;
+    use crate::llm::scripted::ScriptedLlm;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn test_build_digest_caches_summaries() {
+        let llm = ScriptedLlm::new([
+            "- finished the CLI MVP",
+            "- took Gwen to the park",
+            "```json\n{\"topics\": [\"Navi: finished the CLI\"], \"people\": [\"Gwen: went to the park\"], \"accomplishments\": [\"Finished the CLI MVP\"]}\n```",
+        ]);
+        let store = Store::open_in_memory().unwrap();
+        let pages = || {
+            vec![
+                paragraphs("Project Navi", &["Finished the CLI MVP"], Utc::now()),
+                paragraphs("Weekly Review", &["Took Gwen to the park"], Utc::now()),
+            ]
+        };
+
+        let digest = build_digest(&llm, &store, pages(), 1000, 2).await.unwrap();
+        assert_eq!(digest.people, vec!["Gwen: went to the park"]);
+        assert!(digest
+            .to_prompt_text()
+            .contains("## Accomplishments\n- Finished the CLI MVP\n"));
+        // one summary per page, and one to reduce them
+        let requests = llm.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].messages[0].content, DIGEST_INSTRUCTIONS);
+
+        // nothing changed, so everything comes from the cache
+        build_digest(&llm, &store, pages(), 1000, 2).await.unwrap();
+        assert_eq!(llm.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_long_pages_are_chunked() {
+        let count_words = |text: &str| text.split_whitespace().count();
+        let chunks = page_chunks(
+            paragraphs(
+                "Sprint 22",
+                &["one two three", "four five six", "seven"],
+                Utc::now(),
+            ),
+            9,
+            &count_words,
+        );
+        assert_eq!(
+            chunks,
+            vec![
+                "Page Title: Sprint 22\n\tone two three\n\tfour five six\n",
+                "Page Title: Sprint 22\n\tseven\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_keeps_at_least_two_summaries_together() {
+        let count_words = |text: &str| text.split_whitespace().count();
+        let summaries = ["a b c", "d e f", "g", "h i j k"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            batch(summaries, 4, &count_words),
+            vec![vec!["a b c", "d e f"], vec!["g", "h i j k"]]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use crate::core::datatypes::{BlockID, PageID};
+use crate::retry::HttpError;
+use std::fmt::Display;
+use std::io;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, NaviError>;
+
+/// Every error that Navi can run into. Library functions return these instead of panicking, so
+/// that whatever is embedding Navi decides what to do about them.
+#[derive(Debug, Error)]
+pub enum NaviError {
+    /// navi.toml, or an environment variable Navi needs, is missing or invalid
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    /// A note source couldn't be read for a reason other than a failed request, e.g. a
+    /// Notion token that isn't a valid HTTP header
+    #[error("{source_name}: {message}")]
+    Source {
+        source_name: String,
+        message: String,
+    },
+
+    /// A request failed, even after being retried
+    #[error("{request} failed{}: {source}", location(.page_id, .block_id))]
+    Network {
+        request: String,
+        page_id: Option<PageID>,
+        block_id: Option<BlockID>,
+        source: HttpError,
+    },
+
+    /// Some JSON, e.g. an API response or a row of the store, wasn't in the shape we expected
+    #[error("unable to parse {what}: {source}")]
+    Deserialization {
+        what: String,
+        source: serde_json::Error,
+    },
+
+    /// The LLM provider returned an error
+    #[error("LLM error: {0}")]
+    Llm(String),
+
+    #[error("{context}: {source}")]
+    Io { context: String, source: io::Error },
+
+    /// The local store of synced notes couldn't be read or written
+    #[error("store error: {0}")]
+    Store(#[from] rusqlite::Error),
+}
+
+impl NaviError {
+    pub fn io(context: impl Display, source: io::Error) -> Self {
+        NaviError::Io {
+            context: context.to_string(),
+            source,
+        }
+    }
+
+    /// A short name for the kind of error, for reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NaviError::Config(_) => "config",
+            NaviError::Source { .. } => "source",
+            NaviError::Network { .. } => "network",
+            NaviError::Deserialization { .. } => "deserialization",
+            NaviError::Llm(_) => "LLM",
+            NaviError::Io { .. } => "IO",
+            NaviError::Store(_) => "store",
+        }
+    }
+
+    /// Records which Page and Block a failed request was for
+    pub fn at(self, page: &PageID, block: &BlockID) -> Self {
+        match self {
+            NaviError::Network {
+                request, source, ..
+            } => NaviError::Network {
+                request,
+                page_id: Some(page.clone()),
+                block_id: Some(block.clone()),
+                source,
+            },
+            other => other,
+        }
+    }
+
+    /// A suggestion for how the user might fix the error, for `main` to print along with it
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            NaviError::Config(_) => Some("check navi.toml and your .env file"),
+            NaviError::Network {
+                source: HttpError::Status { status, .. },
+                ..
+            } if status.as_u16() == 401 => {
+                Some("the API rejected your token, check that it is set correctly in .env")
+            }
+            NaviError::Network {
+                source: HttpError::Status { status, .. },
+                ..
+            } if status.as_u16() == 404 => Some(
+                "Notion can't find this page or block, make sure it is shared with your integration",
+            ),
+            NaviError::Network {
+                source: HttpError::Status { status, .. },
+                ..
+            } if status.as_u16() == 429 => Some(
+                "you are being rate limited, try lowering requests_per_second in the [notion] section of navi.toml",
+            ),
+            NaviError::Network { .. } => Some("check your internet connection and try again"),
+            NaviError::Store(_) => Some(
+                "the local store may be corrupted, deleting the data_dir from the [storage] section of navi.toml forces a full resync",
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl From<async_openai::error::OpenAIError> for NaviError {
+    fn from(error: async_openai::error::OpenAIError) -> Self {
+        NaviError::Llm(error.to_string())
+    }
+}
+
+fn location(page_id: &Option<PageID>, block_id: &Option<BlockID>) -> String {
+    match (page_id, block_id) {
+        (Some(page_id), Some(block_id)) => format!(" (page {}, block {})", page_id, block_id),
+        (Some(page_id), None) => format!(" (page {})", page_id),
+        (None, Some(block_id)) => format!(" (block {})", block_id),
+        (None, None) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_network_errors_include_their_location() {
+        let error = NaviError::Network {
+            request: "GET /blocks/abc/children".to_string(),
+            page_id: None,
+            block_id: None,
+            source: HttpError::Status {
+                status: StatusCode::NOT_FOUND,
+                body: "not found".to_string(),
+                retry_after: None,
+            },
+        }
+        .at(
+            &PageID::new("page".to_string()),
+            &BlockID::new("abc".to_string()),
+        );
+
+        assert_eq!(
+            error.to_string(),
+            "GET /blocks/abc/children failed (page page, block abc): HTTP 404 Not Found: not found"
+        );
+        assert!(error
+            .hint()
+            .unwrap()
+            .contains("shared with your integration"));
+    }
+}
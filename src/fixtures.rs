@@ -0,0 +1,107 @@
+use crate::core::datatypes::{Block, BlockID, Page, PageID, ParsedPage, TextBlockKind};
+use crate::core::helpers::{build_tree, BlockNode};
+use crate::store::StoredBlock;
+use chrono::{DateTime, Duration, Utc};
+use std::path::{Path, PathBuf};
+
+pub fn days_ago(days: i64) -> DateTime<Utc> {
+    Utc::now() - Duration::days(days)
+}
+
+/// A Page with the ID `id`, created 30 days before it was last edited
+pub fn page(id: &str, title: &str, update_date: DateTime<Utc>) -> Page {
+    Page {
+        id: PageID::new(id.to_string()),
+        title: title.to_string(),
+        url: format!("https://example.com/{}", id),
+        creation_date: update_date - Duration::days(30),
+        update_date,
+        child_blocks: Vec::new(),
+        database: None,
+        properties: Vec::new(),
+    }
+}
+
+/// A Block of the Page with the ID `page_id`
+pub fn block(
+    page_id: &str,
+    id: &str,
+    kind: TextBlockKind,
+    text: &str,
+    update_date: DateTime<Utc>,
+) -> Block {
+    Block::from_text(
+        BlockID::new(id.to_string()),
+        PageID::new(page_id.to_string()),
+        kind,
+        text.to_string(),
+        update_date,
+    )
+}
+
+/// `block` as it is kept in the `Store`, under the Block with the ID `parent_id`
+pub fn stored(block: Block, parent_id: Option<&str>, position: usize) -> StoredBlock {
+    StoredBlock {
+        block,
+        parent_id: parent_id.map(|id| BlockID::new(id.to_string())),
+        position,
+    }
+}
+
+/// A parsed Page with a top-level Block for each of `blocks`, all edited at the same time
+pub fn parsed_page(
+    title: &str,
+    blocks: &[(TextBlockKind, &str)],
+    update_date: DateTime<Utc>,
+) -> ParsedPage {
+    ParsedPage {
+        page_id: PageID::new(title.to_string()),
+        title: title.to_string(),
+        page_content: blocks
+            .iter()
+            .enumerate()
+            .map(|(i, (kind, text))| {
+                build_tree(BlockNode::new(block(
+                    title,
+                    &format!("{}-{}", title, i),
+                    *kind,
+                    text,
+                    update_date,
+                )))
+            })
+            .collect(),
+        database: None,
+        properties: Vec::new(),
+    }
+}
+
+/// A parsed Page with a paragraph for each of `lines`, all edited at the same time
+pub fn paragraphs(title: &str, lines: &[&str], update_date: DateTime<Utc>) -> ParsedPage {
+    let blocks = lines
+        .iter()
+        .map(|line| (TextBlockKind::Paragraph, *line))
+        .collect::<Vec<_>>();
+    parsed_page(title, &blocks, update_date)
+}
+
+/// A directory for a test to write to, which is removed along with everything in it once the test
+/// is done with it
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("navi-{}-{}", name, fastrand::u64(..)));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
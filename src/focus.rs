@@ -0,0 +1,311 @@
+use crate::action_items::to_markdown;
+use crate::error::Result;
+use crate::intelligence::{read_input, stdout_error, Console};
+use crate::llm::{parse_json, JsonSchema, LlmProvider, Message};
+use crate::store::{page_nodes, render_node, Store};
+use crate::transcript::list_sessions;
+use chrono::{DateTime, Duration, Local, Utc};
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Write;
+use std::path::Path;
+use tokio::io::AsyncBufRead;
+
+/// How far back `focus_notes` looks for to-dos that haven't been checked off, which is further
+/// than the notes themselves since to-dos tend to linger
+const OPEN_TO_DO_DAYS: i64 = 30;
+/// The most open to-dos `focus_notes` gives the LLM
+const MAX_OPEN_TO_DOS: usize = 50;
+/// How many of the latest retros `focus_notes` gives the LLM the action items of
+const PAST_RETROS: usize = 3;
+
+const FOCUS_INSTRUCTIONS: &str = "You are Navi, a digital mentor who helps me reflect on my notes. The One Thing, by Gary Keller, asks: what's the one thing I can do such that by doing it everything else will be easier or unnecessary? From my recent notes, my open to-dos and the action items of my past retros, propose the one thing I should focus on in the coming week. Make it a single priority that's specific enough for me to tell at the end of the week how much progress I made on it, and justify it in a few sentences by pointing to what in my notes makes it the one thing.";
+
+/// The One Thing: the single priority the user chose to focus on, which the retros that follow
+/// open by checking on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Focus {
+    pub priority: String,
+    /// Why Navi proposed the priority, or None if the user wrote their own
+    pub justification: Option<String>,
+    pub set_at: DateTime<Utc>,
+}
+
+/// The priority that Navi proposes, for the user to accept or edit
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Proposal {
+    pub priority: String,
+    pub justification: String,
+}
+
+fn proposal_schema() -> JsonSchema {
+    JsonSchema {
+        name: "focus".to_string(),
+        description: "The one priority to focus on in the coming week".to_string(),
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "priority": {
+                    "type": "string",
+                    "description": "The one priority, in a sentence starting with a verb"
+                },
+                "justification": {
+                    "type": "string",
+                    "description": "Why this is the one thing, pointing to the notes"
+                }
+            },
+            "required": ["priority", "justification"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+/// What the LLM is given to propose a focus from: the current focus if there is one, the action
+/// items of the latest retros, the to-dos that haven't been checked off, and the Pages edited since
+/// `cutoff`, the most recently edited first, for as many as fit in `budget` tokens
+pub fn focus_notes(
+    store: &Store,
+    transcripts_dir: &Path,
+    cutoff: DateTime<Utc>,
+    now: DateTime<Utc>,
+    budget: usize,
+    count_tokens: &dyn Fn(&str) -> usize,
+) -> Result<String> {
+    let mut notes = String::new();
+    if let Some(focus) = store.current_focus()? {
+        notes.push_str(&format!(
+            "My current one thing, which I chose on {}, is: {}\n\n",
+            focus.set_at.with_timezone(&Local).format("%Y-%m-%d"),
+            focus.priority
+        ));
+    }
+
+    let sessions = list_sessions(transcripts_dir)?;
+    for (started_at, action_items) in sessions
+        .iter()
+        .rev()
+        .filter_map(|session| Some((session.info.started_at, session.action_items.as_ref()?)))
+        .take(PAST_RETROS)
+    {
+        notes.push_str(&format!(
+            "# Retro of {}\n{}\n",
+            started_at.with_timezone(&Local).format("%Y-%m-%d"),
+            to_markdown(action_items)
+        ));
+    }
+
+    let to_dos = store.open_to_dos(now - Duration::days(OPEN_TO_DO_DAYS), MAX_OPEN_TO_DOS)?;
+    if !to_dos.is_empty() {
+        notes.push_str("# Open to-dos\n");
+        for (title, block) in &to_dos {
+            notes.push_str(&format!("{} (on {})\n", block.to_markdown(), title));
+        }
+        notes.push('\n');
+    }
+
+    let pages = store.pages_edited_between(cutoff, now)?;
+    notes.push_str(&format!(
+        "# Notes edited since {}\n",
+        cutoff.with_timezone(&Local).format("%Y-%m-%d")
+    ));
+    let mut tokens = count_tokens(&notes);
+    for (i, page) in pages.iter().enumerate() {
+        let mut text = format!("Page Title: {}\n", page.title);
+        for node in page_nodes(store.page_blocks(&page.id)?) {
+            render_node(&node, 0, &mut text, false);
+        }
+        tokens += count_tokens(&text);
+        if tokens > budget {
+            debug!(target: "intelligence", "{} of {} pages fit in the budget of {} tokens", i, pages.len(), budget);
+            notes.push_str(&format!(
+                "({} less recently edited pages were left out, as they didn't fit)\n",
+                pages.len() - i
+            ));
+            break;
+        }
+        notes.push_str(&text);
+        notes.push('\n');
+    }
+
+    Ok(notes)
+}
+
+/// Asks the LLM to propose a focus from the `notes` (see `focus_notes`)
+pub async fn propose_focus(llm: &dyn LlmProvider, notes: &str) -> Result<Proposal> {
+    info!(target: "intelligence", "--- Looking for the one thing to focus on");
+    let messages = [Message::system(FOCUS_INSTRUCTIONS), Message::user(notes)];
+    let response = llm.chat_json(&messages, &proposal_schema()).await?;
+    parse_json(&response, "the proposed focus")
+}
+
+/// Shows the user the `proposal` on the `console`, and returns the focus they chose: the proposal
+/// if they accept it, or the priority they typed instead. Returns None if they typed q, which
+/// leaves their focus as it was.
+pub async fn choose_focus<R: AsyncBufRead + Unpin, W: Write>(
+    console: &mut Console<R, W>,
+    proposal: Proposal,
+    now: DateTime<Utc>,
+) -> Result<Option<Focus>> {
+    let Console { input, output } = console;
+    writeln!(
+        output,
+        "Your one thing for the coming week:\n  {}\n\nWhy: {}\n\nPress Enter to make it your focus, type your own priority instead, or type q to leave your focus as it is",
+        proposal.priority, proposal.justification
+    )
+    .map_err(stdout_error)?;
+
+    let focus = match read_input(input, output).await? {
+        None => return Ok(None),
+        Some(line) if line.trim().is_empty() => Focus {
+            priority: proposal.priority,
+            justification: Some(proposal.justification),
+            set_at: now,
+        },
+        Some(line) => Focus {
+            priority: line.trim().to_string(),
+            justification: None,
+            set_at: now,
+        },
+    };
+    writeln!(output, "Your focus is now: {}", focus.priority).map_err(stdout_error)?;
+    Ok(Some(focus))
+}
+
+/// The message that has a retro open by checking on the user's progress on their `focus`
+pub fn focus_prompt(focus: &Focus) -> String {
+    format!(
+        "On {} I chose one thing to focus on: {}. Open the retro by asking me how much progress I made on it and what got in the way, pointing out anything in my notes that bears on it, before you begin step 1.",
+        focus.set_at.with_timezone(&Local).format("%Y-%m-%d"),
+        focus.priority
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::datatypes::TextBlockKind;
+    use crate::fixtures::{block, days_ago, page, stored, TempDir};
+    use crate::llm::scripted::ScriptedLlm;
+    use crate::notion::Notion;
+    use crate::store::StoredBlock;
+
+    /// A top-level Block of the page "Navi launch", edited two days ago
+    fn note(id: &str, kind: TextBlockKind, text: &str, position: usize) -> StoredBlock {
+        stored(block("launch", id, kind, text, days_ago(2)), None, position)
+    }
+
+    #[tokio::test]
+    async fn test_focus_is_proposed_chosen_and_followed_up() {
+        let store = Store::open_in_memory().unwrap();
+        let page = page("launch", "Navi launch", days_ago(2));
+        store
+            .save_page(
+                Notion::NAME,
+                &page,
+                &[
+                    note(
+                        "plan",
+                        TextBlockKind::Paragraph,
+                        "Everything waits on the launch",
+                        0,
+                    ),
+                    note(
+                        "post",
+                        TextBlockKind::ToDo { checked: false },
+                        "Write the launch post",
+                        1,
+                    ),
+                    note(
+                        "domain",
+                        TextBlockKind::ToDo { checked: true },
+                        "Buy the domain",
+                        2,
+                    ),
+                ],
+            )
+            .unwrap();
+
+        let now = Utc::now();
+        let transcripts = TempDir::new("focus");
+        let notes = focus_notes(
+            &store,
+            transcripts.path(),
+            now - Duration::days(7),
+            now,
+            1_000,
+            &|text: &str| text.len() / 4,
+        )
+        .unwrap();
+        assert!(notes.contains("# Open to-dos\n- [ ] Write the launch post (on Navi launch)\n\n"));
+        assert!(notes.contains("Page Title: Navi launch\nEverything waits on the launch\n"));
+        let notes_without_pages = focus_notes(
+            &store,
+            transcripts.path(),
+            now - Duration::days(7),
+            now,
+            10,
+            &|text: &str| text.len() / 4,
+        )
+        .unwrap();
+        assert!(notes_without_pages
+            .ends_with("(1 less recently edited pages were left out, as they didn't fit)\n"));
+
+        let llm = ScriptedLlm::new([
+            r#"{"priority": "Ship the launch post", "justification": "Everything waits on the launch"}"#,
+        ]);
+        let proposal = propose_focus(&llm, &notes).await.unwrap();
+        assert_eq!(proposal.priority, "Ship the launch post");
+        assert_eq!(llm.requests()[0].messages[1].content, notes);
+
+        // the user can accept the proposal, or write their own priority
+        let mut console = Console {
+            input: "\n".as_bytes(),
+            output: Vec::new(),
+        };
+        let accepted = choose_focus(&mut console, proposal.clone(), now)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            accepted.justification.as_deref(),
+            Some("Everything waits on the launch")
+        );
+        let mut console = Console {
+            input: "Finish the onboarding\n".as_bytes(),
+            output: Vec::new(),
+        };
+        let edited = choose_focus(&mut console, proposal.clone(), now)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(edited.priority, "Finish the onboarding");
+        assert_eq!(edited.justification, None);
+        let mut console = Console {
+            input: "q\n".as_bytes(),
+            output: Vec::new(),
+        };
+        assert_eq!(
+            choose_focus(&mut console, proposal, now).await.unwrap(),
+            None
+        );
+
+        store.save_focus(&edited).unwrap();
+        let focus = store.current_focus().unwrap().unwrap();
+        assert_eq!(focus.priority, "Finish the onboarding");
+        assert!(
+            focus_prompt(&focus).contains("I chose one thing to focus on: Finish the onboarding.")
+        );
+        // the next proposal knows what the user is focusing on
+        let notes = focus_notes(
+            &store,
+            transcripts.path(),
+            now - Duration::days(7),
+            now,
+            1_000,
+            &|text: &str| text.len() / 4,
+        )
+        .unwrap();
+        assert!(notes.starts_with("My current one thing, which I chose on "));
+    }
+}
@@ -1,92 +1,741 @@
-use async_openai::{
-    error::OpenAIError,
-    types::{
-        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs,
-        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
-    },
-    Client,
-};
-use log::info;
+use crate::error::{NaviError, Result};
+use crate::llm::{JsonSchema, LlmProvider, Message, ResponseEvent, ResponseStream, Role, ToolCall};
+use crate::retro_format::{RetroFormat, RetroStep};
+use crate::tools::Tools;
+use crate::transcript::Transcript;
+use futures::StreamExt;
+use log::{debug, info};
+use std::future::Future;
+use std::io::Write;
 use std::time::Instant;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Stdin};
 
-const NAVI_INSTRUCTIONS: &str = "I want you to lead a retrospective for me using the information from my weekly notes. You have a vast experience with running retrospectives, and have read all of the most important writing on how to deliver fun, useful, and high-signal retro's from people like Esther Derby and Ben Linders.
+/// The messages a new retro opens with: Navi's instructions for the retro's format, the user's
+/// notes, and the `follow_ups` for the user's focus (see `focus_prompt`) and the action items of
+/// their last retro (see `follow_up_prompt`), if they have any
+pub fn retro_messages(
+    format: &RetroFormat,
+    markdown_notes: String,
+    follow_ups: Vec<String>,
+) -> Vec<Message> {
+    let mut messages = vec![
+        Message::system(format.instructions()),
+        Message::user(format!("Here are my notes:\n{}", markdown_notes)),
+    ];
+    messages.extend(follow_ups.into_iter().map(Message::user));
+    messages
+}
 
-You must adhere to the 4-step outline below in order from 1 to 4 for the retro. Begin with fulfilling the requirements for 1, then 2, then 3, and finally 4. Only proceed to the next step after you have asked the user if it's alright to proceed, and the user acknowledges. The 4 steps are:
-1. Give a synopsis of the user's notes since the last retro. After giving your synopsis, ask the user if the user can add anything to the synopsis that you might have missed. This synopsis must contain:
-    a. a categorization of the topics mentioned in the user's notes
-    b. a list of the people the user communicated with
-    c. a list of the accomplishments the user made. You must sincerely congratulate the user on their accomplishments
-2. Ask the user 'What went well since the last retro?'
-3. Ask the user 'What didn't go well since the last retro?'
-4. Ask the user 'What are 1 to 3 takeaways from this retro that can be made into actionable tasks to work on for the next retro?'
+/// The most rounds of tool calls the LLM may make before a response. The request after the last
+/// round offers no tools, so that the LLM has to answer with what it has found.
+const MAX_TOOL_ROUNDS: usize = 5;
 
-For Step 4, have a conversation with the user about what those tasks should be. Get feedback from the user, but also push back if the user's suggested tasks are not SMART (simple, measurable, achievable, relevant, time oriented).
+/// What the user is told when they type something Navi can't act on
+const COMMANDS_HELP: &str = "type a reply, /next to move on to the next step, /skip to skip this step, /back to go back to the previous one, or exit() to end the retro";
 
-Above all, make sure the vibe for your retro is fun and concise. Do not ramble or go on tangents about topics that will distract the user. Stick to the 4-step outline, and praise the user for their accomplishments.";
+/// Where a retro is in the steps of its format. The user moves it from step to step with
+/// commands (see `take_input`), and each request to the LLM ends with the instructions for the
+/// current step, so that Navi doesn't have to keep track of the steps itself.
+#[derive(Debug, Clone)]
+pub struct RetroState<'a> {
+    format: &'a RetroFormat,
+    /// The current step, counting from 1
+    step: usize,
+}
 
-pub async fn assistant_flow(markdown_notes: String) -> Result<(), OpenAIError> {
-    let mut messages = vec![
-        ChatCompletionRequestSystemMessageArgs::default()
-            .content(NAVI_INSTRUCTIONS)
-            .build()?
-            .into(),
-        ChatCompletionRequestUserMessageArgs::default()
-            .content(format!("Here are my weekly notes:\n{}", markdown_notes).to_string())
-            .build()?
-            .into(),
-    ];
-    //create a client
-    let client = Client::new();
+/// What comes of a line that the user typed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Turn {
+    /// Send the message to Navi, and wait for the response
+    Reply(Message),
+    /// Tell the user this, and wait for their next input
+    Notice(String),
+    /// End the retro
+    End,
+}
+
+impl<'a> RetroState<'a> {
+    /// A retro in `format` that is on the step numbered `step`, counting from 1
+    pub fn new(format: &'a RetroFormat, step: usize) -> RetroState<'a> {
+        RetroState {
+            format,
+            step: step.clamp(1, format.steps.len()),
+        }
+    }
+
+    /// The number of the current step, counting from 1
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    fn current(&self) -> &RetroStep {
+        &self.format.steps[self.step - 1]
+    }
+
+    /// Describes the current step for the user, e.g. "Step 2 of 4: What went well"
+    pub fn heading(&self) -> String {
+        format!(
+            "Step {} of {}: {}",
+            self.step,
+            self.format.steps.len(),
+            self.current().title
+        )
+    }
 
-    // Track timing for each call
-    let mut previous_call_start = Instant::now();
+    /// What the LLM is sent for Navi's next response: the conversation so far, followed by the
+    /// instructions for the current step
+    pub fn request(&self, messages: &[Message]) -> Vec<Message> {
+        let mut request = messages.to_vec();
+        request.push(Message::system(format!(
+            "The retro is on step {} of {} ({}). {} Don't move on from this step until the user does.",
+            self.step,
+            self.format.steps.len(),
+            self.current().title,
+            self.current().instructions()
+        )));
+        request
+    }
+
+    /// Acts on a line that the user typed, which is either a reply to Navi, a command (/next,
+    /// /skip or /back) that moves the retro to another step, or exit() to end the retro
+    pub fn take_input(&mut self, input: &str) -> Turn {
+        let input = input.trim();
+        let last = self.format.steps.len();
+        match input {
+            "exit()" => Turn::End,
+            // an empty line isn't worth a response
+            "" => Turn::Notice(format!("({})", COMMANDS_HELP)),
+            "/next" | "/skip" if self.step == last => Turn::Notice(
+                "This is the last step, type exit() when you're ready to end the retro".to_string(),
+            ),
+            "/next" => {
+                let next = self.step + 1;
+                self.move_to(next, format!("I'm ready to move on to step {}", next))
+            }
+            "/skip" => {
+                let skipped = self.current().title.clone();
+                let next = self.step + 1;
+                self.move_to(
+                    next,
+                    format!(
+                        "Let's skip step {} ({}) and move on to step {}",
+                        self.step, skipped, next
+                    ),
+                )
+            }
+            "/back" if self.step == 1 => Turn::Notice("This is the first step".to_string()),
+            "/back" => {
+                let previous = self.step - 1;
+                self.move_to(previous, format!("Let's go back to step {}", previous))
+            }
+            command if command.starts_with('/') && !command.contains(char::is_whitespace) => {
+                Turn::Notice(format!("{} isn't a command, {}", command, COMMANDS_HELP))
+            }
+            reply => Turn::Reply(Message::user(reply)),
+        }
+    }
+
+    fn move_to(&mut self, step: usize, message: String) -> Turn {
+        self.step = step;
+        debug!(target: "intelligence", "the retro is on step {} ({})", step, self.current().title);
+        Turn::Reply(Message::user(format!(
+            "{}: {}",
+            message,
+            self.current().title
+        )))
+    }
+}
+
+/// Where a retro takes place: the user's input is read from `input`, and the conversation is
+/// written to `output`. Retros are held in the terminal (see `Console::stdio`), and tests hold
+/// them with scripted input instead.
+pub struct Console<R, W> {
+    pub input: R,
+    pub output: W,
+}
+
+impl Console<BufReader<Stdin>, std::io::Stdout> {
+    /// The terminal that Navi was run in
+    pub fn stdio() -> Self {
+        Console {
+            input: BufReader::new(tokio::io::stdin()),
+            output: std::io::stdout(),
+        }
+    }
+}
+
+/// Runs the retro as a conversation between the user and the LLM on the `console`, carrying on
+/// from `messages` at the step that `state` is on, and records each new message in the
+/// `transcript` along with its step. Returns the conversation once the user has ended it.
+///
+/// Responses are streamed to the console's output as the LLM writes them, separately from the log
+/// (which goes to stderr). The LLM may call any of the `tools` before it responds, for up to
+/// `MAX_TOOL_ROUNDS` rounds, and carries on once it has their results. The calls and their results
+/// are recorded in the transcript too.
+///
+/// Ctrl-C stops a response, whether it is being written or hasn't begun yet, and the user can
+/// carry on from there. At the input prompt Ctrl-C ends the retro, as does typing exit() or
+/// closing the input.
+pub async fn assistant_flow<R: AsyncBufRead + Unpin, W: Write>(
+    llm: &dyn LlmProvider,
+    console: &mut Console<R, W>,
+    transcript: &mut Transcript,
+    tools: &Tools,
+    mut state: RetroState<'_>,
+    mut messages: Vec<Message>,
+) -> Result<Vec<Message>> {
+    let model_info = llm.model_info();
+    info!(target: "intelligence", "--- Running the retro with {} ({})", model_info.model, model_info.provider);
+    let definitions = tools.definitions();
+
+    let Console {
+        input: stdin,
+        output: stdout,
+    } = console;
+    writeln!(
+        stdout,
+        "({}. Press Ctrl-C to stop Navi mid-response)\n\n[{}]",
+        COMMANDS_HELP,
+        state.heading()
+    )
+    .map_err(stdout_error)?;
+
+    // a resumed retro that Navi had the last word in carries on with the user's input, after
+    // reminding them what that last word was
+    let mut respond = true;
+    if let Some(last) = messages.last().filter(|last| last.role == Role::Assistant) {
+        writeln!(stdout, "{}", last.content).map_err(stdout_error)?;
+        respond = false;
+    }
 
     // main conversation loop, which consists of first asking the user for input
     // (except for the first loop iteration), then sending that input to the
     // assistant, and finally receiving the assistant's response and printing it
     loop {
-        let request = CreateChatCompletionRequestArgs::default()
-            .max_tokens(16384u32) // this is max number of tokens for gpt-4o-mini
-            .model("gpt-4o-mini")
-            .messages(&*messages)
-            .n(1) // only 1 response
-            .build()?;
+        // the LLM is asked again after each round of tool calls, until it responds without any
+        let mut tool_rounds = 0;
+        while respond {
+            let call_start = Instant::now();
+            // Ctrl-C can stop the response while waiting for it to begin, as well as mid-stream
+            let cancel = tokio::signal::ctrl_c();
+            tokio::pin!(cancel);
+            let offered: &[JsonSchema] = if tool_rounds < MAX_TOOL_ROUNDS {
+                definitions.as_slice()
+            } else {
+                debug!(target: "intelligence", "--- {} rounds of tool calls, asking for a response without tools", tool_rounds);
+                &[]
+            };
+            let request = llm.chat_stream_with_tools(&state.request(&messages), offered);
+            let stream = tokio::select! {
+                stream = request => Some(stream?),
+                _ = &mut cancel => None,
+            };
+            let response = match stream {
+                Some(stream) => print_stream(stream, cancel, stdout).await?,
+                None => StreamedResponse {
+                    cancelled: true,
+                    ..StreamedResponse::default()
+                },
+            };
+            debug!(target: "intelligence", "--- Response took {:.2} seconds", call_start.elapsed().as_secs_f64());
 
-        let response = client.chat().create(request).await?;
-        let new_message = response.choices[0].message.content.clone().unwrap();
+            if response.cancelled {
+                writeln!(stdout, "\n[stopped]").map_err(stdout_error)?;
+            }
+            // calls to tools that weren't offered can't be answered, so only the text is kept
+            if response.tool_calls.is_empty() || response.cancelled || offered.is_empty() {
+                // a response that was stopped before it said anything is left out, and the
+                // user's next message follows straight on from their last one
+                if !response.text.is_empty() {
+                    let message = Message::assistant(response.text);
+                    transcript.record(&message, state.step())?;
+                    messages.push(message);
+                }
+                break;
+            }
 
-        info!(target: "intelligence", "--- Response: {}\n", &new_message);
+            let calls = response.tool_calls.clone();
+            let message = Message::tool_calls(response.text, response.tool_calls);
+            transcript.record(&message, state.step())?;
+            messages.push(message);
+            for call in calls {
+                writeln!(stdout, "[{}]", tools.describe(&call)).map_err(stdout_error)?;
+                let result = tools.call(&call).await;
+                debug!(target: "intelligence", "{} returned:\n{}", call.name, result);
+                let message = Message::tool_result(call.id, result);
+                transcript.record(&message, state.step())?;
+                messages.push(message);
+            }
+            tool_rounds += 1;
+        }
+        respond = true;
 
-        messages.push(
-            ChatCompletionRequestAssistantMessageArgs::default()
-                .content(new_message)
-                .build()?
-                .into(),
-        );
+        write!(stdout, "\n> ").map_err(stdout_error)?;
+        stdout.flush().map_err(stdout_error)?;
+        let mut input = String::new();
+        tokio::select! {
+            read = stdin.read_line(&mut input) => {
+                let read = read.map_err(|e| NaviError::io("unable to read your input", e))?;
+                // stdin was closed, e.g. with Ctrl-D
+                if read == 0 {
+                    break;
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        writeln!(stdout).map_err(stdout_error)?;
 
-        // Calculate and log the time taken for the previous call
-        let elapsed = previous_call_start.elapsed();
-        info!(target: "intelligence", "--- Previous call took {:.2} seconds", elapsed.as_secs_f64());
+        let step = state.step();
+        let message = match state.take_input(&input) {
+            Turn::Reply(message) => message,
+            Turn::Notice(notice) => {
+                writeln!(stdout, "{}", notice).map_err(stdout_error)?;
+                respond = false;
+                continue;
+            }
+            Turn::End => break,
+        };
+        if state.step() != step {
+            writeln!(stdout, "[{}]\n", state.heading()).map_err(stdout_error)?;
+        }
+        transcript.record(&message, state.step())?;
+        messages.push(message);
+    }
 
-        info!(target: "intelligence", "--- Enter your input or type 'exit()' to exit");
-        //get user input
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
+    writeln!(stdout).map_err(stdout_error)?;
+    Ok(messages)
+}
+
+/// What was streamed of a response
+#[derive(Debug, Default, PartialEq, Eq)]
+struct StreamedResponse {
+    text: String,
+    tool_calls: Vec<ToolCall>,
+    /// Whether the user stopped the response before the LLM had finished writing it
+    cancelled: bool,
+}
+
+/// Writes each piece of a streamed response's text to `out` as soon as it arrives, and collects
+/// its tool calls, until the stream ends or `cancel` completes.
+async fn print_stream(
+    mut stream: ResponseStream,
+    cancel: impl Future,
+    out: &mut impl Write,
+) -> Result<StreamedResponse> {
+    tokio::pin!(cancel);
+    let mut response = StreamedResponse::default();
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut cancel => {
+                response.cancelled = true;
+                return Ok(response);
+            }
+            event = stream.next() => match event.transpose()? {
+                Some(ResponseEvent::Text(piece)) => {
+                    write!(out, "{}", piece).map_err(stdout_error)?;
+                    out.flush().map_err(stdout_error)?;
+                    response.text.push_str(&piece);
+                }
+                Some(ResponseEvent::ToolCall(call)) => response.tool_calls.push(call),
+                None => break,
+            },
+        }
+    }
+
+    // a response that only calls tools has no text to end the line of
+    if !response.text.is_empty() {
+        writeln!(out).map_err(stdout_error)?;
+    }
+    Ok(response)
+}
 
-        //break out of the loop if the user enters exit()
-        if input.trim() == "exit()" {
-            break;
+/// Has Ctrl-C end Navi again, as it does by default. Once something has waited on
+/// `tokio::signal::ctrl_c`, tokio's handler stays in place and Ctrl-C does nothing by itself, so
+/// this is for once the retro no longer listens for it (e.g. while the action items are saved).
+pub fn exit_on_ctrl_c() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            // the exit code a shell gives a process that was ended by SIGINT
+            std::process::exit(130);
         }
+    });
+}
 
-        messages.push(
-            ChatCompletionRequestUserMessageArgs::default()
-                .content(input)
-                .build()?
-                .into(),
+/// Prompts for and reads a line of the user's input, or None if the user typed q or closed the
+/// input
+pub async fn read_input<R: AsyncBufRead + Unpin, W: Write>(
+    input: &mut R,
+    output: &mut W,
+) -> Result<Option<String>> {
+    write!(output, "> ").map_err(stdout_error)?;
+    output.flush().map_err(stdout_error)?;
+    let mut line = String::new();
+    let read = input
+        .read_line(&mut line)
+        .await
+        .map_err(|e| NaviError::io("unable to read your input", e))?;
+    if read == 0 || line.trim().eq_ignore_ascii_case("q") {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+pub fn stdout_error(e: std::io::Error) -> NaviError {
+    NaviError::io("unable to write to stdout", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action_items::extract_action_items;
+    use crate::config::Config;
+    use crate::core::prompt::assemble_prompt;
+    use crate::fixtures::TempDir;
+    use crate::llm::{estimate_tokens, scripted::ScriptedLlm};
+    use crate::retro_format::load_format;
+    use crate::sources::{
+        obsidian::Obsidian, parse_last_edited_from_sources, IngestionReport, NoteSource,
+    };
+    use crate::store::Store;
+    use crate::transcript::{load_session, SessionInfo};
+    use chrono::NaiveDate;
+    use futures::{future, stream};
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    fn text_stream(pieces: &[&str]) -> ResponseStream {
+        stream::iter(
+            pieces
+                .iter()
+                .map(|piece| Ok(ResponseEvent::Text(piece.to_string())))
+                .collect::<Vec<_>>(),
+        )
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn test_print_stream_writes_each_piece() {
+        let mut out = Vec::new();
+        let response = print_stream(
+            text_stream(&["Let's ", "begin!"]),
+            future::pending::<()>(),
+            &mut out,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response,
+            StreamedResponse {
+                text: "Let's begin!".to_string(),
+                tool_calls: Vec::new(),
+                cancelled: false,
+            }
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "Let's begin!\n");
+    }
+
+    #[tokio::test]
+    async fn test_print_stream_stops_when_cancelled() {
+        let mut out = Vec::new();
+        // the second piece never arrives, as if the LLM were still thinking
+        let stream = text_stream(&["Let's "]).chain(stream::pending()).boxed();
+        let (cancel_sender, cancel) = tokio::sync::oneshot::channel::<()>();
+
+        let printing = print_stream(stream, cancel, &mut out);
+        let cancelling = async {
+            tokio::task::yield_now().await;
+            cancel_sender.send(()).unwrap();
+        };
+        let (response, _) = tokio::join!(printing, cancelling);
+
+        assert_eq!(
+            response.unwrap(),
+            StreamedResponse {
+                text: "Let's ".to_string(),
+                tool_calls: Vec::new(),
+                cancelled: true,
+            }
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "Let's ");
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_are_answered_before_the_response() {
+        let format = load_format("weekly", None).unwrap();
+        let messages = retro_messages(&format, "- Deployed on Friday".to_string(), Vec::new());
+        let dir = TempDir::new("tools");
+        let info = SessionInfo::new("weekly", "Scripted", "scripted", &messages, String::new());
+        let id = info.id.clone();
+        let mut transcript = Transcript::create(dir.path(), info, &messages).unwrap();
+
+        let search = ToolCall {
+            id: "call_1".to_string(),
+            name: "search_notes".to_string(),
+            arguments: r#"{"query": "deploys"}"#.to_string(),
+        };
+        let llm = ScriptedLlm::replies([
+            Message::tool_calls("", vec![search.clone()]),
+            Message::assistant("This is your first deploy in a while!"),
+        ]);
+        let tools = Tools::new(
+            Rc::new(Store::open_in_memory().unwrap()),
+            dir.path().to_path_buf(),
+        )
+        .with_search(Box::new(ScriptedLlm::new(Vec::<String>::new())), 5);
+        let mut console = Console {
+            input: "".as_bytes(),
+            output: Vec::new(),
+        };
+        let state = RetroState::new(&format, 1);
+        let messages = assistant_flow(&llm, &mut console, &mut transcript, &tools, state, messages)
+            .await
+            .unwrap();
+
+        // the tool call and its result come before the response, and are sent with the request
+        // for it
+        let roles = messages.iter().map(|m| m.role).collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            vec![
+                Role::System,
+                Role::User,
+                Role::Assistant,
+                Role::Tool,
+                Role::Assistant
+            ]
+        );
+        assert_eq!(messages[2].tool_calls, vec![search]);
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(llm.requests()[1].messages[..4], messages[..4]);
+        // and are kept in the transcript, so that a resumed retro has them
+        assert_eq!(
+            load_session(dir.path(), &id).unwrap().conversation(),
+            messages
+        );
+
+        let output = String::from_utf8(console.output).unwrap();
+        assert!(output.contains(
+            "[searching your notes for \"deploys\"]\nThis is your first deploy in a while!\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_stop_after_the_last_round() {
+        let format = load_format("weekly", None).unwrap();
+        let messages = retro_messages(&format, "- Deployed on Friday".to_string(), Vec::new());
+        let dir = TempDir::new("tool-rounds");
+        let info = SessionInfo::new("weekly", "Scripted", "scripted", &messages, String::new());
+        let mut transcript = Transcript::create(dir.path(), info, &messages).unwrap();
+
+        let search = ToolCall {
+            id: "call_1".to_string(),
+            name: "search_notes".to_string(),
+            arguments: r#"{"query": "deploys"}"#.to_string(),
+        };
+        // the LLM would search forever if it were let
+        let mut replies = vec![Message::tool_calls("", vec![search.clone()]); MAX_TOOL_ROUNDS];
+        replies.push(Message::assistant("You deployed on Friday."));
+        let llm = ScriptedLlm::replies(replies);
+        let tools = Tools::new(
+            Rc::new(Store::open_in_memory().unwrap()),
+            dir.path().to_path_buf(),
+        )
+        .with_search(Box::new(ScriptedLlm::new(Vec::<String>::new())), 5);
+        let mut console = Console {
+            input: "".as_bytes(),
+            output: Vec::new(),
+        };
+        let state = RetroState::new(&format, 1);
+        let messages = assistant_flow(&llm, &mut console, &mut transcript, &tools, state, messages)
+            .await
+            .unwrap();
+
+        let requests = llm.requests();
+        assert_eq!(requests.len(), MAX_TOOL_ROUNDS + 1);
+        assert!(requests[..MAX_TOOL_ROUNDS]
+            .iter()
+            .all(|request| !request.tools.is_empty()));
+        assert!(requests[MAX_TOOL_ROUNDS].tools.is_empty());
+        assert_eq!(
+            messages.last(),
+            Some(&Message::assistant("You deployed on Friday."))
+        );
+    }
+
+    #[test]
+    fn test_commands_move_between_steps() {
+        let format = load_format("weekly", None).unwrap();
+        let mut state = RetroState::new(&format, 1);
+
+        assert_eq!(
+            state.take_input("  \n"),
+            Turn::Notice(format!("({})", COMMANDS_HELP))
+        );
+        assert_eq!(
+            state.take_input("/back"),
+            Turn::Notice("This is the first step".to_string())
+        );
+        assert!(matches!(state.take_input("/nxet"), Turn::Notice(_)));
+        assert_eq!(
+            state.take_input("I also ran a marathon\n"),
+            Turn::Reply(Message::user("I also ran a marathon"))
         );
+        assert_eq!(state.step(), 1);
 
-        // Update the start time for the next call
-        previous_call_start = Instant::now();
+        assert_eq!(
+            state.take_input("/next\n"),
+            Turn::Reply(Message::user(
+                "I'm ready to move on to step 2: What went well"
+            ))
+        );
+        assert_eq!(
+            state.take_input("/skip"),
+            Turn::Reply(Message::user(
+                "Let's skip step 2 (What went well) and move on to step 3: What didn't go well"
+            ))
+        );
+        assert_eq!(
+            state.take_input("/back"),
+            Turn::Reply(Message::user("Let's go back to step 2: What went well"))
+        );
+        state.take_input("/next");
+        state.take_input("/next");
+        assert_eq!(state.heading(), "Step 4 of 4: Takeaways");
+        assert!(matches!(state.take_input("/next"), Turn::Notice(_)));
+        assert_eq!(state.take_input("exit()"), Turn::End);
+
+        // the request ends with the instructions for the step the retro is on
+        let request = state.request(&[Message::user("Here are my notes")]);
+        assert_eq!(request.len(), 2);
+        assert_eq!(request[1].role, Role::System);
+        assert!(request[1]
+            .content
+            .starts_with("The retro is on step 4 of 4 (Takeaways). Ask the user 'What are 1 to 3"));
+
+        // a resumed retro can't be on a step the format doesn't have
+        assert_eq!(RetroState::new(&format, 9).step(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_retro_from_notes_to_action_items() {
+        let cutoff = NaiveDate::from_ymd_opt(2024, 11, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc();
+        let sources: Vec<Box<dyn NoteSource>> = vec![Box::new(Obsidian::new(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/obsidian_vault"),
+            Config::default(),
+        ))];
+        let pages =
+            parse_last_edited_from_sources(&sources, cutoff, &mut IngestionReport::default())
+                .await
+                .unwrap();
+        let (notes, _) = assemble_prompt(
+            pages,
+            cutoff,
+            cutoff + chrono::Duration::days(10),
+            10_000,
+            &estimate_tokens,
+        );
+
+        let format = load_format("weekly", None).unwrap();
+        let messages = retro_messages(&format, notes, Vec::new());
+        let dir = TempDir::new("retro");
+        let info = SessionInfo::new(
+            "weekly",
+            "Scripted",
+            "scripted",
+            &messages,
+            "abc".to_string(),
+        );
+        let id = info.id.clone();
+        let mut transcript = Transcript::create(dir.path(), info, &messages).unwrap();
+
+        let llm = ScriptedLlm::new([
+            "You finished the CLI MVP, congratulations! Did I miss anything?",
+            "Well done on the marathon!",
+            "What went well since the last retro?",
+            "Great, resting is important.",
+            "What didn't go well since the last retro?",
+            "Let's make that an action item.",
+            r#"{"action_items": [{"title": "Practice coding challenges", "outcome": "5 challenges solved", "due": "2024-11-15", "topics": ["Interviews"]}]}"#,
+        ]);
+        // the input ends without exit(), as it does when stdin is closed
+        let mut console = Console {
+            input: "I also ran a marathon\n/next\nI rested a lot\n\n/skip\nI'll solve 5 coding challenges by Friday\n".as_bytes(),
+            output: Vec::new(),
+        };
+        let state = RetroState::new(&format, 1);
+        let tools = Tools::new(
+            Rc::new(Store::open_in_memory().unwrap()),
+            dir.path().to_path_buf(),
+        );
+        let messages = assistant_flow(&llm, &mut console, &mut transcript, &tools, state, messages)
+            .await
+            .unwrap();
+
+        let today = NaiveDate::from_ymd_opt(2024, 11, 11).unwrap();
+        let action_items = extract_action_items(&llm, &messages, today).await.unwrap();
+        assert_eq!(action_items.len(), 1);
+        assert_eq!(action_items[0].title, "Practice coding challenges");
+
+        // each request ends with the instructions for the step the retro was on
+        let requests = llm.requests();
+        assert_eq!(requests.len(), 7);
+        assert!(requests[0].messages[1]
+            .content
+            .contains("Finished the CLI MVP"));
+        let steps = requests[..6]
+            .iter()
+            .map(|request| {
+                let instructions = &request.messages.last().unwrap().content;
+                instructions["The retro is on step ".len()..]
+                    .chars()
+                    .next()
+                    .unwrap()
+            })
+            .collect::<String>();
+        assert_eq!(steps, "112233");
+        assert_eq!(
+            requests[4].messages[requests[4].messages.len() - 2],
+            Message::user(
+                "Let's skip step 2 (What went well) and move on to step 3: What didn't go well"
+            )
+        );
+        assert_eq!(requests[6].schema.as_ref().unwrap().name, "action_items");
+
+        let output = String::from_utf8(console.output).unwrap();
+        assert!(output.contains("[Step 2 of 4: What went well]"));
+        assert!(output.contains(&format!("({})", COMMANDS_HELP)));
+        assert!(output.contains("Well done on the marathon!"));
+
+        // the transcript has the step of each message after the opening ones
+        let session = load_session(dir.path(), &id).unwrap();
+        let steps = session
+            .messages
+            .iter()
+            .map(|saved| saved.step)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                None,
+                None,
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(2),
+                Some(2),
+                Some(2),
+                Some(3),
+                Some(3),
+                Some(3),
+                Some(3)
+            ]
+        );
     }
-    Ok(())
 }
@@ -1,4 +1,22 @@
+pub mod action_items;
 pub mod config;
 pub mod core;
+pub mod digest;
+pub mod error;
+/// Notes and pages for the tests of every module to share
+#[cfg(test)]
+mod fixtures;
+pub mod focus;
 pub mod intelligence;
+pub mod llm;
 pub mod notion;
+pub mod retro_format;
+pub mod retro_summary;
+pub mod retry;
+pub mod review;
+pub mod search;
+pub mod sources;
+pub mod srs;
+pub mod store;
+pub mod tools;
+pub mod transcript;
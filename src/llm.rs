@@ -0,0 +1,432 @@
+pub mod anthropic;
+pub mod openai;
+pub mod scripted;
+
+use crate::config::{Config, LlmProviderKind, LlmSettings};
+use crate::error::{NaviError, Result};
+use crate::retry::RetryPolicy;
+use anthropic::Anthropic;
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use openai::OpenAi;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::env;
+
+/// Who a `Message` in a conversation is from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// The result of a tool that the assistant called
+    Tool,
+}
+
+/// One message of a conversation with an LLM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    /// The tools that the assistant called in this message, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// For the result of a tool, the ID of the call it is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Message {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Message::new(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Message::new(Role::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Message::new(Role::Assistant, content)
+    }
+
+    /// A message from the assistant that calls tools, along with whatever it wrote before calling
+    /// them
+    pub fn tool_calls(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Message {
+            tool_calls,
+            ..Message::assistant(content)
+        }
+    }
+
+    /// The result of the tool call with the ID `tool_call_id`
+    pub fn tool_result(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Message {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Message::new(Role::Tool, content)
+        }
+    }
+}
+
+/// A call that the assistant made to one of the tools it was offered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Identifies the call, so that its result can be matched up with it
+    pub id: String,
+    pub name: String,
+    /// The arguments the tool was called with, as a JSON object
+    pub arguments: String,
+}
+
+/// What we know about the model behind an `LlmProvider`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelInfo {
+    /// The name of the provider, e.g. "OpenAI", used in logs
+    pub provider: String,
+    /// The model's name as the provider's API knows it, e.g. "gpt-4o-mini"
+    pub model: String,
+    /// The most tokens the model accepts, prompt and response together
+    pub context_window: u32,
+    /// The most tokens the model will write in a single response
+    pub max_output_tokens: u32,
+}
+
+impl ModelInfo {
+    /// Looks up the token limits of well known models, falling back to the `[llm]` section of
+    /// navi.toml for the rest (e.g. local models), or to conservative defaults if those aren't set.
+    pub fn new(provider: &str, model: &str, settings: &LlmSettings) -> Self {
+        let (context_window, max_output_tokens) = known_token_limits(model)
+            .unwrap_or((DEFAULT_CONTEXT_WINDOW, DEFAULT_MAX_OUTPUT_TOKENS));
+        ModelInfo {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            context_window: settings.context_window.unwrap_or(context_window),
+            max_output_tokens: settings.max_output_tokens.unwrap_or(max_output_tokens),
+        }
+    }
+}
+
+/// The token limits we assume for models we don't know, small enough that most local models
+/// can handle them
+const DEFAULT_CONTEXT_WINDOW: u32 = 8_192;
+const DEFAULT_MAX_OUTPUT_TOKENS: u32 = 2_048;
+
+/// Returns the (context window, max output tokens) of the models we know about. Model names
+/// are matched by prefix, so that dated snapshots (e.g. "gpt-4o-2024-08-06") match too.
+fn known_token_limits(model: &str) -> Option<(u32, u32)> {
+    const KNOWN_MODELS: [(&str, u32, u32); 8] = [
+        ("gpt-4o-mini", 128_000, 16_384),
+        ("gpt-4o", 128_000, 16_384),
+        ("gpt-4-turbo", 128_000, 4_096),
+        ("o1-mini", 128_000, 65_536),
+        ("claude-3-5-sonnet", 200_000, 8_192),
+        ("claude-3-5-haiku", 200_000, 8_192),
+        ("claude-3-opus", 200_000, 4_096),
+        ("claude-3-haiku", 200_000, 4_096),
+    ];
+
+    KNOWN_MODELS
+        .into_iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))
+        .map(|(_, context_window, max_output_tokens)| (context_window, max_output_tokens))
+}
+
+/// Roughly how many tokens `text` is, for models whose tokenizer we don't have. English text
+/// averages about 4 characters per token with most tokenizers.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// How many tokens of notes go in the retro prompt. Unless navi.toml sets a budget, the notes get
+/// half of what's left of the context window once the response is accounted for, so that there's
+/// room for the instructions and the rest of the conversation.
+pub fn notes_token_budget(settings: &LlmSettings, model_info: &ModelInfo) -> usize {
+    match settings.notes_token_budget {
+        Some(budget) => budget as usize,
+        None => {
+            (model_info
+                .context_window
+                .saturating_sub(model_info.max_output_tokens)
+                / 2) as usize
+        }
+    }
+}
+
+/// A JSON schema that a response has to match, for asking a model for structured output
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonSchema {
+    /// A name for what the schema describes, e.g. "action_items"
+    pub name: String,
+    pub description: String,
+    /// The schema itself, whose top level must be an object
+    pub schema: serde_json::Value,
+}
+
+/// Parses the JSON out of a model's response, which may have wrapped it in a Markdown code block
+/// or said something before it. `what` names what the JSON is, for the error if it doesn't parse.
+pub fn parse_json<T: DeserializeOwned>(response: &str, what: &str) -> Result<T> {
+    let json = match (response.find('{'), response.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &response[start..=end],
+        _ => response,
+    };
+
+    serde_json::from_str(json).map_err(|source| NaviError::Deserialization {
+        what: what.to_string(),
+        source,
+    })
+}
+
+/// A stream of the pieces of text an LLM writes as it generates a response
+pub type TextStream = BoxStream<'static, Result<String>>;
+
+/// Something an LLM did while generating a response that may call tools
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseEvent {
+    /// A piece of the response's text
+    Text(String),
+    /// A call to a tool, which is only streamed once the whole call has been written
+    ToolCall(ToolCall),
+}
+
+/// A stream of what an LLM does as it generates a response that may call tools
+pub type ResponseStream = BoxStream<'static, Result<ResponseEvent>>;
+
+/// An LLM that Navi can hold a conversation with, such as OpenAI's or Anthropic's models, or a
+/// local model served over an OpenAI-compatible API.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    fn model_info(&self) -> &ModelInfo;
+
+    /// How many tokens `text` is for this provider's model
+    fn count_tokens(&self, text: &str) -> usize {
+        estimate_tokens(text)
+    }
+
+    /// Returns the model's response to the conversation so far
+    async fn chat(&self, messages: &[Message]) -> Result<String>;
+
+    /// Like `chat`, but returns the response piece by piece as the model writes it
+    async fn chat_stream(&self, messages: &[Message]) -> Result<TextStream>;
+
+    /// Like `chat_stream`, but the model may call any of the `tools` (each described by the schema
+    /// of its arguments) as part of its response. Once the calls' results have been added to the
+    /// conversation as `Message::tool_result`s, the model carries on from them.
+    ///
+    /// Providers that can't call tools leave this as it is, which streams a response without
+    /// offering the tools at all.
+    async fn chat_stream_with_tools(
+        &self,
+        messages: &[Message],
+        _tools: &[JsonSchema],
+    ) -> Result<ResponseStream> {
+        Ok(self
+            .chat_stream(messages)
+            .await?
+            .map(|piece| piece.map(ResponseEvent::Text))
+            .boxed())
+    }
+
+    /// Returns the model's response to the conversation as JSON that matches `schema`.
+    ///
+    /// Providers that can constrain their output to a schema override this. By default the schema
+    /// is only asked for in the prompt, so the response may still need `parse_json` to clean it up.
+    async fn chat_json(&self, messages: &[Message], schema: &JsonSchema) -> Result<String> {
+        self.chat(&json_prompt(messages, schema)).await
+    }
+
+    /// Embeds each of `texts` as a vector, such that texts about the same things have similar
+    /// vectors, which is how the notes are searched (see search.rs). Only providers created by
+    /// `embedder_from_config` are asked for embeddings.
+    async fn embed(&self, _texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Err(NaviError::Llm(format!(
+            "{} can't embed text",
+            self.model_info().provider
+        )))
+    }
+}
+
+/// The conversation in `messages`, followed by a request to reply with JSON that matches `schema`,
+/// for providers that can't hold their output to a schema (see `LlmProvider::chat_json`)
+pub fn json_prompt(messages: &[Message], schema: &JsonSchema) -> Vec<Message> {
+    let mut messages = messages.to_vec();
+    messages.push(Message::user(format!(
+        "Reply with only a JSON object that matches this JSON schema:\n{}",
+        schema.schema
+    )));
+    messages
+}
+
+/// Creates the `LlmProvider` selected by the `[llm]` section of navi.toml. API keys are read from
+/// the environment (e.g. OPENAI_API_KEY), so that they stay out of navi.toml.
+pub fn provider_from_config(config: &Config) -> Result<Box<dyn LlmProvider>> {
+    let settings = &config.llm;
+    let api_key = |variable: &str| read_api_key(variable, settings, "[llm]");
+
+    Ok(match settings.provider {
+        LlmProviderKind::OpenAi => Box::new(OpenAi::new(
+            settings
+                .base_url
+                .as_deref()
+                .unwrap_or(openai::OPENAI_API_URL),
+            api_key(settings.api_key_env.as_deref().unwrap_or("OPENAI_API_KEY"))?,
+            settings.model.as_deref().unwrap_or(openai::DEFAULT_MODEL),
+            settings,
+        )),
+        LlmProviderKind::Anthropic => Box::new(Anthropic::new(
+            settings
+                .base_url
+                .as_deref()
+                .unwrap_or(anthropic::ANTHROPIC_API_URL),
+            api_key(
+                settings
+                    .api_key_env
+                    .as_deref()
+                    .unwrap_or("ANTHROPIC_API_KEY"),
+            )?,
+            settings
+                .model
+                .as_deref()
+                .unwrap_or(anthropic::DEFAULT_MODEL),
+            settings,
+            RetryPolicy::from_settings(&config.retry),
+        )?),
+        LlmProviderKind::OpenAiCompatible => {
+            Box::new(openai_compatible_provider(settings, "[llm]")?)
+        }
+    })
+}
+
+/// What's known of the model that the `[llm]` section of navi.toml selects, without creating its
+/// provider (which needs its API key), e.g. for fitting notes to its token budget
+pub fn model_info_from_config(settings: &LlmSettings) -> ModelInfo {
+    let model = settings
+        .model
+        .as_deref()
+        .unwrap_or(match settings.provider {
+            LlmProviderKind::OpenAi => openai::DEFAULT_MODEL,
+            LlmProviderKind::Anthropic => anthropic::DEFAULT_MODEL,
+            // these have no default model, so Navi assumes the limits of a small one
+            LlmProviderKind::OpenAiCompatible => "",
+        });
+    ModelInfo::new(settings.provider.name(), model, settings)
+}
+
+/// Creates the provider that the notes are embedded with for searching them, as selected by the
+/// `[search.embeddings]` section of navi.toml. This can be a local model served over an
+/// OpenAI-compatible API, so that the notes never leave the user's machine.
+pub fn embedder_from_config(config: &Config) -> Result<Box<dyn LlmProvider>> {
+    let settings = &config.search.embeddings;
+    let section = "[search.embeddings]";
+    Ok(match settings.provider {
+        LlmProviderKind::OpenAi => Box::new(OpenAi::new(
+            settings
+                .base_url
+                .as_deref()
+                .unwrap_or(openai::OPENAI_API_URL),
+            read_api_key(
+                settings.api_key_env.as_deref().unwrap_or("OPENAI_API_KEY"),
+                settings,
+                section,
+            )?,
+            settings
+                .model
+                .as_deref()
+                .unwrap_or(openai::DEFAULT_EMBEDDING_MODEL),
+            settings,
+        )),
+        LlmProviderKind::Anthropic => {
+            return Err(NaviError::Config(format!(
+                "Anthropic has no embedding models, so the provider in the {} section of navi.toml must be openai or openai_compatible",
+                section
+            )))
+        }
+        LlmProviderKind::OpenAiCompatible => {
+            Box::new(openai_compatible_provider(settings, section)?)
+        }
+    })
+}
+
+/// Creates a provider for a server that implements OpenAI's API, as set up by `settings`, which
+/// are the `section` of navi.toml
+fn openai_compatible_provider(settings: &LlmSettings, section: &str) -> Result<OpenAi> {
+    let missing = |setting: &str| {
+        NaviError::Config(format!(
+            "{} must be set in the {} section of navi.toml to use an OpenAI-compatible provider",
+            setting, section
+        ))
+    };
+    let base_url = settings
+        .base_url
+        .as_deref()
+        .ok_or_else(|| missing("base_url"))?;
+    let model = settings.model.as_deref().ok_or_else(|| missing("model"))?;
+    // local servers such as Ollama usually don't check the API key at all
+    let api_key = match &settings.api_key_env {
+        Some(variable) => read_api_key(variable, settings, section)?,
+        None => String::new(),
+    };
+    Ok(OpenAi::new(base_url, api_key, model, settings))
+}
+
+/// Reads an API key from the environment `variable`, for the provider that `settings` (the
+/// `section` of navi.toml) selects
+fn read_api_key(variable: &str, settings: &LlmSettings, section: &str) -> Result<String> {
+    env::var(variable).map_err(|_| {
+        NaviError::Config(format!(
+            "{} must be set to use the {} provider in the {} section of navi.toml",
+            variable,
+            settings.provider.name(),
+            section
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_info_token_limits() {
+        let settings = LlmSettings::default();
+        let info = ModelInfo::new("OpenAI", "gpt-4o-2024-08-06", &settings);
+        assert_eq!(
+            (info.context_window, info.max_output_tokens),
+            (128_000, 16_384)
+        );
+
+        // unknown models get the defaults, unless navi.toml says otherwise
+        let info = ModelInfo::new("OpenAI-compatible", "llama3.1", &settings);
+        assert_eq!(
+            (info.context_window, info.max_output_tokens),
+            (DEFAULT_CONTEXT_WINDOW, DEFAULT_MAX_OUTPUT_TOKENS)
+        );
+
+        let settings = LlmSettings {
+            context_window: Some(32_768),
+            ..LlmSettings::default()
+        };
+        let info = ModelInfo::new("OpenAI-compatible", "llama3.1", &settings);
+        assert_eq!(info.context_window, 32_768);
+    }
+
+    #[test]
+    fn test_notes_token_budget() {
+        let settings = LlmSettings::default();
+        let info = ModelInfo::new("OpenAI", "gpt-4o-mini", &settings);
+        assert_eq!(notes_token_budget(&settings, &info), 55_808);
+
+        let settings = LlmSettings {
+            notes_token_budget: Some(20_000),
+            ..LlmSettings::default()
+        };
+        assert_eq!(notes_token_budget(&settings, &info), 20_000);
+    }
+}
@@ -0,0 +1,700 @@
+use super::{
+    JsonSchema, LlmProvider, Message, ModelInfo, ResponseEvent, ResponseStream, Role, TextStream,
+    ToolCall,
+};
+use crate::config::LlmSettings;
+use crate::error::{NaviError, Result};
+use crate::retry::{send, send_for_response, with_retry, HttpError, RetryPolicy};
+use async_trait::async_trait;
+use futures::{future, stream, stream::BoxStream, StreamExt};
+use log::debug;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+pub const ANTHROPIC_API_URL: &str = "https://api.anthropic.com/v1";
+pub const DEFAULT_MODEL: &str = "claude-3-5-sonnet-latest";
+/// The version of the Messages API that the types below describe
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// An `LlmProvider` for Anthropic's [Messages API](https://docs.anthropic.com/en/api/messages).
+///
+/// There's no Anthropic client as widely used as `async_openai`, so like `Notion` this talks to
+/// the API over plain HTTP, retrying failed requests according to the `[retry]` section of navi.toml.
+pub struct Anthropic {
+    http: reqwest::Client,
+    base_url: String,
+    retry_policy: RetryPolicy,
+    model_info: ModelInfo,
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    /// Anthropic takes the system prompt separately from the rest of the conversation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<Value>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice<'a>>,
+}
+
+/// A tool the model can use. Besides the tools Navi offers during a retro, Navi gets structured
+/// output by giving the model a single tool whose input is the JSON it wants, and making the model
+/// use it.
+#[derive(Serialize)]
+struct Tool<'a> {
+    name: &'a str,
+    description: &'a str,
+    input_schema: &'a serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct ToolChoice<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: &'a str,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+    /// The input of a tool_use block
+    #[serde(default)]
+    input: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+/// The events of a streamed response that we care about. See
+/// [Streaming Messages](https://docs.anthropic.com/en/api/messages-streaming) for the rest.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    ContentBlockStart {
+        index: usize,
+        content_block: StartedBlock,
+    },
+    ContentBlockDelta {
+        #[serde(default)]
+        index: usize,
+        delta: Delta,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    Error {
+        error: ApiError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// The start of a content block of a streamed response, which for a tool call says which tool
+/// is being called
+#[derive(Deserialize)]
+struct StartedBlock {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    #[serde(default)]
+    text: String,
+    /// The next piece of the JSON input of a tool call
+    #[serde(default)]
+    partial_json: String,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    message: String,
+}
+
+impl Anthropic {
+    pub fn new(
+        base_url: &str,
+        api_key: String,
+        model: &str,
+        settings: &LlmSettings,
+        retry_policy: RetryPolicy,
+    ) -> Result<Self> {
+        let mut api_key_header = HeaderValue::from_str(&api_key).map_err(|_| {
+            NaviError::Config("the Anthropic API key is not a valid HTTP header value".to_string())
+        })?;
+        api_key_header.set_sensitive(true);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", api_key_header);
+        headers.insert(
+            "anthropic-version",
+            HeaderValue::from_static(ANTHROPIC_VERSION),
+        );
+        let http = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(|e| NaviError::Llm(format!("unable to build an HTTP client: {}", e)))?;
+
+        Ok(Anthropic {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            retry_policy,
+            model_info: ModelInfo::new(settings.provider.name(), model, settings),
+        })
+    }
+
+    /// The body of a request for a response to `messages` that may use the `tools`, or that has
+    /// to use the tool called `forced_tool`
+    fn request_body(
+        &self,
+        messages: &[Message],
+        stream: bool,
+        tools: &[JsonSchema],
+        forced_tool: Option<&str>,
+    ) -> Result<String> {
+        let system = messages
+            .iter()
+            .filter(|message| message.role == Role::System)
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let request = MessagesRequest {
+            model: &self.model_info.model,
+            max_tokens: self.model_info.max_output_tokens,
+            system: (!system.is_empty()).then_some(system),
+            messages: to_anthropic_messages(messages),
+            stream,
+            tools: tools
+                .iter()
+                .map(|tool| Tool {
+                    name: &tool.name,
+                    description: &tool.description,
+                    input_schema: &tool.schema,
+                })
+                .collect(),
+            tool_choice: forced_tool.map(|name| ToolChoice { kind: "tool", name }),
+        };
+
+        serde_json::to_string(&request).map_err(|source| NaviError::Deserialization {
+            what: "the body of POST /messages".to_string(),
+            source,
+        })
+    }
+
+    fn post_messages(&self, body: &str) -> reqwest::RequestBuilder {
+        self.http
+            .post(format!("{}/messages", self.base_url))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+    }
+
+    async fn send_messages(&self, body: &str) -> Result<MessagesResponse> {
+        let response = with_retry(&self.retry_policy, "POST /messages", || {
+            send(self.post_messages(body))
+        })
+        .await
+        .map_err(network_error)?;
+
+        let response: MessagesResponse =
+            serde_json::from_str(&response).map_err(|source| NaviError::Deserialization {
+                what: "Anthropic's response to POST /messages".to_string(),
+                source,
+            })?;
+        if let Some(usage) = &response.usage {
+            debug!(target: "intelligence", "{} used {} input and {} output tokens", self.model_info.model, usage.input_tokens, usage.output_tokens);
+        }
+        Ok(response)
+    }
+
+    /// Sends a request for a streamed response, and returns the events it is streamed as
+    async fn stream_events(&self, body: &str) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+        let response = with_retry(&self.retry_policy, "POST /messages", || {
+            send_for_response(self.post_messages(body))
+        })
+        .await
+        .map_err(network_error)?;
+
+        let data = stream::unfold(
+            (response.bytes_stream().boxed(), Vec::new(), false),
+            |(mut body, mut buffer, mut finished)| async move {
+                loop {
+                    if let Some(data) = next_event_data(&mut buffer, finished) {
+                        return Some((Ok(data), (body, buffer, finished)));
+                    }
+                    if finished {
+                        return None;
+                    }
+
+                    match body.next().await {
+                        Some(Ok(bytes)) => buffer.extend(bytes.iter().filter(|b| **b != b'\r')),
+                        Some(Err(e)) => {
+                            return Some((Err(network_error(e.into())), (body, buffer, true)))
+                        }
+                        None => finished = true,
+                    }
+                }
+            },
+        );
+
+        Ok(data
+            .filter_map(|data| async move {
+                let data = match data {
+                    Ok(data) => data,
+                    Err(e) => return Some(Err(e)),
+                };
+                match serde_json::from_str::<StreamEvent>(&data) {
+                    Ok(StreamEvent::Error { error }) => Some(Err(NaviError::Llm(error.message))),
+                    Ok(StreamEvent::Other) => None,
+                    Ok(event) => Some(Ok(event)),
+                    Err(source) => Some(Err(NaviError::Deserialization {
+                        what: "an event streamed from POST /messages".to_string(),
+                        source,
+                    })),
+                }
+            })
+            .boxed())
+    }
+}
+
+/// Converts the conversation (other than the system prompt) to Anthropic's messages. Tool calls
+/// are content blocks of the assistant's message, and their results are content blocks of the
+/// user's next message.
+fn to_anthropic_messages(messages: &[Message]) -> Vec<Value> {
+    let mut anthropic_messages: Vec<Value> = Vec::new();
+    for message in messages {
+        match message.role {
+            Role::System => {}
+            Role::User => {
+                anthropic_messages.push(json!({"role": "user", "content": message.content}))
+            }
+            Role::Assistant if message.tool_calls.is_empty() => {
+                anthropic_messages.push(json!({"role": "assistant", "content": message.content}))
+            }
+            Role::Assistant => {
+                let mut content = Vec::new();
+                if !message.content.is_empty() {
+                    content.push(json!({"type": "text", "text": message.content}));
+                }
+                for call in &message.tool_calls {
+                    let input = serde_json::from_str::<Value>(&call.arguments)
+                        .unwrap_or_else(|_| json!({}));
+                    content.push(json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": input
+                    }));
+                }
+                anthropic_messages.push(json!({"role": "assistant", "content": content}));
+            }
+            Role::Tool => {
+                let result = json!({
+                    "type": "tool_result",
+                    "tool_use_id": message.tool_call_id,
+                    "content": message.content
+                });
+                // the results of the calls an assistant message made all go in one user message
+                match anthropic_messages.last_mut() {
+                    Some(last) if last["role"] == "user" && last["content"].is_array() => {
+                        last["content"].as_array_mut().unwrap().push(result)
+                    }
+                    _ => anthropic_messages.push(json!({"role": "user", "content": [result]})),
+                }
+            }
+        }
+    }
+    anthropic_messages
+}
+
+fn network_error(source: HttpError) -> NaviError {
+    NaviError::Network {
+        request: "POST /messages".to_string(),
+        page_id: None,
+        block_id: None,
+        source,
+    }
+}
+
+#[async_trait]
+impl LlmProvider for Anthropic {
+    fn model_info(&self) -> &ModelInfo {
+        &self.model_info
+    }
+
+    async fn chat(&self, messages: &[Message]) -> Result<String> {
+        let body = self.request_body(messages, false, &[], None)?;
+        let text = self
+            .send_messages(&body)
+            .await?
+            .content
+            .into_iter()
+            .filter(|block| block.kind == "text")
+            .map(|block| block.text)
+            .collect::<String>();
+        if text.is_empty() {
+            return Err(NaviError::Llm(
+                "the response had no message in it".to_string(),
+            ));
+        }
+        Ok(text)
+    }
+
+    /// Makes the model reply by using a tool whose input is the JSON, which Anthropic
+    /// [recommends](https://docs.anthropic.com/en/docs/build-with-claude/tool-use#json-mode) for
+    /// getting JSON that matches a schema
+    async fn chat_json(&self, messages: &[Message], schema: &JsonSchema) -> Result<String> {
+        let body = self.request_body(
+            messages,
+            false,
+            std::slice::from_ref(schema),
+            Some(&schema.name),
+        )?;
+        let input = self
+            .send_messages(&body)
+            .await?
+            .content
+            .into_iter()
+            .find(|block| block.kind == "tool_use")
+            .and_then(|block| block.input)
+            .ok_or_else(|| NaviError::Llm(format!("the response had no {} in it", schema.name)))?;
+
+        Ok(input.to_string())
+    }
+
+    async fn chat_stream(&self, messages: &[Message]) -> Result<TextStream> {
+        let body = self.request_body(messages, true, &[], None)?;
+        Ok(self
+            .stream_events(&body)
+            .await?
+            .filter_map(|event| {
+                future::ready(match event {
+                    Ok(StreamEvent::ContentBlockDelta { delta, .. }) => Some(Ok(delta.text)),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                })
+            })
+            .boxed())
+    }
+
+    async fn chat_stream_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[JsonSchema],
+    ) -> Result<ResponseStream> {
+        let body = self.request_body(messages, true, tools, None)?;
+        // a tool call's input arrives as pieces of JSON, and the call is passed on once its
+        // content block stops
+        Ok(self
+            .stream_events(&body)
+            .await?
+            .scan(HashMap::<usize, ToolCall>::new(), |calls, event| {
+                let event = match event {
+                    Ok(StreamEvent::ContentBlockStart {
+                        index,
+                        content_block,
+                    }) if content_block.kind == "tool_use" => {
+                        calls.insert(
+                            index,
+                            ToolCall {
+                                id: content_block.id,
+                                name: content_block.name,
+                                arguments: String::new(),
+                            },
+                        );
+                        None
+                    }
+                    Ok(StreamEvent::ContentBlockDelta { index, delta }) => {
+                        match calls.get_mut(&index) {
+                            Some(call) => {
+                                call.arguments.push_str(&delta.partial_json);
+                                None
+                            }
+                            None if delta.text.is_empty() => None,
+                            None => Some(Ok(ResponseEvent::Text(delta.text))),
+                        }
+                    }
+                    Ok(StreamEvent::ContentBlockStop { index }) => {
+                        calls.remove(&index).map(|mut call| {
+                            // a tool without parameters is called without any input
+                            if call.arguments.is_empty() {
+                                call.arguments = "{}".to_string();
+                            }
+                            Ok(ResponseEvent::ToolCall(call))
+                        })
+                    }
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                };
+                future::ready(Some(event))
+            })
+            .filter_map(future::ready)
+            .boxed())
+    }
+}
+
+/// Takes the next complete [server-sent event](https://html.spec.whatwg.org/multipage/server-sent-events.html)
+/// off the front of `buffer`, and returns its data. Once the body has `finished`, whatever is
+/// left in the buffer counts as the last event.
+fn next_event_data(buffer: &mut Vec<u8>, finished: bool) -> Option<String> {
+    loop {
+        let event = match buffer.windows(2).position(|window| window == b"\n\n") {
+            Some(end) => {
+                let event = buffer[..end].to_vec();
+                buffer.drain(..end + 2);
+                event
+            }
+            None if finished && !buffer.is_empty() => std::mem::take(buffer),
+            None => return None,
+        };
+
+        let data = String::from_utf8_lossy(&event)
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim_start)
+            .collect::<Vec<_>>()
+            .join("\n");
+        // events without data (e.g. comments used as keep-alives) are skipped
+        if !data.is_empty() {
+            return Some(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LlmProviderKind;
+    use futures::TryStreamExt;
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn mock_anthropic(server: &MockServer) -> Anthropic {
+        let settings = LlmSettings {
+            provider: LlmProviderKind::Anthropic,
+            ..LlmSettings::default()
+        };
+        Anthropic::new(
+            &server.uri(),
+            "test-key".to_string(),
+            "claude-3-5-haiku-latest",
+            &settings,
+            RetryPolicy::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chat_sends_the_system_prompt_separately() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .and(header("x-api-key", "test-key"))
+            .and(header("anthropic-version", ANTHROPIC_VERSION))
+            .and(body_partial_json(serde_json::json!({
+                "model": "claude-3-5-haiku-latest",
+                "max_tokens": 8192,
+                "system": "Lead a retro",
+                "messages": [{"role": "user", "content": "Here are my notes"}]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "msg_1",
+                "type": "message",
+                "role": "assistant",
+                "content": [{"type": "text", "text": "Let's begin!"}],
+                "usage": {"input_tokens": 12, "output_tokens": 3}
+            })))
+            .mount(&server)
+            .await;
+
+        let response = mock_anthropic(&server)
+            .chat(&[
+                Message::system("Lead a retro"),
+                Message::user("Here are my notes"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(response, "Let's begin!");
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_yields_text_deltas() {
+        let server = MockServer::start().await;
+        let events = "event: message_start\n\
+                      data: {\"type\": \"message_start\", \"message\": {}}\n\n\
+                      event: content_block_delta\n\
+                      data: {\"type\": \"content_block_delta\", \"index\": 0, \"delta\": {\"type\": \"text_delta\", \"text\": \"Let's \"}}\n\n\
+                      event: ping\n\
+                      data: {\"type\": \"ping\"}\n\n\
+                      event: content_block_delta\n\
+                      data: {\"type\": \"content_block_delta\", \"index\": 0, \"delta\": {\"type\": \"text_delta\", \"text\": \"begin!\"}}\n\n\
+                      event: message_stop\n\
+                      data: {\"type\": \"message_stop\"}\n\n";
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .and(body_partial_json(serde_json::json!({"stream": true})))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Content-Type", "text/event-stream")
+                    .set_body_string(events),
+            )
+            .mount(&server)
+            .await;
+
+        let pieces: Vec<String> = mock_anthropic(&server)
+            .chat_stream(&[Message::user("Here are my notes")])
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(pieces, vec!["Let's ", "begin!"]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_stream_with_tools_yields_tool_calls() {
+        let server = MockServer::start().await;
+        let events = "data: {\"type\": \"content_block_start\", \"index\": 0, \"content_block\": {\"type\": \"text\", \"text\": \"\"}}\n\n\
+                      data: {\"type\": \"content_block_delta\", \"index\": 0, \"delta\": {\"type\": \"text_delta\", \"text\": \"Let me look.\"}}\n\n\
+                      data: {\"type\": \"content_block_stop\", \"index\": 0}\n\n\
+                      data: {\"type\": \"content_block_start\", \"index\": 1, \"content_block\": {\"type\": \"tool_use\", \"id\": \"toolu_2\", \"name\": \"search_notes\", \"input\": {}}}\n\n\
+                      data: {\"type\": \"content_block_delta\", \"index\": 1, \"delta\": {\"type\": \"input_json_delta\", \"partial_json\": \"{\\\"query\\\": \"}}\n\n\
+                      data: {\"type\": \"content_block_delta\", \"index\": 1, \"delta\": {\"type\": \"input_json_delta\", \"partial_json\": \"\\\"blockers\\\"}\"}}\n\n\
+                      data: {\"type\": \"content_block_stop\", \"index\": 1}\n\n\
+                      data: {\"type\": \"message_stop\"}\n\n";
+        // the earlier call and its result are sent back as content blocks
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .and(body_partial_json(serde_json::json!({
+                "tools": [{"name": "search_notes"}],
+                "messages": [
+                    {"role": "user", "content": "Have I been stuck on this before?"},
+                    {"role": "assistant", "content": [
+                        {"type": "tool_use", "id": "toolu_1", "name": "search_notes", "input": {"query": "stuck"}}
+                    ]},
+                    {"role": "user", "content": [
+                        {"type": "tool_result", "tool_use_id": "toolu_1", "content": "Nothing matched"}
+                    ]}
+                ]
+            })))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Content-Type", "text/event-stream")
+                    .set_body_string(events),
+            )
+            .mount(&server)
+            .await;
+
+        let tool = JsonSchema {
+            name: "search_notes".to_string(),
+            description: "Searches the notes".to_string(),
+            schema: serde_json::json!({"type": "object"}),
+        };
+        let messages = [
+            Message::user("Have I been stuck on this before?"),
+            Message::tool_calls(
+                "",
+                vec![ToolCall {
+                    id: "toolu_1".to_string(),
+                    name: "search_notes".to_string(),
+                    arguments: "{\"query\": \"stuck\"}".to_string(),
+                }],
+            ),
+            Message::tool_result("toolu_1", "Nothing matched"),
+        ];
+        let events: Vec<ResponseEvent> = mock_anthropic(&server)
+            .chat_stream_with_tools(&messages, &[tool])
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                ResponseEvent::Text("Let me look.".to_string()),
+                ResponseEvent::ToolCall(ToolCall {
+                    id: "toolu_2".to_string(),
+                    name: "search_notes".to_string(),
+                    arguments: "{\"query\": \"blockers\"}".to_string(),
+                })
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chat_json_forces_the_schema_tool() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/messages"))
+            .and(body_partial_json(serde_json::json!({
+                "tools": [{"name": "answer"}],
+                "tool_choice": {"type": "tool", "name": "answer"}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "msg_1",
+                "type": "message",
+                "role": "assistant",
+                "content": [{"type": "tool_use", "id": "toolu_1", "name": "answer", "input": {"answer": 42}}],
+                "usage": {"input_tokens": 12, "output_tokens": 3}
+            })))
+            .mount(&server)
+            .await;
+
+        let schema = JsonSchema {
+            name: "answer".to_string(),
+            description: "The answer".to_string(),
+            schema: serde_json::json!({
+                "type": "object",
+                "properties": {"answer": {"type": "integer"}},
+                "required": ["answer"]
+            }),
+        };
+        let response = mock_anthropic(&server)
+            .chat_json(&[Message::user("What is the answer?")], &schema)
+            .await
+            .unwrap();
+        assert_eq!(response, "{\"answer\":42}");
+    }
+
+    #[test]
+    fn test_next_event_data() {
+        let mut buffer = b": keep-alive\n\ndata: {\"a\": 1}\n\ndata: {\"b\"".to_vec();
+        assert_eq!(
+            next_event_data(&mut buffer, false),
+            Some("{\"a\": 1}".to_string())
+        );
+        // the last event is incomplete until the body has finished
+        assert_eq!(next_event_data(&mut buffer, false), None);
+        buffer.extend(b": 2}");
+        assert_eq!(
+            next_event_data(&mut buffer, true),
+            Some("{\"b\": 2}".to_string())
+        );
+        assert_eq!(next_event_data(&mut buffer, true), None);
+    }
+}
@@ -0,0 +1,466 @@
+use super::{
+    estimate_tokens, json_prompt, JsonSchema, LlmProvider, Message, ModelInfo, ResponseEvent,
+    ResponseStream, Role, TextStream, ToolCall,
+};
+use crate::config::{LlmProviderKind, LlmSettings};
+use crate::error::{NaviError, Result};
+use async_openai::{
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
+        ChatCompletionTool, ChatCompletionToolType, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, CreateEmbeddingRequestArgs, FunctionCall, FunctionObject,
+        ResponseFormat, ResponseFormatJsonSchema,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use log::debug;
+use tiktoken_rs::CoreBPE;
+
+pub const OPENAI_API_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+/// The model that notes are embedded with, unless the `[search.embeddings]` section of navi.toml
+/// picks another
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// An `LlmProvider` for OpenAI's chat completions API, or for any server that implements it
+/// (Ollama, a llama.cpp server, vLLM, ...), which is how Navi talks to local models.
+pub struct OpenAi {
+    client: Client<OpenAIConfig>,
+    model_info: ModelInfo,
+    /// The model's tokenizer, if it's one of OpenAI's. Other models fall back to `estimate_tokens`
+    tokenizer: Option<CoreBPE>,
+    /// Whether `chat_json` uses structured outputs, see `LlmSettings::strict_json`
+    strict_json: bool,
+}
+
+impl OpenAi {
+    pub fn new(api_base: &str, api_key: String, model: &str, settings: &LlmSettings) -> Self {
+        let config = OpenAIConfig::new()
+            .with_api_base(api_base.trim_end_matches('/'))
+            .with_api_key(api_key);
+        OpenAi {
+            client: Client::with_config(config),
+            model_info: ModelInfo::new(settings.provider.name(), model, settings),
+            tokenizer: tiktoken_rs::get_bpe_from_model(model).ok(),
+            strict_json: settings
+                .strict_json
+                .unwrap_or(settings.provider == LlmProviderKind::OpenAi),
+        }
+    }
+
+    fn request(&self, messages: &[Message]) -> Result<CreateChatCompletionRequest> {
+        let messages = messages
+            .iter()
+            .map(to_openai_message)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(CreateChatCompletionRequestArgs::default()
+            .max_tokens(self.model_info.max_output_tokens)
+            .model(&self.model_info.model)
+            .messages(messages)
+            .n(1) // only 1 response
+            .build()?)
+    }
+
+    async fn complete(&self, request: CreateChatCompletionRequest) -> Result<String> {
+        let response = self.client.chat().create(request).await?;
+        if let Some(usage) = &response.usage {
+            debug!(target: "intelligence", "{} used {} prompt and {} completion tokens", self.model_info.model, usage.prompt_tokens, usage.completion_tokens);
+        }
+
+        response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .ok_or_else(|| NaviError::Llm("the response had no message in it".to_string()))
+    }
+}
+
+fn to_openai_message(message: &Message) -> Result<ChatCompletionRequestMessage> {
+    let content = message.content.as_str();
+    Ok(match message.role {
+        Role::System => ChatCompletionRequestSystemMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+        Role::User => ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+        Role::Assistant if message.tool_calls.is_empty() => {
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(content)
+                .build()?
+                .into()
+        }
+        Role::Assistant => {
+            let mut builder = ChatCompletionRequestAssistantMessageArgs::default();
+            if !content.is_empty() {
+                builder.content(content);
+            }
+            builder
+                .tool_calls(
+                    message
+                        .tool_calls
+                        .iter()
+                        .map(|call| ChatCompletionMessageToolCall {
+                            id: call.id.clone(),
+                            r#type: ChatCompletionToolType::Function,
+                            function: FunctionCall {
+                                name: call.name.clone(),
+                                arguments: call.arguments.clone(),
+                            },
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .build()?
+                .into()
+        }
+        Role::Tool => ChatCompletionRequestToolMessageArgs::default()
+            .content(content)
+            .tool_call_id(message.tool_call_id.clone().unwrap_or_default())
+            .build()?
+            .into(),
+    })
+}
+
+fn to_openai_tool(tool: &JsonSchema) -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: tool.name.clone(),
+            description: Some(tool.description.clone()),
+            parameters: Some(tool.schema.clone()),
+            strict: None,
+        },
+    }
+}
+
+/// Adds a piece of a streamed tool call to the `calls` streamed so far. The first piece of each
+/// call has its ID and name, and the arguments are spread over the rest.
+fn add_tool_call_piece(
+    calls: &mut Vec<ToolCall>,
+    index: usize,
+    id: Option<String>,
+    name: Option<String>,
+    arguments: Option<String>,
+) {
+    while calls.len() <= index {
+        calls.push(ToolCall {
+            id: String::new(),
+            name: String::new(),
+            arguments: String::new(),
+        });
+    }
+    let call = &mut calls[index];
+    if let Some(id) = id {
+        call.id = id;
+    }
+    if let Some(name) = name {
+        call.name.push_str(&name);
+    }
+    if let Some(arguments) = arguments {
+        call.arguments.push_str(&arguments);
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAi {
+    fn model_info(&self) -> &ModelInfo {
+        &self.model_info
+    }
+
+    fn count_tokens(&self, text: &str) -> usize {
+        match &self.tokenizer {
+            Some(tokenizer) => tokenizer.encode_with_special_tokens(text).len(),
+            None => estimate_tokens(text),
+        }
+    }
+
+    async fn chat(&self, messages: &[Message]) -> Result<String> {
+        self.complete(self.request(messages)?).await
+    }
+
+    /// Uses [structured outputs](https://platform.openai.com/docs/guides/structured-outputs), so
+    /// the response is guaranteed to match the schema. Servers that may not support them (see
+    /// `LlmSettings::strict_json`) are only asked for the schema in the prompt instead.
+    async fn chat_json(&self, messages: &[Message], schema: &JsonSchema) -> Result<String> {
+        if !self.strict_json {
+            return self.chat(&json_prompt(messages, schema)).await;
+        }
+
+        let mut request = self.request(messages)?;
+        request.response_format = Some(ResponseFormat::JsonSchema {
+            json_schema: ResponseFormatJsonSchema {
+                description: Some(schema.description.clone()),
+                name: schema.name.clone(),
+                schema: Some(schema.schema.clone()),
+                strict: Some(true),
+            },
+        });
+        self.complete(request).await
+    }
+
+    async fn chat_stream(&self, messages: &[Message]) -> Result<TextStream> {
+        let stream = self
+            .client
+            .chat()
+            .create_stream(self.request(messages)?)
+            .await?;
+
+        Ok(stream
+            .filter_map(|chunk| async move {
+                match chunk {
+                    Ok(chunk) => chunk
+                        .choices
+                        .into_iter()
+                        .next()
+                        .and_then(|choice| choice.delta.content)
+                        .map(Ok),
+                    Err(e) => Some(Err(NaviError::from(e))),
+                }
+            })
+            .boxed())
+    }
+
+    async fn chat_stream_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[JsonSchema],
+    ) -> Result<ResponseStream> {
+        let mut request = self.request(messages)?;
+        if !tools.is_empty() {
+            request.tools = Some(tools.iter().map(to_openai_tool).collect());
+        }
+        let chunks = self.client.chat().create_stream(request).await?;
+
+        // the text is passed on as it arrives, but tool calls arrive in pieces, and are only
+        // passed on once the stream has ended and every piece is in
+        let events = stream::unfold(
+            (chunks, Vec::new(), false),
+            |(mut chunks, mut calls, finished)| async move {
+                if finished {
+                    return None;
+                }
+                loop {
+                    let chunk = match chunks.next().await {
+                        Some(Ok(chunk)) => chunk,
+                        Some(Err(e)) => {
+                            return Some((vec![Err(NaviError::from(e))], (chunks, calls, true)))
+                        }
+                        None => {
+                            let events = calls
+                                .into_iter()
+                                .map(|call| Ok(ResponseEvent::ToolCall(call)))
+                                .collect();
+                            return Some((events, (chunks, Vec::new(), true)));
+                        }
+                    };
+                    let delta = match chunk.choices.into_iter().next() {
+                        Some(choice) => choice.delta,
+                        None => continue,
+                    };
+                    for piece in delta.tool_calls.unwrap_or_default() {
+                        let (name, arguments) = match piece.function {
+                            Some(function) => (function.name, function.arguments),
+                            None => (None, None),
+                        };
+                        add_tool_call_piece(
+                            &mut calls,
+                            piece.index as usize,
+                            piece.id,
+                            name,
+                            arguments,
+                        );
+                    }
+                    if let Some(text) = delta.content.filter(|text| !text.is_empty()) {
+                        return Some((vec![Ok(ResponseEvent::Text(text))], (chunks, calls, false)));
+                    }
+                }
+            },
+        );
+
+        Ok(events.map(stream::iter).flatten().boxed())
+    }
+
+    /// Embeds with the model the provider was created with, which has to be an embedding model
+    /// such as text-embedding-3-small (or nomic-embed-text, for Ollama)
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(&self.model_info.model)
+            .input(texts.to_vec())
+            .build()?;
+        let mut response = self.client.embeddings().create(request).await?;
+        if response.data.len() != texts.len() {
+            return Err(NaviError::Llm(format!(
+                "{} embedded {} texts when it was given {}",
+                self.model_info.model,
+                response.data.len(),
+                texts.len()
+            )));
+        }
+
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[tokio::test]
+    async fn test_chat_with_openai_compatible_server() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("Authorization", "Bearer local-key"))
+            .and(body_partial_json(serde_json::json!({
+                "model": "llama3.1",
+                "max_tokens": 2048,
+                "messages": [
+                    {"role": "system", "content": "Lead a retro"},
+                    {"role": "user", "content": "Here are my notes"}
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1731163522,
+                "model": "llama3.1",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Let's begin!"},
+                    "finish_reason": "stop"
+                }],
+                "usage": {"prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15}
+            })))
+            .mount(&server)
+            .await;
+
+        let settings = LlmSettings {
+            provider: LlmProviderKind::OpenAiCompatible,
+            ..LlmSettings::default()
+        };
+        let provider = OpenAi::new(
+            &format!("{}/v1", server.uri()),
+            "local-key".to_string(),
+            "llama3.1",
+            &settings,
+        );
+        assert_eq!(provider.model_info().provider, "OpenAI-compatible");
+
+        let response = provider
+            .chat(&[
+                Message::system("Lead a retro"),
+                Message::user("Here are my notes"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(response, "Let's begin!");
+    }
+
+    #[tokio::test]
+    async fn test_chat_json_with_openai_compatible_server_asks_in_the_prompt() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1731163522,
+                "model": "llama3.1",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "{\"priority\": \"Ship it\"}"},
+                    "finish_reason": "stop"
+                }]
+            })))
+            .mount(&server)
+            .await;
+
+        let settings = LlmSettings {
+            provider: LlmProviderKind::OpenAiCompatible,
+            ..LlmSettings::default()
+        };
+        let provider = OpenAi::new(
+            &format!("{}/v1", server.uri()),
+            String::new(),
+            "llama3.1",
+            &settings,
+        );
+        let schema = JsonSchema {
+            name: "focus".to_string(),
+            description: "The one priority".to_string(),
+            schema: serde_json::json!({"type": "object"}),
+        };
+        let response = provider
+            .chat_json(&[Message::user("What should I focus on?")], &schema)
+            .await
+            .unwrap();
+        assert_eq!(response, "{\"priority\": \"Ship it\"}");
+
+        // the server isn't asked for structured outputs, which it may not support
+        let requests = server.received_requests().await.unwrap();
+        let body = requests[0].body_json::<serde_json::Value>().unwrap();
+        assert!(body.get("response_format").is_none());
+        assert!(body["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("Reply with only a JSON object"));
+    }
+
+    #[tokio::test]
+    async fn test_embed_with_openai_compatible_server() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embeddings"))
+            .and(body_partial_json(serde_json::json!({
+                "model": "nomic-embed-text",
+                "input": ["Page: Weekly Review", "Page: Project Navi"]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "model": "nomic-embed-text",
+                "data": [
+                    {"object": "embedding", "index": 1, "embedding": [0.0, 1.0]},
+                    {"object": "embedding", "index": 0, "embedding": [1.0, 0.0]}
+                ],
+                "usage": {"prompt_tokens": 8, "total_tokens": 8}
+            })))
+            .mount(&server)
+            .await;
+
+        let settings = LlmSettings {
+            provider: LlmProviderKind::OpenAiCompatible,
+            ..LlmSettings::default()
+        };
+        let provider = OpenAi::new(
+            &format!("{}/v1", server.uri()),
+            String::new(),
+            "nomic-embed-text",
+            &settings,
+        );
+
+        let embeddings = provider
+            .embed(&[
+                "Page: Weekly Review".to_string(),
+                "Page: Project Navi".to_string(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+    }
+}
@@ -0,0 +1,170 @@
+use super::{
+    JsonSchema, LlmProvider, Message, ModelInfo, ResponseEvent, ResponseStream, TextStream,
+};
+use crate::config::LlmSettings;
+use crate::core::helpers::hash_parts;
+use crate::error::{NaviError, Result};
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// The size of a `ScriptedLlm`'s embeddings
+const EMBEDDING_DIMENSIONS: usize = 256;
+
+/// An `LlmProvider` that replays canned responses in order instead of calling a model, and keeps
+/// every request it was sent, so that tests can run a whole retro without network access and then
+/// check what Navi asked for. It can embed text too, well enough for searching a few notes.
+pub struct ScriptedLlm {
+    model_info: ModelInfo,
+    responses: Mutex<VecDeque<Message>>,
+    requests: Mutex<Vec<ScriptedRequest>>,
+}
+
+/// A request that a `ScriptedLlm` was sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptedRequest {
+    pub messages: Vec<Message>,
+    /// The schema the response had to match, for requests made with `chat_json`
+    pub schema: Option<JsonSchema>,
+    /// The tools the LLM was offered, for requests made with `chat_stream_with_tools`
+    pub tools: Vec<JsonSchema>,
+}
+
+impl ScriptedLlm {
+    /// A provider that gives the `responses` in order, one per request, whether or not the
+    /// request is streamed or asks for JSON
+    pub fn new(responses: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self::replies(responses.into_iter().map(Message::assistant))
+    }
+
+    /// Like `new`, but the responses are assistant messages, so that they can call tools (see
+    /// `Message::tool_calls`) when they're asked for with `chat_stream_with_tools`
+    pub fn replies(responses: impl IntoIterator<Item = Message>) -> Self {
+        ScriptedLlm {
+            model_info: ModelInfo::new("Scripted", "scripted", &LlmSettings::default()),
+            responses: Mutex::new(responses.into_iter().collect()),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The requests the provider has been sent so far, oldest first
+    pub fn requests(&self) -> Vec<ScriptedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Records the request and returns the next response of the script
+    fn respond(
+        &self,
+        messages: &[Message],
+        schema: Option<&JsonSchema>,
+        tools: &[JsonSchema],
+    ) -> Result<Message> {
+        let mut requests = self.requests.lock().unwrap();
+        requests.push(ScriptedRequest {
+            messages: messages.to_vec(),
+            schema: schema.cloned(),
+            tools: tools.to_vec(),
+        });
+        self.responses.lock().unwrap().pop_front().ok_or_else(|| {
+            NaviError::Llm(format!(
+                "the script ran out of responses at request {}",
+                requests.len()
+            ))
+        })
+    }
+}
+
+#[async_trait]
+impl LlmProvider for ScriptedLlm {
+    fn model_info(&self) -> &ModelInfo {
+        &self.model_info
+    }
+
+    async fn chat(&self, messages: &[Message]) -> Result<String> {
+        Ok(self.respond(messages, None, &[])?.content)
+    }
+
+    /// Streams the response a word at a time, like a model writing it
+    async fn chat_stream(&self, messages: &[Message]) -> Result<TextStream> {
+        let response = self.respond(messages, None, &[])?;
+        Ok(stream::iter(words(&response.content).map(Ok).collect::<Vec<_>>()).boxed())
+    }
+
+    /// Streams the response's text a word at a time, then its tool calls
+    async fn chat_stream_with_tools(
+        &self,
+        messages: &[Message],
+        tools: &[JsonSchema],
+    ) -> Result<ResponseStream> {
+        let response = self.respond(messages, None, tools)?;
+        let events = words(&response.content)
+            .map(ResponseEvent::Text)
+            .chain(response.tool_calls.into_iter().map(ResponseEvent::ToolCall))
+            .map(Ok)
+            .collect::<Vec<_>>();
+        Ok(stream::iter(events).boxed())
+    }
+
+    async fn chat_json(&self, messages: &[Message], schema: &JsonSchema) -> Result<String> {
+        Ok(self.respond(messages, Some(schema), &[])?.content)
+    }
+
+    /// Embeds each text as how many times it uses each word, with the words hashed into a fixed
+    /// number of dimensions, so that texts which share words come out as similar
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts
+            .iter()
+            .map(|text| {
+                let mut embedding = vec![0.0; EMBEDDING_DIMENSIONS];
+                for word in text
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                {
+                    let hash = hash_parts(&[&word.to_lowercase()]);
+                    let dimension = usize::from_str_radix(&hash[..8], 16).unwrap();
+                    embedding[dimension % EMBEDDING_DIMENSIONS] += 1.0;
+                }
+                embedding
+            })
+            .collect())
+    }
+}
+
+/// Splits `text` into words, keeping the spaces after them
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split_inclusive(' ').map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_replays_responses_and_records_requests() {
+        let llm = ScriptedLlm::new(["Let's begin!", "{}"]);
+
+        let stream = llm
+            .chat_stream(&[Message::user("Here are my notes")])
+            .await
+            .unwrap();
+        let pieces = stream.map(|piece| piece.unwrap()).collect::<Vec<_>>().await;
+        assert_eq!(pieces, vec!["Let's ", "begin!"]);
+
+        let schema = JsonSchema {
+            name: "nothing".to_string(),
+            description: "Nothing".to_string(),
+            schema: serde_json::json!({"type": "object"}),
+        };
+        assert_eq!(llm.chat_json(&[], &schema).await.unwrap(), "{}");
+        assert!(matches!(llm.chat(&[]).await, Err(NaviError::Llm(_))));
+
+        let requests = llm.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[0].messages,
+            vec![Message::user("Here are my notes")]
+        );
+        assert_eq!(requests[1].schema, Some(schema));
+    }
+}
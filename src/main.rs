@@ -1,51 +1,372 @@
-use chrono::Duration;
-use clap::Parser;
-use log::{debug, info};
-use navi::{intelligence::assistant_flow, notion::Notion};
-use std::{env, fs, path::Path, time::Instant};
+use chrono::{DateTime, Duration, Local, Utc};
+use clap::{error::ErrorKind, ArgAction, CommandFactory, Parser, Subcommand};
+use log::{debug, info, warn};
+use navi::{
+    action_items::{extract_action_items, follow_up_prompt, to_markdown},
+    config::{Config, DEFAULT_CONFIG_PATH},
+    core::{
+        datatypes::ParsedPage,
+        helpers::hash_parts,
+        prompt::{assemble_prompt, PromptReport},
+    },
+    digest::build_digest,
+    error::{NaviError, Result},
+    focus::{choose_focus, focus_notes, focus_prompt, propose_focus},
+    intelligence::{assistant_flow, exit_on_ctrl_c, retro_messages, Console, RetroState},
+    llm::{
+        embedder_from_config, estimate_tokens, model_info_from_config, notes_token_budget,
+        provider_from_config, LlmProvider, Message, Role,
+    },
+    notion::Notion,
+    retro_format::{load_format, RetroFormat},
+    retro_summary::extract_summary,
+    review::{review_session, update_cards},
+    search::{index_notes, search_notes},
+    sources::{
+        logseq::Logseq, obsidian::Obsidian, parse_last_edited_from_sources, IngestionReport,
+        NoteSource,
+    },
+    srs::Scheduler,
+    store::{sync_source, Store, StoredNotes},
+    tools::Tools,
+    transcript::{list_sessions, load_session, snapshot_id, SessionInfo, Transcript},
+};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    rc::Rc,
+    time::Instant,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Number of days to look back for notes
-    #[arg(short, long, default_value = "7")]
-    days: i64,
+    /// Number of days to look back for notes, by default 7 or however many the retro format asks for
+    #[arg(short, long, global = true)]
+    days: Option<i64>,
 
-    /// If true, use the prompt_info.md file to store the prompt info, otherwise generate a new prompt
-    #[arg(short, long, default_value = "false")]
-    use_prompt_info_file: bool,
+    /// The configuration file, by default navi.toml in the working directory
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// The directory Navi keeps its local data in, instead of the data_dir set in navi.toml
+    #[arg(long, global = true, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Log more of what Navi is doing: -v for warnings, -vv for its progress and -vvv for
+    /// debugging. RUST_LOG takes precedence
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Sync recently edited notes into the local store, without starting a retro
+    Sync {
+        /// Also remove the pages that have been deleted from your notes, which means listing
+        /// every page of every source
+        #[arg(long)]
+        prune: bool,
+    },
+    /// Start a retro, which is also what Navi does when no command is given
+    Retro {
+        /// Carry on with a past retro instead of starting a new one, see `navi history`
+        #[arg(long, value_name = "ID")]
+        resume: Option<String>,
+        /// The format of the retro, e.g. weekly, start-stop-continue, 4ls, sailboat, mad-sad-glad,
+        /// monthly or quarterly-okr, or one of your own
+        #[arg(long, conflicts_with = "resume")]
+        format: Option<String>,
+        /// Hold the retro on notes written by `navi export`, instead of syncing them
+        #[arg(long, value_name = "PATH", conflicts_with = "resume")]
+        snapshot: Option<PathBuf>,
+    },
+    /// Write the notes a retro would be given as they were last synced, without calling an LLM
+    Export {
+        /// The retro format whose number of days of notes to export
+        #[arg(long)]
+        format: Option<String>,
+        /// The file to write the notes to, instead of stdout
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Search all of your synced notes by meaning, see the [search] section of navi.toml
+    Search {
+        /// What to search for
+        query: String,
+    },
+    /// List past retros
+    History,
+    /// Print the action items agreed on in a retro as Markdown
+    Actions {
+        /// The retro to print the action items of, by default the latest one that has some
+        id: Option<String>,
+    },
+    /// Pick the one thing to focus on in the coming week, which the retros that follow check on
+    Focus,
+    /// Quiz yourself on flashcards made from your notes, see `navi sync` to bring them up to date
+    Review,
+    /// Show how many of your notes Navi has synced, indexed and made flashcards of
+    Stats,
+    /// Check the configuration for mistakes, such as a missing API key
+    Config,
+}
+
+/// How many days of notes a retro is about, unless --days or the retro format says otherwise
+const DEFAULT_DAYS: i64 = 7;
+
 #[tokio::main]
-async fn main() {
-    let program_start = Instant::now();
+async fn main() -> ExitCode {
+    // under `cargo run` the .env file lives next to Cargo.toml, otherwise we look for it in
+    // the working directory (and its parents)
+    if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
+        dotenv::from_path(Path::new(&manifest_dir).join(".env")).ok();
+    } else {
+        dotenv::dotenv().ok();
+    }
+
+    let args = Args::parse();
+    // a resumed retro carries on with the notes it was started on, and as --days is global clap
+    // can't reject it along with --format and --snapshot
+    if let (
+        Some(_),
+        Some(Command::Retro {
+            resume: Some(_), ..
+        }),
+    ) = (args.days, &args.command)
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "the argument '--days <DAYS>' cannot be used with '--resume <ID>'",
+            )
+            .exit();
+    }
+    let level = match args.verbose {
+        0 => "error",
+        1 => "warn",
+        2 => "info",
+        3 => "debug",
+        _ => "trace",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
 
-    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
-    let dotenv_path = format!("{}/.env", manifest_dir);
-    dotenv::from_path(dotenv_path).ok();
-    env_logger::init();
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            if let Some(hint) = e.hint() {
+                eprintln!("hint: {}", hint);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
 
+async fn run(args: Args) -> Result<()> {
     let Args {
         days,
-        use_prompt_info_file,
-    } = Args::parse();
-    let dur = Duration::days(days);
+        config: config_path,
+        data_dir,
+        command,
+        ..
+    } = args;
+    let mut config = Config::load(config_path.as_deref())?;
+    if let Some(data_dir) = data_dir {
+        config.storage.data_dir = data_dir;
+    }
 
-    let notion = Notion::new(env::var("NOTION_TOKEN").expect("NOTION_TOKEN must be set")).unwrap();
+    match command {
+        None => retro(&config, days, None, None).await,
+        Some(Command::Retro {
+            resume: Some(id), ..
+        }) => resume_retro(&config, &id).await,
+        Some(Command::Retro {
+            format, snapshot, ..
+        }) => retro(&config, days, format.as_deref(), snapshot.as_deref()).await,
+        Some(Command::Sync { prune }) => {
+            let format = load_format(&config.retro.format, config.retro.formats_dir.as_deref())?;
+            let mut ingestion_report = IngestionReport::default();
+            sync_notes(
+                &config,
+                lookback(days, &format),
+                prune,
+                &mut ingestion_report,
+            )
+            .await?;
+            if !ingestion_report.is_empty() {
+                eprint!("{}", ingestion_report);
+            }
+            Ok(())
+        }
+        Some(Command::Export { format, output }) => {
+            export(&config, days, format.as_deref(), output.as_deref()).await
+        }
+        Some(Command::Search { query }) => search(&config, &query).await,
+        Some(Command::History) => print_history(&config),
+        Some(Command::Actions { id }) => print_action_items(&config, id.as_deref()),
+        Some(Command::Focus) => focus(&config, days).await,
+        Some(Command::Review) => review(&config).await,
+        Some(Command::Stats) => print_stats(&config),
+        Some(Command::Config) => check_config(&config, config_path.as_deref()),
+    }
+}
 
-    // ingest notes data from Notion (or from a cached file if it exists)
-    let prompt_info = if use_prompt_info_file && Path::new("prompt_info.md").exists() {
-        info!(target: "notion", "Using cached prompt info from prompt_info.md");
-        fs::read_to_string("prompt_info.md").unwrap()
-    } else {
-        let parsed_pages = notion.parse_last_edited(dur).await.unwrap();
-        let prompt = Notion::to_prompt_text(parsed_pages).unwrap();
-        if use_prompt_info_file {
-            fs::write("prompt_info.md", &prompt).unwrap();
+/// How long before now a retro in `format` looks for notes: --days, or however many days the
+/// format asks for, or `DEFAULT_DAYS`
+fn lookback(days: Option<i64>, format: &RetroFormat) -> Duration {
+    Duration::days(days.or(format.days).unwrap_or(DEFAULT_DAYS))
+}
+
+/// Syncs the notes edited in the last `dur` from the sources navi.toml enables into the store,
+/// and then brings the search index up to date if search is enabled. Pages that can't be read are
+/// skipped rather than failing the sync, and listed in the `ingestion_report`. With `prune`, the
+/// Pages deleted from the sources are removed from the store too.
+async fn sync_notes(
+    config: &Config,
+    dur: Duration,
+    prune: bool,
+    ingestion_report: &mut IngestionReport,
+) -> Result<Rc<Store>> {
+    let mut sources: Vec<Box<dyn NoteSource>> = Vec::new();
+    if config.sources.notion {
+        let token = notion_token("or set notion = false in the [sources] section of navi.toml")?;
+        sources.push(Box::new(Notion::new(token, config.clone())?));
+    }
+    if let Some(vault) = &config.sources.obsidian_vault {
+        sources.push(Box::new(Obsidian::new(vault.clone(), config.clone())));
+    }
+    if let Some(graph) = &config.sources.logseq_graph {
+        sources.push(Box::new(Logseq::new(graph.clone(), config.clone())));
+    }
+
+    let store = open_store(config)?;
+
+    info!(target: "notion", "Thanks for choosing Navi as your digital mentor! Navi will begin by syncing your last {} {} of notes. The first sync may take several minutes, depending on how dedicated a notetaker you are...", dur.num_days(), if dur.num_days() == 1 { "day" } else { "days" });
+    let cutoff = Utc::now() - dur;
+    for source in &sources {
+        let report = sync_source(&store, source.as_ref(), cutoff, prune, ingestion_report).await?;
+        info!(target: "store", "synced {}: {} of {} edited pages changed, {} blocks updated, {} deleted pages removed", source.name(), report.pages_updated, report.pages_checked, report.blocks_written, report.pages_deleted);
+    }
+    // the index covers every page in the store, so that a retro can search beyond its notes
+    if config.search.enabled {
+        match embedder_from_config(config) {
+            Ok(embedder) => match index_notes(&store, embedder.as_ref()).await {
+                Ok(report) => {
+                    info!(target: "search", "indexed your notes: {} of {} pages changed, {} chunks embedded", report.pages_updated, report.pages_checked, report.chunks_embedded)
+                }
+                Err(e) => {
+                    warn!(target: "search", "unable to index your notes, so the retro will search the index as it was: {}", e)
+                }
+            },
+            Err(e) => warn!(target: "search", "unable to index your notes: {}", e),
         }
-        debug!(target: "notion", "prompt info:\n{}", prompt);
-        prompt
+    }
+
+    Ok(store)
+}
+
+/// The Pages edited since `cutoff` of the sources navi.toml enables, as they were last synced into
+/// the `store`. Pages that can't be read are listed in the `ingestion_report`.
+async fn stored_pages(
+    config: &Config,
+    store: &Rc<Store>,
+    cutoff: DateTime<Utc>,
+    ingestion_report: &mut IngestionReport,
+) -> Result<Vec<ParsedPage>> {
+    let mut names = Vec::new();
+    if config.sources.notion {
+        names.push(Notion::NAME);
+    }
+    if config.sources.obsidian_vault.is_some() {
+        names.push(Obsidian::NAME);
+    }
+    if config.sources.logseq_graph.is_some() {
+        names.push(Logseq::NAME);
+    }
+    let stored_sources = names
+        .into_iter()
+        .map(|name| Box::new(StoredNotes::new(store.clone(), name)) as Box<dyn NoteSource>)
+        .collect::<Vec<_>>();
+
+    parse_last_edited_from_sources(&stored_sources, cutoff, ingestion_report).await
+}
+
+/// Starts a retro in `format` (or the format navi.toml sets) on the notes of the last `days`,
+/// which are synced first, or on the notes in the `snapshot` that `navi export` wrote
+async fn retro(
+    config: &Config,
+    days: Option<i64>,
+    format: Option<&str>,
+    snapshot: Option<&Path>,
+) -> Result<()> {
+    let program_start = Instant::now();
+
+    let format = load_format(
+        format.unwrap_or(&config.retro.format),
+        config.retro.formats_dir.as_deref(),
+    )?;
+    let dur = lookback(days, &format);
+    let cutoff = Utc::now() - dur;
+    let snapshot_notes = snapshot
+        .map(|path| {
+            fs::read_to_string(path)
+                .map_err(|e| NaviError::io(format!("unable to read {}", path.display()), e))
+        })
+        .transpose()?;
+
+    let mut ingestion_report = IngestionReport::default();
+    let (store, parsed_pages) = match &snapshot_notes {
+        Some(_) => (open_store(config)?, Vec::new()),
+        None => {
+            let store = sync_notes(config, dur, false, &mut ingestion_report).await?;
+            // the retro reads from the store, which the sync above has brought up to date
+            let pages = stored_pages(config, &store, cutoff, &mut ingestion_report).await?;
+            (store, pages)
+        }
+    };
+
+    let llm = provider_from_config(config)?;
+
+    let snapshot = match &snapshot_notes {
+        Some(notes) => hash_parts(&[notes.as_str()])[..12].to_string(),
+        None => snapshot_id(&parsed_pages),
+    };
+    // the retro opens by checking on the user's focus, and the latest retro that agreed on action
+    // items is followed up on in this one
+    let mut follow_ups = Vec::new();
+    follow_ups.extend(store.current_focus()?.map(|focus| focus_prompt(&focus)));
+    follow_ups.extend(
+        list_sessions(&config.storage.transcripts_dir())?
+            .into_iter()
+            .rev()
+            .find_map(|session| {
+                let action_items = session.action_items.filter(|items| !items.is_empty())?;
+                let retro_date = session.info.started_at.with_timezone(&Local).date_naive();
+                Some(follow_up_prompt(
+                    &action_items,
+                    retro_date,
+                    &parsed_pages,
+                    format.follow_up_step(),
+                ))
+            }),
+    );
+    let budget = notes_token_budget(&config.llm, llm.model_info());
+
+    let (mut prompt_info, prompt_report) = match snapshot_notes {
+        Some(notes) => (notes, PromptReport::default()),
+        None => retro_notes(config, llm.as_ref(), &store, parsed_pages, cutoff, budget).await,
     };
+    info!(target: "intelligence", "the notes are {} tokens, of a budget of {}", llm.count_tokens(&prompt_info), budget);
+    if let Some(note) = ingestion_report.prompt_note() {
+        prompt_info.push_str("\n\n");
+        prompt_info.push_str(&note);
+    }
+    debug!(target: "notion", "prompt info:\n{}", prompt_info);
 
     info!(target: "notion", "Analysis complete! Navi is now ready to guide you through the process of reflecting on your notes");
     info!(target: "notion", "Let's begin by asking Navi to start the retro, and see what Navi's response is...");
@@ -54,5 +375,491 @@ async fn main() {
     let total_elapsed = program_start.elapsed();
     info!(target: "intelligence", "--- Total time to first prompt: {:.2} seconds", total_elapsed.as_secs_f64());
 
-    assistant_flow(prompt_info).await.unwrap();
+    if !ingestion_report.is_empty() {
+        eprint!("{}", ingestion_report);
+    }
+    if !prompt_report.is_complete() {
+        eprint!("{}", prompt_report);
+    }
+
+    info!(target: "intelligence", "running a {} retro: {}", format.name, format.description);
+    let messages = retro_messages(&format, prompt_info, follow_ups);
+    let model_info = llm.model_info();
+    let info = SessionInfo::new(
+        &format.name,
+        &model_info.provider,
+        &model_info.model,
+        &messages,
+        snapshot,
+    );
+    let mut transcript = Transcript::create(&config.storage.transcripts_dir(), info, &messages)?;
+    let tools = retro_tools(config, store)?;
+    run_retro(
+        config,
+        &format,
+        llm.as_ref(),
+        &mut transcript,
+        &tools,
+        1,
+        messages,
+    )
+    .await
+}
+
+/// The notes a retro is given: with the digest enabled, a summary of the `pages` rather than the
+/// pages themselves, falling back to the pages if the digest can't be built
+async fn retro_notes(
+    config: &Config,
+    llm: &dyn LlmProvider,
+    store: &Store,
+    pages: Vec<ParsedPage>,
+    cutoff: DateTime<Utc>,
+    budget: usize,
+) -> (String, PromptReport) {
+    if config.digest.enabled {
+        match build_digest(llm, store, pages.clone(), budget, config.digest.concurrency).await {
+            Ok(digest) => return (digest.to_prompt_text(), PromptReport::default()),
+            Err(e) => {
+                warn!(target: "intelligence", "unable to build the digest, so the retro will use your notes instead: {}", e);
+            }
+        }
+    }
+
+    assemble_prompt(pages, cutoff, Utc::now(), budget, &|text: &str| {
+        llm.count_tokens(text)
+    })
+}
+
+/// Writes the notes of the last `days` that a retro in `format` would be given to `output`, or to
+/// stdout, as they were last synced. No LLM is called, so the notes are never a digest, and their
+/// tokens are estimated.
+async fn export(
+    config: &Config,
+    days: Option<i64>,
+    format: Option<&str>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let format = load_format(
+        format.unwrap_or(&config.retro.format),
+        config.retro.formats_dir.as_deref(),
+    )?;
+    let now = Utc::now();
+    let cutoff = now - lookback(days, &format);
+    let store = open_store(config)?;
+    let mut ingestion_report = IngestionReport::default();
+    let pages = stored_pages(config, &store, cutoff, &mut ingestion_report).await?;
+
+    let budget = notes_token_budget(&config.llm, &model_info_from_config(&config.llm));
+    let (mut notes, prompt_report) = assemble_prompt(pages, cutoff, now, budget, &estimate_tokens);
+    if let Some(note) = ingestion_report.prompt_note() {
+        notes.push_str("\n\n");
+        notes.push_str(&note);
+    }
+
+    match output {
+        Some(path) => {
+            fs::write(path, &notes)
+                .map_err(|e| NaviError::io(format!("unable to write {}", path.display()), e))?;
+            eprintln!(
+                "Your notes were written to {}, you can hold a retro on them with `navi retro --snapshot {}`",
+                path.display(),
+                path.display()
+            );
+        }
+        None => print!("{}", notes),
+    }
+    if !ingestion_report.is_empty() {
+        eprint!("{}", ingestion_report);
+    }
+    if !prompt_report.is_complete() {
+        eprint!("{}", prompt_report);
+    }
+
+    Ok(())
+}
+
+/// Carries on with the retro that has the ID `id`
+async fn resume_retro(config: &Config, id: &str) -> Result<()> {
+    let llm = provider_from_config(config)?;
+    let (mut transcript, session) = Transcript::resume(&config.storage.transcripts_dir(), id)?;
+    let info = transcript.info();
+    if info.model != llm.model_info().model {
+        warn!(target: "intelligence", "retro {} was started with {}, and will carry on with {}", info.id, info.model, llm.model_info().model);
+    }
+    let format = load_format(&info.format, config.retro.formats_dir.as_deref())?;
+    let tools = retro_tools(config, open_store(config)?)?;
+
+    run_retro(
+        config,
+        &format,
+        llm.as_ref(),
+        &mut transcript,
+        &tools,
+        session.step(),
+        session.conversation(),
+    )
+    .await
+}
+
+/// Runs the retro from the step numbered `step` and saves what came of it, and then tells the
+/// user how to carry on with it, even if it ended with an error
+async fn run_retro(
+    config: &Config,
+    format: &RetroFormat,
+    llm: &dyn LlmProvider,
+    transcript: &mut Transcript,
+    tools: &Tools,
+    step: usize,
+    messages: Vec<Message>,
+) -> Result<()> {
+    let state = RetroState::new(format, step);
+    let conversation = assistant_flow(
+        llm,
+        &mut Console::stdio(),
+        transcript,
+        tools,
+        state,
+        messages,
+    )
+    .await;
+    // the conversation is over, so Ctrl-C goes back to ending Navi rather than stopping a response
+    exit_on_ctrl_c();
+    let result = match conversation {
+        // there's nothing to extract until the user has replied to Navi
+        Ok(messages)
+            if messages
+                .iter()
+                .skip_while(|message| message.role != Role::Assistant)
+                .any(|message| message.role == Role::User) =>
+        {
+            save_outcome(config, format, llm, transcript, &messages).await
+        }
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    };
+    let id = &transcript.info().id;
+    eprintln!(
+        "This retro was saved as {}, you can carry on with it using `navi retro --resume {}`",
+        id, id
+    );
+
+    result
+}
+
+/// Saves the action items agreed on in the retro, and writes the retro to Notion if navi.toml
+/// asks for that
+async fn save_outcome(
+    config: &Config,
+    format: &RetroFormat,
+    llm: &dyn LlmProvider,
+    transcript: &mut Transcript,
+    messages: &[Message],
+) -> Result<()> {
+    let action_items = extract_action_items(llm, messages, Local::now().date_naive()).await?;
+    transcript.record_action_items(&action_items)?;
+    println!("{}", to_markdown(&action_items));
+
+    if config.notion.retro_parent_page_id.is_some() {
+        let summary = extract_summary(llm, messages, format).await?;
+        let token =
+            notion_token("or unset retro_parent_page_id in the [notion] section of navi.toml")?;
+        let title = format!(
+            "Retro {}",
+            transcript
+                .info()
+                .started_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d")
+        );
+        let url = Notion::new(token, config.clone())?
+            .create_retro_page(&title, &summary, &action_items)
+            .await?;
+        println!("Your retro was written to Notion: {}", url);
+    }
+
+    Ok(())
+}
+
+/// Has Navi propose the one thing to focus on from the last `days` of synced notes, and saves it as
+/// the user's focus once they accept or edit it
+async fn focus(config: &Config, days: Option<i64>) -> Result<()> {
+    let llm = provider_from_config(config)?;
+    let store = open_store(config)?;
+    if let Some(focus) = store.current_focus()? {
+        println!(
+            "Your current focus, since {}: {}\n",
+            focus.set_at.with_timezone(&Local).format("%Y-%m-%d"),
+            focus.priority
+        );
+    }
+
+    let now = Utc::now();
+    let notes = focus_notes(
+        &store,
+        &config.storage.transcripts_dir(),
+        now - Duration::days(days.unwrap_or(DEFAULT_DAYS)),
+        now,
+        notes_token_budget(&config.llm, llm.model_info()),
+        &|text: &str| llm.count_tokens(text),
+    )?;
+    let proposal = propose_focus(llm.as_ref(), &notes).await?;
+    if let Some(focus) = choose_focus(&mut Console::stdio(), proposal, now).await? {
+        store.save_focus(&focus)?;
+    }
+
+    Ok(())
+}
+
+/// Brings the flashcards up to date with the synced notes, and then quizzes the user on the ones
+/// that are due
+async fn review(config: &Config) -> Result<()> {
+    let store = open_store(config)?;
+    // without an LLM, the cards written by it are kept as they were
+    let llm = match provider_from_config(config) {
+        Ok(llm) => Some(llm),
+        Err(e) => {
+            if config.review.highlight_tag.is_some() {
+                warn!(target: "review", "unable to write cards for highlighted pages: {}", e);
+            }
+            None
+        }
+    };
+    let report = update_cards(
+        &store,
+        llm.as_deref(),
+        config.review.highlight_tag.as_deref(),
+        Utc::now(),
+    )
+    .await?;
+    info!(target: "review", "updated the cards: {} added, {} updated, {} removed", report.added, report.updated, report.removed);
+
+    let report = review_session(
+        &store,
+        &Scheduler::new(&config.review),
+        &mut Console::stdio(),
+        config.review.cards_per_session,
+    )
+    .await?;
+    if report.reviewed > 0 {
+        println!(
+            "Reviewed {} cards, {} still due",
+            report.reviewed, report.skipped
+        );
+    }
+
+    Ok(())
+}
+
+/// Opens the store of synced notes in the data directory, creating the directory if need be
+fn open_store(config: &Config) -> Result<Rc<Store>> {
+    fs::create_dir_all(&config.storage.data_dir).map_err(|e| {
+        NaviError::io(
+            format!("unable to create {}", config.storage.data_dir.display()),
+            e,
+        )
+    })?;
+    Ok(Rc::new(Store::open(&config.storage.database_path())?))
+}
+
+/// The tools the LLM can call during a retro, which read the notes from Notion as well as the
+/// store when Notion is a source, and include searching the notes when navi.toml enables search
+/// and the embedding model can be set up
+fn retro_tools(config: &Config, store: Rc<Store>) -> Result<Tools> {
+    let mut tools = Tools::new(store, config.storage.transcripts_dir());
+    if config.sources.notion {
+        let token = notion_token("or set notion = false in the [sources] section of navi.toml")?;
+        tools = tools.with_notion(Notion::new(token, config.clone())?);
+    }
+    if !config.search.enabled {
+        return Ok(tools);
+    }
+    Ok(match embedder_from_config(config) {
+        Ok(embedder) => tools.with_search(embedder, config.search.results),
+        Err(e) => {
+            warn!(target: "search", "the retro will run without searching your notes: {}", e);
+            tools
+        }
+    })
+}
+
+/// Reads the Notion token from the environment. `fix` is how else the user can make the error go
+/// away, e.g. by not using Notion.
+fn notion_token(fix: &str) -> Result<String> {
+    env::var("NOTION_TOKEN")
+        .map_err(|_| NaviError::Config(format!("NOTION_TOKEN must be set, {}", fix)))
+}
+
+/// Searches the index of the notes for the passages closest in meaning to `query`, and prints
+/// them best match first
+async fn search(config: &Config, query: &str) -> Result<()> {
+    if !config.search.enabled {
+        return Err(NaviError::Config(
+            "search is not enabled, set enabled = true in the [search] section of navi.toml and run `navi sync` to index your notes".to_string(),
+        ));
+    }
+    let store = open_store(config)?;
+    let embedder = embedder_from_config(config)?;
+    let results = search_notes(&store, embedder.as_ref(), query, config.search.results).await?;
+    if results.is_empty() {
+        println!("Nothing was found, run `navi sync` to index your notes");
+    }
+    for result in results {
+        println!(
+            "--- {:.0}% match, last edited {}\n{}\n",
+            result.score.max(0.0) * 100.0,
+            result
+                .chunk
+                .update_date
+                .with_timezone(&Local)
+                .format("%Y-%m-%d"),
+            result.chunk.text.trim_end()
+        );
+    }
+
+    Ok(())
+}
+
+fn print_stats(config: &Config) -> Result<()> {
+    let store = open_store(config)?;
+    let stats = store.stats(Utc::now())?;
+    if stats.pages.is_empty() {
+        println!("No notes have been synced yet, sync them with `navi sync`");
+    }
+    for (source, pages) in &stats.pages {
+        let last_synced = match store.sync_state(source)? {
+            Some(state) => format!(
+                ", last synced {}",
+                state
+                    .last_synced_at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+            ),
+            None => String::new(),
+        };
+        println!("{}: {} pages{}", source, pages, last_synced);
+    }
+    println!("Blocks: {}", stats.blocks);
+    println!(
+        "Search index: {} passages{}",
+        stats.chunks,
+        if config.search.enabled {
+            ""
+        } else {
+            " (search is not enabled)"
+        }
+    );
+    println!(
+        "Flashcards: {}, {} due for review",
+        stats.cards, stats.due_cards
+    );
+
+    let sessions = list_sessions(&config.storage.transcripts_dir())?;
+    let action_items = sessions
+        .iter()
+        .filter_map(|session| session.action_items.as_ref())
+        .map(Vec::len)
+        .sum::<usize>();
+    println!(
+        "Retros: {}, which agreed on {} action items",
+        sessions.len(),
+        action_items
+    );
+    if let Some(focus) = store.current_focus()? {
+        println!(
+            "Focus: {} (since {})",
+            focus.priority,
+            focus.set_at.with_timezone(&Local).format("%Y-%m-%d")
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks the configuration at `path` (or navi.toml) for mistakes, including the ones that
+/// would otherwise only show up once Navi needs the setting, such as a missing API key
+fn check_config(config: &Config, path: Option<&Path>) -> Result<()> {
+    let path = path.unwrap_or(Path::new(DEFAULT_CONFIG_PATH));
+    let mut problems = config.validate();
+    if config.sources.notion {
+        if let Err(e) = notion_token("or set notion = false in the [sources] section") {
+            problems.push(e.to_string());
+        }
+    }
+    if let Err(e) = provider_from_config(config) {
+        problems.push(e.to_string());
+    }
+    if config.search.enabled {
+        if let Err(e) = embedder_from_config(config) {
+            problems.push(e.to_string());
+        }
+    }
+    if let Err(e) = load_format(&config.retro.format, config.retro.formats_dir.as_deref()) {
+        problems.push(e.to_string());
+    }
+
+    if problems.is_empty() {
+        if path.exists() {
+            println!("{} is valid", path.display());
+        } else {
+            println!(
+                "There is no {}, and Navi's defaults are valid",
+                path.display()
+            );
+        }
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("- {}", problem);
+    }
+    Err(NaviError::Config(format!(
+        "{} has {} {}",
+        path.display(),
+        problems.len(),
+        if problems.len() == 1 {
+            "problem"
+        } else {
+            "problems"
+        }
+    )))
+}
+
+fn print_history(config: &Config) -> Result<()> {
+    let sessions = list_sessions(&config.storage.transcripts_dir())?;
+    if sessions.is_empty() {
+        println!("No retros yet, start one with `navi retro`");
+    }
+    for session in sessions {
+        let action_items = match &session.action_items {
+            Some(items) => format!(", {} action items", items.len()),
+            None => String::new(),
+        };
+        println!(
+            "{}  {} ({}), {} responses{}, last active {}",
+            session.info.id,
+            session.info.model,
+            session.info.provider,
+            session.responses(),
+            action_items,
+            session.last_active().format("%Y-%m-%d %H:%M")
+        );
+    }
+
+    Ok(())
+}
+
+fn print_action_items(config: &Config, id: Option<&str>) -> Result<()> {
+    let dir = config.storage.transcripts_dir();
+    let session = match id {
+        Some(id) => Some(load_session(&dir, id)?),
+        None => list_sessions(&dir)?
+            .into_iter()
+            .rev()
+            .find(|session| session.action_items.is_some()),
+    };
+
+    match session.and_then(|session| session.action_items) {
+        Some(action_items) => print!("{}", to_markdown(&action_items)),
+        None => println!("No action items yet, they are saved at the end of each retro"),
+    }
+    Ok(())
 }
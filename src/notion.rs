@@ -1,203 +1,351 @@
+use crate::action_items::ActionItem;
 use crate::config::Config;
-use crate::core::{
-    datatypes::{Block, BlockID, Page, PageID},
-    helpers::build_markdown_from_trees,
-};
+use crate::core::datatypes::{Block, BlockID, Page, PageID, ParsedPage, Property};
+use crate::error::{NaviError, Result};
+use crate::retro_summary::RetroSummary;
+use crate::retry::{send, with_retry, HttpError, RetryPolicy};
+use crate::sources::{IngestionReport, NoteSource, PageChanges};
+use crate::store::{page_changes, StoredBlock};
+use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use dendron::{Node, Tree};
-use log::{debug, error, info, trace, warn};
+use futures::{stream, StreamExt};
+use log::{debug, info, trace, warn};
 use notion_client::{
-    endpoints::{
-        blocks::retrieve::response::RetrieveBlockChilerenResponse,
-        search::title::{
-            request::{Filter, SearchByTitleRequestBuilder, Sort, SortDirection, Timestamp},
-            response::PageOrDatabase,
+    endpoints::search::title::{
+        request::{
+            Filter, SearchByTitleRequest, SearchByTitleRequestBuilder, Sort, SortDirection,
+            Timestamp,
         },
-        Client,
+        response::{PageOrDatabase, SearchByTitleResponse},
+    },
+    objects::{
+        block::{Block as NotionBlock, BlockType},
+        database::Database as NotionDatabase,
+        page::Page as NotionPage,
+        parent::Parent,
+        rich_text::RichText,
     },
-    objects::page::Page as NotionPage,
-    NotionClientError,
 };
-use std::collections::{HashSet, VecDeque};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    Method,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration as StdDuration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+const NOTION_API_URL: &str = "https://api.notion.com/v1";
+/// The version of the Notion API that the `notion_client` types describe
+const NOTION_VERSION: &str = "2022-06-28";
 
+/// We talk to the Notion API over our own HTTP client rather than `notion_client`'s, because
+/// `notion_client` hides the response headers (so we couldn't honour Retry-After), but we still
+/// use `notion_client`'s types for the requests and responses.
 pub struct Notion {
-    client: Client,
+    http: reqwest::Client,
+    base_url: String,
     config: Config,
+    rate_limiter: RateLimiter,
+    retry_policy: RetryPolicy,
 }
 
-/// A ParsedNotionPage represents a page that has been processed and contains its content as a tree of blocks.
-#[derive(Debug, Clone)]
-pub struct ParsedNotionPage {
-    pub page_id: PageID,
-    pub title: String,
-    pub page_content: Vec<Tree<Block>>,
+/// Spaces requests out evenly so that, however many of them are in flight at once, we never
+/// send Notion more than `requests_per_second` of them on average.
+struct RateLimiter {
+    interval: StdDuration,
+    /// The earliest time the next request may be sent
+    next_slot: Mutex<Instant>,
 }
 
-impl Notion {
-    pub fn new(token: String) -> Result<Self, NotionClientError> {
-        let client = Client::new(token, None);
-        let config = Config::load().unwrap_or_else(|e| {
-            debug!("no navi.toml config file found: {}, using default", e);
-            Config::default()
-        });
-
-        match client {
-            Ok(c) => Ok(Notion { client: c, config }),
-            Err(e) => Err(e),
+impl RateLimiter {
+    fn new(requests_per_second: f64) -> Self {
+        RateLimiter {
+            interval: StdDuration::from_secs_f64(1.0 / requests_per_second.max(0.1)),
+            next_slot: Mutex::new(Instant::now()),
         }
     }
 
-    /// Ingests and parsed the last edited pages in the Notion integration's workspace that have been
-    /// edited since the cutoff date
-    ///
-    /// # Returns
-    /// A `Result` containing a `Vec` of `ParsedNotionPage`s, which contain the page's ID, title, and content as a tree of blocks.
-    pub async fn parse_last_edited(
-        &self,
-        dur: Duration,
-    ) -> Result<Vec<ParsedNotionPage>, NotionClientError> {
-        info!(target: "notion", "Thanks for choosing Navi as your digital mentor! Navi will begin by analyzing your last {} {} of notes. This may take several minutes, depending on how dedicated a notetaker you are...", dur.num_days(), if dur.num_days() == 1 { "day" } else { "days" });
-        let cutoff = Utc::now() - dur;
+    /// Waits until it's our turn to send a request
+    async fn wait(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        sleep_until(slot).await;
+    }
+}
 
-        let pages_edited_after_cutoff_date = self.get_last_edited_pages(cutoff).await.unwrap();
-        info!(target: "notion", "retrieved {} Pages edited in the last {} days", pages_edited_after_cutoff_date.len(), dur.num_days());
-        info!(target: "notion", "From these Pages, Navi will fetch the notes it needs to guide you in reflecting on the past {} days", dur.num_days());
+/// The most characters Notion allows in one piece of rich text
+const MAX_RICH_TEXT_LENGTH: usize = 2000;
+/// The most children Notion accepts in one request, whether for a new Page or for appending to one
+const MAX_CHILDREN_PER_REQUEST: usize = 100;
 
-        // TODO: idea: instead of storing the whole Block data, which is 95% worthless data, just strip out the
-        // text and id, store that in a struct, and use that to build the markdown
-        let mut parsed_pages = Vec::new();
+/// The part of Notion's response to POST /pages that we use
+#[derive(Deserialize)]
+struct CreatedPage {
+    id: String,
+    url: String,
+}
 
-        let mut block_roots_duplicates_checker: HashSet<Block> = HashSet::new();
-        let mut expanded_blocks_duplicates_checker: HashSet<Block> = HashSet::new();
-        for page in pages_edited_after_cutoff_date {
-            debug!(target: "notion", "Page URL: {}", page.url);
+/// The children of a Block, as returned by `Notion::retrieve_all_block_children`
+#[derive(Debug, Default)]
+pub struct BlockChildren {
+    pub blocks: Vec<Block>,
+    /// The children that Notion returned in a shape we couldn't parse, along with their
+    /// IDs when those could be read
+    pub unreadable: Vec<(Option<BlockID>, NaviError)>,
+}
 
-            // Check if this page should be excluded based on configuration
-            if self.config.should_exclude_page(&page.title, &page.url) {
-                debug!(target: "notion", "Skipping excluded page: {}", page.title);
-                continue;
-            }
+/// One page of the response to GET /blocks/{id}/children. We parse the results one at a time
+/// rather than with `notion_client`'s response type, so that a single Block we can't parse
+/// (e.g. a block type `notion_client` doesn't know about yet) doesn't lose us all its siblings.
+#[derive(Deserialize)]
+struct BlockChildrenResponse {
+    results: Vec<serde_json::Value>,
+    next_cursor: Option<String>,
+    has_more: bool,
+}
 
-            let new_block_roots = self
-                .get_page_block_roots(&page, cutoff, &mut block_roots_duplicates_checker)
-                .await
-                .unwrap();
+impl Notion {
+    /// What Notion's Pages are kept under in the `Store`, see `NoteSource::name`
+    pub const NAME: &str = "Notion";
 
-            if new_block_roots.len() > 0 {
-                debug!(target: "notion", "found {} new block roots for page: {}",  new_block_roots.len(), page.title);
-                let trees = self
-                    .expand_block_roots(new_block_roots, &mut expanded_blocks_duplicates_checker)
-                    .await
-                    .unwrap();
+    pub fn new(token: String, config: Config) -> Result<Self> {
+        Self::with_base_url(token, config, NOTION_API_URL)
+    }
 
-                parsed_pages.push(ParsedNotionPage {
-                    page_id: page.id,
-                    title: page.title,
-                    page_content: trees,
-                });
+    fn with_base_url(token: String, config: Config, base_url: &str) -> Result<Self> {
+        let mut authorization =
+            HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| {
+                NaviError::Config("NOTION_TOKEN is not a valid HTTP header value".to_string())
+            })?;
+        authorization.set_sensitive(true);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization);
+        headers.insert("Notion-Version", HeaderValue::from_static(NOTION_VERSION));
+        let http = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(|e| NaviError::Source {
+                source_name: "Notion".to_string(),
+                message: format!("unable to build an HTTP client: {}", e),
+            })?;
+
+        Ok(Notion {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            rate_limiter: RateLimiter::new(config.notion.requests_per_second),
+            retry_policy: RetryPolicy::from_settings(&config.retry),
+            config,
+        })
+    }
+
+    /// Sends a request to the Notion API and parses its response. Every request waits its turn with
+    /// the rate limiter, and is retried according to the `[retry]` section of navi.toml if it fails
+    /// for a reason that may go away on its own, such as being rate limited.
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<String>,
+    ) -> Result<T> {
+        let description = format!("{} {}", method, path);
+        let url = format!("{}{}", self.base_url, path);
+
+        let response = with_retry(&self.retry_policy, &description, || {
+            let mut request = self.http.request(method.clone(), &url).query(query);
+            if let Some(body) = &body {
+                request = request
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.clone());
             }
-        }
 
-        debug!(target: "notion", "retrieved {} pages with non-empty block roots", parsed_pages.len());
-        trace!(target: "notion", "the parsed pages look like:\n{:#?}", parsed_pages.iter().map(|p| (&p.page_id, p.page_content.iter().map(|t| (t.root().borrow_data().id.clone(), t.root().borrow_data().text.clone())).collect::<Vec<_>>())).collect::<Vec<_>>());
+            async move {
+                self.rate_limiter.wait().await;
+                send(request).await
+            }
+        })
+        .await
+        .map_err(|source| NaviError::Network {
+            request: description.clone(),
+            page_id: None,
+            block_id: None,
+            source,
+        })?;
 
-        Ok(parsed_pages)
+        serde_json::from_str(&response).map_err(|source| {
+            debug!(target: "notion", "unable to parse the response to {}: {}", description, response);
+            NaviError::Deserialization {
+                what: format!("Notion's response to {}", description),
+                source,
+            }
+        })
+    }
+
+    async fn search(&self, request: &SearchByTitleRequest) -> Result<SearchByTitleResponse> {
+        let body = serde_json::to_string(request).map_err(|source| NaviError::Deserialization {
+            what: "the body of POST /search".to_string(),
+            source,
+        })?;
+        self.request(Method::POST, "/search", &[], Some(body)).await
     }
 
-    /// Converts Navi's internal representation of a Vec of Page's content into a markdown
-    /// prompt that can be used to guide Navi in reflecting on the past duration's worth of notetaking.
+    /// Creates a Page titled `title` for a retro under the `retro_parent_page_id` of the `[notion]`
+    /// section of navi.toml, with the retro's summary and a to-do for each of its action items.
+    /// Returns the new Page's URL.
     ///
-    /// # Returns
-    /// A `Result` containing a `String` of prompt markdown.
-    pub fn to_prompt_text(
-        notion_pages: Vec<ParsedNotionPage>,
-    ) -> Result<String, NotionClientError> {
-        let mut every_prompt_markdown = Vec::new();
-        for page in notion_pages {
-            let single_page_prompt_markdown = build_markdown_from_trees(page.page_content);
-            every_prompt_markdown.push(format!(
-                "Page Title: {}\n{}",
-                page.title, single_page_prompt_markdown
-            ));
+    /// Notion only takes so many Blocks at a time, so a long retro is created with its first
+    /// Blocks and has the rest appended to it afterwards.
+    pub async fn create_retro_page(
+        &self,
+        title: &str,
+        summary: &RetroSummary,
+        action_items: &[ActionItem],
+    ) -> Result<String> {
+        let parent = self.config.notion.retro_parent_page_id.as_deref().ok_or_else(|| {
+            NaviError::Config(
+                "retro_parent_page_id must be set in the [notion] section of navi.toml to write retros to Notion"
+                    .to_string(),
+            )
+        })?;
+        let blocks = retro_page_blocks(summary, action_items);
+        let mut batches = blocks.chunks(MAX_CHILDREN_PER_REQUEST);
+        let body = json!({
+            "parent": {"page_id": parent},
+            "properties": {"title": {"title": rich_text(title)}},
+            "children": batches.next().unwrap_or_default(),
+        });
+
+        let page: CreatedPage = self
+            .request(Method::POST, "/pages", &[], Some(body.to_string()))
+            .await?;
+        for batch in batches {
+            let body = json!({ "children": batch });
+            let _: Value = self
+                .request(
+                    Method::PATCH,
+                    &format!("/blocks/{}/children", page.id),
+                    &[],
+                    Some(body.to_string()),
+                )
+                .await?;
         }
+        info!(target: "notion", "wrote the retro to {}", page.url);
+        Ok(page.url)
+    }
+
+    /// Whether `notion_page` is the Page that retros are written under, or one of the retros
+    fn is_retro_page(&self, notion_page: &NotionPage) -> bool {
+        let Some(retro_parent) = &self.config.notion.retro_parent_page_id else {
+            return false;
+        };
+        // Notion accepts page IDs with or without their dashes
+        let same_page = |page_id: &str| page_id.replace('-', "") == retro_parent.replace('-', "");
+        same_page(&notion_page.id)
+            || matches!(&notion_page.parent, Parent::PageId { page_id } if same_page(page_id))
+    }
 
-        Ok(every_prompt_markdown.join("\n\n"))
+    /// The number of Block children requests we allow in flight at once
+    fn concurrency(&self) -> usize {
+        self.config.notion.concurrency.max(1)
     }
 
-    /// Returns all `Page`s in the Notion integration's workspace that have been edited since the cutoff date.
-    /// The `Page`s will be ordered by last edited date in descending order.
+    /// Retrieves the children of every one of the given Blocks, with up to `concurrency()` requests
+    /// in flight at once. The children are returned in the same order as the given Blocks, so the
+    /// `Tree`s we build from them come out the same no matter which request finishes first.
     ///
-    /// Note: we purposefully ignore Databases here, so that Navi can be simple and focus on notetaking `Page`s.
-    pub async fn get_last_edited_pages(
+    /// Each Block gets its own `Result`, so that one Block whose children can't be fetched doesn't
+    /// stop us from reading the others.
+    async fn retrieve_children_of_blocks(
         &self,
-        cutoff: DateTime<Utc>,
-    ) -> Result<Vec<Page>, NotionClientError> {
-        let mut pages: Vec<Page> = Vec::new();
+        blocks: Vec<(BlockID, PageID)>,
+    ) -> Vec<Result<BlockChildren>> {
+        stream::iter(blocks)
+            .map(|(block_id, page_id)| async move {
+                trace!(target: "notion", "fetching children of block with id {}", &block_id);
+                self.retrieve_all_block_children(&block_id, &page_id).await
+            })
+            .buffered(self.concurrency())
+            .collect()
+            .await
+    }
+
+    /// Returns the children of `block_id` that could be read, recording the ones that couldn't
+    /// (or, if the request for them failed, `block_id` itself) in `report`.
+    fn readable_children(
+        &self,
+        page: &Page,
+        block_id: &BlockID,
+        children: Result<BlockChildren>,
+        report: &mut IngestionReport,
+    ) -> Vec<Block> {
+        match children {
+            Ok(children) => {
+                for (child_id, error) in &children.unreadable {
+                    report.record(
+                        self.name(),
+                        &page.title,
+                        &page.url,
+                        Some(child_id.as_ref().unwrap_or(block_id)),
+                        error,
+                    );
+                }
+                children.blocks
+            }
+            Err(e) => {
+                report.record(self.name(), &page.title, &page.url, Some(block_id), &e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Returns the titles of all the databases shared with the Notion integration, keyed by database ID.
+    pub async fn get_databases(&self) -> Result<HashMap<String, String>> {
+        let mut databases = HashMap::new();
         let mut current_cursor: Option<String> = None;
 
         let mut req_builder = SearchByTitleRequestBuilder::default();
         req_builder
             .filter(Filter {
-                value: notion_client::endpoints::search::title::request::FilterValue::Page,
+                value: notion_client::endpoints::search::title::request::FilterValue::Database,
                 property: notion_client::endpoints::search::title::request::FilterProperty::Object,
             })
-            .sort(Sort {
-                timestamp: Timestamp::LastEditedTime,
-                direction: SortDirection::Descending,
-            })
             .page_size(100);
 
         loop {
-            // this cursor is for request pagination
             if let Some(cursor) = current_cursor {
                 req_builder.start_cursor(cursor);
             }
 
-            let res = self
-                .client
-                .search
-                .search_by_title(req_builder.build().unwrap())
-                .await?;
+            let res = self.search(&build_search(&req_builder)?).await?;
 
-            current_cursor = res.next_cursor;
-            let res_len = res.results.len();
-            let mut current_notion_pages = res
-                .results
-                .into_iter()
-                .filter_map(|page_or_db| match page_or_db {
-                    PageOrDatabase::Page(page) => Some(page),
-                    PageOrDatabase::Database(_) => None, // TODO: support databases
-                })
-                .collect::<Vec<NotionPage>>();
-            debug_assert!(current_notion_pages.len() == res_len, "something other than a page was found in returned info. res_len: {} current_notion_pages.len(): {}", res_len, current_notion_pages.len());
-
-            // we only care about pages edited after the cutoff, so we need to
-            // cut out the Pages that were edited prior to the cutoff
-            let cutoff_index = current_notion_pages
-                .iter()
-                .position(|page| page.last_edited_time < cutoff);
-            if let Some(index) = cutoff_index {
-                current_notion_pages = current_notion_pages.split_at(index).0.to_vec();
-            }
-
-            for notion_page in current_notion_pages {
-                let page = self.notion_page_to_navi_page(notion_page).await?;
-                pages.push(page);
+            for page_or_db in res.results {
+                if let PageOrDatabase::Database(database) = page_or_db {
+                    databases.insert(database.id.clone(), database_title(&database));
+                }
             }
 
-            // here we've either ran out of pages in the workspace, or found all the pages that were edited after the cutoff,
-            // so we exit the loop
-            if !res.has_more || cutoff_index.is_some() {
+            if !res.has_more {
                 break;
             }
+            current_cursor = res.next_cursor;
         }
 
-        Ok(pages)
+        Ok(databases)
     }
 
     /// For a given `Page`, retrieve all of its non-empty children, grandchildren, etc... `Block`s that were edited within the specified duration.
     ///
-    /// Uses breadth-first-search to recursively fetch all the `Block` descendants of the `Page`.
+    /// Uses breadth-first-search to recursively fetch all the `Block` descendants of the `Page`, one
+    /// level of the tree at a time, fetching the children of every `Block` in a level concurrently.
     ///
     /// Note: we do not include the `Page` itself as a block root, because then the content of every single `Page` that
     /// was updated within the duration would be included (that's a ton!), when all we want is the individual
@@ -205,16 +353,19 @@ impl Notion {
     /// from the fact that Notion treats Pages and Blocks both as Blocks, even though a Page is a special
     /// type of Block by way of it's `last_edited_time` property being updated whenever a child Block is updated.
     ///
+    /// Blocks whose children can't be fetched are recorded in `report`, and their descendants are left out.
+    ///
     /// # Returns
-    /// A `Result` containing a `Vec` of all the `Page`'s descendant `Block`s that were updated between within `dur`.
+    /// A `Vec` of all the `Page`'s descendant `Block`s that were updated between within `dur`.
     /// Note that the order of the `Block`s is not guaranteed and cannot be relied upon.
     pub async fn get_page_block_roots(
         &self,
         page: &Page,
         cutoff: DateTime<Utc>,
         duplicates_checker: &mut HashSet<Block>,
-    ) -> Result<Vec<Block>, NotionClientError> {
-        let mut blocks_to_process = VecDeque::from(page.child_blocks.clone());
+        report: &mut IngestionReport,
+    ) -> Vec<Block> {
+        let mut level = page.child_blocks.clone();
         let mut block_roots: Vec<Block> = Vec::new();
 
         // some user's Pages are huuuge, so long that we don't know if we'll spend too much time
@@ -224,7 +375,7 @@ impl Notion {
         let time_to_spend_fetching_children = Duration::seconds(30);
         let abort_time = Utc::now() + time_to_spend_fetching_children;
 
-        while let Some(block) = blocks_to_process.pop_front() {
+        while !level.is_empty() {
             if Utc::now() > abort_time {
                 // we've spent too much time fetching children, so stop recursing and return
                 // the (truncated) block roots that we have. This means we may miss out on
@@ -234,110 +385,54 @@ impl Notion {
                 break;
             }
 
-            // traversing blocks in Notion is a complicated process, so complicated that we
-            // don't know if there are cycles and we're going to get stuck in an infinite loop.
-            // To prevent that, we check for duplicates and skip them, preventing any infinite loops
-            if duplicates_checker.contains(&block) {
-                trace!(
-                    target: "notion",
-                    "already visited this block {}, skipping it...",
-                    &block.id
-                );
-                continue;
-            }
-            duplicates_checker.insert(block.clone());
-            trace!(target: "notion", "duplicates_checker.insert({})", &block.id);
+            let mut blocks_to_expand = Vec::new();
+            for block in level {
+                // traversing blocks in Notion is a complicated process, so complicated that we
+                // don't know if there are cycles and we're going to get stuck in an infinite loop.
+                // To prevent that, we check for duplicates and skip them, preventing any infinite loops
+                if duplicates_checker.contains(&block) {
+                    trace!(
+                        target: "notion",
+                        "already visited this block {}, skipping it...",
+                        &block.id
+                    );
+                    continue;
+                }
+                duplicates_checker.insert(block.clone());
+                trace!(target: "notion", "duplicates_checker.insert({})", &block.id);
 
-            // was the block updated recently enough that we should include it in the results?
-            if block.update_date >= cutoff {
-                if !block.is_empty() {
-                    block_roots.push(block.clone());
+                // was the block updated recently enough that we should include it in the results?
+                if block.update_date >= cutoff {
+                    if !block.is_empty() {
+                        block_roots.push(block);
+                    }
+                    continue;
                 }
-                continue;
-            }
 
-            if block.has_children {
-                trace!(
-                    target: "notion",
-                    "fetching children block roots of block with id {}",
-                    &block.id
-                );
-                let children = self
-                    .retrieve_all_block_children(&block.id, &page.id)
-                    .await?;
-
-                for child_block in children {
-                    trace!(target: "notion", "fetched child block: (id: {}, text: {:?})", &child_block.id, &child_block.text);
-                    // keep recursing down the tree of children blocks
-                    blocks_to_process.push_back(child_block.clone());
+                if block.has_children {
+                    blocks_to_expand.push((block.id, page.id.clone()));
                 }
             }
+
+            // keep recursing down the tree of children blocks
+            let block_ids = blocks_to_expand
+                .iter()
+                .map(|(block_id, _)| block_id.clone())
+                .collect::<Vec<_>>();
+            let children_of_blocks = self.retrieve_children_of_blocks(blocks_to_expand).await;
+            level = block_ids
+                .iter()
+                .zip(children_of_blocks)
+                .flat_map(|(block_id, children)| {
+                    self.readable_children(page, block_id, children, report)
+                })
+                .collect();
         }
 
         debug!(target: "notion", "fetched {} descendant Blocks from Page {}", block_roots.len(), page.title);
         trace!(target: "notion", "{:#?}", block_roots);
 
-        Ok(block_roots)
-    }
-
-    /// Given a Block that has been recently edited, return a Tree whose root is the
-    /// given Block, whose next level is the children of the given Block, and so on
-    /// until there are no more descendants. This Tree contains all the notes that
-    /// are relevant to the last duration's worth of notetaking.
-    ///
-    /// Note: while the given Block has been edited recently, there is no guarantee
-    /// that the descendants of the given Block have been edited recently.
-    async fn expand_block_root(
-        &self,
-        block_root: Node<Block>,
-        duplicates_checker: &mut HashSet<Block>,
-    ) -> Result<(), NotionClientError> {
-        let mut queue = VecDeque::from(vec![block_root]);
-
-        while let Some(node) = queue.pop_front() {
-            let grant = node.tree().grant_hierarchy_edit().unwrap();
-            let borrowed_node = node.borrow_data();
-            debug!(target: "notion", "borrowed_node: {:?}", (&borrowed_node.id, &borrowed_node.text));
-
-            if duplicates_checker.contains(&borrowed_node) {
-                trace!(target: "notion", "already visited this block {:?}, skipping it...", (&borrowed_node.id, &borrowed_node.text));
-                // Note: this is kind of a hack, because I'm seeing duplicate blocks from a single block root,
-                // and the solution here is it just skips over the duplicate, which is not ideal.
-                // In the future we should figure out what's going on here and actually do it right, but I'm
-                // following make it work, make it right, make it fast, and I'm still trying to make it work.
-                continue;
-            }
-            duplicates_checker.insert(borrowed_node.clone());
-
-            if borrowed_node.has_children {
-                trace!(target: "notion", "block with id {} has children, fetching them...", &borrowed_node.id);
-
-                let children = self
-                    .retrieve_all_block_children(&borrowed_node.id, &borrowed_node.page_id)
-                    .await?;
-                for child in children {
-                    debug!(target: "notion", "child: {:?}", (&child.id, &child.text));
-                    if duplicates_checker.contains(&child) {
-                        trace!(target: "notion", "already visited this child block {:?}, skipping it...", (&child.id, &child.text));
-
-                        // Note: this is kind of a hack, because I should diagnose why we're seeing duplicate blocks
-                        // and stop it at its source. However, I'm following make it work, make it right, make it fast,
-                        // and this is a simple way to prevent duplicates from being added to the tree.
-                        continue;
-                    } else if !child.is_empty() {
-                        // here is where we actually add the Block to the Tree. We add Blocks to the Tree
-                        // in this children-fetching codeblock instead of at the beginning of the while
-                        // loop simply because the block_root is already in the Tree, and we don't want
-                        // to double add it
-                        let new_node = node.create_as_last_child(&grant, child);
-                        debug_assert_eq!(new_node, node.last_child().unwrap());
-                        queue.push_back(new_node);
-                    }
-                }
-            }
-        }
-
-        Ok(())
+        block_roots
     }
 
     /// Given a `Vec` of `Block`s (call these `Block`s "roots") that have been updated recently,
@@ -365,107 +460,704 @@ impl Notion {
     ///    | |    | |   | |       | | |       | |      .
     ///    D E    F G   H I       L M N       O P      .
     /// ```
+    ///
+    /// The `Tree`s are expanded together, one level at a time (first A through ZZZ, then D through P,
+    /// ...), so that the children of every `Block` in a level can be fetched concurrently.
+    ///
+    /// Blocks of `page` whose children can't be fetched are recorded in `report`, and left as leaves.
+    ///
+    /// Note: while the roots have been edited recently, there is no guarantee that their
+    /// descendants have been edited recently.
     pub async fn expand_block_roots(
         &self,
+        page: &Page,
         block_roots: Vec<Block>,
         duplicates_checker: &mut HashSet<Block>,
-    ) -> Result<Vec<Tree<Block>>, NotionClientError> {
-        let mut expanded_roots = Vec::new();
-        for block in block_roots {
-            let root = Node::new_tree(block);
-            expanded_roots.push(root.tree());
+        report: &mut IngestionReport,
+    ) -> Vec<Tree<Block>> {
+        let roots = block_roots
+            .into_iter()
+            .map(Node::new_tree)
+            .collect::<Vec<_>>();
+        let expanded_roots = roots.iter().map(|root| root.tree()).collect();
+
+        let mut level = roots;
+        while !level.is_empty() {
+            let mut nodes_to_expand = Vec::new();
+            for node in level {
+                let borrowed_node = node.borrow_data();
+                debug!(target: "notion", "borrowed_node: {:?}", (&borrowed_node.id, &borrowed_node.text));
 
-            self.expand_block_root(root, duplicates_checker).await?;
+                if duplicates_checker.contains(&borrowed_node) {
+                    trace!(target: "notion", "already visited this block {:?}, skipping it...", (&borrowed_node.id, &borrowed_node.text));
+                    // Note: this is kind of a hack, because I'm seeing duplicate blocks from a single block root,
+                    // and the solution here is it just skips over the duplicate, which is not ideal.
+                    // In the future we should figure out what's going on here and actually do it right, but I'm
+                    // following make it work, make it right, make it fast, and I'm still trying to make it work.
+                    continue;
+                }
+                duplicates_checker.insert(borrowed_node.clone());
+
+                if borrowed_node.has_children {
+                    trace!(target: "notion", "block with id {} has children, fetching them...", &borrowed_node.id);
+                    drop(borrowed_node);
+                    nodes_to_expand.push(node);
+                }
+            }
+
+            let children_of_nodes = self
+                .retrieve_children_of_blocks(
+                    nodes_to_expand
+                        .iter()
+                        .map(|node| {
+                            let block = node.borrow_data();
+                            (block.id.clone(), block.page_id.clone())
+                        })
+                        .collect(),
+                )
+                .await;
+
+            let mut next_level = Vec::new();
+            for (node, children) in nodes_to_expand.into_iter().zip(children_of_nodes) {
+                let block_id = node.borrow_data().id.clone();
+                let children = self.readable_children(page, &block_id, children, report);
+                let grant = node.tree().grant_hierarchy_edit().unwrap();
+                for child in children {
+                    debug!(target: "notion", "child: {:?}", (&child.id, &child.text));
+                    if duplicates_checker.contains(&child) {
+                        trace!(target: "notion", "already visited this child block {:?}, skipping it...", (&child.id, &child.text));
+
+                        // Note: this is kind of a hack, because I should diagnose why we're seeing duplicate blocks
+                        // and stop it at its source. However, I'm following make it work, make it right, make it fast,
+                        // and this is a simple way to prevent duplicates from being added to the tree.
+                        continue;
+                    } else if !child.is_empty() {
+                        // here is where we actually add the Block to the Tree. We add Blocks to the Tree
+                        // in this children-fetching codeblock instead of when visiting the node simply
+                        // because the block_root is already in the Tree, and we don't want to double add it
+                        let new_node = node.create_as_last_child(&grant, child);
+                        debug_assert_eq!(new_node, node.last_child().unwrap());
+                        next_level.push(new_node);
+                    }
+                }
+            }
+            level = next_level;
         }
 
-        Ok(expanded_roots)
+        expanded_roots
+    }
+
+    /// Retrieves every Block of `page`, one level of the tree at a time, for keeping in the
+    /// `Store`. Unlike `get_page_block_roots` and `expand_block_roots`, which only keep the Blocks
+    /// with text, this keeps the Blocks that only hold others (e.g. column lists, columns and
+    /// synced blocks) and expands them too, so that nothing inside them is lost.
+    ///
+    /// Child pages and databases are Pages of their own, which are synced separately, so they are
+    /// kept but not expanded. Blocks whose children can't be fetched are recorded in `report`.
+    async fn retrieve_page_blocks(
+        &self,
+        page: &Page,
+        report: &mut IngestionReport,
+    ) -> Vec<StoredBlock> {
+        let mut blocks = Vec::new();
+        let mut visited = HashSet::new();
+        let mut level = page
+            .child_blocks
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(position, block)| (None, position, block))
+            .collect::<Vec<_>>();
+
+        while !level.is_empty() {
+            let mut blocks_to_expand = Vec::new();
+            for (parent_id, position, block) in level {
+                // as in `get_page_block_roots`, a Block we've already seen is skipped in case
+                // Notion's Blocks form a cycle
+                if !visited.insert(block.id.clone()) {
+                    continue;
+                }
+                let is_child_page = matches!(
+                    block.block_type,
+                    BlockType::ChildPage { .. } | BlockType::ChildDatabase { .. }
+                );
+                if block.has_children && !is_child_page {
+                    blocks_to_expand.push(block.id.clone());
+                }
+                blocks.push(StoredBlock {
+                    block,
+                    parent_id,
+                    position,
+                });
+            }
+
+            let children_of_blocks = self
+                .retrieve_children_of_blocks(
+                    blocks_to_expand
+                        .iter()
+                        .map(|block_id| (block_id.clone(), page.id.clone()))
+                        .collect(),
+                )
+                .await;
+            level = Vec::new();
+            for (block_id, children) in blocks_to_expand.into_iter().zip(children_of_blocks) {
+                let children = self.readable_children(page, &block_id, children, report);
+                for (position, child) in children.into_iter().enumerate() {
+                    level.push((Some(block_id.clone()), position, child));
+                }
+            }
+        }
+
+        debug!(target: "notion", "fetched all {} Blocks of Page {}", blocks.len(), page.title);
+        blocks
     }
 
     /// Retrieves all of the children Blocks of a Block with the given ID.
     ///
     /// Notion's API only allows for retrieving 100 children at a time, so this
     /// function exists to paginate through the results and return them as a single Vec.
+    ///
+    /// Children that can't be parsed are returned separately in `BlockChildren::unreadable`,
+    /// rather than failing the whole request.
     pub async fn retrieve_all_block_children(
         &self,
         block_id: &BlockID,
         page_id: &PageID,
-    ) -> Result<Vec<Block>, NotionClientError> {
-        let mut children_blocks: Vec<Block> = Vec::new();
+    ) -> Result<BlockChildren> {
+        let mut children = BlockChildren::default();
         let mut current_cursor: Option<String> = None;
 
         loop {
-            let res = self
-                .client
-                .blocks
-                .retrieve_block_children(block_id, current_cursor.as_deref(), Some(100))
-                .await;
+            let mut query = vec![("page_size", "100")];
+            if let Some(cursor) = &current_cursor {
+                query.push(("start_cursor", cursor.as_str()));
+            }
+            let res: BlockChildrenResponse = self
+                .request(
+                    Method::GET,
+                    &format!("/blocks/{}/children", block_id),
+                    &query,
+                    None,
+                )
+                .await
+                .map_err(|e| e.at(page_id, block_id))?;
 
-            let res: RetrieveBlockChilerenResponse = match res {
-                Ok(res) => res,
-                Err(e) => match e {
-                    NotionClientError::FailedToDeserialize { source: _, body } => {
-                        let json_value: serde_json::Value = serde_json::from_str(&body).unwrap();
-                        let pretty_json = serde_json::to_string_pretty(&json_value).unwrap();
-                        debug!(target: "notion", "Custom Failed to deserialize response body");
-                        debug!(target: "notion", "{}", pretty_json);
-                        // there seems to be some bug in notion-client where it's unable to handle these
-                        // Response bodies, so I need to manually deserialize them here
-                        // TODO research further what's going on here
-                        serde_json::from_str(&body).unwrap()
-                    }
-                    _ => {
-                        error!(target: "notion", "Custom error in retrieve_block_children {}", e);
-                        panic!("{}", e)
+            for result in res.results {
+                let child_id = result
+                    .get("id")
+                    .and_then(serde_json::Value::as_str)
+                    .map(|id| BlockID::new(id.to_string()));
+                match serde_json::from_value::<NotionBlock>(result) {
+                    Ok(block) => children
+                        .blocks
+                        .push(Block::from_notion_block(block, page_id.to_string())),
+                    Err(source) => {
+                        debug!(target: "notion", "unable to parse a child of block {}: {}", block_id, source);
+                        children.unreadable.push((
+                            child_id,
+                            NaviError::Deserialization {
+                                what: format!("a child of block {}", block_id),
+                                source,
+                            },
+                        ));
                     }
-                },
-            };
-
-            children_blocks.append(
-                &mut res
-                    .results
-                    .into_iter()
-                    .map(|block| Block::from_notion_block(block, page_id.to_string()))
-                    .collect(),
-            );
+                }
+            }
 
             if !res.has_more {
                 break;
             }
-            current_cursor = res.next_cursor.clone();
+            current_cursor = res.next_cursor;
         }
 
-        Ok(children_blocks)
+        Ok(children)
     }
 
-    /// Converts a Notion Page to a Navi Page.
+    /// Converts a Notion Page to a Navi Page. `database` is the title of the database the
+    /// Page is a row of, if any, in which case the Page's properties are kept as well.
+    ///
+    /// The Page's `child_blocks` are left empty, see `retrieve_page_children` for filling them in.
     ///
     /// Note that the title extraction is a bit hacky and may not work for every page title, but it's good enough for getting the gist of what the page is called.
-    async fn notion_page_to_navi_page(
-        &self,
-        notion_page: NotionPage,
-    ) -> Result<Page, NotionClientError> {
-        Ok(Page {
+    fn notion_page_to_navi_page(notion_page: NotionPage, database: Option<String>) -> Page {
+        let (title_property, properties) = if database.is_some() {
+            page_properties(&notion_page)
+        } else {
+            (None, Vec::new())
+        };
+
+        Page {
             id: PageID::new(notion_page.id.clone()),
             // convert https://www.notion.so/August-19-2024-651d530e07a14f9c97b4084614c5049b -> August 19 2024
             // Note: yes, this is kinda hacky and won't work for every page title, but it's good enough
-            // for getting the gist of what the page is called
-            title: match notion_page.url.split("/").last() {
-                Some(name) => {
+            // for getting the gist of what the page is called. Database rows have a proper title
+            // property, so we prefer that when we have it
+            title: match (title_property, notion_page.url.split("/").last()) {
+                (Some(title), _) => title,
+                (None, Some(name)) => {
                     let parts = name.split("-").collect::<Vec<&str>>();
                     parts.split_at(parts.len() - 1).0.join(" ")
                 }
-                None => "Unknown Page Title".to_string(),
+                (None, None) => "Unknown Page Title".to_string(),
             },
             url: notion_page.url.clone(),
             creation_date: notion_page.created_time,
             update_date: notion_page.last_edited_time,
-            child_blocks: self
-                .retrieve_all_block_children(
-                    &BlockID::new(notion_page.id.clone()),
-                    &PageID::new(notion_page.id),
-                )
-                .await?,
+            child_blocks: Vec::new(),
+            database,
+            properties,
+        }
+    }
+
+    /// Searches for the Pages edited since the cutoff, the most recently edited first, leaving out
+    /// retros written by Navi and whatever navi.toml excludes. Their `child_blocks` are left empty.
+    async fn search_pages(&self, cutoff: DateTime<Utc>) -> Result<Vec<Page>> {
+        let databases = self.get_databases().await?;
+        debug!(target: "notion", "found {} databases shared with the integration", databases.len());
+
+        let mut pages: Vec<Page> = Vec::new();
+        let mut current_cursor: Option<String> = None;
+
+        let mut req_builder = SearchByTitleRequestBuilder::default();
+        req_builder
+            .filter(Filter {
+                value: notion_client::endpoints::search::title::request::FilterValue::Page,
+                property: notion_client::endpoints::search::title::request::FilterProperty::Object,
+            })
+            .sort(Sort {
+                timestamp: Timestamp::LastEditedTime,
+                direction: SortDirection::Descending,
+            })
+            .page_size(100);
+
+        loop {
+            // this cursor is for request pagination
+            if let Some(cursor) = current_cursor {
+                req_builder.start_cursor(cursor);
+            }
+
+            let res = self.search(&build_search(&req_builder)?).await?;
+
+            current_cursor = res.next_cursor;
+            let res_len = res.results.len();
+            let mut current_notion_pages = res
+                .results
+                .into_iter()
+                .filter_map(|page_or_db| match page_or_db {
+                    PageOrDatabase::Page(page) => Some(page),
+                    // databases themselves have no notes in them, only rows, and
+                    // those rows are returned to us as Pages
+                    PageOrDatabase::Database(_) => None,
+                })
+                .collect::<Vec<NotionPage>>();
+            debug_assert!(current_notion_pages.len() == res_len, "something other than a page was found in returned info. res_len: {} current_notion_pages.len(): {}", res_len, current_notion_pages.len());
+
+            // we only care about pages edited after the cutoff, so we need to
+            // cut out the Pages that were edited prior to the cutoff
+            let cutoff_index = current_notion_pages
+                .iter()
+                .position(|page| page.last_edited_time < cutoff);
+            if let Some(index) = cutoff_index {
+                current_notion_pages = current_notion_pages.split_at(index).0.to_vec();
+            }
+
+            for notion_page in current_notion_pages {
+                if self.is_retro_page(&notion_page) {
+                    debug!(target: "notion", "Skipping a retro written by Navi: {}", notion_page.url);
+                    continue;
+                }
+
+                let database = match &notion_page.parent {
+                    Parent::DatabaseId { database_id } => {
+                        let title = databases
+                            .get(database_id)
+                            .cloned()
+                            .unwrap_or_else(|| "Unknown Database".to_string());
+                        if !self.config.should_include_database(&title, database_id) {
+                            debug!(target: "notion", "Skipping row of excluded database: {}", title);
+                            continue;
+                        }
+                        Some(title)
+                    }
+                    _ => None,
+                };
+
+                let page = Self::notion_page_to_navi_page(notion_page, database);
+
+                // Check if this page should be excluded based on configuration, before anyone
+                // spends any requests on fetching its children
+                if self.config.should_exclude_page(&page.title, &page.url) {
+                    debug!(target: "notion", "Skipping excluded page: {}", page.title);
+                    continue;
+                }
+
+                pages.push(page);
+            }
+
+            // here we've either ran out of pages in the workspace, or found all the pages that were edited after the cutoff,
+            // so we exit the loop
+            if !res.has_more || cutoff_index.is_some() {
+                break;
+            }
+        }
+
+        Ok(pages)
+    }
+
+    /// Fills in the `child_blocks` of every one of the given Pages, fetching them concurrently.
+    /// Pages whose children can't be fetched are recorded in `report` and left out.
+    async fn retrieve_page_children(
+        &self,
+        pages: Vec<Page>,
+        report: &mut IngestionReport,
+    ) -> Vec<Page> {
+        let children = self
+            .retrieve_children_of_blocks(
+                pages
+                    .iter()
+                    .map(|page| (BlockID::new(page.id.to_string()), page.id.clone()))
+                    .collect(),
+            )
+            .await;
+
+        let mut pages_with_children = Vec::new();
+        for (mut page, children) in pages.into_iter().zip(children) {
+            match children {
+                Ok(children) => {
+                    let page_block_id = BlockID::new(page.id.to_string());
+                    page.child_blocks =
+                        self.readable_children(&page, &page_block_id, Ok(children), report);
+                    pages_with_children.push(page);
+                }
+                Err(e) => report.record(self.name(), &page.title, &page.url, None, &e),
+            }
+        }
+
+        pages_with_children
+    }
+}
+
+#[async_trait(?Send)]
+impl NoteSource for Notion {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    /// Ingests and parsed the last edited pages in the Notion integration's workspace that have been
+    /// edited since the cutoff date
+    ///
+    /// We override the default implementation so that the duplicates checkers are shared across
+    /// every page, because Notion lets the same Block show up under more than one page (e.g. child pages).
+    ///
+    /// # Returns
+    /// A `Result` containing a `Vec` of `ParsedPage`s, which contain the page's ID, title, and content as a tree of blocks.
+    async fn parse_last_edited(
+        &self,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<ParsedPage>> {
+        let pages_edited_after_cutoff_date = self.get_last_edited_pages(cutoff, report).await?;
+        info!(target: "notion", "retrieved {} Pages edited since {}", pages_edited_after_cutoff_date.len(), cutoff);
+        info!(target: "notion", "From these Pages, Navi will fetch the notes it needs to guide you in reflecting on your notes since {}", cutoff);
+
+        // TODO: idea: instead of storing the whole Block data, which is 95% worthless data, just strip out the
+        // text and id, store that in a struct, and use that to build the markdown
+        let mut parsed_pages = Vec::new();
+
+        let mut block_roots_duplicates_checker: HashSet<Block> = HashSet::new();
+        let mut expanded_blocks_duplicates_checker: HashSet<Block> = HashSet::new();
+        for page in pages_edited_after_cutoff_date {
+            debug!(target: "notion", "Page URL: {}", page.url);
+
+            let new_block_roots = self
+                .get_page_block_roots(&page, cutoff, &mut block_roots_duplicates_checker, report)
+                .await;
+
+            // database rows are worth including even when only their properties were edited
+            // (e.g. a task's status changed), so we keep them even if there are no block roots
+            if new_block_roots.len() > 0 || !page.properties.is_empty() {
+                debug!(target: "notion", "found {} new block roots for page: {}",  new_block_roots.len(), page.title);
+                let trees = self
+                    .expand_block_roots(
+                        &page,
+                        new_block_roots,
+                        &mut expanded_blocks_duplicates_checker,
+                        report,
+                    )
+                    .await;
+
+                parsed_pages.push(ParsedPage {
+                    page_id: page.id,
+                    title: page.title,
+                    page_content: trees,
+                    database: page.database,
+                    properties: page.properties,
+                });
+            }
+        }
+
+        debug!(target: "notion", "retrieved {} pages with non-empty block roots", parsed_pages.len());
+        trace!(target: "notion", "the parsed pages look like:\n{:#?}", parsed_pages.iter().map(|p| (&p.page_id, p.page_content.iter().map(|t| (t.root().borrow_data().id.clone(), t.root().borrow_data().text.clone())).collect::<Vec<_>>())).collect::<Vec<_>>());
+
+        Ok(parsed_pages)
+    }
+
+    /// Returns all `Page`s in the Notion integration's workspace that have been edited since the cutoff date.
+    /// The `Page`s will be ordered by last edited date in descending order.
+    ///
+    /// Rows of a database are `Page`s too, and are returned along with their properties unless
+    /// their database has been filtered out by the `[databases]` section of navi.toml.
+    ///
+    /// Pages whose children can't be fetched are recorded in `report` and left out.
+    async fn get_last_edited_pages(
+        &self,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<Page>> {
+        let pages = self.search_pages(cutoff).await?;
+        Ok(self.retrieve_page_children(pages, report).await)
+    }
+
+    /// Walks the whole of `page` with `retrieve_page_blocks`, so that the Blocks without text are
+    /// stored along with everything inside them.
+    ///
+    /// The changes are only as fine-grained as the Page: Notion doesn't change a Block's
+    /// `last_edited_time` when its children are edited, so there's no telling which of a Page's
+    /// Blocks are unchanged without fetching them all. Only the edited Blocks are written though.
+    async fn get_page_changes(
+        &self,
+        page: &Page,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<PageChanges> {
+        let blocks = self.retrieve_page_blocks(page, report).await;
+        Ok(page_changes(&blocks, cutoff))
+    }
+
+    /// Lists the Pages with Notion's search alone, without fetching any of their children
+    async fn get_page_ids(&self, _report: &mut IngestionReport) -> Result<HashSet<PageID>> {
+        Ok(self
+            .search_pages(DateTime::<Utc>::MIN_UTC)
+            .await?
+            .into_iter()
+            .map(|page| page.id)
+            .collect())
+    }
+
+    /// Returns the `Tree`s of `Block`s in `page` that were edited since the cutoff, see
+    /// `get_page_block_roots` and `expand_block_roots` for how these are found.
+    async fn get_block_trees(
+        &self,
+        page: &Page,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<Tree<Block>>> {
+        let block_roots = self
+            .get_page_block_roots(page, cutoff, &mut HashSet::new(), report)
+            .await;
+        Ok(self
+            .expand_block_roots(page, block_roots, &mut HashSet::new(), report)
+            .await)
+    }
+}
+
+/// Builds a search request out of `req_builder`, which only fails if a field it needs wasn't set
+fn build_search(req_builder: &SearchByTitleRequestBuilder) -> Result<SearchByTitleRequest> {
+    req_builder.build().map_err(|e| NaviError::Source {
+        source_name: Notion::NAME.to_string(),
+        message: format!("unable to build a search request: {}", e),
+    })
+}
+
+/// The Notion API's JSON for a piece of plain rich text, cut short if it's too long for Notion
+fn rich_text(text: &str) -> Value {
+    let content = text.chars().take(MAX_RICH_TEXT_LENGTH).collect::<String>();
+    json!([{"type": "text", "text": {"content": content}}])
+}
+
+/// The Notion API's JSON for a Block of the type `kind` that holds `text`
+fn text_block(kind: &str, text: &str) -> Value {
+    let mut block = serde_json::Map::new();
+    block.insert("object".to_string(), json!("block"));
+    block.insert("type".to_string(), json!(kind));
+    block.insert(kind.to_string(), json!({ "rich_text": rich_text(text) }));
+    Value::Object(block)
+}
+
+/// The Blocks of a retro's Page: the synopsis, the lists of the retro's format (e.g. what went
+/// well and what didn't), and the action items as to-dos
+fn retro_page_blocks(summary: &RetroSummary, action_items: &[ActionItem]) -> Vec<Value> {
+    let mut blocks = vec![text_block("heading_2", "Synopsis")];
+    blocks.extend(
+        summary
+            .synopsis
+            .split("\n\n")
+            .map(str::trim)
+            .filter(|paragraph| !paragraph.is_empty())
+            .map(|paragraph| text_block("paragraph", paragraph)),
+    );
+    for list in &summary.lists {
+        blocks.push(text_block("heading_2", &list.heading));
+        blocks.extend(
+            list.items
+                .iter()
+                .map(|item| text_block("bulleted_list_item", item)),
+        );
+    }
+
+    blocks.push(text_block("heading_2", "Action items"));
+    for item in action_items {
+        let mut text = format!("{}: {}", item.title, item.outcome);
+        if let Some(due) = item.due {
+            text.push_str(&format!(" (due {})", due.format("%Y-%m-%d")));
+        }
+        let mut to_do = text_block("to_do", &text);
+        to_do["to_do"]["checked"] = json!(false);
+        blocks.push(to_do);
+    }
+
+    blocks
+}
+
+/// Returns the plain text title of a Notion database.
+fn database_title(database: &NotionDatabase) -> String {
+    let title = database
+        .title
+        .iter()
+        .filter_map(|rich_text| match rich_text {
+            RichText::Text { plain_text, .. } => plain_text.clone(),
+            _ => None,
         })
+        .collect::<Vec<String>>()
+        .join("");
+
+    if title.is_empty() {
+        "Untitled Database".to_string()
+    } else {
+        title
+    }
+}
+
+/// Extracts a database row's properties, rendered to plain text and sorted by name so the
+/// prompt is stable between runs. The row's title property is returned separately, since
+/// it is the title of the Page rather than front-matter.
+fn page_properties(notion_page: &NotionPage) -> (Option<String>, Vec<Property>) {
+    let mut title = None;
+    let mut properties = Vec::new();
+
+    for (name, property) in &notion_page.properties {
+        // notion-client has a separate Rust type for every kind of property, so rather than
+        // matching on each of them we render from the JSON shape that the Notion API documents
+        let json = match serde_json::to_value(property) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!(target: "notion", "unable to serialize property {} of page {}: {}", name, notion_page.id, e);
+                continue;
+            }
+        };
+
+        let Some(value) = render_property_value(&json) else {
+            continue;
+        };
+        if property_kind(&json) == Some("title") {
+            title = Some(value);
+        } else {
+            properties.push(Property {
+                name: name.clone(),
+                value,
+            });
+        }
+    }
+
+    properties.sort_by(|a, b| a.name.cmp(&b.name));
+    (title, properties)
+}
+
+/// The kinds of property values that are worth putting in a prompt. Anything else (relations,
+/// rollups, created_by, etc...) is mostly IDs and would only add noise.
+const RENDERED_PROPERTY_KINDS: [&str; 12] = [
+    "title",
+    "rich_text",
+    "status",
+    "select",
+    "multi_select",
+    "people",
+    "date",
+    "checkbox",
+    "number",
+    "url",
+    "email",
+    "formula",
+];
+
+/// Returns the kind of a property (e.g. "status" or "date"), which is given by its "type"
+/// field, or failing that by which of the known value keys is present.
+fn property_kind(property: &serde_json::Value) -> Option<&str> {
+    match property.get("type").and_then(serde_json::Value::as_str) {
+        Some(kind) => Some(kind),
+        None => RENDERED_PROPERTY_KINDS
+            .into_iter()
+            .find(|kind| property.get(*kind).is_some()),
+    }
+}
+
+/// Renders a Notion page property, in the JSON shape of the Notion API's
+/// [Page property values](https://developers.notion.com/reference/page-property-values), as plain text.
+///
+/// Returns `None` if the property is empty, or is of a kind we don't render.
+fn render_property_value(property: &serde_json::Value) -> Option<String> {
+    let kind = property_kind(property)?;
+    let value = property.get(kind)?;
+
+    let names = |values: &serde_json::Value| {
+        values
+            .as_array()
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.get("name").and_then(serde_json::Value::as_str))
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            })
+            .unwrap_or_default()
+    };
+
+    let rendered = match kind {
+        "title" | "rich_text" => value
+            .as_array()?
+            .iter()
+            .filter_map(|v| v.get("plain_text").and_then(serde_json::Value::as_str))
+            .collect::<Vec<&str>>()
+            .join(""),
+        "status" | "select" => value.get("name")?.as_str()?.to_string(),
+        "multi_select" | "people" => names(value),
+        "date" => {
+            let start = value.get("start")?.as_str()?;
+            match value.get("end").and_then(serde_json::Value::as_str) {
+                Some(end) => format!("{} to {}", start, end),
+                None => start.to_string(),
+            }
+        }
+        "checkbox" => String::from(if value.as_bool()? { "Yes" } else { "No" }),
+        "number" if value.is_number() => value.to_string(),
+        "url" | "email" => value.as_str()?.to_string(),
+        "formula" => {
+            // formulas wrap their result in another typed value, e.g. {"type": "string", "string": "..."}
+            let result_kind = value.get("type")?.as_str()?;
+            match value.get(result_kind)? {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Bool(b) => b.to_string(),
+                serde_json::Value::Number(n) => n.to_string(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    if rendered.is_empty() {
+        None
+    } else {
+        Some(rendered)
     }
 }
 
@@ -473,6 +1165,11 @@ impl Notion {
 mod tests {
     use super::*;
     use crate::config::Config;
+    use crate::retro_summary::SummaryList;
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
 
     #[tokio::test]
     async fn test_parse_last_edited_excludes_pages() {
@@ -484,10 +1181,8 @@ mod tests {
             .push(".*exclude.*".to_string());
 
         // Create a Notion instance with the config
-        let notion = Notion {
-            client: Client::new("fake_token".to_string(), None).unwrap(),
-            config,
-        };
+        let notion =
+            Notion::with_base_url("fake_token".to_string(), config, NOTION_API_URL).unwrap();
 
         // Test that the config is properly loaded
         assert!(notion
@@ -497,4 +1192,433 @@ mod tests {
             .config
             .should_exclude_page("include this page", "https://example.com"));
     }
+
+    fn mock_notion(server: &MockServer) -> Notion {
+        let mut config = Config::default();
+        config.notion.requests_per_second = 100.0;
+        config.retry.initial_backoff_ms = 1;
+        config.retry.max_backoff_ms = 5;
+        Notion::with_base_url("fake_token".to_string(), config, &server.uri()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_block_children_requests_are_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/blocks/abc/children"))
+            .and(header("Notion-Version", NOTION_VERSION))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/blocks/abc/children"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "results": [],
+                "next_cursor": null,
+                "has_more": false,
+                "type": "block",
+                "block": {}
+            })))
+            .mount(&server)
+            .await;
+
+        let children = mock_notion(&server)
+            .retrieve_all_block_children(
+                &BlockID::new("abc".to_string()),
+                &PageID::new("page".to_string()),
+            )
+            .await
+            .unwrap();
+        assert!(children.blocks.is_empty());
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unparseable_block_children_are_skipped() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/blocks/abc/children"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "results": [{"object": "block", "id": "def", "type": "not_a_block_type"}],
+                "next_cursor": null,
+                "has_more": false,
+                "type": "block",
+                "block": {}
+            })))
+            .mount(&server)
+            .await;
+
+        let children = mock_notion(&server)
+            .retrieve_all_block_children(
+                &BlockID::new("abc".to_string()),
+                &PageID::new("page".to_string()),
+            )
+            .await
+            .unwrap();
+        assert!(children.blocks.is_empty());
+        assert_eq!(children.unreadable.len(), 1);
+        let (block_id, error) = &children.unreadable[0];
+        assert_eq!(block_id, &Some(BlockID::new("def".to_string())));
+        assert!(matches!(error, NaviError::Deserialization { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_block_children_errors_are_returned() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/blocks/abc/children"))
+            .respond_with(ResponseTemplate::new(404).set_body_json(serde_json::json!({
+                "object": "error",
+                "status": 404,
+                "code": "object_not_found",
+                "message": "Could not find block with ID: abc."
+            })))
+            .mount(&server)
+            .await;
+
+        let error = mock_notion(&server)
+            .retrieve_all_block_children(
+                &BlockID::new("abc".to_string()),
+                &PageID::new("page".to_string()),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(error, NaviError::Network { .. }));
+        assert!(error.to_string().contains("GET /blocks/abc/children"));
+        // a missing block won't appear by asking again, so there is only the one request
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    /// A Notion Page with the ID `id` under `parent`, as Notion's API returns it
+    fn notion_page(id: &str, parent: Value) -> NotionPage {
+        serde_json::from_value(json!({
+            "object": "page",
+            "id": id,
+            "created_time": "2024-11-01T09:00:00.000Z",
+            "last_edited_time": "2024-11-08T09:00:00.000Z",
+            "created_by": {"object": "user", "id": "user"},
+            "last_edited_by": {"object": "user", "id": "user"},
+            "cover": null,
+            "icon": null,
+            "parent": parent,
+            "archived": false,
+            "properties": {"title": {"id": "title", "type": "title", "title": []}},
+            "url": format!("https://www.notion.so/{}", id),
+            "public_url": null
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_retros_are_recognised_as_retro_pages() {
+        let with_retro_parent = |retro_parent: Option<&str>| {
+            let mut config = Config::default();
+            config.notion.retro_parent_page_id = retro_parent.map(str::to_string);
+            Notion::with_base_url("fake_token".to_string(), config, NOTION_API_URL).unwrap()
+        };
+        let retros_page = notion_page(
+            "1234abcd-0000-0000-0000-000000000000",
+            json!({"type": "workspace", "workspace": true}),
+        );
+        let retro = notion_page(
+            "5678",
+            json!({"type": "page_id", "page_id": "1234abcd-0000-0000-0000-000000000000"}),
+        );
+        let notes = notion_page("9999", json!({"type": "page_id", "page_id": "elsewhere"}));
+
+        // Notion accepts page IDs with or without their dashes, so navi.toml may have either
+        let notion = with_retro_parent(Some("1234abcd000000000000000000000000"));
+        assert!(notion.is_retro_page(&retros_page));
+        assert!(notion.is_retro_page(&retro));
+        assert!(!notion.is_retro_page(&notes));
+
+        // without a page to write retros under, no page is a retro
+        assert!(!with_retro_parent(None).is_retro_page(&retro));
+    }
+
+    /// Notion's JSON for a Block, with `content` under its type
+    fn notion_block(id: &str, kind: &str, content: Value, has_children: bool) -> Value {
+        json!({
+            "object": "block",
+            "id": id,
+            "created_time": "2024-11-01T09:00:00.000Z",
+            "last_edited_time": "2024-11-08T09:00:00.000Z",
+            "has_children": has_children,
+            "archived": false,
+            "type": kind,
+            kind: content
+        })
+    }
+
+    fn paragraph(id: &str, text: &str) -> Value {
+        notion_block(
+            id,
+            "paragraph",
+            json!({
+                "rich_text": [{
+                    "type": "text",
+                    "text": {"content": text, "link": null},
+                    "plain_text": text,
+                    "href": null
+                }],
+                "color": "default"
+            }),
+            false,
+        )
+    }
+
+    async fn mock_children(server: &MockServer, block_id: &str, children: Vec<Value>) {
+        Mock::given(method("GET"))
+            .and(path(format!("/blocks/{}/children", block_id)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "object": "list",
+                "results": children,
+                "next_cursor": null,
+                "has_more": false,
+                "type": "block",
+                "block": {}
+            })))
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_page_changes_keep_what_is_inside_columns() {
+        let server = MockServer::start().await;
+        mock_children(
+            &server,
+            "page",
+            vec![
+                paragraph("intro", "Launch plan"),
+                notion_block("columns", "column_list", json!({}), true),
+            ],
+        )
+        .await;
+        mock_children(
+            &server,
+            "columns",
+            vec![
+                notion_block("left", "column", json!({}), true),
+                notion_block("right", "column", json!({}), true),
+            ],
+        )
+        .await;
+        mock_children(&server, "left", vec![paragraph("todo", "Write the post")]).await;
+        mock_children(&server, "right", vec![paragraph("done", "Buy the domain")]).await;
+
+        let notion = mock_notion(&server);
+        let page = Page {
+            id: PageID::new("page".to_string()),
+            title: "Launch".to_string(),
+            url: "https://www.notion.so/Launch-page".to_string(),
+            creation_date: Utc::now(),
+            update_date: Utc::now(),
+            child_blocks: Vec::new(),
+            database: None,
+            properties: Vec::new(),
+        };
+        let mut report = IngestionReport::default();
+        let page = notion
+            .retrieve_page_children(vec![page], &mut report)
+            .await
+            .remove(0);
+        let changes = notion
+            .get_page_changes(&page, DateTime::<Utc>::MIN_UTC, &mut report)
+            .await
+            .unwrap();
+        assert!(report.is_empty());
+
+        let placements = changes
+            .placements
+            .iter()
+            .map(|placement| {
+                (
+                    placement.id.as_str(),
+                    placement.parent_id.as_ref().map(|id| id.as_str()),
+                    placement.position,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            placements,
+            vec![
+                ("intro", None, 0),
+                ("columns", None, 1),
+                ("left", Some("columns"), 0),
+                ("right", Some("columns"), 1),
+                ("todo", Some("left"), 0),
+                ("done", Some("right"), 0),
+            ]
+        );
+        assert_eq!(changes.edited.len(), 6);
+        assert_eq!(changes.edited[4].text, "Write the post");
+    }
+
+    #[tokio::test]
+    async fn test_create_retro_page() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/pages"))
+            .and(body_partial_json(serde_json::json!({
+                "parent": {"page_id": "retros"},
+                "children": [
+                    {"type": "heading_2", "heading_2": {"rich_text": [{"text": {"content": "Synopsis"}}]}},
+                    {"type": "paragraph", "paragraph": {"rich_text": [{"text": {"content": "A restful week"}}]}},
+                    {"type": "heading_2"},
+                    {"type": "bulleted_list_item", "bulleted_list_item": {"rich_text": [{"text": {"content": "Time with Gwen"}}]}},
+                    {"type": "heading_2"},
+                    {"type": "heading_2"},
+                    {"type": "to_do", "to_do": {"checked": false, "rich_text": [{"text": {"content": "Practice coding challenges: 5 challenges solved"}}]}}
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "page",
+                "id": "new-page",
+                "url": "https://www.notion.so/Retro-2024-11-11-newpage"
+            })))
+            .mount(&server)
+            .await;
+
+        let mut notion = mock_notion(&server);
+        notion.config.notion.retro_parent_page_id = Some("retros".to_string());
+        let summary = RetroSummary {
+            synopsis: "A restful week".to_string(),
+            lists: vec![
+                SummaryList {
+                    heading: "What went well".to_string(),
+                    items: vec!["Time with Gwen".to_string()],
+                },
+                SummaryList {
+                    heading: "What didn't go well".to_string(),
+                    items: Vec::new(),
+                },
+            ],
+        };
+        let action_items = vec![ActionItem {
+            title: "Practice coding challenges".to_string(),
+            outcome: "5 challenges solved".to_string(),
+            due: None,
+            topics: Vec::new(),
+        }];
+
+        let url = notion
+            .create_retro_page("Retro 2024-11-11", &summary, &action_items)
+            .await
+            .unwrap();
+        assert_eq!(url, "https://www.notion.so/Retro-2024-11-11-newpage");
+    }
+
+    #[tokio::test]
+    async fn test_long_retro_pages_are_written_in_batches() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/pages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "page",
+                "id": "new-page",
+                "url": "https://www.notion.so/Retro-2024-11-11-newpage"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/blocks/new-page/children"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "results": []
+            })))
+            .mount(&server)
+            .await;
+
+        let mut notion = mock_notion(&server);
+        notion.config.notion.retro_parent_page_id = Some("retros".to_string());
+        // a heading and 249 items for the list, after the synopsis' heading and paragraph
+        let summary = RetroSummary {
+            synopsis: "A busy week".to_string(),
+            lists: vec![SummaryList {
+                heading: "What went well".to_string(),
+                items: (1..250).map(|i| format!("Thing {}", i)).collect(),
+            }],
+        };
+        notion
+            .create_retro_page("Retro 2024-11-11", &summary, &[])
+            .await
+            .unwrap();
+
+        // plus the action items' heading makes 253 Blocks, which go 100, 100 and 53 at a time
+        let children = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                let body: Value = serde_json::from_slice(&request.body).unwrap();
+                (
+                    request.method.to_string(),
+                    body["children"].as_array().unwrap().len(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            children,
+            vec![
+                ("POST".to_string(), 100),
+                ("PATCH".to_string(), 100),
+                ("PATCH".to_string(), 53)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_spaces_out_requests() {
+        let rate_limiter = RateLimiter::new(20.0);
+        let start = Instant::now();
+
+        // the first request goes straight away, and each of the other four waits 50ms more
+        futures::future::join_all((0..5).map(|_| rate_limiter.wait())).await;
+        assert!(start.elapsed() >= StdDuration::from_millis(200));
+    }
+
+    #[test]
+    fn test_render_property_value() {
+        let status = serde_json::json!({"id": "a", "type": "status", "status": {"name": "In progress", "color": "blue"}});
+        assert_eq!(
+            render_property_value(&status),
+            Some("In progress".to_string())
+        );
+
+        let tags = serde_json::json!({"type": "multi_select", "multi_select": [{"name": "history"}, {"name": "economics"}]});
+        assert_eq!(
+            render_property_value(&tags),
+            Some("history, economics".to_string())
+        );
+
+        let people =
+            serde_json::json!({"type": "people", "people": [{"object": "user", "name": "Alex"}]});
+        assert_eq!(render_property_value(&people), Some("Alex".to_string()));
+
+        let due = serde_json::json!({"type": "date", "date": {"start": "2024-11-09", "end": null}});
+        assert_eq!(render_property_value(&due), Some("2024-11-09".to_string()));
+
+        let done = serde_json::json!({"type": "checkbox", "checkbox": true});
+        assert_eq!(render_property_value(&done), Some("Yes".to_string()));
+
+        // the "type" field is optional, the kind can also be inferred from the value key
+        let select = serde_json::json!({"select": {"name": "Book"}});
+        assert_eq!(render_property_value(&select), Some("Book".to_string()));
+    }
+
+    #[test]
+    fn test_render_property_value_skips_empty_and_unknown() {
+        let empty_select = serde_json::json!({"type": "select", "select": null});
+        assert_eq!(render_property_value(&empty_select), None);
+
+        let empty_tags = serde_json::json!({"type": "multi_select", "multi_select": []});
+        assert_eq!(render_property_value(&empty_tags), None);
+
+        let relation = serde_json::json!({"type": "relation", "relation": [{"id": "abc"}]});
+        assert_eq!(render_property_value(&relation), None);
+    }
 }
@@ -0,0 +1,272 @@
+use crate::error::{NaviError, Result};
+use log::debug;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// The format a retro is run in unless navi.toml or --format picks another
+pub const DEFAULT_FORMAT: &str = "weekly";
+
+/// The formats that ship with Navi, as (name, TOML)
+const BUILT_IN_FORMATS: [(&str, &str); 7] = [
+    ("weekly", include_str!("../formats/weekly.toml")),
+    (
+        "start-stop-continue",
+        include_str!("../formats/start-stop-continue.toml"),
+    ),
+    ("4ls", include_str!("../formats/4ls.toml")),
+    ("sailboat", include_str!("../formats/sailboat.toml")),
+    ("mad-sad-glad", include_str!("../formats/mad-sad-glad.toml")),
+    ("monthly", include_str!("../formats/monthly.toml")),
+    (
+        "quarterly-okr",
+        include_str!("../formats/quarterly-okr.toml"),
+    ),
+];
+
+/// How a retro is run: the steps Navi leads the user through, and what is kept from it once it's
+/// over. Formats are TOML files, see the formats directory for the ones that ship with Navi.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RetroFormat {
+    /// What --format calls the format, which is also the name of its file
+    pub name: String,
+    pub description: String,
+    /// Who Navi is and what the retro is for, which goes before the steps in Navi's instructions
+    pub intro: String,
+    /// Anything else Navi should keep in mind, which goes after the steps
+    #[serde(default)]
+    pub outro: String,
+    /// How many days of notes the retro is about, unless --days says otherwise
+    #[serde(default)]
+    pub days: Option<i64>,
+    pub steps: Vec<RetroStep>,
+    /// The lists that the retro's summary is made of (e.g. what went well), besides its synopsis
+    #[serde(default)]
+    pub outputs: Vec<OutputList>,
+}
+
+/// One step of a retro
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RetroStep {
+    pub title: String,
+    /// The questions Navi asks the user in this step, word for word
+    #[serde(default)]
+    pub questions: Vec<String>,
+    /// What else Navi should do in this step
+    #[serde(default)]
+    pub instructions: String,
+    /// Whether this step opens by checking on the action items of the last retro
+    #[serde(default)]
+    pub follow_up: bool,
+}
+
+/// A list that is extracted from the retro for its summary
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct OutputList {
+    /// The list's key in the JSON that the LLM is asked for
+    pub key: String,
+    /// The list's heading when the summary is written out, e.g. to Notion
+    pub heading: String,
+    /// What goes in the list, for the LLM
+    pub description: String,
+}
+
+impl RetroFormat {
+    /// Parses a format from its TOML. `origin` names where the TOML came from, for errors.
+    pub fn parse(toml: &str, origin: &str) -> Result<RetroFormat> {
+        let format: RetroFormat = toml::from_str(toml).map_err(|e| {
+            NaviError::Config(format!("the retro format {} is invalid: {}", origin, e))
+        })?;
+
+        if format.steps.is_empty() {
+            return Err(NaviError::Config(format!(
+                "the retro format {} has no steps",
+                origin
+            )));
+        }
+        for (i, output) in format.outputs.iter().enumerate() {
+            if output.key == "synopsis" || format.outputs[..i].iter().any(|o| o.key == output.key) {
+                return Err(NaviError::Config(format!(
+                    "the retro format {} uses the output key {} more than once (the synopsis has the key synopsis)",
+                    origin, output.key
+                )));
+            }
+        }
+        Ok(format)
+    }
+
+    /// Navi's instructions for leading a retro in this format, i.e. the system prompt
+    pub fn instructions(&self) -> String {
+        let n = self.steps.len();
+        let mut instructions = format!(
+            "{}\n\nYou must adhere to the {}-step outline below in order from 1 to {} for the retro. Begin with fulfilling the requirements for 1, then 2, and so on up to {}. The user moves the retro on to the next step by typing /next, skips a step with /skip and goes back to the previous step with /back, and you will be told which step the retro is on. Stay on that step until the user moves on, and when it seems done, ask the user if they're ready to move on with /next. The {} steps are:\n",
+            self.intro.trim(),
+            n,
+            n,
+            n,
+            n
+        );
+        for (i, step) in self.steps.iter().enumerate() {
+            instructions.push_str(&format!("{}. {}\n", i + 1, step.instructions()));
+        }
+        if !self.outro.trim().is_empty() {
+            instructions.push('\n');
+            instructions.push_str(self.outro.trim());
+        }
+
+        instructions
+    }
+
+    /// The number of the step (counting from 1) that opens by checking on the action items of the
+    /// last retro, which is the first step unless the format marks another
+    pub fn follow_up_step(&self) -> usize {
+        self.steps
+            .iter()
+            .position(|step| step.follow_up)
+            .map_or(1, |i| i + 1)
+    }
+}
+
+impl RetroStep {
+    /// What Navi is told to do in this step
+    pub fn instructions(&self) -> String {
+        let mut parts = Vec::new();
+        if self.follow_up {
+            parts.push("If the user has given you action items from their last retro, begin this step by checking on the user's progress on each of them.".to_string());
+        }
+        parts.extend(
+            self.questions
+                .iter()
+                .map(|question| format!("Ask the user '{}'", question)),
+        );
+        if !self.instructions.trim().is_empty() {
+            parts.push(self.instructions.trim().to_string());
+        }
+
+        parts.join(" ")
+    }
+}
+
+/// Loads the format called `name`. The user's formats in `formats_dir` (named after their files,
+/// e.g. `team.toml` is the format `team`) take precedence over the ones that ship with Navi.
+///
+/// A user's format has to have the same `name` as its file, since that's what its retros are
+/// resumed by (see `SessionInfo::format`).
+pub fn load_format(name: &str, formats_dir: Option<&Path>) -> Result<RetroFormat> {
+    if let Some(dir) = formats_dir {
+        let path = dir.join(format!("{}.toml", name));
+        match fs::read_to_string(&path) {
+            Ok(toml) => {
+                debug!(target: "intelligence", "using the retro format in {}", path.display());
+                let format = RetroFormat::parse(&toml, &path.display().to_string())?;
+                if format.name != name {
+                    return Err(NaviError::Config(format!(
+                        "the retro format {} is called {}, but needs to be called {} after its file",
+                        path.display(),
+                        format.name,
+                        name
+                    )));
+                }
+                return Ok(format);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(NaviError::io(
+                    format!("unable to read {}", path.display()),
+                    e,
+                ))
+            }
+        }
+    }
+
+    match BUILT_IN_FORMATS
+        .iter()
+        .find(|(built_in, _)| *built_in == name)
+    {
+        Some((_, toml)) => RetroFormat::parse(toml, name),
+        None => Err(NaviError::Config(format!(
+            "there is no retro format called {}, the formats are: {}",
+            name,
+            format_names(formats_dir).join(", ")
+        ))),
+    }
+}
+
+/// The names of every format, built in or the user's
+fn format_names(formats_dir: Option<&Path>) -> Vec<String> {
+    let mut names = BUILT_IN_FORMATS
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<_>>();
+    let user_formats = formats_dir
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "toml" {
+                return None;
+            }
+            Some(path.file_stem()?.to_string_lossy().into_owned())
+        });
+    for name in user_formats {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TempDir;
+
+    #[test]
+    fn test_built_in_formats_parse() {
+        for (name, _) in BUILT_IN_FORMATS {
+            let format = load_format(name, None).unwrap();
+            assert_eq!(format.name, name);
+        }
+
+        let weekly = load_format(DEFAULT_FORMAT, None).unwrap();
+        assert_eq!(weekly.follow_up_step(), 2);
+        let instructions = weekly.instructions();
+        assert!(instructions.contains("The 4 steps are:\n1. Give a synopsis"));
+        assert!(instructions
+            .contains("\n3. Ask the user 'What didn't go well since the last retro?'\n"));
+    }
+
+    #[test]
+    fn test_user_formats_take_precedence() {
+        let dir = TempDir::new("formats");
+        fs::write(
+            dir.path().join("weekly.toml"),
+            "name = \"weekly\"\ndescription = \"Short\"\nintro = \"Be brief.\"\n\n[[steps]]\ntitle = \"Wins\"\nquestions = [\"What went well?\"]\n",
+        )
+        .unwrap();
+
+        let weekly = load_format("weekly", Some(dir.path())).unwrap();
+        assert_eq!(weekly.steps.len(), 1);
+        assert!(weekly
+            .instructions()
+            .ends_with("The 1 steps are:\n1. Ask the user 'What went well?'\n"));
+        assert!(matches!(
+            load_format("no-such-format", Some(dir.path())),
+            Err(NaviError::Config(_))
+        ));
+
+        // a format named differently from its file couldn't be found again to resume its retros
+        fs::write(
+            dir.path().join("team.toml"),
+            "name = \"Team retro\"\ndescription = \"Short\"\nintro = \"Be brief.\"\n\n[[steps]]\ntitle = \"Wins\"\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            load_format("team", Some(dir.path())),
+            Err(NaviError::Config(message)) if message.contains("needs to be called team")
+        ));
+    }
+}
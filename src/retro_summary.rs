@@ -0,0 +1,178 @@
+use crate::error::Result;
+use crate::llm::{parse_json, JsonSchema, LlmProvider, Message};
+use crate::retro_format::{OutputList, RetroFormat};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+const SUMMARY_INSTRUCTIONS: &str = "The retro is over. Summarise it for me to keep: a synopsis of my notes, including anything I added to it, and the answers I gave to your questions, sorted into the lists the schema asks for. Write it in the first person, as if I had written it.";
+
+/// What was said in a retro, for keeping alongside its action items
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetroSummary {
+    /// The synopsis of the user's notes
+    pub synopsis: String,
+    /// The lists that the retro's format asks for, in the format's order
+    pub lists: Vec<SummaryList>,
+}
+
+/// One of the lists of a `RetroSummary`, e.g. what went well
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SummaryList {
+    pub heading: String,
+    pub items: Vec<String>,
+}
+
+/// The JSON that `summary_schema` describes
+#[derive(Deserialize)]
+struct SummaryResponse {
+    synopsis: String,
+    #[serde(flatten)]
+    lists: HashMap<String, Vec<String>>,
+}
+
+/// The schema of the JSON that `extract_summary` asks for: the synopsis, and a list of strings for
+/// each of the `outputs`. Like `action_items_schema`, it works with OpenAI's strict structured
+/// outputs.
+fn summary_schema(outputs: &[OutputList]) -> JsonSchema {
+    let mut properties = Map::new();
+    properties.insert(
+        "synopsis".to_string(),
+        json!({
+            "type": "string",
+            "description": "The synopsis of the notes, as a paragraph or two"
+        }),
+    );
+    for output in outputs {
+        properties.insert(
+            output.key.clone(),
+            json!({
+                "type": "array",
+                "items": {"type": "string"},
+                "description": output.description
+            }),
+        );
+    }
+    let required = properties.keys().cloned().collect::<Vec<_>>();
+
+    JsonSchema {
+        name: "retro_summary".to_string(),
+        description: "A summary of a retro".to_string(),
+        schema: json!({
+            "type": "object",
+            "properties": Value::Object(properties),
+            "required": required,
+            "additionalProperties": false
+        }),
+    }
+}
+
+/// Asks the LLM to summarise the retro that `messages` is the conversation of, into the lists
+/// that its `format` asks for
+pub async fn extract_summary(
+    llm: &dyn LlmProvider,
+    messages: &[Message],
+    format: &RetroFormat,
+) -> Result<RetroSummary> {
+    info!(target: "intelligence", "--- Summarising the retro");
+    let mut messages = messages.to_vec();
+    messages.push(Message::user(SUMMARY_INSTRUCTIONS));
+
+    let response = llm
+        .chat_json(&messages, &summary_schema(&format.outputs))
+        .await?;
+    let mut response: SummaryResponse = parse_json(&response, "the retro summary")?;
+    Ok(RetroSummary {
+        synopsis: response.synopsis,
+        lists: format
+            .outputs
+            .iter()
+            .map(|output| SummaryList {
+                heading: output.heading.clone(),
+                items: response.lists.remove(&output.key).unwrap_or_default(),
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::scripted::ScriptedLlm;
+    use crate::retro_format::load_format;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_summary_schema_asks_for_every_output() {
+        let format = load_format("start-stop-continue", None).unwrap();
+        let schema = summary_schema(&format.outputs).schema;
+
+        let required = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key.as_str().unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            required,
+            HashSet::from(["synopsis", "start", "stop", "continue"])
+        );
+        assert_eq!(
+            schema["properties"]["stop"]["description"],
+            "What the user will stop doing, one thing per item"
+        );
+        assert_eq!(schema["additionalProperties"], false);
+    }
+
+    #[tokio::test]
+    async fn test_extract_summary_follows_the_formats_outputs() {
+        let format = load_format("start-stop-continue", None).unwrap();
+        // the lists come back out of order, with one missing and one the format doesn't have
+        let llm = ScriptedLlm::new([r#"{
+            "synopsis": "A week of deploys",
+            "continue": ["Pairing on Fridays"],
+            "start": ["Writing release notes", "Deploying on Mondays"],
+            "later": ["Something else"]
+        }"#]);
+        let messages = vec![Message::user("Here are my notes")];
+
+        let summary = extract_summary(&llm, &messages, &format).await.unwrap();
+        assert_eq!(
+            summary,
+            RetroSummary {
+                synopsis: "A week of deploys".to_string(),
+                lists: vec![
+                    SummaryList {
+                        heading: "Start".to_string(),
+                        items: vec![
+                            "Writing release notes".to_string(),
+                            "Deploying on Mondays".to_string()
+                        ],
+                    },
+                    SummaryList {
+                        heading: "Stop".to_string(),
+                        items: Vec::new(),
+                    },
+                    SummaryList {
+                        heading: "Continue".to_string(),
+                        items: vec!["Pairing on Fridays".to_string()],
+                    },
+                ],
+            }
+        );
+
+        let request = &llm.requests()[0];
+        assert_eq!(request.messages[0], messages[0]);
+        assert_eq!(request.messages[1].content, SUMMARY_INSTRUCTIONS);
+        assert_eq!(request.schema.as_ref().unwrap().name, "retro_summary");
+    }
+
+    #[tokio::test]
+    async fn test_extract_summary_needs_a_synopsis() {
+        let format = load_format("start-stop-continue", None).unwrap();
+        let llm = ScriptedLlm::new([r#"{"start": []}"#]);
+
+        assert!(extract_summary(&llm, &[], &format).await.is_err());
+    }
+}
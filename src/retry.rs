@@ -0,0 +1,325 @@
+use crate::config::RetrySettings;
+use chrono::{DateTime, Utc};
+use log::warn;
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use thiserror::Error;
+
+/// How many times, and how patiently, a failed request is retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most times a request is attempted, including the first attempt
+    pub max_attempts: u32,
+    /// The backoff before the first retry, which doubles with every retry after that
+    pub initial_backoff: Duration,
+    /// The longest backoff between two attempts
+    pub max_backoff: Duration,
+    /// The longest wait between two attempts when the server asks for a wait (see
+    /// `Retryable::retry_after`)
+    pub max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &RetrySettings) -> Self {
+        RetryPolicy {
+            max_attempts: settings.max_attempts.max(1),
+            initial_backoff: Duration::from_millis(settings.initial_backoff_ms),
+            max_backoff: Duration::from_millis(settings.max_backoff_ms),
+            max_retry_after: Duration::from_millis(settings.max_retry_after_ms),
+        }
+    }
+
+    /// Returns how long to wait after the given (1-indexed) attempt failed. We back off
+    /// exponentially, with "equal jitter": half of the backoff is fixed and the other half is
+    /// random, so that concurrent requests that failed together don't all retry together.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let capped = exponential.min(self.max_backoff);
+        let half = capped / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::from_settings(&RetrySettings::default())
+    }
+}
+
+/// An error that may go away if the request that caused it is tried again
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+
+    /// How long the server asked us to wait before trying again, if it said
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with an error that isn't `Retryable`, or has been
+/// attempted `policy.max_attempts` times. Between attempts we wait for as long as the server
+/// asked us to (e.g. with a Retry-After header) up to `policy.max_retry_after`, or otherwise for
+/// the policy's backoff.
+///
+/// `description` says what the operation is (e.g. "GET /blocks/abc/children"), for the logs.
+pub async fn with_retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    description: &str,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: Retryable + Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+
+        if attempt >= policy.max_attempts || !error.is_retryable() {
+            return Err(error);
+        }
+
+        let delay = match error.retry_after() {
+            Some(retry_after) => retry_after.min(policy.max_retry_after),
+            None => policy.backoff(attempt),
+        };
+        warn!(target: "retry", "{} failed (attempt {} of {}): {}. Retrying in {:.1}s", description, attempt, policy.max_attempts, error, delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// An error from an HTTP request to an API that Navi talks to
+#[derive(Debug, Error)]
+pub enum HttpError {
+    /// The request never got a response, e.g. because the connection failed or timed out
+    #[error("network error: {0}")]
+    Network(#[from] reqwest::Error),
+    /// The server responded with an error status
+    #[error("HTTP {status}: {body}")]
+    Status {
+        status: StatusCode,
+        body: String,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl Retryable for HttpError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            // a request that failed to build, or a response we failed to decode, will fail the same way again
+            HttpError::Network(e) => !e.is_builder() && !e.is_decode(),
+            HttpError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || status.is_server_error()
+            }
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            HttpError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Sends a request once, and returns the body of the response if it was successful
+pub async fn send(request: RequestBuilder) -> Result<String, HttpError> {
+    Ok(send_for_response(request).await?.text().await?)
+}
+
+/// Sends a request once, and returns the response if it was successful, leaving its body unread
+/// (e.g. so that it can be streamed)
+pub async fn send_for_response(request: RequestBuilder) -> Result<Response, HttpError> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = parse_retry_after(response.headers(), Utc::now());
+    let body = response.text().await.unwrap_or_default();
+    Err(HttpError::Status {
+        status,
+        body,
+        retry_after,
+    })
+}
+
+/// Parses a [Retry-After](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Retry-After)
+/// header, which is either a number of seconds or an HTTP date. Numbers of seconds that are
+/// negative, or too large to be a `Duration`, are ignored.
+pub fn parse_retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+
+    let date = DateTime::parse_from_rfc2822(value)
+        .ok()?
+        .with_timezone(&Utc);
+    Some((date - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderValue, RETRY_AFTER};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            max_retry_after: Duration::from_millis(5),
+        }
+    }
+
+    async fn get(server: &MockServer, policy: &RetryPolicy) -> Result<String, HttpError> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/notes", server.uri());
+        with_retry(policy, "GET /notes", || send(client.get(&url))).await
+    }
+
+    #[tokio::test]
+    async fn test_retries_rate_limited_requests() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/notes"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/notes"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("notes"))
+            .mount(&server)
+            .await;
+
+        let body = get(&server, &fast_policy(5)).await.unwrap();
+        assert_eq!(body, "notes");
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/notes"))
+            .respond_with(ResponseTemplate::new(503).set_body_string("try later"))
+            .mount(&server)
+            .await;
+
+        let error = get(&server, &fast_policy(3)).await.unwrap_err();
+        assert!(
+            matches!(error, HttpError::Status { status, .. } if status == StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_client_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/notes"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("not found"))
+            .mount(&server)
+            .await;
+
+        let error = get(&server, &fast_policy(5)).await.unwrap_err();
+        assert!(!error.is_retryable());
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2024-11-09T14:45:22Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers, now), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("2"));
+        assert_eq!(
+            parse_retry_after(&headers, now),
+            Some(Duration::from_secs(2))
+        );
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Sat, 09 Nov 2024 14:45:52 GMT"),
+        );
+        assert_eq!(
+            parse_retry_after(&headers, now),
+            Some(Duration::from_secs(30))
+        );
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(parse_retry_after(&headers, now), None);
+        // more seconds than a Duration can hold are ignored, rather than panicking
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("1e20"));
+        assert_eq!(parse_retry_after(&headers, now), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("-1"));
+        assert_eq!(parse_retry_after(&headers, now), None);
+    }
+
+    #[tokio::test]
+    async fn test_long_retry_afters_are_capped() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/notes"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/notes"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("notes"))
+            .mount(&server)
+            .await;
+
+        // the server asks for an hour, but the policy waits for 5ms at most
+        let body = tokio::time::timeout(Duration::from_secs(5), get(&server, &fast_policy(2)))
+            .await
+            .expect("the Retry-After wasn't capped")
+            .unwrap();
+        assert_eq!(body, "notes");
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            max_retry_after: Duration::from_millis(1000),
+        };
+
+        for _ in 0..20 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+            let capped = policy.backoff(9);
+            assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_millis(1000));
+        }
+    }
+}
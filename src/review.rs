@@ -0,0 +1,622 @@
+use crate::core::{
+    datatypes::{BlockID, Page, PageID},
+    helpers::{hash_parts, BlockNode},
+};
+use crate::error::Result;
+use crate::intelligence::{read_input, stdout_error, Console};
+use crate::llm::{parse_json, JsonSchema, LlmProvider, Message};
+use crate::srs::{Grade, Schedule, Scheduler};
+use crate::store::{page_nodes, render_node, Store, StoredCard};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use tokio::io::AsyncBufRead;
+
+/// What a Block starts with to make it the question of a card
+const QUESTION_MARKER: &str = "Q::";
+/// What starts a card's answer, in the question's Block or in the Block after it
+const ANSWER_MARKER: &str = "A::";
+
+const CARD_INSTRUCTIONS: &str = "Write flashcards for the facts and ideas in this page of my notes that are worth remembering. Write at most one card per block, and skip blocks with nothing worth remembering. Each line of the page starts with the ID of its block in square brackets, and each card's block_id is the ID of the block that the card is about. Questions should make sense without the page, and answers should be short.";
+
+/// How a card was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardKind {
+    /// From a toggle Block, whose text is the question and whose children are the answer
+    Toggle,
+    /// From a Block written as `Q:: question`, with an `A:: answer` in the same Block, its first
+    /// child or the Block after it
+    Pattern,
+    /// Written by the LLM, from a Page that has the highlight tag
+    Generated,
+}
+
+impl CardKind {
+    /// What the kind is called in the `Store`
+    pub fn name(&self) -> &'static str {
+        match self {
+            CardKind::Toggle => "toggle",
+            CardKind::Pattern => "pattern",
+            CardKind::Generated => "generated",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CardKind> {
+        match name {
+            "toggle" => Some(CardKind::Toggle),
+            "pattern" => Some(CardKind::Pattern),
+            "generated" => Some(CardKind::Generated),
+            _ => None,
+        }
+    }
+}
+
+/// A flashcard made from the notes, which is keyed by the Block it was made from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Card {
+    pub block_id: BlockID,
+    pub page_id: PageID,
+    pub kind: CardKind,
+    pub question: String,
+    pub answer: String,
+    /// When the Blocks the card was made from were last edited
+    pub update_date: DateTime<Utc>,
+}
+
+/// Finds the cards written into a Page's Blocks, as toggles or with `Q::` and `A::`
+pub fn extract_cards(page_id: &PageID, nodes: &[BlockNode]) -> Vec<Card> {
+    let mut cards = Vec::new();
+    let mut i = 0;
+    while i < nodes.len() {
+        let node = &nodes[i];
+        let text = node.block.text.trim();
+        i += 1;
+
+        if let Some(question) = text.strip_prefix(QUESTION_MARKER) {
+            let card = |question: &str, answer: &str, update_date| Card {
+                block_id: node.block.id.clone(),
+                page_id: page_id.clone(),
+                kind: CardKind::Pattern,
+                question: question.trim().to_string(),
+                answer: answer.trim().to_string(),
+                update_date,
+            };
+            if let Some((question, answer)) = question.split_once(ANSWER_MARKER) {
+                cards.push(card(question, answer, node.block.update_date));
+            } else if let Some((answer, update_date)) = node.children.first().and_then(answer_of) {
+                cards.push(card(
+                    question,
+                    &answer,
+                    node.block.update_date.max(update_date),
+                ));
+            } else if let Some((answer, update_date)) = nodes.get(i).and_then(answer_of) {
+                cards.push(card(
+                    question,
+                    &answer,
+                    node.block.update_date.max(update_date),
+                ));
+                // the answer is part of the card, not a Block of its own
+                i += 1;
+            }
+            continue;
+        }
+
+        if node.block.is_toggle() && !text.is_empty() {
+            let mut answer = String::new();
+            let mut update_date = node.block.update_date;
+            for child in &node.children {
+                update_date = update_date.max(render_node(child, 0, &mut answer, false));
+            }
+            if !answer.trim().is_empty() {
+                cards.push(Card {
+                    block_id: node.block.id.clone(),
+                    page_id: page_id.clone(),
+                    kind: CardKind::Toggle,
+                    question: text.to_string(),
+                    answer: answer.trim_end().to_string(),
+                    update_date,
+                });
+            }
+            // the toggle's children are its answer
+            continue;
+        }
+
+        cards.extend(extract_cards(page_id, &node.children));
+    }
+    cards
+}
+
+/// The answer in a Block that starts with `A::`, along with when the Block and its children were
+/// last edited
+fn answer_of(node: &BlockNode) -> Option<(String, DateTime<Utc>)> {
+    let answer = node.block.text.trim().strip_prefix(ANSWER_MARKER)?;
+    let mut answer = answer.trim().to_string();
+    let mut update_date = node.block.update_date;
+    for child in &node.children {
+        answer.push('\n');
+        update_date = update_date.max(render_node(child, 0, &mut answer, false));
+    }
+    Some((answer.trim_end().to_string(), update_date))
+}
+
+/// A card as the LLM writes it
+#[derive(Deserialize)]
+struct GeneratedCard {
+    block_id: String,
+    question: String,
+    answer: String,
+}
+
+#[derive(Deserialize)]
+struct GeneratedCards {
+    cards: Vec<GeneratedCard>,
+}
+
+fn cards_schema() -> JsonSchema {
+    JsonSchema {
+        name: "flashcards".to_string(),
+        description: "Flashcards written from a page of notes".to_string(),
+        schema: json!({
+            "type": "object",
+            "properties": {
+                "cards": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "block_id": {"type": "string", "description": "The ID of the block the card is about"},
+                            "question": {"type": "string"},
+                            "answer": {"type": "string"}
+                        },
+                        "required": ["block_id", "question", "answer"],
+                        "additionalProperties": false
+                    }
+                }
+            },
+            "required": ["cards"],
+            "additionalProperties": false
+        }),
+    }
+}
+
+/// Asks the LLM to write cards for a Page. The LLM's response is kept in the `Store`, so the LLM
+/// is only asked again once the Page has changed.
+async fn generate_cards(
+    llm: &dyn LlmProvider,
+    store: &Store,
+    page: &Page,
+    nodes: &[BlockNode],
+) -> Result<Vec<Card>> {
+    let mut blocks = HashMap::new();
+    let mut text = format!("Page Title: {}\n", page.title);
+    for node in nodes {
+        number_lines(node, 0, &mut text, &mut blocks);
+    }
+
+    let hash = hash_parts(&[&llm.model_info().model, CARD_INSTRUCTIONS, &text]);
+    let response = match store.generated_cards(&hash)? {
+        Some(response) => {
+            debug!(target: "review", "using the cached cards of {}", page.title);
+            response
+        }
+        None => {
+            info!(target: "review", "writing cards for {}", page.title);
+            let response = llm
+                .chat_json(
+                    &[Message::system(CARD_INSTRUCTIONS), Message::user(text)],
+                    &cards_schema(),
+                )
+                .await?;
+            store.save_generated_cards(&hash, &response)?;
+            response
+        }
+    };
+    let response: GeneratedCards = parse_json(&response, "the flashcards")?;
+
+    let mut cards = Vec::new();
+    let mut seen = HashSet::new();
+    for generated in response.cards {
+        let block_id = BlockID::new(generated.block_id);
+        // cards about Blocks the page doesn't have are made up, and only one card per Block fits
+        // in the Store
+        let update_date = match blocks.get(&block_id) {
+            Some(update_date) if seen.insert(block_id.clone()) => *update_date,
+            _ => continue,
+        };
+        cards.push(Card {
+            block_id,
+            page_id: page.id.clone(),
+            kind: CardKind::Generated,
+            question: generated.question,
+            answer: generated.answer,
+            update_date,
+        });
+    }
+    Ok(cards)
+}
+
+/// Writes a Block and its descendants to `text` with their IDs, for the LLM to refer to, and
+/// records when each was last edited in `blocks`
+fn number_lines(
+    node: &BlockNode,
+    depth: usize,
+    text: &mut String,
+    blocks: &mut HashMap<BlockID, DateTime<Utc>>,
+) {
+    if !node.block.is_empty() {
+        text.push_str(&format!(
+            "[{}] {}{}\n",
+            node.block.id,
+            "\t".repeat(depth),
+            node.block.to_markdown()
+        ));
+        blocks.insert(node.block.id.clone(), node.block.update_date);
+    }
+    for child in &node.children {
+        number_lines(child, depth + 1, text, blocks);
+    }
+}
+
+/// Whether the Page's title or any of its Blocks has the `tag`
+fn has_tag(page: &Page, nodes: &[BlockNode], tag: &str) -> bool {
+    let tag = tag.to_lowercase();
+    page.title.to_lowercase().contains(&tag)
+        || nodes
+            .iter()
+            .any(|node| node.block.text.to_lowercase().contains(&tag) || has_tag_below(node, &tag))
+}
+
+fn has_tag_below(node: &BlockNode, tag: &str) -> bool {
+    node.children
+        .iter()
+        .any(|child| child.block.text.to_lowercase().contains(tag) || has_tag_below(child, tag))
+}
+
+/// A summary of what a call to `update_cards` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CardReport {
+    pub added: usize,
+    /// The number of cards whose Blocks had been edited, and so were brought up to date
+    pub updated: usize,
+    /// The number of cards whose Blocks no longer make a card
+    pub removed: usize,
+}
+
+/// Brings the cards in the `Store` up to date with the notes in it. New cards are due straight
+/// away, and cards whose Blocks were edited get their new answer but keep their schedule.
+///
+/// A card whose question changed starts over with a new schedule, since it may well be a
+/// different question: Blocks read from files (e.g. Obsidian's) are identified by their line, so
+/// the Block a card was made from can change under it when lines are added above it.
+///
+/// Pages with the `highlight_tag` also have cards written for them by the `llm`, if there is one.
+/// If it can't write them, the Page keeps the cards it had.
+pub async fn update_cards(
+    store: &Store,
+    llm: Option<&dyn LlmProvider>,
+    highlight_tag: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<CardReport> {
+    let mut report = CardReport::default();
+    for page in store.all_pages()? {
+        let nodes = page_nodes(store.page_blocks(&page.id)?);
+        let mut cards = extract_cards(&page.id, &nodes);
+
+        let highlighted = highlight_tag.is_some_and(|tag| has_tag(&page, &nodes, tag));
+        let mut keep_generated = highlighted;
+        if let (true, Some(llm)) = (highlighted, llm) {
+            match generate_cards(llm, store, &page, &nodes).await {
+                Ok(generated) => {
+                    let written = cards
+                        .iter()
+                        .map(|card| card.block_id.clone())
+                        .collect::<HashSet<_>>();
+                    cards.extend(
+                        generated
+                            .into_iter()
+                            .filter(|card| !written.contains(&card.block_id)),
+                    );
+                    keep_generated = false;
+                }
+                Err(e) => {
+                    warn!(target: "review", "unable to write cards for {}, so it keeps the ones it had: {}", page.title, e)
+                }
+            }
+        }
+
+        let mut stored = store
+            .page_cards(&page.id)?
+            .into_iter()
+            .map(|stored| (stored.card.block_id.clone(), stored))
+            .collect::<HashMap<_, _>>();
+        for card in cards {
+            match stored.remove(&card.block_id) {
+                Some(existing) if existing.card == card => {}
+                Some(existing) if existing.card.question == card.question => {
+                    store.save_card(&StoredCard {
+                        card,
+                        schedule: existing.schedule,
+                    })?;
+                    report.updated += 1;
+                }
+                Some(_) => {
+                    store.save_card(&StoredCard {
+                        card,
+                        schedule: Schedule::new(now),
+                    })?;
+                    report.updated += 1;
+                }
+                None => {
+                    store.save_card(&StoredCard {
+                        card,
+                        schedule: Schedule::new(now),
+                    })?;
+                    report.added += 1;
+                }
+            }
+        }
+        for (block_id, existing) in stored {
+            if keep_generated && existing.card.kind == CardKind::Generated {
+                continue;
+            }
+            store.delete_card(&block_id)?;
+            report.removed += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+/// A summary of a review session
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReviewReport {
+    pub reviewed: usize,
+    /// The number of cards that were due but not reviewed, because the session was stopped
+    pub skipped: usize,
+}
+
+/// Quizzes the user on up to `limit` of the cards that are due on the `console`, one at a time:
+/// the question is shown, then the answer once the user presses Enter, and the user grades how
+/// well they remembered it. Each card is rescheduled by the `scheduler` as soon as it is graded.
+pub async fn review_session<R: AsyncBufRead + Unpin, W: Write>(
+    store: &Store,
+    scheduler: &Scheduler,
+    console: &mut Console<R, W>,
+    limit: usize,
+) -> Result<ReviewReport> {
+    let Console { input, output } = console;
+    let cards = store.due_cards(Utc::now(), limit)?;
+    let mut report = ReviewReport {
+        reviewed: 0,
+        skipped: cards.len(),
+    };
+    if cards.is_empty() {
+        writeln!(output, "No cards are due for review").map_err(stdout_error)?;
+        return Ok(report);
+    }
+    writeln!(
+        output,
+        "{} cards are due. Press Enter to see each answer, then grade how well you remembered it: 1 (again), 2 (hard), 3 (good) or 4 (easy). Type q to stop",
+        cards.len()
+    )
+    .map_err(stdout_error)?;
+
+    let total = cards.len();
+    for (i, stored) in cards.into_iter().enumerate() {
+        writeln!(
+            output,
+            "\n[Card {} of {}]\nQ: {}",
+            i + 1,
+            total,
+            stored.card.question
+        )
+        .map_err(stdout_error)?;
+        if read_input(input, output).await?.is_none() {
+            break;
+        }
+        writeln!(output, "A: {}", stored.card.answer).map_err(stdout_error)?;
+
+        let grade = loop {
+            match read_input(input, output).await? {
+                None => return Ok(report),
+                Some(grade) => match Grade::parse(&grade) {
+                    Some(grade) => break grade,
+                    None => writeln!(output, "Type 1 (again), 2 (hard), 3 (good) or 4 (easy)")
+                        .map_err(stdout_error)?,
+                },
+            }
+        };
+        let schedule = scheduler.review(&stored.schedule, grade, Utc::now());
+        let days = schedule.interval_days.round() as i64;
+        store.save_card(&StoredCard {
+            card: stored.card,
+            schedule,
+        })?;
+        report.reviewed += 1;
+        report.skipped -= 1;
+        writeln!(
+            output,
+            "Next review in {} {}",
+            days,
+            if days == 1 { "day" } else { "days" }
+        )
+        .map_err(stdout_error)?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReviewSettings;
+    use crate::core::datatypes::{Block, TextBlockKind};
+    use crate::fixtures::{block, days_ago, page, stored};
+    use crate::llm::scripted::ScriptedLlm;
+    use crate::store::StoredBlock;
+
+    /// A Block of the page "Biology #flashcards"
+    fn note(id: &str, kind: TextBlockKind, text: &str) -> Block {
+        block("biology", id, kind, text, days_ago(3))
+    }
+
+    fn blocks() -> Vec<StoredBlock> {
+        use TextBlockKind::{BulletedListItem as Bullet, Toggle};
+        vec![
+            stored(
+                note("cell", Toggle, "What is the powerhouse of the cell?"),
+                None,
+                0,
+            ),
+            stored(
+                note("mitochondria", Bullet, "The mitochondria"),
+                Some("cell"),
+                0,
+            ),
+            stored(
+                note("dna", Bullet, "Q:: What shape is DNA? A:: A double helix"),
+                None,
+                1,
+            ),
+            stored(note("atp", Bullet, "Q:: What does ATP stand for?"), None, 2),
+            stored(
+                note("atp-answer", Bullet, "A:: Adenosine triphosphate"),
+                None,
+                3,
+            ),
+            stored(
+                note("ribosomes", Bullet, "Ribosomes make proteins"),
+                None,
+                4,
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_extract_cards() {
+        let page = page("biology", "Biology #flashcards", days_ago(3));
+        let cards = extract_cards(&page.id, &page_nodes(blocks()));
+        let cards = cards
+            .iter()
+            .map(|card| {
+                (
+                    card.block_id.as_str(),
+                    card.kind,
+                    card.question.as_str(),
+                    card.answer.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cards,
+            vec![
+                (
+                    "cell",
+                    CardKind::Toggle,
+                    "What is the powerhouse of the cell?",
+                    "- The mitochondria"
+                ),
+                (
+                    "dna",
+                    CardKind::Pattern,
+                    "What shape is DNA?",
+                    "A double helix"
+                ),
+                (
+                    "atp",
+                    CardKind::Pattern,
+                    "What does ATP stand for?",
+                    "Adenosine triphosphate"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cards_follow_their_blocks_and_are_reviewed() {
+        let store = Store::open_in_memory().unwrap();
+        let page = page("biology", "Biology #flashcards", days_ago(3));
+        store.save_page("Obsidian", &page, &blocks()).unwrap();
+        let cards = r#"{"cards": [{"block_id": "ribosomes", "question": "What makes proteins?", "answer": "Ribosomes"}, {"block_id": "made-up", "question": "?", "answer": "!"}]}"#;
+        let llm = ScriptedLlm::new([cards, cards, cards]);
+
+        let now = Utc::now();
+        let report = update_cards(&store, Some(&llm), Some("#flashcards"), now)
+            .await
+            .unwrap();
+        assert_eq!(report.added, 4);
+        // the LLM is only asked again once the page has changed
+        let report = update_cards(&store, Some(&llm), Some("#flashcards"), now)
+            .await
+            .unwrap();
+        assert_eq!(report, CardReport::default());
+        assert_eq!(llm.requests().len(), 1);
+
+        let dna_card = || {
+            store
+                .page_cards(&page.id)
+                .unwrap()
+                .into_iter()
+                .find(|stored| stored.card.block_id.as_str() == "dna")
+                .unwrap()
+        };
+        let scheduler = Scheduler::new(&ReviewSettings::default());
+        let mut dna = dna_card();
+        dna.schedule = scheduler.review(&dna.schedule, Grade::Good, now);
+        store.save_card(&dna).unwrap();
+
+        // editing a Block's answer updates its card, and keeps the schedule it was reviewed to
+        let mut edited = blocks();
+        edited[2].block.text = "Q:: What shape is DNA? A:: A right-handed double helix".to_string();
+        edited[2].block.update_date = now;
+        store.save_page("Obsidian", &page, &edited).unwrap();
+        let report = update_cards(&store, Some(&llm), Some("#flashcards"), now)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            CardReport {
+                added: 0,
+                updated: 1,
+                removed: 0
+            }
+        );
+        assert_eq!(llm.requests().len(), 2);
+        assert_eq!(dna_card().card.answer, "A right-handed double helix");
+        assert_eq!(dna_card().schedule, dna.schedule);
+
+        // but a new question is a new card, which starts over
+        edited[2].block.text = "Q:: What shape is RNA? A:: A single strand".to_string();
+        store.save_page("Obsidian", &page, &edited).unwrap();
+        let report = update_cards(&store, Some(&llm), Some("#flashcards"), now)
+            .await
+            .unwrap();
+        assert_eq!(report.updated, 1);
+        assert_eq!(llm.requests().len(), 3);
+        assert_eq!(dna_card().card.question, "What shape is RNA?");
+        assert_eq!(dna_card().schedule, Schedule::new(now));
+
+        let mut console = Console {
+            input: "\n3\n\nmaybe\n1\nq\n".as_bytes(),
+            output: Vec::new(),
+        };
+        let report = review_session(&store, &scheduler, &mut console, 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ReviewReport {
+                reviewed: 2,
+                skipped: 2
+            }
+        );
+        let output = String::from_utf8(console.output).unwrap();
+        assert!(output.contains("[Card 1 of 4]\nQ: "));
+        assert!(output.contains("Type 1 (again), 2 (hard), 3 (good) or 4 (easy)"));
+        // the cards that were reviewed aren't due until tomorrow at the earliest
+        assert_eq!(store.due_cards(Utc::now(), 10).unwrap().len(), 2);
+    }
+}
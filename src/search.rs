@@ -0,0 +1,313 @@
+use crate::core::{
+    datatypes::{BlockID, Page},
+    helpers::{hash_parts, BlockNode},
+};
+use crate::error::{NaviError, Result};
+use crate::llm::LlmProvider;
+use crate::store::{page_nodes, render_node, Store, StoredBlock, StoredChunk};
+use chrono::{DateTime, Utc};
+use log::debug;
+use std::collections::HashMap;
+
+/// The most characters of notes in a chunk. A Block with more than this under it is split into
+/// a chunk of its own and a chunk for each of its children.
+const MAX_CHUNK_CHARS: usize = 1_500;
+/// The most characters of each of the Blocks a chunk is under that go in the chunk's context
+const MAX_ANCESTOR_CHARS: usize = 80;
+/// How many chunks are embedded per request to the embedding model
+const EMBEDDING_BATCH_SIZE: usize = 64;
+
+/// A piece of a Page's notes that is searched as a whole: a Block and everything under it as
+/// Markdown, preceded by the Page's title and the Blocks it is under, so that the chunk makes
+/// sense on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// The Block the chunk starts at
+    pub block_id: BlockID,
+    pub text: String,
+    /// When the chunk's most recently edited Block was edited
+    pub update_date: DateTime<Utc>,
+}
+
+/// Splits a Page's stored Blocks into `Chunk`s. Each top-level Block is a chunk unless it has too
+/// much under it, in which case its children are chunked instead, with the Block as their context.
+pub fn chunk_page(page: &Page, blocks: Vec<StoredBlock>) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    for node in page_nodes(blocks) {
+        chunk_node(&page.title, &node, &mut Vec::new(), &mut chunks);
+    }
+    chunks
+}
+
+fn chunk_node(title: &str, node: &BlockNode, ancestry: &mut Vec<String>, chunks: &mut Vec<Chunk>) {
+    let mut markdown = String::new();
+    let update_date = render_node(node, 0, &mut markdown, false);
+    if markdown.chars().count() <= MAX_CHUNK_CHARS || node.children.is_empty() {
+        if !markdown.trim().is_empty() {
+            chunks.push(Chunk {
+                block_id: node.block.id.clone(),
+                text: chunk_text(title, ancestry, &markdown),
+                update_date,
+            });
+        }
+        return;
+    }
+
+    // Blocks without text (e.g. Notion's columns) only group their children, and aren't context
+    let has_text = !node.block.is_empty();
+    if has_text {
+        chunks.push(Chunk {
+            block_id: node.block.id.clone(),
+            text: chunk_text(title, ancestry, &node.block.to_markdown()),
+            update_date: node.block.update_date,
+        });
+        ancestry.push(truncate(&node.block.text, MAX_ANCESTOR_CHARS));
+    }
+    for child in &node.children {
+        chunk_node(title, child, ancestry, chunks);
+    }
+    if has_text {
+        ancestry.pop();
+    }
+}
+
+fn chunk_text(title: &str, ancestry: &[String], markdown: &str) -> String {
+    let mut text = format!("Page: {}\n", title);
+    if !ancestry.is_empty() {
+        text.push_str(&format!("Under: {}\n", ancestry.join(" > ")));
+    }
+    text.push_str(&truncate(markdown.trim_end(), MAX_CHUNK_CHARS));
+    text
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// A summary of what a call to `index_notes` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexReport {
+    /// The number of Pages in the `Store`
+    pub pages_checked: usize,
+    /// The number of those Pages whose chunks had changed since they were last indexed
+    pub pages_updated: usize,
+    /// The number of chunks that were new or had changed, and so were embedded
+    pub chunks_embedded: usize,
+}
+
+/// Brings the search index up to date with every Page in the `Store`, however long ago it was
+/// edited. Only the chunks that are new or have changed since the last run are embedded (with
+/// the `embedder`), so after the first run this is quick.
+pub async fn index_notes(store: &Store, embedder: &dyn LlmProvider) -> Result<IndexReport> {
+    let model = embedder.model_info().model.clone();
+    let mut report = IndexReport::default();
+    for page in store.all_pages()? {
+        report.pages_checked += 1;
+        // keyed by the Block as well as the text, since two chunks of a Page can say the same thing
+        let mut indexed = store
+            .page_chunks(&page.id)?
+            .into_iter()
+            .map(|chunk| ((chunk.block_id.clone(), chunk.content_hash.clone()), chunk))
+            .collect::<HashMap<_, _>>();
+
+        let mut chunks = Vec::new();
+        let mut to_embed = Vec::new();
+        for chunk in chunk_page(&page, store.page_blocks(&page.id)?) {
+            let content_hash = hash_parts(&[&model, &chunk.text]);
+            match indexed.remove(&(chunk.block_id.clone(), content_hash.clone())) {
+                Some(indexed_chunk) => chunks.push(StoredChunk {
+                    update_date: chunk.update_date,
+                    ..indexed_chunk
+                }),
+                None => to_embed.push((content_hash, chunk)),
+            }
+        }
+        // every chunk is already indexed, and none were removed from the Page
+        if to_embed.is_empty() && indexed.is_empty() {
+            continue;
+        }
+
+        debug!(target: "search", "embedding {} chunks of {}", to_embed.len(), page.title);
+        for batch in to_embed.chunks(EMBEDDING_BATCH_SIZE) {
+            let texts = batch
+                .iter()
+                .map(|(_, chunk)| chunk.text.clone())
+                .collect::<Vec<_>>();
+            let embeddings = embedder.embed(&texts).await?;
+            if embeddings.len() != batch.len() {
+                return Err(NaviError::Llm(format!(
+                    "{} embedded {} chunks when it was given {}",
+                    model,
+                    embeddings.len(),
+                    batch.len()
+                )));
+            }
+            for ((content_hash, chunk), embedding) in batch.iter().zip(embeddings) {
+                chunks.push(StoredChunk {
+                    page_id: page.id.clone(),
+                    block_id: chunk.block_id.clone(),
+                    content_hash: content_hash.clone(),
+                    text: chunk.text.clone(),
+                    update_date: chunk.update_date,
+                    embedding,
+                });
+            }
+        }
+        store.save_chunks(&page.id, &chunks)?;
+        report.pages_updated += 1;
+        report.chunks_embedded += to_embed.len();
+    }
+
+    Ok(report)
+}
+
+/// A chunk of the notes that matched a search
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub chunk: StoredChunk,
+    /// How close the chunk is in meaning to what was searched for, from -1 to 1
+    pub score: f32,
+}
+
+/// Searches the index for the `limit` chunks of notes that are closest in meaning to `query`,
+/// best match first.
+///
+/// Every chunk is compared with the query, which for the notes of one person is quick enough that
+/// the index doesn't need to be anything cleverer than a table.
+pub async fn search_notes(
+    store: &Store,
+    embedder: &dyn LlmProvider,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<SearchResult>> {
+    let query_embedding = embedder
+        .embed(&[query.to_string()])
+        .await?
+        .pop()
+        .ok_or_else(|| NaviError::Llm("the search query wasn't embedded".to_string()))?;
+
+    let mut results = store
+        .all_chunks()?
+        .into_iter()
+        // chunks that were embedded with a different model can't be compared with the query,
+        // and are replaced the next time the notes are indexed
+        .filter(|chunk| chunk.embedding.len() == query_embedding.len())
+        .map(|chunk| SearchResult {
+            score: cosine_similarity(&query_embedding, &chunk.embedding),
+            chunk,
+        })
+        .collect::<Vec<_>>();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.truncate(limit);
+
+    Ok(results)
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norms =
+        a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|y| y * y).sum::<f32>().sqrt();
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::datatypes::{Block, TextBlockKind};
+    use crate::fixtures::{block, days_ago, page, stored};
+    use crate::llm::scripted::ScriptedLlm;
+
+    /// A bullet point of the page "March", edited `days` days ago
+    fn bullet(id: &str, text: &str, days: i64) -> Block {
+        block(
+            "page",
+            id,
+            TextBlockKind::BulletedListItem,
+            text,
+            days_ago(days),
+        )
+    }
+
+    #[test]
+    fn test_chunk_page_splits_large_blocks() {
+        // too long to go in a chunk with the rest of "Standup", but short enough for a chunk of its own
+        let long_text = "word ".repeat(295);
+        let blocks = vec![
+            stored(bullet("standup", "Standup", 200), None, 0),
+            stored(
+                bullet("blocker", "Blocked on the deploy pipeline", 200),
+                Some("standup"),
+                0,
+            ),
+            stored(bullet("details", long_text.trim(), 199), Some("standup"), 1),
+            stored(bullet("gym", "Went to the gym", 1), None, 1),
+            stored(bullet("squats", "Squats", 1), Some("gym"), 0),
+        ];
+
+        let chunks = chunk_page(&page("page", "March", days_ago(1)), blocks);
+        let chunks = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.block_id.as_str(),
+                    chunk.text.lines().take(3).collect::<Vec<_>>().join("\n"),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            vec![
+                ("standup", "Page: March\n- Standup".to_string()),
+                (
+                    "blocker",
+                    "Page: March\nUnder: Standup\n- Blocked on the deploy pipeline".to_string()
+                ),
+                (
+                    "details",
+                    format!("Page: March\nUnder: Standup\n- {}", long_text.trim())
+                ),
+                (
+                    "gym",
+                    "Page: March\n- Went to the gym\n\t- Squats".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_index_and_search_notes() {
+        let store = Store::open_in_memory().unwrap();
+        let blocks = vec![
+            stored(
+                bullet("blocker", "Blocked on the deploy pipeline again", 200),
+                None,
+                0,
+            ),
+            stored(bullet("gym", "Went to the gym", 1), None, 1),
+        ];
+        store
+            .save_page("Obsidian", &page("page", "March", days_ago(1)), &blocks)
+            .unwrap();
+        let embedder = ScriptedLlm::new(Vec::<String>::new());
+
+        let report = index_notes(&store, &embedder).await.unwrap();
+        assert_eq!(report.pages_updated, 1);
+        assert_eq!(report.chunks_embedded, 2);
+        // nothing has changed since, so nothing is embedded again
+        let report = index_notes(&store, &embedder).await.unwrap();
+        assert_eq!(report.pages_updated, 0);
+
+        let results = search_notes(&store, &embedder, "the deploy pipeline", 1)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].chunk.block_id.as_str(), "blocker");
+    }
+}
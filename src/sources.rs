@@ -0,0 +1,298 @@
+pub mod logseq;
+pub mod markdown;
+pub mod obsidian;
+
+use crate::core::datatypes::{Block, BlockID, Page, PageID, ParsedPage};
+use crate::error::{NaviError, Result};
+use crate::store::{flatten_trees, page_changes};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dendron::Tree;
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::fmt;
+
+/// A NoteSource is somewhere that Navi can ingest notes from, such as a Notion workspace.
+///
+/// Every NoteSource has the concept of a collection of `Block`s (a `Page`), and can tell us
+/// which of those collections have been edited recently. Navi uses that to build up `Tree`s
+/// of the recently edited `Block`s, which are what ends up in the retro prompt.
+///
+/// Note: `dendron` `Tree`s are not `Send`, so neither are the futures returned by a NoteSource.
+#[async_trait(?Send)]
+pub trait NoteSource {
+    /// A short, human readable name for this source (e.g. "Notion"), used in logs
+    fn name(&self) -> &str;
+
+    /// Returns all the `Page`s that have been edited since the cutoff date, ordered by
+    /// last edited date in descending order.
+    ///
+    /// Pages that can't be read are recorded in `report` and left out, rather than failing
+    /// the whole ingestion.
+    async fn get_last_edited_pages(
+        &self,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<Page>>;
+
+    /// Returns a `Tree` for every `Block` in `page` that was edited since the cutoff date,
+    /// where each `Tree` is rooted at the edited `Block` and contains all of its descendants.
+    ///
+    /// Blocks that can't be read are recorded in `report` and left out of the `Tree`s.
+    async fn get_block_trees(
+        &self,
+        page: &Page,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<Tree<Block>>>;
+
+    /// Returns the `Block`s in `page` that were edited since the cutoff, along with where every
+    /// one of the Page's Blocks sits now, so that a stored copy of the Page can be brought up to
+    /// date (see `store::sync_source`).
+    ///
+    /// By default this walks the whole Page with `get_block_trees`, which sources whose
+    /// `get_block_trees` leaves Blocks out (e.g. Notion's, which skips the Blocks without text)
+    /// should override.
+    async fn get_page_changes(
+        &self,
+        page: &Page,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<PageChanges> {
+        let trees = self
+            .get_block_trees(page, DateTime::<Utc>::MIN_UTC, report)
+            .await?;
+        Ok(page_changes(&flatten_trees(&trees), cutoff))
+    }
+
+    /// Returns the IDs of every `Page` the source has, so that a sync can tell which Pages were
+    /// deleted. Pages that can't be read are recorded in `report`.
+    ///
+    /// By default this lists every Page with `get_last_edited_pages`, which sources that can
+    /// list their Pages more cheaply should override.
+    async fn get_page_ids(&self, report: &mut IngestionReport) -> Result<HashSet<PageID>> {
+        Ok(self
+            .get_last_edited_pages(DateTime::<Utc>::MIN_UTC, report)
+            .await?
+            .into_iter()
+            .map(|page| page.id)
+            .collect())
+    }
+
+    /// Ingests and parses the `Page`s that have been edited since the cutoff date. A `Page`
+    /// that fails to be read is recorded in `report` and skipped, so one malformed `Page`
+    /// doesn't stop the rest from being ingested.
+    ///
+    /// # Returns
+    /// A `Result` containing a `Vec` of `ParsedPage`s, one for each `Page` with recently edited content.
+    async fn parse_last_edited(
+        &self,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<ParsedPage>> {
+        let pages = self.get_last_edited_pages(cutoff, report).await?;
+        debug!(target: "sources", "{} returned {} Pages edited since {}", self.name(), pages.len(), cutoff);
+
+        let mut parsed_pages = Vec::new();
+        for page in pages {
+            let trees = match self.get_block_trees(&page, cutoff, report).await {
+                Ok(trees) => trees,
+                Err(e) => {
+                    report.record(self.name(), &page.title, &page.url, None, &e);
+                    continue;
+                }
+            };
+
+            if !trees.is_empty() || !page.properties.is_empty() {
+                parsed_pages.push(ParsedPage {
+                    page_id: page.id,
+                    title: page.title,
+                    page_content: trees,
+                    database: page.database,
+                    properties: page.properties,
+                });
+            }
+        }
+
+        Ok(parsed_pages)
+    }
+}
+
+/// Ingests the notes edited since the cutoff date from every one of the given sources, and
+/// merges them into a single `Vec` so that one retro can be run across all of them.
+pub async fn parse_last_edited_from_sources(
+    sources: &[Box<dyn NoteSource>],
+    cutoff: DateTime<Utc>,
+    report: &mut IngestionReport,
+) -> Result<Vec<ParsedPage>> {
+    let mut parsed_pages = Vec::new();
+    for source in sources {
+        info!(target: "sources", "ingesting notes from {}...", source.name());
+        let mut source_pages = source.parse_last_edited(cutoff, report).await?;
+        info!(target: "sources", "ingested {} pages from {}", source_pages.len(), source.name());
+        parsed_pages.append(&mut source_pages);
+    }
+
+    Ok(parsed_pages)
+}
+
+/// Where a `Block` sits in its Page's tree of Blocks, see `PageChanges`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockPlacement {
+    pub id: BlockID,
+    /// The Block's parent, or `None` if the Block is at the top level of its Page
+    pub parent_id: Option<BlockID>,
+    /// The Block's position among its siblings
+    pub position: usize,
+}
+
+/// How a Page's Blocks changed since some cutoff, see `NoteSource::get_page_changes`
+#[derive(Debug, Default, Clone)]
+pub struct PageChanges {
+    /// The Blocks edited since the cutoff
+    pub edited: Vec<Block>,
+    /// Where every one of the Page's Blocks sits now, edited or not. Blocks that aren't in here
+    /// have been deleted.
+    pub placements: Vec<BlockPlacement>,
+}
+
+/// A Page, or a Block of a Page, that couldn't be read during ingestion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IngestionFailure {
+    pub source_name: String,
+    pub page_title: String,
+    pub url: String,
+    /// The Block that couldn't be read, or `None` if the whole Page couldn't be read
+    pub block_id: Option<BlockID>,
+    /// What kind of error it was, see `NaviError::kind`
+    pub kind: &'static str,
+    pub message: String,
+}
+
+/// Everything that went wrong while ingesting notes, so that the rest of the notes can still
+/// be used for the retro and the user can be told what is missing
+#[derive(Debug, Default, Clone)]
+pub struct IngestionReport {
+    pub failures: Vec<IngestionFailure>,
+}
+
+impl IngestionReport {
+    pub fn record(
+        &mut self,
+        source_name: &str,
+        page_title: &str,
+        url: &str,
+        block_id: Option<&BlockID>,
+        error: &NaviError,
+    ) {
+        warn!(target: "sources", "unable to read {} from {}: {}", page_title, source_name, error);
+        self.failures.push(IngestionFailure {
+            source_name: source_name.to_string(),
+            page_title: page_title.to_string(),
+            url: url.to_string(),
+            block_id: block_id.cloned(),
+            kind: error.kind(),
+            message: error.to_string(),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The number of distinct Pages with at least one failure
+    pub fn failed_page_count(&self) -> usize {
+        self.failures
+            .iter()
+            .map(|failure| (&failure.source_name, &failure.url))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// A short note for the end of the retro prompt, so the LLM knows that some notes are missing
+    pub fn prompt_note(&self) -> Option<String> {
+        match self.failed_page_count() {
+            0 => None,
+            1 => Some(
+                "Note: 1 page could not be read, so it is missing from these notes.".to_string(),
+            ),
+            n => Some(format!(
+                "Note: {} pages could not be read, so they are missing from these notes.",
+                n
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IngestionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pages = self.failed_page_count();
+        writeln!(
+            f,
+            "{} {} could not be read:",
+            pages,
+            if pages == 1 { "page" } else { "pages" }
+        )?;
+        for failure in &self.failures {
+            write!(
+                f,
+                "  - [{}] {} ({})",
+                failure.source_name, failure.page_title, failure.url
+            )?;
+            if let Some(block_id) = &failure.block_id {
+                write!(f, ", block {}", block_id)?;
+            }
+            writeln!(f, ": {} error: {}", failure.kind, failure.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ingestion_report_counts_pages() {
+        let mut report = IngestionReport::default();
+        assert_eq!(report.prompt_note(), None);
+
+        let error = NaviError::Config("bad".to_string());
+        let block = BlockID::new("abc".to_string());
+        report.record(
+            "Notion",
+            "Sprint 22",
+            "https://notion.so/sprint-22",
+            Some(&block),
+            &error,
+        );
+        report.record(
+            "Notion",
+            "Sprint 22",
+            "https://notion.so/sprint-22",
+            None,
+            &error,
+        );
+        assert_eq!(
+            report.prompt_note(),
+            Some("Note: 1 page could not be read, so it is missing from these notes.".to_string())
+        );
+
+        report.record(
+            "Obsidian",
+            "Weekly Review",
+            "file:///vault/Weekly Review.md",
+            None,
+            &error,
+        );
+        assert_eq!(report.failed_page_count(), 2);
+        assert_eq!(
+            report.to_string(),
+            "2 pages could not be read:\n\
+             \x20 - [Notion] Sprint 22 (https://notion.so/sprint-22), block abc: config error: configuration error: bad\n\
+             \x20 - [Notion] Sprint 22 (https://notion.so/sprint-22): config error: configuration error: bad\n\
+             \x20 - [Obsidian] Weekly Review (file:///vault/Weekly Review.md): config error: configuration error: bad\n"
+        );
+    }
+}
@@ -0,0 +1,546 @@
+use super::{
+    markdown::{nest_by_indent, resolve_wikilinks, split_indent},
+    IngestionReport, NoteSource,
+};
+use crate::config::Config;
+use crate::core::{
+    datatypes::{Block, BlockID, Page, PageID, Property, TextBlockKind},
+    helpers::build_tree,
+};
+use crate::error::{NaviError, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use dendron::Tree;
+use log::{debug, warn};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The folders of a Logseq graph that contain notes. Everything else (e.g. `logseq/`, which
+/// holds config and backups) is ignored.
+const GRAPH_FOLDERS: [&str; 2] = ["journals", "pages"];
+
+/// A `NoteSource` for a [Logseq](https://logseq.com/) graph.
+///
+/// Logseq notes are outlines, where every line is a block and nesting is done with indentation,
+/// which maps directly onto the `Tree`s of Blocks that Navi builds. Journal pages are dated by
+/// their file name (e.g. `journals/2024_11_09.md`), and that date is what decides whether they
+/// fall within the lookback window. All other pages use their file's modification time.
+pub struct Logseq {
+    graph: PathBuf,
+    config: Config,
+    /// Lookup tables for resolving `[[page refs]]` and `((block refs))`. Built the first time
+    /// they are needed.
+    index: OnceLock<GraphIndex>,
+}
+
+struct GraphIndex {
+    /// Maps each page's lowercased name to its title
+    page_titles: HashMap<String, String>,
+    /// Maps the `id::` of every block that has one to the block's text
+    block_texts: HashMap<String, String>,
+}
+
+/// A block of a Logseq page, before its references are resolved and it is placed into a tree
+#[derive(Debug)]
+struct LogseqBlock {
+    indent: usize,
+    line_number: usize,
+    /// The block's `id::` property, which is how other blocks reference it
+    id: Option<String>,
+    /// The block's `collapsed::` property. Collapsed blocks hide their children, like a toggle
+    collapsed: bool,
+    text: String,
+}
+
+impl Logseq {
+    /// The name that a graph's pages are synced under
+    pub const NAME: &str = "Logseq";
+
+    pub fn new(graph: PathBuf, config: Config) -> Self {
+        Logseq {
+            graph,
+            config,
+            index: OnceLock::new(),
+        }
+    }
+
+    /// Returns the graph-relative path of every page in the graph
+    fn page_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for folder in GRAPH_FOLDERS {
+            let dir = self.graph.join(folder);
+            if !dir.is_dir() {
+                continue;
+            }
+
+            let io_error = |e| NaviError::io(format!("unable to list {}", dir.display()), e);
+            for entry in fs::read_dir(&dir).map_err(io_error)? {
+                let path = entry.map_err(io_error)?.path();
+                if path.extension().is_some_and(|extension| extension == "md") {
+                    files.push(Path::new(folder).join(path.file_name().unwrap_or_default()));
+                }
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    fn index(&self) -> Result<&GraphIndex> {
+        if let Some(index) = self.index.get() {
+            return Ok(index);
+        }
+
+        let mut index = GraphIndex {
+            page_titles: HashMap::new(),
+            block_texts: HashMap::new(),
+        };
+        for relative_path in self.page_files()? {
+            let path = self.graph.join(&relative_path);
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    // the page itself is reported when it's ingested, here we only lose its links
+                    warn!(target: "logseq", "unable to index {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let (properties, blocks) = parse_page(&contents);
+
+            let title = page_title(&relative_path, &properties);
+            index
+                .page_titles
+                .insert(page_name(&relative_path).to_lowercase(), title.clone());
+            index.page_titles.insert(title.to_lowercase(), title);
+
+            for block in blocks {
+                if let Some(id) = block.id {
+                    let first_line = block.text.lines().next().unwrap_or_default();
+                    index
+                        .block_texts
+                        .insert(id.to_lowercase(), first_line.to_string());
+                }
+            }
+        }
+
+        Ok(self.index.get_or_init(|| index))
+    }
+
+    fn read_page(&self, relative_path: &Path) -> Result<Page> {
+        let path = self.graph.join(relative_path);
+        let io_error = |e| NaviError::io(format!("unable to read {}", path.display()), e);
+        let contents = fs::read_to_string(&path).map_err(io_error)?;
+        let metadata = fs::metadata(&path).map_err(io_error)?;
+        let modified: DateTime<Utc> = metadata.modified().map_err(io_error)?.into();
+        let created = metadata
+            .created()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or(modified);
+
+        let (properties, _) = parse_page(&contents);
+        // a journal's date is authoritative, no matter when its file was last touched
+        let update_date = match journal_date(relative_path) {
+            Some(date) => date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc(),
+            None => modified,
+        };
+
+        Ok(Page {
+            id: PageID::new(relative_path.to_string_lossy().replace('\\', "/")),
+            title: page_title(relative_path, &properties),
+            url: format!("file://{}", path.display()),
+            creation_date: created.min(update_date),
+            update_date,
+            child_blocks: Vec::new(),
+            database: None,
+            properties: properties
+                .into_iter()
+                .filter(|property| property.name != "title")
+                .collect(),
+        })
+    }
+}
+
+/// Parses a `key:: value` Logseq property
+fn parse_property(content: &str) -> Option<Property> {
+    let (key, value) = content.split_once(":: ")?;
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+
+    Some(Property {
+        name: key.to_lowercase(),
+        value: value.trim().to_string(),
+    })
+}
+
+/// Parses a Logseq page into its page properties (the `key:: value` lines before the first
+/// block) and its blocks. Block properties are consumed here: `id::` and `collapsed::` are kept
+/// on the block, and any others are Logseq metadata rather than notes, so they are dropped.
+fn parse_page(contents: &str) -> (Vec<Property>, Vec<LogseqBlock>) {
+    let mut properties = Vec::new();
+    let mut blocks: Vec<LogseqBlock> = Vec::new();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let (indent, content) = split_indent(line);
+        if content.is_empty() {
+            continue;
+        }
+
+        let block_text = match content {
+            "-" => Some(""),
+            _ => content.strip_prefix("- "),
+        };
+        if let Some(text) = block_text {
+            blocks.push(LogseqBlock {
+                indent,
+                line_number,
+                id: None,
+                collapsed: false,
+                text: text.trim().to_string(),
+            });
+            continue;
+        }
+
+        match (blocks.last_mut(), parse_property(content)) {
+            (None, Some(property)) => properties.push(property),
+            (Some(block), Some(property)) if property.name == "id" => {
+                block.id = Some(property.value)
+            }
+            (Some(block), Some(property)) if property.name == "collapsed" => {
+                block.collapsed = property.value == "true"
+            }
+            (Some(_), Some(_)) => {}
+            // the continuation of a multi-line block
+            (Some(block), None) => {
+                block.text.push('\n');
+                block.text.push_str(content);
+            }
+            // text that isn't in a block, which happens when pages are written outside of Logseq
+            (None, None) => blocks.push(LogseqBlock {
+                indent,
+                line_number,
+                id: None,
+                collapsed: false,
+                text: content.to_string(),
+            }),
+        }
+    }
+
+    (properties, blocks)
+}
+
+/// Returns the date of a journal page from its file name, or `None` if the page is not a journal.
+/// Logseq names journals `yyyy_MM_dd` by default, and `yyyy-MM-dd` is also common.
+fn journal_date(relative_path: &Path) -> Option<NaiveDate> {
+    if !relative_path.starts_with("journals") {
+        return None;
+    }
+
+    let file_stem = relative_path.file_stem()?.to_string_lossy();
+    ["%Y_%m_%d", "%Y-%m-%d"]
+        .into_iter()
+        .find_map(|format| NaiveDate::parse_from_str(&file_stem, format).ok())
+}
+
+/// Returns the name Logseq uses to refer to a page, which is its file name with namespaces
+/// (written as `___` or `%2F` in file names) turned back into "/"
+fn page_name(relative_path: &Path) -> String {
+    relative_path
+        .file_stem()
+        .map(|stem| {
+            stem.to_string_lossy()
+                .replace("___", "/")
+                .replace("%2F", "/")
+        })
+        .unwrap_or_default()
+}
+
+/// Returns a page's title: a journal's date written out in full, the page's `title::` property
+/// if it has one, and otherwise its name
+fn page_title(relative_path: &Path, properties: &[Property]) -> String {
+    if let Some(date) = journal_date(relative_path) {
+        return date.format("%A, %B %-d, %Y").to_string();
+    }
+
+    properties
+        .iter()
+        .find(|property| property.name == "title")
+        .map(|property| property.value.clone())
+        .unwrap_or_else(|| page_name(relative_path))
+}
+
+/// Returns the kind of Block a Logseq block is, and its text without any task marker
+fn block_kind(text: &str, collapsed: bool) -> (TextBlockKind, &str) {
+    if let Some((marker, rest)) = text.split_once(' ') {
+        match marker {
+            "TODO" | "DOING" | "NOW" | "LATER" | "WAITING" => {
+                return (TextBlockKind::ToDo { checked: false }, rest)
+            }
+            "DONE" => return (TextBlockKind::ToDo { checked: true }, rest),
+            _ => {}
+        }
+    }
+
+    for (prefix, heading) in [
+        ("### ", TextBlockKind::Heading3),
+        ("## ", TextBlockKind::Heading2),
+        ("# ", TextBlockKind::Heading1),
+    ] {
+        if let Some(rest) = text.strip_prefix(prefix) {
+            return (heading, rest);
+        }
+    }
+
+    if collapsed {
+        (TextBlockKind::Toggle, text)
+    } else {
+        (TextBlockKind::BulletedListItem, text)
+    }
+}
+
+fn block_ref_regex() -> &'static Regex {
+    static BLOCK_REF: OnceLock<Regex> = OnceLock::new();
+    BLOCK_REF.get_or_init(|| {
+        Regex::new(r"(?:\{\{embed )?\(\((?P<id>[0-9a-fA-F-]{36})\)\)(?:\}\})?").unwrap()
+    })
+}
+
+/// Replaces every `((block ref))` and `{{embed ((block ref))}}` in `text` with the text of the
+/// referenced block. References to blocks that can't be found are left as they are.
+fn resolve_block_refs(text: &str, block_texts: &HashMap<String, String>) -> String {
+    block_ref_regex()
+        .replace_all(text, |caps: &Captures| {
+            block_texts
+                .get(&caps["id"].to_lowercase())
+                .cloned()
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
+}
+
+#[async_trait(?Send)]
+impl NoteSource for Logseq {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    async fn get_last_edited_pages(
+        &self,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<Page>> {
+        let mut pages = Vec::new();
+        for relative_path in self.page_files()? {
+            let page = match self.read_page(&relative_path) {
+                Ok(page) => page,
+                Err(e) => {
+                    let url = format!("file://{}", self.graph.join(&relative_path).display());
+                    report.record(self.name(), &page_name(&relative_path), &url, None, &e);
+                    continue;
+                }
+            };
+            let edited_since_cutoff = match journal_date(&relative_path) {
+                // compare journals by day, so the journal for the day of the cutoff is included
+                Some(date) => date >= cutoff.date_naive(),
+                None => page.update_date >= cutoff,
+            };
+            if !edited_since_cutoff {
+                continue;
+            }
+
+            if self.config.should_exclude_page(&page.title, &page.url) {
+                debug!(target: "logseq", "Skipping excluded page: {}", page.title);
+                continue;
+            }
+
+            pages.push(page);
+        }
+
+        pages.sort_by(|a, b| b.update_date.cmp(&a.update_date));
+        Ok(pages)
+    }
+
+    /// Returns every top-level block of the page (and their descendants), since Logseq doesn't
+    /// record when each block was edited.
+    async fn get_block_trees(
+        &self,
+        page: &Page,
+        _cutoff: DateTime<Utc>,
+        _report: &mut IngestionReport,
+    ) -> Result<Vec<Tree<Block>>> {
+        let path = self.graph.join(page.id.as_str());
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| NaviError::io(format!("unable to read {}", path.display()), e))?;
+        let (_, logseq_blocks) = parse_page(&contents);
+
+        let index = self.index()?;
+        let resolve_link = |target: &str| {
+            // unlike Obsidian, a Logseq page ref is the page's name, so it reads fine as it is
+            Some(
+                index
+                    .page_titles
+                    .get(&target.to_lowercase())
+                    .cloned()
+                    .unwrap_or_else(|| target.to_string()),
+            )
+        };
+
+        let mut blocks = Vec::new();
+        for logseq_block in logseq_blocks {
+            let (kind, text) = block_kind(&logseq_block.text, logseq_block.collapsed);
+            // block refs are resolved first, so that page refs inside the referenced text are resolved too
+            let text = resolve_block_refs(text, &index.block_texts);
+            let text = resolve_wikilinks(&text, &resolve_link);
+            if text.is_empty() {
+                continue;
+            }
+
+            let id = logseq_block
+                .id
+                .unwrap_or_else(|| format!("{}#L{}", page.id, logseq_block.line_number + 1));
+            let block = Block::from_text(
+                BlockID::new(id),
+                page.id.clone(),
+                kind,
+                text,
+                page.update_date,
+            );
+            blocks.push((logseq_block.indent, block));
+        }
+
+        Ok(nest_by_indent(blocks).into_iter().map(build_tree).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::helpers::build_markdown_from_trees;
+
+    fn fixture_graph() -> Logseq {
+        Logseq::new(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/logseq_graph"),
+            Config::default(),
+        )
+    }
+
+    fn cutoff() -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2024, 11, 9)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn test_journal_date() {
+        assert_eq!(
+            journal_date(Path::new("journals/2024_11_09.md")),
+            NaiveDate::from_ymd_opt(2024, 11, 9)
+        );
+        assert_eq!(journal_date(Path::new("pages/2024_11_09.md")), None);
+        assert_eq!(journal_date(Path::new("journals/notes.md")), None);
+    }
+
+    #[test]
+    fn test_parse_page_properties() {
+        let (properties, blocks) = parse_page(
+            "tags:: project\n\n- first\n  id:: 6543a1b2-0000-4000-8000-000000000001\n  second line\n\t- child\n\t  collapsed:: true\n",
+        );
+
+        assert_eq!(properties.len(), 1);
+        assert_eq!(properties[0].value, "project");
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            blocks[0].id.as_deref(),
+            Some("6543a1b2-0000-4000-8000-000000000001")
+        );
+        assert_eq!(blocks[0].text, "first\nsecond line");
+        assert!(blocks[1].collapsed);
+    }
+
+    #[tokio::test]
+    async fn test_journals_are_dated_by_file_name() {
+        let pages = fixture_graph()
+            .get_last_edited_pages(cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+
+        let mut titles = pages.iter().map(|p| p.title.as_str()).collect::<Vec<_>>();
+        titles.sort();
+        // the 2020 journal's file was modified recently, but its name dates it outside the window,
+        // while the journal for the day of the cutoff is inside it
+        assert_eq!(
+            titles,
+            vec!["Evan Litwin", "Navi", "Saturday, November 9, 2024"]
+        );
+
+        let navi = pages.iter().find(|p| p.title == "Navi").unwrap();
+        let properties = navi
+            .properties
+            .iter()
+            .map(|p| (p.name.as_str(), p.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(properties, vec![("tags", "project"), ("status", "active")]);
+    }
+
+    #[tokio::test]
+    async fn test_journal_block_trees() {
+        let graph = fixture_graph();
+        let pages = graph
+            .get_last_edited_pages(cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+        let journal = pages
+            .iter()
+            .find(|p| p.id == PageID::new("journals/2024_11_09.md".to_string()))
+            .unwrap();
+
+        let trees = graph
+            .get_block_trees(journal, cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            build_markdown_from_trees(trees),
+            "\t- Worked on Navi\n\
+             \t\t> Finished the Logseq importer\n\
+             \t\t\t- hidden detail\n\
+             \t- [x] Review PR from Evan Litwin\n\
+             \t- [ ] Practice coding challenges\n\
+             \t- Quote from the book: The best way to predict the future is to invent it\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_ids_come_from_id_property() {
+        let graph = fixture_graph();
+        let pages = graph
+            .get_last_edited_pages(cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+        let navi = pages.iter().find(|p| p.title == "Navi").unwrap();
+
+        let trees = graph
+            .get_block_trees(navi, cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+        let ids = trees
+            .iter()
+            .map(|tree| tree.root().borrow_data().id.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                "pages/Navi.md#L4".to_string(),
+                "6543a1b2-0000-4000-8000-000000000001".to_string()
+            ]
+        );
+    }
+}
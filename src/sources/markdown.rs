@@ -0,0 +1,492 @@
+use crate::core::{
+    datatypes::{Block, BlockID, PageID, Property, TextBlockKind},
+    helpers::BlockNode,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use regex::{Captures, Regex};
+use std::sync::OnceLock;
+
+/// Splits a Markdown file into its YAML front-matter and its body.
+///
+/// We only understand the simple `key: value`, `key: [a, b]` and `key:` followed by `- item`
+/// forms of YAML, which covers what Obsidian writes into front-matter. Lists are joined with ", ".
+pub fn parse_front_matter(contents: &str) -> (Vec<Property>, &str) {
+    let Some(rest) = contents
+        .strip_prefix("---\n")
+        .or_else(|| contents.strip_prefix("---\r\n"))
+    else {
+        return (Vec::new(), contents);
+    };
+    let Some(end) = rest.find("\n---") else {
+        return (Vec::new(), contents);
+    };
+
+    let yaml = &rest[..end];
+    // skip past the closing "---" line
+    let body = match rest[end + 1..].find('\n') {
+        Some(newline) => &rest[end + 1 + newline + 1..],
+        None => "",
+    };
+
+    let mut properties: Vec<Property> = Vec::new();
+    for line in yaml.lines() {
+        let trimmed = line.trim();
+        if let Some(item) = trimmed.strip_prefix("- ") {
+            // an item of a list that belongs to the last key we saw
+            if let Some(property) = properties.last_mut() {
+                if !property.value.is_empty() {
+                    property.value.push_str(", ");
+                }
+                property.value.push_str(trim_yaml_quotes(item));
+            }
+            continue;
+        }
+
+        // indented keys belong to nested maps, which we don't support
+        if line.starts_with(char::is_whitespace) {
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('[')
+                .and_then(|v| v.strip_suffix(']'))
+                .map(|list| {
+                    list.split(',')
+                        .map(trim_yaml_quotes)
+                        .filter(|item| !item.is_empty())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                })
+                .unwrap_or_else(|| trim_yaml_quotes(value).to_string());
+
+            properties.push(Property {
+                name: key.trim().to_string(),
+                value,
+            });
+        }
+    }
+
+    properties.retain(|property| !property.value.is_empty());
+    (properties, body)
+}
+
+fn trim_yaml_quotes(value: &str) -> &str {
+    value.trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+/// Parses the kinds of dates people write into front-matter, e.g. `2024-11-09`,
+/// `2024-11-09 14:45` or `2024-11-09T14:45:22Z`. Dates without a timezone are assumed to be UTC,
+/// and dates without a time are assumed to be at the start of the day.
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        return Some(date_time.with_timezone(&Utc));
+    }
+
+    for format in [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(date_time) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(date_time.and_utc());
+        }
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date_time| date_time.and_utc())
+}
+
+fn wikilink_regex() -> &'static Regex {
+    static WIKILINK: OnceLock<Regex> = OnceLock::new();
+    WIKILINK.get_or_init(|| {
+        Regex::new(
+            r"!?\[\[(?P<target>[^\]|#]*)(?:#(?P<heading>[^\]|]*))?(?:\|(?P<alias>[^\]]*))?\]\]",
+        )
+        .unwrap()
+    })
+}
+
+/// Replaces every `[[wikilink]]` in `text` with the title of the page it links to, so that
+/// the prompt reads naturally. `resolve_link` maps a link target to a page title, and
+/// unresolved links fall back to the last segment of their target.
+///
+/// `[[Page|Alias]]` becomes `Alias`, and `[[Page#Heading]]` becomes `Page > Heading`.
+pub fn resolve_wikilinks(text: &str, resolve_link: &dyn Fn(&str) -> Option<String>) -> String {
+    wikilink_regex()
+        .replace_all(text, |caps: &Captures| {
+            if let Some(alias) = caps.name("alias") {
+                return alias.as_str().trim().to_string();
+            }
+
+            let target = caps["target"].trim();
+            let heading = caps.name("heading").map(|heading| heading.as_str().trim());
+            let title = resolve_link(target)
+                .unwrap_or_else(|| target.rsplit('/').next().unwrap_or(target).to_string());
+
+            match heading {
+                Some(heading) if title.is_empty() => heading.to_string(),
+                Some(heading) => format!("{} > {}", title, heading),
+                None => title,
+            }
+        })
+        .into_owned()
+}
+
+fn block_id_regex() -> &'static Regex {
+    static BLOCK_ID: OnceLock<Regex> = OnceLock::new();
+    BLOCK_ID.get_or_init(|| Regex::new(r"\s+\^[A-Za-z0-9-]+$").unwrap())
+}
+
+/// A single line of Markdown, classified but not yet placed into a tree
+struct MarkdownLine {
+    indent: usize,
+    line_number: usize,
+    kind: TextBlockKind,
+    text: String,
+}
+
+/// Returns the width of a line's leading whitespace (counting a tab as 4 spaces), and the
+/// rest of the line.
+pub fn split_indent(line: &str) -> (usize, &str) {
+    let content = line.trim_start();
+    let indent = line[..line.len() - content.len()]
+        .chars()
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum();
+    (indent, content)
+}
+
+/// Classifies a line of Markdown (with its indentation already removed) by the kind of Block it is.
+/// Returns `None` for lines that hold no notes, such as horizontal rules.
+fn classify(content: &str) -> Option<(TextBlockKind, &str)> {
+    if matches!(content, "---" | "***" | "___") {
+        return None;
+    }
+
+    let hashes = content.chars().take_while(|c| *c == '#').count();
+    if (1..=6).contains(&hashes) && content[hashes..].starts_with(' ') {
+        let kind = match hashes {
+            1 => TextBlockKind::Heading1,
+            2 => TextBlockKind::Heading2,
+            _ => TextBlockKind::Heading3,
+        };
+        return Some((kind, content[hashes..].trim()));
+    }
+
+    for bullet in ["- ", "* ", "+ "] {
+        if let Some(item) = content.strip_prefix(bullet) {
+            for (checkbox, checked) in [("[ ] ", false), ("[x] ", true), ("[X] ", true)] {
+                if let Some(to_do) = item.strip_prefix(checkbox) {
+                    return Some((TextBlockKind::ToDo { checked }, to_do.trim()));
+                }
+            }
+            return Some((TextBlockKind::BulletedListItem, item.trim()));
+        }
+    }
+
+    let digits = content.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0 {
+        if let Some(item) = content[digits..]
+            .strip_prefix(". ")
+            .or_else(|| content[digits..].strip_prefix(") "))
+        {
+            return Some((TextBlockKind::NumberedListItem, item.trim()));
+        }
+    }
+
+    Some((TextBlockKind::Paragraph, content.trim_end()))
+}
+
+/// Parses Markdown `body` into the lines that will become Blocks, understanding headings, nested
+/// bullets, to-dos, numbered lists, fenced code and Obsidian's callouts (where a foldable callout
+/// such as `> [!note]- Title` is a toggle, and its contents are the toggle's children).
+fn parse_lines(body: &str) -> Vec<MarkdownLine> {
+    let mut lines = Vec::new();
+    let mut code_fence: Option<MarkdownLine> = None;
+    let mut callout_indent: Option<usize> = None;
+
+    for (line_number, line) in body.lines().enumerate() {
+        let (indent, content) = split_indent(line);
+
+        // everything inside a fenced code block is kept verbatim as a single Block
+        if let Some(code) = code_fence.as_mut() {
+            code.text.push('\n');
+            code.text.push_str(line);
+            if content.starts_with("```") || content.starts_with("~~~") {
+                lines.extend(code_fence.take());
+            }
+            continue;
+        }
+        if content.starts_with("```") || content.starts_with("~~~") {
+            code_fence = Some(MarkdownLine {
+                indent,
+                line_number,
+                kind: TextBlockKind::Paragraph,
+                text: content.to_string(),
+            });
+            continue;
+        }
+
+        let (indent, content) = match content.strip_prefix('>') {
+            Some(quoted) => {
+                let quoted = quoted.strip_prefix(' ').unwrap_or(quoted);
+                if let Some(callout) = quoted.strip_prefix("[!") {
+                    let (callout_type, title) = callout.split_once(']').unwrap_or((callout, ""));
+                    let (kind, title) = match title.strip_prefix(['-', '+']) {
+                        Some(title) => (TextBlockKind::Toggle, title),
+                        None => (TextBlockKind::Quote, title),
+                    };
+                    // callouts without a title are displayed with their type as the title
+                    let title = match title.trim() {
+                        "" => callout_type,
+                        title => title,
+                    };
+                    callout_indent = Some(indent);
+                    lines.push(MarkdownLine {
+                        indent,
+                        line_number,
+                        kind,
+                        text: title.to_string(),
+                    });
+                    continue;
+                }
+
+                match callout_indent {
+                    // the contents of a callout are nested underneath it
+                    Some(callout_indent) => {
+                        let (quoted_indent, quoted_content) = split_indent(quoted);
+                        (callout_indent + 1 + quoted_indent, quoted_content)
+                    }
+                    None => {
+                        lines.push(MarkdownLine {
+                            indent,
+                            line_number,
+                            kind: TextBlockKind::Quote,
+                            text: quoted.trim().to_string(),
+                        });
+                        continue;
+                    }
+                }
+            }
+            None => {
+                callout_indent = None;
+                (indent, content)
+            }
+        };
+
+        if let Some((kind, text)) = classify(content) {
+            lines.push(MarkdownLine {
+                indent,
+                line_number,
+                kind,
+                text: text.to_string(),
+            });
+        }
+    }
+
+    // an unclosed code fence runs to the end of the file
+    lines.extend(code_fence);
+    lines
+}
+
+/// Parses the body of a Markdown file into trees of Blocks. A line becomes a child of the closest
+/// line above it that has less indentation, which is how nested bullets are written in Markdown.
+///
+/// Every Block's ID is made from the Page's ID and the Block's line number, and `[[wikilinks]]`
+/// in the Block's text are resolved using `resolve_link` (see `resolve_wikilinks`).
+pub fn parse_markdown_blocks(
+    body: &str,
+    page_id: &PageID,
+    update_date: DateTime<Utc>,
+    resolve_link: &dyn Fn(&str) -> Option<String>,
+) -> Vec<BlockNode> {
+    let mut blocks = Vec::new();
+
+    for line in parse_lines(body) {
+        let text = resolve_wikilinks(&line.text, resolve_link);
+        let text = block_id_regex().replace(&text, "").into_owned();
+        if text.is_empty() {
+            continue;
+        }
+
+        // headings are never nested, even if they are indented
+        let indent = match line.kind {
+            TextBlockKind::Heading1 | TextBlockKind::Heading2 | TextBlockKind::Heading3 => 0,
+            _ => line.indent,
+        };
+
+        let block = Block::from_text(
+            BlockID::new(format!("{}#L{}", page_id, line.line_number + 1)),
+            page_id.clone(),
+            line.kind,
+            text,
+            update_date,
+        );
+        blocks.push((indent, block));
+    }
+
+    nest_by_indent(blocks)
+}
+
+/// Nests a flat list of `(indentation, Block)`s into trees, where each Block becomes a child of
+/// the closest Block before it that has less indentation.
+pub fn nest_by_indent(blocks: Vec<(usize, Block)>) -> Vec<BlockNode> {
+    let mut roots: Vec<BlockNode> = Vec::new();
+    let mut stack: Vec<(usize, BlockNode)> = Vec::new();
+
+    for (indent, block) in blocks {
+        while stack
+            .last()
+            .is_some_and(|(top_indent, _)| *top_indent >= indent)
+        {
+            pop_into_parent(&mut stack, &mut roots);
+        }
+        stack.push((indent, BlockNode::new(block)));
+    }
+
+    while !stack.is_empty() {
+        pop_into_parent(&mut stack, &mut roots);
+    }
+
+    roots
+}
+
+fn pop_into_parent(stack: &mut Vec<(usize, BlockNode)>, roots: &mut Vec<BlockNode>) {
+    if let Some((_, node)) = stack.pop() {
+        match stack.last_mut() {
+            Some((_, parent)) => parent.children.push(node),
+            None => roots.push(node),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_front_matter() {
+        let contents = "---\nupdated: 2024-11-09\ntags: [retro, \"navi\"]\naliases:\n  - weekly\n  - review\n---\n# Title\n";
+        let (properties, body) = parse_front_matter(contents);
+
+        let properties = properties
+            .into_iter()
+            .map(|p| (p.name, p.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            properties,
+            vec![
+                ("updated".to_string(), "2024-11-09".to_string()),
+                ("tags".to_string(), "retro, navi".to_string()),
+                ("aliases".to_string(), "weekly, review".to_string()),
+            ]
+        );
+        assert_eq!(body, "# Title\n");
+
+        let (properties, body) = parse_front_matter("no front matter here");
+        assert!(properties.is_empty());
+        assert_eq!(body, "no front matter here");
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let expected = NaiveDate::from_ymd_opt(2024, 11, 9)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc();
+        assert_eq!(parse_date("2024-11-09"), Some(expected));
+        assert_eq!(
+            parse_date("2024-11-09 14:45"),
+            Some(expected + chrono::Duration::minutes(14 * 60 + 45))
+        );
+        assert_eq!(
+            parse_date("2024-11-09T14:45:22Z"),
+            Some(expected + chrono::Duration::seconds(14 * 3600 + 45 * 60 + 22))
+        );
+        assert_eq!(parse_date("last tuesday"), None);
+    }
+
+    #[test]
+    fn test_resolve_wikilinks() {
+        let resolve = |target: &str| {
+            if target.eq_ignore_ascii_case("project navi") {
+                Some("Project Navi".to_string())
+            } else {
+                None
+            }
+        };
+
+        assert_eq!(
+            resolve_wikilinks("worked on [[project navi]] today", &resolve),
+            "worked on Project Navi today"
+        );
+        assert_eq!(
+            resolve_wikilinks("played with [[Gwen|my daughter]]", &resolve),
+            "played with my daughter"
+        );
+        assert_eq!(
+            resolve_wikilinks("see [[project navi#Roadmap]] and [[people/Evan]]", &resolve),
+            "see Project Navi > Roadmap and Evan"
+        );
+    }
+
+    #[test]
+    fn test_parse_markdown_blocks_nests_by_indentation() {
+        let body = "# Planning\n- Team availability\n\t- PTOs\n- [x] Sprint review\n1. first\nplain text ^abc123\n";
+        let roots = parse_markdown_blocks(
+            body,
+            &PageID::new("Sprint.md".to_string()),
+            Utc::now(),
+            &|_| None,
+        );
+
+        let summary = roots
+            .iter()
+            .map(|node| {
+                (
+                    node.block.text.as_str(),
+                    node.children
+                        .iter()
+                        .map(|child| child.block.text.as_str())
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("Planning", vec![]),
+                ("Team availability", vec!["PTOs"]),
+                ("Sprint review", vec![]),
+                ("first", vec![]),
+                ("plain text", vec![]),
+            ]
+        );
+        assert_eq!(roots[1].block.id, BlockID::new("Sprint.md#L2".to_string()));
+        assert_eq!(roots[2].block.to_markdown(), "- [x] Sprint review");
+    }
+
+    #[test]
+    fn test_parse_markdown_blocks_callout_toggle() {
+        let body = "> [!question]- What went well?\n> - shipped the MVP\n>   - with tests\nafter\n";
+        let roots = parse_markdown_blocks(
+            body,
+            &PageID::new("Retro.md".to_string()),
+            Utc::now(),
+            &|_| None,
+        );
+
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].block.to_markdown(), "> What went well?");
+        assert_eq!(roots[0].children[0].block.text, "shipped the MVP");
+        assert_eq!(roots[0].children[0].children[0].block.text, "with tests");
+        assert_eq!(roots[1].block.text, "after");
+    }
+}
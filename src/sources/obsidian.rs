@@ -0,0 +1,300 @@
+use super::{
+    markdown::{parse_date, parse_front_matter, parse_markdown_blocks},
+    IngestionReport, NoteSource,
+};
+use crate::config::Config;
+use crate::core::{
+    datatypes::{Block, Page, PageID},
+    helpers::build_tree,
+};
+use crate::error::{NaviError, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dendron::Tree;
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// A `NoteSource` for an [Obsidian](https://obsidian.md/) vault, or any other directory of
+/// Markdown files.
+///
+/// Markdown files don't record when each of their lines was edited, so a note edited since
+/// the cutoff has all of its content included, not just the recently edited parts.
+pub struct Obsidian {
+    vault: PathBuf,
+    config: Config,
+    /// Maps the lowercased title and vault-relative path of every note to its title, for
+    /// resolving `[[wikilinks]]`. Built the first time it is needed.
+    link_index: OnceLock<HashMap<String, String>>,
+}
+
+impl Obsidian {
+    /// The name that notes from a vault are synced under
+    pub const NAME: &str = "Obsidian";
+
+    pub fn new(vault: PathBuf, config: Config) -> Self {
+        Obsidian {
+            vault,
+            config,
+            link_index: OnceLock::new(),
+        }
+    }
+
+    /// Returns the vault-relative path of every Markdown file in the vault, skipping hidden
+    /// folders such as `.obsidian` and `.trash`.
+    fn markdown_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        collect_markdown_files(&self.vault, &self.vault, &mut files).map_err(|e| {
+            NaviError::io(
+                format!("unable to list the notes in {}", self.vault.display()),
+                e,
+            )
+        })?;
+        files.sort();
+        Ok(files)
+    }
+
+    fn link_index(&self) -> Result<&HashMap<String, String>> {
+        if let Some(link_index) = self.link_index.get() {
+            return Ok(link_index);
+        }
+
+        let mut link_index = HashMap::new();
+        for relative_path in self.markdown_files()? {
+            let title = note_title(&relative_path);
+            // links can be written either as [[Title]] or as [[folder/Title]]
+            link_index.insert(
+                relative_path_id(&relative_path.with_extension("")).to_lowercase(),
+                title.clone(),
+            );
+            link_index.entry(title.to_lowercase()).or_insert(title);
+        }
+
+        Ok(self.link_index.get_or_init(|| link_index))
+    }
+
+    /// Reads a note into a `Page`. The note's dates come from the `updated` and `created` fields of its
+    /// front-matter when they exist, and from the file's timestamps otherwise. The rest of the
+    /// front-matter becomes the `Page`'s properties.
+    fn read_page(&self, relative_path: &Path) -> Result<Page> {
+        let path = self.vault.join(relative_path);
+        let io_error = |e| NaviError::io(format!("unable to read {}", path.display()), e);
+        let contents = fs::read_to_string(&path).map_err(io_error)?;
+        let metadata = fs::metadata(&path).map_err(io_error)?;
+        let modified: DateTime<Utc> = metadata.modified().map_err(io_error)?.into();
+        let created = metadata
+            .created()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or(modified);
+
+        let (mut properties, _) = parse_front_matter(&contents);
+        let front_matter_date = |name: &str| {
+            properties
+                .iter()
+                .find(|property| property.name.eq_ignore_ascii_case(name))
+                .and_then(|property| parse_date(&property.value))
+        };
+        let update_date = front_matter_date("updated").unwrap_or(modified);
+        let creation_date = front_matter_date("created").unwrap_or(created);
+        properties.retain(|property| {
+            !property.name.eq_ignore_ascii_case("updated")
+                && !property.name.eq_ignore_ascii_case("created")
+        });
+
+        Ok(Page {
+            id: PageID::new(relative_path_id(relative_path)),
+            title: note_title(relative_path),
+            url: format!("file://{}", path.display()),
+            creation_date,
+            update_date,
+            child_blocks: Vec::new(),
+            database: None,
+            properties,
+        })
+    }
+}
+
+/// Recursively collects the Markdown files under `dir`, as paths relative to `root`
+fn collect_markdown_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_markdown_files(root, &path, files)?;
+        } else if path.extension().is_some_and(|extension| extension == "md") {
+            files.push(path.strip_prefix(root).unwrap_or(&path).to_path_buf());
+        }
+    }
+
+    Ok(())
+}
+
+/// A note's title is its file name, without the .md extension
+fn note_title(relative_path: &Path) -> String {
+    relative_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Converts a vault-relative path to the form used for `PageID`s and wikilinks, which always
+/// uses "/" as the separator
+fn relative_path_id(relative_path: &Path) -> String {
+    relative_path.to_string_lossy().replace('\\', "/")
+}
+
+#[async_trait(?Send)]
+impl NoteSource for Obsidian {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    async fn get_last_edited_pages(
+        &self,
+        cutoff: DateTime<Utc>,
+        report: &mut IngestionReport,
+    ) -> Result<Vec<Page>> {
+        let mut pages = Vec::new();
+        for relative_path in self.markdown_files()? {
+            let page = match self.read_page(&relative_path) {
+                Ok(page) => page,
+                Err(e) => {
+                    let url = format!("file://{}", self.vault.join(&relative_path).display());
+                    report.record(self.name(), &note_title(&relative_path), &url, None, &e);
+                    continue;
+                }
+            };
+            if page.update_date < cutoff {
+                continue;
+            }
+
+            if self.config.should_exclude_page(&page.title, &page.url) {
+                debug!(target: "obsidian", "Skipping excluded page: {}", page.title);
+                continue;
+            }
+
+            pages.push(page);
+        }
+
+        pages.sort_by(|a, b| b.update_date.cmp(&a.update_date));
+        Ok(pages)
+    }
+
+    /// Returns every top-level Block of the note (and their descendants), since we can't tell which
+    /// parts of a Markdown file were edited since the cutoff.
+    async fn get_block_trees(
+        &self,
+        page: &Page,
+        _cutoff: DateTime<Utc>,
+        _report: &mut IngestionReport,
+    ) -> Result<Vec<Tree<Block>>> {
+        let path = self.vault.join(page.id.as_str());
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| NaviError::io(format!("unable to read {}", path.display()), e))?;
+        let (_, body) = parse_front_matter(&contents);
+
+        let link_index = self.link_index()?;
+        let resolve_link = |target: &str| {
+            link_index
+                .get(&target.trim_end_matches(".md").to_lowercase())
+                .cloned()
+        };
+
+        Ok(
+            parse_markdown_blocks(body, &page.id, page.update_date, &resolve_link)
+                .into_iter()
+                .map(build_tree)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::helpers::build_markdown_from_trees;
+    use chrono::NaiveDate;
+
+    fn fixture_vault() -> Obsidian {
+        Obsidian::new(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/obsidian_vault"),
+            Config::default(),
+        )
+    }
+
+    fn cutoff() -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2024, 11, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[tokio::test]
+    async fn test_get_last_edited_pages() {
+        let pages = fixture_vault()
+            .get_last_edited_pages(cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+
+        // "Old Note" was updated before the cutoff, and the trash folder is hidden
+        let titles = pages.iter().map(|p| p.title.as_str()).collect::<Vec<_>>();
+        assert_eq!(titles, vec!["Weekly Review", "Project Navi"]);
+        assert_eq!(
+            pages[1].id,
+            PageID::new("Projects/Project Navi.md".to_string())
+        );
+
+        let properties = pages[0]
+            .properties
+            .iter()
+            .map(|p| (p.name.as_str(), p.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(properties, vec![("tags", "retro"), ("status", "draft")]);
+    }
+
+    #[tokio::test]
+    async fn test_exclusions_apply_to_vault() {
+        let mut config = Config::default();
+        config
+            .exclusions
+            .page_patterns
+            .push("^Weekly Review$".to_string());
+        let vault = Obsidian::new(fixture_vault().vault, config);
+
+        let pages = vault
+            .get_last_edited_pages(cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+        let titles = pages.iter().map(|p| p.title.as_str()).collect::<Vec<_>>();
+        assert_eq!(titles, vec!["Project Navi"]);
+    }
+
+    #[tokio::test]
+    async fn test_parse_last_edited_builds_block_trees() {
+        let parsed_pages = fixture_vault()
+            .parse_last_edited(cutoff(), &mut IngestionReport::default())
+            .await
+            .unwrap();
+        assert_eq!(parsed_pages.len(), 2);
+
+        let markdown = build_markdown_from_trees(parsed_pages[0].page_content.clone());
+        assert_eq!(
+            markdown,
+            "\t# This week\n\
+             \t- Worked on Project Navi\n\
+             \t\t- Finished the CLI MVP\n\
+             \t- [x] Took my daughter to the park\n\
+             \t- [ ] Practice coding challenges\n\
+             \t> What went well?\n\
+             \t\tRested a lot\n"
+        );
+    }
+}
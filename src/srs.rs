@@ -0,0 +1,293 @@
+use crate::config::{ReviewSettings, SchedulerKind};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The weights of FSRS-4.5's model of memory, as fitted to a large set of Anki reviews
+const FSRS_WEIGHTS: [f64; 17] = [
+    0.4072, 1.1829, 3.1262, 15.4722, 7.2102, 0.5316, 1.0651, 0.0234, 1.616, 0.1544, 1.0824, 1.9813,
+    0.0953, 0.2975, 2.2042, 0.2407, 2.9466,
+];
+/// The shape of FSRS's forgetting curve, chosen so that a card is remembered with a 90% chance
+/// after as many days as its stability
+const FSRS_DECAY: f64 = -0.5;
+const FSRS_FACTOR: f64 = 19.0 / 81.0;
+/// The longest a card goes between reviews
+const MAX_INTERVAL_DAYS: f64 = 36_500.0;
+
+/// How well the user remembered a card, as graded by the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grade {
+    /// Forgot it
+    Again,
+    /// Remembered it, but with difficulty
+    Hard,
+    Good,
+    /// Remembered it without effort
+    Easy,
+}
+
+impl Grade {
+    /// Reads a grade the user typed: 1 to 4, or its name
+    pub fn parse(input: &str) -> Option<Grade> {
+        match input.trim().to_lowercase().as_str() {
+            "1" | "again" => Some(Grade::Again),
+            "2" | "hard" => Some(Grade::Hard),
+            "3" | "good" => Some(Grade::Good),
+            "4" | "easy" => Some(Grade::Easy),
+            _ => None,
+        }
+    }
+
+    /// The grade as a number from 1 (Again) to 4 (Easy), which is how FSRS counts them
+    fn number(self) -> f64 {
+        match self {
+            Grade::Again => 1.0,
+            Grade::Hard => 2.0,
+            Grade::Good => 3.0,
+            Grade::Easy => 4.0,
+        }
+    }
+
+    /// The grade as the quality of the response from 0 to 5, which is how SM-2 counts them
+    fn quality(self) -> f64 {
+        match self {
+            Grade::Again => 1.0,
+            Grade::Hard => 3.0,
+            Grade::Good => 4.0,
+            Grade::Easy => 5.0,
+        }
+    }
+}
+
+/// When a card is next due, along with what each scheduler keeps track of to decide that. Both
+/// schedulers' state is kept, so that the scheduler can be changed in navi.toml at any time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub due: DateTime<Utc>,
+    pub last_review: Option<DateTime<Utc>>,
+    pub reviews: u32,
+    /// How many times the card was forgotten after it had been learnt
+    pub lapses: u32,
+    /// The days between the last review and `due`, as the scheduler in use decided
+    pub interval_days: f64,
+    /// The interval SM-2 scheduled, which its next interval grows from
+    #[serde(default)]
+    pub sm2_interval_days: f64,
+    /// The interval FSRS scheduled
+    #[serde(default)]
+    pub fsrs_interval_days: f64,
+    /// SM-2's ease factor, by which the interval grows after each successful review
+    pub ease: f64,
+    /// SM-2's count of successful reviews in a row
+    pub repetitions: u32,
+    /// FSRS's stability: how many days until the chance of remembering the card drops to 90%
+    pub stability: f64,
+    /// FSRS's difficulty, from 1 to 10
+    pub difficulty: f64,
+}
+
+impl Schedule {
+    /// The schedule of a card that has never been reviewed, which is due straight away
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Schedule {
+            due: now,
+            last_review: None,
+            reviews: 0,
+            lapses: 0,
+            interval_days: 0.0,
+            sm2_interval_days: 0.0,
+            fsrs_interval_days: 0.0,
+            ease: 2.5,
+            repetitions: 0,
+            stability: 0.0,
+            difficulty: 0.0,
+        }
+    }
+}
+
+/// Decides when each card is next reviewed, after the user grades it
+#[derive(Debug, Clone, Copy)]
+pub struct Scheduler {
+    kind: SchedulerKind,
+    desired_retention: f64,
+}
+
+impl Scheduler {
+    pub fn new(settings: &ReviewSettings) -> Self {
+        Scheduler {
+            kind: settings.scheduler,
+            desired_retention: settings.desired_retention.clamp(0.5, 0.99),
+        }
+    }
+
+    /// The card's schedule after it was given `grade` at `now`
+    pub fn review(&self, schedule: &Schedule, grade: Grade, now: DateTime<Utc>) -> Schedule {
+        let mut next = schedule.clone();
+        if grade == Grade::Again && schedule.reviews > 0 {
+            next.lapses += 1;
+        }
+        next.reviews += 1;
+        next.last_review = Some(now);
+
+        sm2(&mut next, grade);
+        fsrs(&mut next, schedule, grade, now, self.desired_retention);
+        next.interval_days = match self.kind {
+            SchedulerKind::Sm2 => next.sm2_interval_days,
+            SchedulerKind::Fsrs => next.fsrs_interval_days,
+        };
+        next.due = now + Duration::minutes((next.interval_days * 24.0 * 60.0).round() as i64);
+        next
+    }
+}
+
+/// Updates SM-2's state, including its interval
+fn sm2(next: &mut Schedule, grade: Grade) {
+    // cards saved before SM-2's interval was kept apart grow from the interval they were given
+    let previous_interval = if next.sm2_interval_days > 0.0 {
+        next.sm2_interval_days
+    } else {
+        next.interval_days
+    };
+    let quality = grade.quality();
+    if quality < 3.0 {
+        next.repetitions = 0;
+        next.sm2_interval_days = 1.0;
+    } else {
+        next.repetitions += 1;
+        next.sm2_interval_days = match next.repetitions {
+            1 => 1.0,
+            2 => 6.0,
+            _ => (previous_interval.max(1.0) * next.ease)
+                .round()
+                .min(MAX_INTERVAL_DAYS),
+        };
+    }
+    next.ease = (next.ease + 0.1 - (5.0 - quality) * (0.08 + (5.0 - quality) * 0.02)).max(1.3);
+}
+
+/// Updates FSRS's state, including its interval
+fn fsrs(
+    next: &mut Schedule,
+    previous: &Schedule,
+    grade: Grade,
+    now: DateTime<Utc>,
+    desired_retention: f64,
+) {
+    let w = &FSRS_WEIGHTS;
+    let g = grade.number();
+    let initial_difficulty = |g: f64| (w[4] - (g - 3.0) * w[5]).clamp(1.0, 10.0);
+
+    // cards that were last scheduled by SM-2 are new to FSRS
+    if previous.stability <= 0.0 {
+        next.stability = w[g as usize - 1];
+        next.difficulty = initial_difficulty(g);
+    } else {
+        let elapsed_days = previous
+            .last_review
+            .map_or(0.0, |last| (now - last).num_seconds() as f64 / 86_400.0)
+            .max(0.0);
+        let retrievability =
+            (1.0 + FSRS_FACTOR * elapsed_days / previous.stability).powf(FSRS_DECAY);
+        let difficulty = previous.difficulty;
+
+        next.stability = if grade == Grade::Again {
+            (w[11]
+                * difficulty.powf(-w[12])
+                * ((previous.stability + 1.0).powf(w[13]) - 1.0)
+                * (w[14] * (1.0 - retrievability)).exp())
+            .min(previous.stability)
+        } else {
+            let hard_penalty = if grade == Grade::Hard { w[15] } else { 1.0 };
+            let easy_bonus = if grade == Grade::Easy { w[16] } else { 1.0 };
+            previous.stability
+                * (w[8].exp()
+                    * (11.0 - difficulty)
+                    * previous.stability.powf(-w[9])
+                    * ((w[10] * (1.0 - retrievability)).exp() - 1.0)
+                    * hard_penalty
+                    * easy_bonus
+                    + 1.0)
+        };
+        // difficulty moves with the grade, and drifts back towards that of a card graded Easy
+        let moved = difficulty - w[6] * (g - 3.0);
+        next.difficulty = (w[7] * initial_difficulty(4.0) + (1.0 - w[7]) * moved).clamp(1.0, 10.0);
+    }
+
+    next.fsrs_interval_days = (next.stability / FSRS_FACTOR
+        * (desired_retention.powf(1.0 / FSRS_DECAY) - 1.0))
+        .clamp(1.0, MAX_INTERVAL_DAYS);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(kind: SchedulerKind) -> Scheduler {
+        Scheduler::new(&ReviewSettings {
+            scheduler: kind,
+            ..ReviewSettings::default()
+        })
+    }
+
+    /// Reviews a new card with each of the `grades` in turn, each one on the day it's due, and
+    /// returns the intervals in days
+    fn intervals(scheduler: Scheduler, grades: &[Grade]) -> Vec<i64> {
+        let mut schedule = Schedule::new(Utc::now());
+        grades
+            .iter()
+            .map(|grade| {
+                schedule = scheduler.review(&schedule, *grade, schedule.due);
+                schedule.interval_days.round() as i64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sm2_intervals() {
+        let sm2 = scheduler(SchedulerKind::Sm2);
+        assert_eq!(
+            intervals(sm2, &[Grade::Good, Grade::Good, Grade::Good]),
+            vec![1, 6, 15]
+        );
+        assert_eq!(
+            intervals(sm2, &[Grade::Good, Grade::Good, Grade::Again, Grade::Good]),
+            vec![1, 6, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_fsrs_intervals_grow_with_the_grade() {
+        let fsrs = scheduler(SchedulerKind::Fsrs);
+        let good = intervals(fsrs, &[Grade::Good, Grade::Good, Grade::Good]);
+        assert_eq!(good[0], 3);
+        assert!(good[0] < good[1] && good[1] < good[2]);
+        let easy = intervals(fsrs, &[Grade::Good, Grade::Easy]);
+        let hard = intervals(fsrs, &[Grade::Good, Grade::Hard]);
+        assert!(hard[1] < good[1] && good[1] < easy[1]);
+
+        let mut schedule = Schedule::new(Utc::now());
+        schedule = fsrs.review(&schedule, Grade::Good, schedule.due);
+        let forgotten = fsrs.review(&schedule, Grade::Again, schedule.due);
+        assert_eq!(forgotten.lapses, 1);
+        assert!(forgotten.stability < schedule.stability);
+        assert!(forgotten.difficulty > schedule.difficulty);
+    }
+
+    #[test]
+    fn test_changing_scheduler_keeps_each_schedulers_interval() {
+        let (sm2, fsrs) = (
+            scheduler(SchedulerKind::Sm2),
+            scheduler(SchedulerKind::Fsrs),
+        );
+        let mut schedule = Schedule::new(Utc::now());
+        for scheduler in [sm2, sm2, fsrs] {
+            schedule = scheduler.review(&schedule, Grade::Good, schedule.due);
+        }
+        assert_eq!(schedule.interval_days, schedule.fsrs_interval_days);
+        assert_eq!(schedule.sm2_interval_days, 15.0);
+
+        // SM-2 carries on from its own interval, not the one FSRS gave the card
+        schedule = sm2.review(&schedule, Grade::Good, schedule.due);
+        assert_eq!(schedule.interval_days, 38.0);
+    }
+}
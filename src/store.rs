@@ -0,0 +1,1432 @@
+use crate::core::{
+    datatypes::{Block, BlockID, Page, PageID},
+    helpers::{build_tree, BlockNode},
+};
+use crate::error::Result;
+use crate::focus::Focus;
+use crate::review::{Card, CardKind};
+use crate::sources::{BlockPlacement, IngestionReport, NoteSource, PageChanges};
+use crate::srs::Schedule;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use dendron::{Node, Tree};
+use log::{debug, info};
+use rusqlite::{params, types::Type, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::rc::Rc;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS pages (
+    id TEXT PRIMARY KEY,
+    source TEXT NOT NULL,
+    title TEXT NOT NULL,
+    url TEXT NOT NULL,
+    creation_date TEXT NOT NULL,
+    update_date TEXT NOT NULL,
+    database TEXT,
+    properties TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS pages_by_update_date ON pages (source, update_date);
+
+CREATE TABLE IF NOT EXISTS blocks (
+    id TEXT NOT NULL,
+    page_id TEXT NOT NULL,
+    parent_id TEXT,
+    position INTEGER NOT NULL,
+    update_date TEXT NOT NULL,
+    block TEXT NOT NULL,
+    PRIMARY KEY (page_id, id)
+);
+
+-- Pages that were only partly read during a sync (see IngestionReport), and need fetching again
+CREATE TABLE IF NOT EXISTS incomplete_pages (
+    page_id TEXT PRIMARY KEY
+);
+
+-- LLM summaries of notes, keyed by a hash of what was summarised and how (see digest.rs)
+CREATE TABLE IF NOT EXISTS summaries (
+    content_hash TEXT PRIMARY KEY,
+    summary TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+-- The search index of the notes: chunks of each Page's Blocks, along with their embeddings (see
+-- search.rs). A chunk is keyed by the Block it starts at.
+CREATE TABLE IF NOT EXISTS chunks (
+    page_id TEXT NOT NULL,
+    block_id TEXT NOT NULL,
+    content_hash TEXT NOT NULL,
+    text TEXT NOT NULL,
+    update_date TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    PRIMARY KEY (page_id, block_id)
+);
+
+-- Flashcards made from the notes, keyed by the Block they were made from, along with when each is
+-- next due for review (see review.rs)
+CREATE TABLE IF NOT EXISTS cards (
+    block_id TEXT PRIMARY KEY,
+    page_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    question TEXT NOT NULL,
+    answer TEXT NOT NULL,
+    update_date TEXT NOT NULL,
+    due TEXT NOT NULL,
+    schedule TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS cards_by_due ON cards (due);
+
+-- The LLM's flashcards for each Page, keyed by a hash of the Page's notes and how the cards were
+-- asked for, so that an unchanged Page isn't sent again (see review.rs)
+CREATE TABLE IF NOT EXISTS generated_cards (
+    content_hash TEXT PRIMARY KEY,
+    response TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+-- The One Thing the user chose to focus on with `navi focus`, one row each time it was set (see
+-- focus.rs)
+CREATE TABLE IF NOT EXISTS focus (
+    set_at TEXT PRIMARY KEY,
+    priority TEXT NOT NULL,
+    justification TEXT
+);
+
+CREATE TABLE IF NOT EXISTS sync_state (
+    source TEXT PRIMARY KEY,
+    synced_since TEXT NOT NULL,
+    last_synced_at TEXT NOT NULL
+);
+";
+
+/// How far back before the last sync we look for edited pages, in case the clocks of Navi
+/// and the source disagree a little
+const SYNC_OVERLAP_MINUTES: i64 = 5;
+
+/// The Store is a local SQLite database of every Page and Block that Navi has ingested, keyed
+/// by `PageID` and `BlockID`. Keeping notes locally means a retro doesn't need to re-walk the
+/// whole of a source (which for Notion can take several minutes), only the parts that changed
+/// since the last sync. See `sync_source`.
+pub struct Store {
+    conn: Connection,
+}
+
+/// A `Block` as it is kept in the `Store`, along with where it sits in its Page's tree of Blocks
+#[derive(Debug, Clone)]
+pub struct StoredBlock {
+    pub block: Block,
+    /// The Block's parent, or `None` if the Block is at the top level of its Page
+    pub parent_id: Option<BlockID>,
+    /// The Block's position among its siblings
+    pub position: usize,
+}
+
+/// A chunk of a Page's notes as it is kept in the search index, see `search::index_notes`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredChunk {
+    pub page_id: PageID,
+    /// The Block the chunk starts at
+    pub block_id: BlockID,
+    /// A hash of the chunk's text and the model it was embedded with, so that the chunk is only
+    /// embedded again when one of them changes
+    pub content_hash: String,
+    pub text: String,
+    /// When the chunk's most recently edited Block was edited
+    pub update_date: DateTime<Utc>,
+    pub embedding: Vec<f32>,
+}
+
+/// A flashcard as it is kept in the `Store`, along with its review schedule
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCard {
+    pub card: Card,
+    pub schedule: Schedule,
+}
+
+/// Records how much of a source the `Store` holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncState {
+    /// Every Page of the source edited since this date is in the `Store`
+    pub synced_since: DateTime<Utc>,
+    /// When the source was last synced
+    pub last_synced_at: DateTime<Utc>,
+}
+
+/// How much the `Store` holds, see `Store::stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreStats {
+    /// How many Pages each source has, by name
+    pub pages: Vec<(String, usize)>,
+    pub blocks: usize,
+    /// The chunks of notes in the search index
+    pub chunks: usize,
+    pub cards: usize,
+    /// The cards that are due for review
+    pub due_cards: usize,
+}
+
+/// A summary of what a call to `sync_source` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyncReport {
+    /// The number of Pages the source reported as edited since the last sync
+    pub pages_checked: usize,
+    /// The number of those Pages that had changed, and so were refetched
+    pub pages_updated: usize,
+    /// The number of Blocks that were new or had a new `update_date`
+    pub blocks_written: usize,
+    /// The number of Pages that are no longer in the source, and so were deleted when pruning
+    pub pages_deleted: usize,
+}
+
+impl Store {
+    /// Opens the `Store` at `path`, creating it if it does not exist yet
+    pub fn open(path: &Path) -> Result<Self> {
+        let store = Store {
+            conn: Connection::open(path)?,
+        };
+        store.conn.execute_batch(SCHEMA)?;
+        Ok(store)
+    }
+
+    /// Opens a `Store` that only lives in memory, which is useful for tests
+    pub fn open_in_memory() -> Result<Self> {
+        let store = Store {
+            conn: Connection::open_in_memory()?,
+        };
+        store.conn.execute_batch(SCHEMA)?;
+        Ok(store)
+    }
+
+    /// Returns the `update_date` of the stored Page with the given ID, if there is one
+    pub fn page_update_date(&self, page_id: &PageID) -> Result<Option<DateTime<Utc>>> {
+        let update_date = self
+            .conn
+            .query_row(
+                "SELECT update_date FROM pages WHERE id = ?1",
+                params![page_id.as_str()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        Ok(update_date
+            .map(|update_date| from_timestamp(&update_date))
+            .transpose()?)
+    }
+
+    /// Returns the `update_date` of every stored Block in the Page with the given ID
+    pub fn block_update_dates(&self, page_id: &PageID) -> Result<HashMap<BlockID, DateTime<Utc>>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT id, update_date FROM blocks WHERE page_id = ?1")?;
+        let rows = statement.query_map(params![page_id.as_str()], |row| {
+            Ok((
+                BlockID::new(row.get(0)?),
+                from_timestamp(&row.get::<_, String>(1)?)?,
+            ))
+        })?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Saves a Page and replaces all of its Blocks with `blocks`.
+    ///
+    /// # Returns
+    /// The number of Blocks that are new or have a different `update_date` than before.
+    pub fn save_page(&self, source: &str, page: &Page, blocks: &[StoredBlock]) -> Result<usize> {
+        self.update_page(
+            source,
+            page,
+            &page_changes(blocks, DateTime::<Utc>::MIN_UTC),
+        )
+    }
+
+    /// Saves a Page and brings its Blocks up to date with `changes`: the edited Blocks are
+    /// written, the others are moved to wherever they sit now, and the ones no longer in the Page
+    /// are deleted.
+    ///
+    /// If a Block that wasn't edited isn't in the `Store` either, the Page is marked incomplete so
+    /// that the next sync fetches all of it.
+    ///
+    /// # Returns
+    /// The number of Blocks that are new or have a different `update_date` than before.
+    pub fn update_page(&self, source: &str, page: &Page, changes: &PageChanges) -> Result<usize> {
+        let previous_update_dates = self.block_update_dates(&page.id)?;
+        let changed_blocks = changes
+            .edited
+            .iter()
+            .filter(|block| previous_update_dates.get(&block.id) != Some(&block.update_date))
+            .count();
+        let edited = changes
+            .edited
+            .iter()
+            .map(|block| (&block.id, block))
+            .collect::<HashMap<_, _>>();
+        let placed = changes
+            .placements
+            .iter()
+            .map(|placement| &placement.id)
+            .collect::<HashSet<_>>();
+
+        let transaction = self.conn.unchecked_transaction()?;
+        transaction.execute(
+            "INSERT INTO pages (id, source, title, url, creation_date, update_date, database, properties)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (id) DO UPDATE SET
+                source = excluded.source,
+                title = excluded.title,
+                url = excluded.url,
+                creation_date = excluded.creation_date,
+                update_date = excluded.update_date,
+                database = excluded.database,
+                properties = excluded.properties",
+            params![
+                page.id.as_str(),
+                source,
+                page.title,
+                page.url,
+                to_timestamp(page.creation_date),
+                to_timestamp(page.update_date),
+                page.database,
+                to_json(&page.properties)?,
+            ],
+        )?;
+        transaction.execute(
+            "DELETE FROM incomplete_pages WHERE page_id = ?1",
+            params![page.id.as_str()],
+        )?;
+        {
+            let mut delete =
+                transaction.prepare("DELETE FROM blocks WHERE page_id = ?1 AND id = ?2")?;
+            for block_id in previous_update_dates.keys() {
+                if !placed.contains(block_id) {
+                    delete.execute(params![page.id.as_str(), block_id.as_str()])?;
+                }
+            }
+
+            let mut insert = transaction.prepare(
+                "INSERT OR REPLACE INTO blocks (id, page_id, parent_id, position, update_date, block)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            let mut place = transaction.prepare(
+                "UPDATE blocks SET parent_id = ?3, position = ?4 WHERE page_id = ?1 AND id = ?2",
+            )?;
+            let mut missing_blocks = 0;
+            for placement in &changes.placements {
+                let parent_id = placement.parent_id.as_ref().map(|id| id.as_str());
+                if let Some(block) = edited.get(&placement.id) {
+                    insert.execute(params![
+                        block.id.as_str(),
+                        page.id.as_str(),
+                        parent_id,
+                        placement.position as i64,
+                        to_timestamp(block.update_date),
+                        to_json(block)?,
+                    ])?;
+                } else if place.execute(params![
+                    page.id.as_str(),
+                    placement.id.as_str(),
+                    parent_id,
+                    placement.position as i64,
+                ])? == 0
+                {
+                    missing_blocks += 1;
+                }
+            }
+
+            if missing_blocks > 0 {
+                debug!(target: "store", "{} unedited Blocks of Page {} aren't stored, it will be fetched again", missing_blocks, page.title);
+                transaction.execute(
+                    "INSERT OR IGNORE INTO incomplete_pages (page_id) VALUES (?1)",
+                    params![page.id.as_str()],
+                )?;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(changed_blocks)
+    }
+
+    /// Deletes a Page, along with its Blocks, its chunks in the search index and its flashcards
+    pub fn delete_page(&self, page_id: &PageID) -> Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        transaction.execute(
+            "DELETE FROM blocks WHERE page_id = ?1",
+            params![page_id.as_str()],
+        )?;
+        transaction.execute(
+            "DELETE FROM chunks WHERE page_id = ?1",
+            params![page_id.as_str()],
+        )?;
+        transaction.execute(
+            "DELETE FROM cards WHERE page_id = ?1",
+            params![page_id.as_str()],
+        )?;
+        transaction.execute(
+            "DELETE FROM incomplete_pages WHERE page_id = ?1",
+            params![page_id.as_str()],
+        )?;
+        transaction.execute("DELETE FROM pages WHERE id = ?1", params![page_id.as_str()])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Returns the IDs of every stored Page from `source`
+    pub fn page_ids(&self, source: &str) -> Result<Vec<PageID>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT id FROM pages WHERE source = ?1")?;
+        let rows = statement.query_map(params![source], |row| Ok(PageID::new(row.get(0)?)))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Marks a saved Page as only partly read, so that the next sync fetches it again even if it
+    /// hasn't been edited
+    pub fn mark_incomplete(&self, page_id: &PageID) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO incomplete_pages (page_id) VALUES (?1)",
+            params![page_id.as_str()],
+        )?;
+        Ok(())
+    }
+
+    pub fn is_incomplete(&self, page_id: &PageID) -> Result<bool> {
+        Ok(self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM incomplete_pages WHERE page_id = ?1)",
+            params![page_id.as_str()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Returns the stored Pages of a source that were edited since the cutoff, ordered by last
+    /// edited date in descending order.
+    ///
+    /// Note: the returned Pages' `child_blocks` are always empty, use `page_blocks` instead.
+    pub fn pages_edited_since(&self, source: &str, cutoff: DateTime<Utc>) -> Result<Vec<Page>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, title, url, creation_date, update_date, database, properties
+             FROM pages
+             WHERE source = ?1 AND update_date >= ?2
+             ORDER BY update_date DESC",
+        )?;
+        let rows = statement.query_map(params![source, to_timestamp(cutoff)], page_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns every stored Page of every source, ordered by last edited date in descending order.
+    ///
+    /// Note: like `pages_edited_since`, the returned Pages' `child_blocks` are always empty.
+    pub fn all_pages(&self) -> Result<Vec<Page>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, title, url, creation_date, update_date, database, properties
+             FROM pages
+             ORDER BY update_date DESC",
+        )?;
+        let rows = statement.query_map([], page_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns every stored Block of the Page with the given ID
+    pub fn page_blocks(&self, page_id: &PageID) -> Result<Vec<StoredBlock>> {
+        let mut statement = self.conn.prepare(
+            "SELECT parent_id, position, block FROM blocks WHERE page_id = ?1 ORDER BY position",
+        )?;
+        let rows = statement.query_map(params![page_id.as_str()], stored_block_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns the stored Block with the given ID, if there is one
+    pub fn find_block(&self, block_id: &BlockID) -> Result<Option<StoredBlock>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT parent_id, position, block FROM blocks WHERE id = ?1 LIMIT 1",
+                params![block_id.as_str()],
+                stored_block_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Returns the stored Pages whose titles contain `title`, ignoring case, ordered by last
+    /// edited date in descending order.
+    ///
+    /// Note: like `pages_edited_since`, the returned Pages' `child_blocks` are always empty.
+    pub fn find_pages(&self, title: &str) -> Result<Vec<Page>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, title, url, creation_date, update_date, database, properties
+             FROM pages
+             WHERE instr(lower(title), lower(?1)) > 0
+             ORDER BY update_date DESC",
+        )?;
+        let rows = statement.query_map(params![title], page_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns the stored Pages of every source that were last edited in [`start`, `end`),
+    /// ordered by last edited date in descending order.
+    ///
+    /// Note: like `pages_edited_since`, the returned Pages' `child_blocks` are always empty.
+    pub fn pages_edited_between(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Page>> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, title, url, creation_date, update_date, database, properties
+             FROM pages
+             WHERE update_date >= ?1 AND update_date < ?2
+             ORDER BY update_date DESC",
+        )?;
+        let rows = statement.query_map(
+            params![to_timestamp(start), to_timestamp(end)],
+            page_from_row,
+        )?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns the stored Pages of every source with Blocks that were edited in [`start`, `end`),
+    /// each with when the last of those Blocks was edited, most recently edited first. Unlike
+    /// `pages_edited_between`, this finds a Page that was edited on a day even if it has been
+    /// edited again since.
+    ///
+    /// Note: like `pages_edited_since`, the returned Pages' `child_blocks` are always empty.
+    pub fn pages_with_blocks_edited_between(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<(Page, DateTime<Utc>)>> {
+        let mut statement = self.conn.prepare(
+            "SELECT pages.id, pages.title, pages.url, pages.creation_date, pages.update_date,
+                    pages.database, pages.properties, MAX(blocks.update_date) AS blocks_edited
+             FROM pages JOIN blocks ON blocks.page_id = pages.id
+             WHERE blocks.update_date >= ?1 AND blocks.update_date < ?2
+             GROUP BY pages.id
+             ORDER BY blocks_edited DESC",
+        )?;
+        let rows = statement.query_map(params![to_timestamp(start), to_timestamp(end)], |row| {
+            Ok((
+                page_from_row(row)?,
+                from_timestamp(&row.get::<_, String>(7)?)?,
+            ))
+        })?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns the name of the source that the Page with the given ID was synced from
+    pub fn page_source(&self, page_id: &PageID) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT source FROM pages WHERE id = ?1",
+                params![page_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Returns the indexed chunks of the Page with the given ID
+    pub fn page_chunks(&self, page_id: &PageID) -> Result<Vec<StoredChunk>> {
+        let mut statement = self.conn.prepare(
+            "SELECT page_id, block_id, content_hash, text, update_date, embedding
+             FROM chunks WHERE page_id = ?1",
+        )?;
+        let rows = statement.query_map(params![page_id.as_str()], chunk_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns every indexed chunk, of every Page
+    pub fn all_chunks(&self) -> Result<Vec<StoredChunk>> {
+        let mut statement = self.conn.prepare(
+            "SELECT page_id, block_id, content_hash, text, update_date, embedding FROM chunks",
+        )?;
+        let rows = statement.query_map([], chunk_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Replaces the indexed chunks of the Page with the given ID with `chunks`
+    pub fn save_chunks(&self, page_id: &PageID, chunks: &[StoredChunk]) -> Result<()> {
+        let transaction = self.conn.unchecked_transaction()?;
+        transaction.execute(
+            "DELETE FROM chunks WHERE page_id = ?1",
+            params![page_id.as_str()],
+        )?;
+        {
+            let mut insert = transaction.prepare(
+                "INSERT OR REPLACE INTO chunks (page_id, block_id, content_hash, text, update_date, embedding)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for chunk in chunks {
+                insert.execute(params![
+                    page_id.as_str(),
+                    chunk.block_id.as_str(),
+                    chunk.content_hash,
+                    chunk.text,
+                    to_timestamp(chunk.update_date),
+                    to_blob(&chunk.embedding),
+                ])?;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Returns the cards made from the Page with the given ID
+    pub fn page_cards(&self, page_id: &PageID) -> Result<Vec<StoredCard>> {
+        let mut statement = self.conn.prepare(
+            "SELECT block_id, page_id, kind, question, answer, update_date, schedule
+             FROM cards WHERE page_id = ?1",
+        )?;
+        let rows = statement.query_map(params![page_id.as_str()], card_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns up to `limit` cards that are due for review at `now`, the longest overdue first
+    pub fn due_cards(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<StoredCard>> {
+        let mut statement = self.conn.prepare(
+            "SELECT block_id, page_id, kind, question, answer, update_date, schedule
+             FROM cards WHERE due <= ?1 ORDER BY due LIMIT ?2",
+        )?;
+        let rows = statement.query_map(params![to_timestamp(now), limit as i64], card_from_row)?;
+
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Saves a card, replacing any card made from the same Block
+    pub fn save_card(&self, stored: &StoredCard) -> Result<()> {
+        let card = &stored.card;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO cards
+             (block_id, page_id, kind, question, answer, update_date, due, schedule)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                card.block_id.as_str(),
+                card.page_id.as_str(),
+                card.kind.name(),
+                card.question,
+                card.answer,
+                to_timestamp(card.update_date),
+                to_timestamp(stored.schedule.due),
+                to_json(&stored.schedule)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes the card made from the Block with the given ID
+    pub fn delete_card(&self, block_id: &BlockID) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM cards WHERE block_id = ?1",
+            params![block_id.as_str()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns up to `limit` to-dos edited since `cutoff` that haven't been checked off, along with
+    /// the titles of their Pages, the most recently edited first
+    pub fn open_to_dos(&self, cutoff: DateTime<Utc>, limit: usize) -> Result<Vec<(String, Block)>> {
+        let mut statement = self.conn.prepare(
+            "SELECT pages.title, blocks.block
+             FROM blocks JOIN pages ON pages.id = blocks.page_id
+             WHERE blocks.update_date >= ?1
+             ORDER BY blocks.update_date DESC",
+        )?;
+        let rows = statement.query_map(params![to_timestamp(cutoff)], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                from_json::<Block>(&row.get::<_, String>(1)?)?,
+            ))
+        })?;
+
+        let mut to_dos = Vec::new();
+        for row in rows {
+            let (title, block) = row?;
+            if block.is_to_do() && !block.is_checked() {
+                to_dos.push((title, block));
+            }
+            if to_dos.len() == limit {
+                break;
+            }
+        }
+        Ok(to_dos)
+    }
+
+    /// Returns the focus the user set most recently, if they have set one
+    pub fn current_focus(&self) -> Result<Option<Focus>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT set_at, priority, justification FROM focus ORDER BY set_at DESC LIMIT 1",
+                [],
+                |row| {
+                    Ok(Focus {
+                        set_at: from_timestamp(&row.get::<_, String>(0)?)?,
+                        priority: row.get(1)?,
+                        justification: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Saves a focus, which becomes the current focus. Past ones are kept.
+    pub fn save_focus(&self, focus: &Focus) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO focus (set_at, priority, justification) VALUES (?1, ?2, ?3)",
+            params![
+                to_timestamp(focus.set_at),
+                focus.priority,
+                focus.justification
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Counts what the `Store` holds, with the cards that are due at `now`
+    pub fn stats(&self, now: DateTime<Utc>) -> Result<StoreStats> {
+        let count = |sql: &str, params: &[&dyn rusqlite::ToSql]| -> Result<usize> {
+            Ok(self
+                .conn
+                .query_row(sql, params, |row| row.get::<_, i64>(0))? as usize)
+        };
+
+        let mut statement = self
+            .conn
+            .prepare("SELECT source, COUNT(*) FROM pages GROUP BY source ORDER BY source")?;
+        let pages = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(StoreStats {
+            pages,
+            blocks: count("SELECT COUNT(*) FROM blocks", &[])?,
+            chunks: count("SELECT COUNT(*) FROM chunks", &[])?,
+            cards: count("SELECT COUNT(*) FROM cards", &[])?,
+            due_cards: count(
+                "SELECT COUNT(*) FROM cards WHERE due <= ?1",
+                &[&to_timestamp(now)],
+            )?,
+        })
+    }
+
+    /// Returns the summary saved under `content_hash`, if there is one
+    pub fn summary(&self, content_hash: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT summary FROM summaries WHERE content_hash = ?1",
+                params![content_hash],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn save_summary(&self, content_hash: &str, summary: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO summaries (content_hash, summary, created_at) VALUES (?1, ?2, ?3)",
+            params![content_hash, summary, to_timestamp(Utc::now())],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the LLM's response for the flashcards saved under `content_hash`, if there is one
+    pub fn generated_cards(&self, content_hash: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT response FROM generated_cards WHERE content_hash = ?1",
+                params![content_hash],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn save_generated_cards(&self, content_hash: &str, response: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO generated_cards (content_hash, response, created_at) VALUES (?1, ?2, ?3)",
+            params![content_hash, response, to_timestamp(Utc::now())],
+        )?;
+        Ok(())
+    }
+
+    pub fn sync_state(&self, source: &str) -> Result<Option<SyncState>> {
+        let state = self
+            .conn
+            .query_row(
+                "SELECT synced_since, last_synced_at FROM sync_state WHERE source = ?1",
+                params![source],
+                |row| {
+                    Ok(SyncState {
+                        synced_since: from_timestamp(&row.get::<_, String>(0)?)?,
+                        last_synced_at: from_timestamp(&row.get::<_, String>(1)?)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(state)
+    }
+
+    pub fn set_sync_state(&self, source: &str, state: SyncState) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sync_state (source, synced_since, last_synced_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (source) DO UPDATE SET
+                synced_since = excluded.synced_since,
+                last_synced_at = excluded.last_synced_at",
+            params![
+                source,
+                to_timestamp(state.synced_since),
+                to_timestamp(state.last_synced_at)
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Formats `date` for the `Store`, with every fraction of a second kept so that a date reads back
+/// exactly as it was saved, and with a fixed width so that timestamps compare correctly as text
+fn to_timestamp(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+fn from_timestamp(timestamp: &str) -> rusqlite::Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+}
+
+fn page_from_row(row: &rusqlite::Row) -> rusqlite::Result<Page> {
+    Ok(Page {
+        id: PageID::new(row.get(0)?),
+        title: row.get(1)?,
+        url: row.get(2)?,
+        creation_date: from_timestamp(&row.get::<_, String>(3)?)?,
+        update_date: from_timestamp(&row.get::<_, String>(4)?)?,
+        child_blocks: Vec::new(),
+        database: row.get(5)?,
+        properties: from_json(&row.get::<_, String>(6)?)?,
+    })
+}
+
+fn stored_block_from_row(row: &rusqlite::Row) -> rusqlite::Result<StoredBlock> {
+    Ok(StoredBlock {
+        parent_id: row.get::<_, Option<String>>(0)?.map(BlockID::new),
+        position: row.get::<_, i64>(1)? as usize,
+        block: from_json(&row.get::<_, String>(2)?)?,
+    })
+}
+
+fn card_from_row(row: &rusqlite::Row) -> rusqlite::Result<StoredCard> {
+    let kind = row.get::<_, String>(2)?;
+    Ok(StoredCard {
+        card: Card {
+            block_id: BlockID::new(row.get(0)?),
+            page_id: PageID::new(row.get(1)?),
+            kind: CardKind::from_name(&kind).ok_or_else(|| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    2,
+                    Type::Text,
+                    format!("unknown card kind {}", kind).into(),
+                )
+            })?,
+            question: row.get(3)?,
+            answer: row.get(4)?,
+            update_date: from_timestamp(&row.get::<_, String>(5)?)?,
+        },
+        schedule: from_json(&row.get::<_, String>(6)?)?,
+    })
+}
+
+fn chunk_from_row(row: &rusqlite::Row) -> rusqlite::Result<StoredChunk> {
+    Ok(StoredChunk {
+        page_id: PageID::new(row.get(0)?),
+        block_id: BlockID::new(row.get(1)?),
+        content_hash: row.get(2)?,
+        text: row.get(3)?,
+        update_date: from_timestamp(&row.get::<_, String>(4)?)?,
+        embedding: from_blob(&row.get::<_, Vec<u8>>(5)?),
+    })
+}
+
+/// Embeddings are stored as their little-endian f32s, one after the other
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+fn to_json<T: Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+}
+
+/// Flattens `Tree`s of Blocks into the form they are kept in the `Store`. The root of each `Tree`
+/// is treated as a top-level Block of its Page.
+pub fn flatten_trees(trees: &[Tree<Block>]) -> Vec<StoredBlock> {
+    let mut stored = Vec::new();
+    for (position, tree) in trees.iter().enumerate() {
+        flatten_node(&tree.root(), None, position, &mut stored);
+    }
+    stored
+}
+
+/// The `PageChanges` of a Page made up of `blocks`, where the Blocks edited since the cutoff count
+/// as edited
+pub fn page_changes(blocks: &[StoredBlock], cutoff: DateTime<Utc>) -> PageChanges {
+    PageChanges {
+        edited: blocks
+            .iter()
+            .filter(|stored| stored.block.update_date >= cutoff)
+            .map(|stored| stored.block.clone())
+            .collect(),
+        placements: blocks
+            .iter()
+            .map(|stored| BlockPlacement {
+                id: stored.block.id.clone(),
+                parent_id: stored.parent_id.clone(),
+                position: stored.position,
+            })
+            .collect(),
+    }
+}
+
+fn flatten_node(
+    node: &Node<Block>,
+    parent_id: Option<BlockID>,
+    position: usize,
+    stored: &mut Vec<StoredBlock>,
+) {
+    let block = node.borrow_data().clone();
+    let id = block.id.clone();
+    stored.push(StoredBlock {
+        block,
+        parent_id,
+        position,
+    });
+
+    let mut child = node.first_child();
+    let mut child_position = 0;
+    while let Some(current) = child {
+        flatten_node(&current, Some(id.clone()), child_position, stored);
+        child_position += 1;
+        child = current.next_sibling();
+    }
+}
+
+/// Builds the `Tree`s of a Page's stored Blocks that were edited since the cutoff.
+///
+/// This works the same way as `Notion::get_page_block_roots` followed by `Notion::expand_block_roots`,
+/// only without any network requests: we search breadth-first for Blocks edited since the cutoff,
+/// and each one we find becomes the root of a `Tree` containing all of its non-empty descendants.
+pub fn block_trees_edited_since(
+    blocks: Vec<StoredBlock>,
+    cutoff: DateTime<Utc>,
+) -> Vec<Tree<Block>> {
+    let children = children_by_parent(blocks);
+    let mut visited = HashSet::new();
+    let mut block_roots = Vec::new();
+    let mut blocks_to_process = VecDeque::from(children.get(&None).cloned().unwrap_or_default());
+    while let Some(block) = blocks_to_process.pop_front() {
+        if !visited.insert(block.id.clone()) {
+            continue;
+        }
+
+        if block.update_date >= cutoff {
+            if !block.is_empty() {
+                block_roots.push(expand_stored_block(block, &children, &mut visited));
+            }
+            continue;
+        }
+
+        if let Some(block_children) = children.get(&Some(block.id.clone())) {
+            blocks_to_process.extend(block_children.iter().cloned());
+        }
+    }
+
+    block_roots.into_iter().map(build_tree).collect()
+}
+
+fn expand_stored_block(
+    block: Block,
+    children: &HashMap<Option<BlockID>, Vec<Block>>,
+    visited: &mut HashSet<BlockID>,
+) -> BlockNode {
+    let mut node = BlockNode::new(block);
+    if let Some(block_children) = children.get(&Some(node.block.id.clone())) {
+        for child in block_children {
+            // the visited check guards against cycles, in case a source ever gives us one
+            if !child.is_empty() && visited.insert(child.id.clone()) {
+                node.children
+                    .push(expand_stored_block(child.clone(), children, visited));
+            }
+        }
+    }
+    node
+}
+
+/// Groups a Page's stored Blocks by their parent, with the top-level Blocks under `None`, and
+/// every set of siblings in order
+fn children_by_parent(mut blocks: Vec<StoredBlock>) -> HashMap<Option<BlockID>, Vec<Block>> {
+    // positions are relative to siblings, so a stable sort keeps every set of siblings in order
+    blocks.sort_by_key(|stored| stored.position);
+    let mut children: HashMap<Option<BlockID>, Vec<Block>> = HashMap::new();
+    for stored in blocks {
+        children
+            .entry(stored.parent_id)
+            .or_default()
+            .push(stored.block);
+    }
+    children
+}
+
+/// Rebuilds the trees of a Page's stored Blocks, one for each of its top-level Blocks. Unlike
+/// `block_trees_edited_since`, this keeps the Blocks without text, which may still group others
+/// (e.g. Notion's columns).
+pub fn page_nodes(blocks: Vec<StoredBlock>) -> Vec<BlockNode> {
+    build_nodes(None, &children_by_parent(blocks), &mut HashSet::new())
+}
+
+fn build_nodes(
+    parent_id: Option<BlockID>,
+    children: &HashMap<Option<BlockID>, Vec<Block>>,
+    visited: &mut HashSet<BlockID>,
+) -> Vec<BlockNode> {
+    let mut nodes = Vec::new();
+    for block in children
+        .get(&parent_id)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+    {
+        // the visited check guards against cycles, as it does in `expand_stored_block`
+        if visited.insert(block.id.clone()) {
+            let mut node = BlockNode::new(block.clone());
+            node.children = build_nodes(Some(block.id.clone()), children, visited);
+            nodes.push(node);
+        }
+    }
+    nodes
+}
+
+/// Writes a Block and its descendants to `markdown` as lines of Markdown (see `block_line`),
+/// leaving out the Blocks without text. Returns when the most recently edited of them was edited.
+pub fn render_node(
+    node: &BlockNode,
+    depth: usize,
+    markdown: &mut String,
+    block_ids: bool,
+) -> DateTime<Utc> {
+    if !node.block.is_empty() {
+        markdown.push_str(&block_line(&node.block, depth, block_ids));
+    }
+
+    let mut update_date = node.block.update_date;
+    for child in &node.children {
+        update_date = update_date.max(render_node(child, depth + 1, markdown, block_ids));
+    }
+    update_date
+}
+
+/// A Block as a line of Markdown, indented by depth. With `block_ids`, Blocks with children are
+/// followed by their ID, so that the LLM can ask for the children with the expand_block tool.
+pub fn block_line(block: &Block, depth: usize, block_ids: bool) -> String {
+    let mut line = format!("{}{}", "\t".repeat(depth), block.to_markdown());
+    if block_ids && block.has_children {
+        line.push_str(&format!(" [block {}]", block.id));
+    }
+    line.push('\n');
+    line
+}
+
+/// Brings the `Store`'s copy of a source up to date, so that it holds every Page of the source
+/// edited since the cutoff. With `prune`, the stored Pages the source no longer has are deleted
+/// as well, which means listing every Page of the source (see `NoteSource::get_page_ids`), so
+/// it's left for when the user asks for it.
+///
+/// If the `Store` already covers the cutoff, only Pages edited since the last sync are checked,
+/// and of those only the ones whose `update_date` changed are updated, with just the Blocks edited
+/// since the last sync (see `NoteSource::get_page_changes`). Otherwise this backfills everything
+/// edited since the cutoff.
+///
+/// Pages that fail to be read are recorded in `report`. Pages that were only partly read are saved
+/// with what could be read, and fetched again by the next sync, as are Pages that couldn't be read at all.
+pub async fn sync_source(
+    store: &Store,
+    source: &dyn NoteSource,
+    cutoff: DateTime<Utc>,
+    prune: bool,
+    report: &mut IngestionReport,
+) -> Result<SyncReport> {
+    let sync_started_at = Utc::now();
+    let previous_state = store.sync_state(source.name())?;
+    let since = match previous_state {
+        Some(state) if state.synced_since <= cutoff => {
+            state.last_synced_at - Duration::minutes(SYNC_OVERLAP_MINUTES)
+        }
+        _ => cutoff,
+    };
+    info!(target: "store", "syncing {} Pages edited since {}", source.name(), since);
+
+    let failures_before_sync = report.failures.len();
+    let mut sync_report = SyncReport::default();
+    for page in source.get_last_edited_pages(since, report).await? {
+        sync_report.pages_checked += 1;
+        let stored_update_date = store.page_update_date(&page.id)?;
+        let is_incomplete = store.is_incomplete(&page.id)?;
+        if stored_update_date == Some(page.update_date) && !is_incomplete {
+            debug!(target: "store", "Page {} is unchanged since the last sync, skipping it", page.title);
+            continue;
+        }
+
+        // a Page we don't have all of yet needs every one of its Blocks, not just the recently
+        // edited ones, so that any later retro can pick out whichever Blocks fall within its own cutoff
+        let edited_since = if stored_update_date.is_some() && !is_incomplete {
+            since
+        } else {
+            DateTime::<Utc>::MIN_UTC
+        };
+        let failures_before_page = report.failures.len();
+        let changes = match source.get_page_changes(&page, edited_since, report).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                report.record(source.name(), &page.title, &page.url, None, &e);
+                continue;
+            }
+        };
+        sync_report.blocks_written += store.update_page(source.name(), &page, &changes)?;
+        if report.failures.len() > failures_before_page {
+            store.mark_incomplete(&page.id)?;
+        }
+        sync_report.pages_updated += 1;
+    }
+
+    if prune {
+        sync_report.pages_deleted = prune_pages(store, source).await?;
+    }
+
+    // if any Page couldn't be read, the next sync needs to look at least as far back as this one
+    // did, so that it finds that Page again
+    let last_synced_at = if report.failures.len() > failures_before_sync {
+        since + Duration::minutes(SYNC_OVERLAP_MINUTES)
+    } else {
+        sync_started_at
+    };
+    let synced_since = match previous_state {
+        Some(state) => state.synced_since.min(cutoff),
+        None => cutoff,
+    };
+    store.set_sync_state(
+        source.name(),
+        SyncState {
+            synced_since,
+            last_synced_at,
+        },
+    )?;
+
+    Ok(sync_report)
+}
+
+/// Deletes the stored Pages of `source` that it no longer has, and returns how many there were
+async fn prune_pages(store: &Store, source: &dyn NoteSource) -> Result<usize> {
+    // Pages that couldn't be listed may well still exist, so nothing is deleted unless every Page was
+    let mut listing_report = IngestionReport::default();
+    let page_ids = source.get_page_ids(&mut listing_report).await?;
+    if !listing_report.is_empty() {
+        debug!(target: "store", "not all of the Pages in {} could be listed, so none are deleted", source.name());
+        return Ok(0);
+    }
+
+    let mut pages_deleted = 0;
+    for page_id in store.page_ids(source.name())? {
+        if !page_ids.contains(&page_id) {
+            debug!(target: "store", "Page {} is no longer in {}, deleting it", page_id, source.name());
+            store.delete_page(&page_id)?;
+            pages_deleted += 1;
+        }
+    }
+    Ok(pages_deleted)
+}
+
+/// A `NoteSource` that reads another source's notes out of the `Store`, rather than from the
+/// source itself. Run `sync_source` first to bring the `Store` up to date.
+pub struct StoredNotes {
+    store: Rc<Store>,
+    source: String,
+}
+
+impl StoredNotes {
+    pub fn new(store: Rc<Store>, source: &str) -> Self {
+        StoredNotes {
+            store,
+            source: source.to_string(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl NoteSource for StoredNotes {
+    fn name(&self) -> &str {
+        &self.source
+    }
+
+    async fn get_last_edited_pages(
+        &self,
+        cutoff: DateTime<Utc>,
+        _report: &mut IngestionReport,
+    ) -> Result<Vec<Page>> {
+        if let Some(state) = self.store.sync_state(&self.source)? {
+            if state.synced_since > cutoff {
+                // this shouldn't happen if sync_source was called with the same cutoff
+                debug!(target: "store", "the Store only holds {} notes edited since {}", self.source, state.synced_since);
+            }
+        }
+
+        Ok(self.store.pages_edited_since(&self.source, cutoff)?)
+    }
+
+    async fn get_block_trees(
+        &self,
+        page: &Page,
+        cutoff: DateTime<Utc>,
+        _report: &mut IngestionReport,
+    ) -> Result<Vec<Tree<Block>>> {
+        Ok(block_trees_edited_since(
+            self.store.page_blocks(&page.id)?,
+            cutoff,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        datatypes::TextBlockKind,
+        helpers::{build_markdown_from_trees, build_tree},
+    };
+    use crate::fixtures::{block, days_ago, page};
+    use std::cell::{Cell, RefCell};
+
+    /// A bullet point of the page
+    fn bullet(id: &str, text: &str, update_date: DateTime<Utc>) -> Block {
+        block(
+            "page",
+            id,
+            TextBlockKind::BulletedListItem,
+            text,
+            update_date,
+        )
+    }
+
+    /// The "Planning notes" tree of the page, with `ptos` under "Team availability"
+    fn planning_tree(ptos: Block) -> Tree<Block> {
+        let mut planning = BlockNode::new(bullet("1", "Planning notes", days_ago(20)));
+        let mut availability = BlockNode::new(bullet("2", "Team availability", days_ago(1)));
+        availability.children.push(BlockNode::new(ptos));
+        planning.children.push(availability);
+        planning.children.push(BlockNode::new(bullet(
+            "4",
+            "Last sprint review",
+            days_ago(20),
+        )));
+        build_tree(planning)
+    }
+
+    /// The page's tree looks like this, where only "Team availability" and "PTOs" are recent:
+    ///   - Planning notes (old)
+    ///       - Team availability (recent)
+    ///           - PTOs (old)
+    ///       - Last sprint review (old)
+    ///   - Retro (old)
+    fn page_tree() -> Vec<Tree<Block>> {
+        vec![
+            planning_tree(bullet("3", "PTOs", days_ago(20))),
+            build_tree(BlockNode::new(bullet("5", "Retro", days_ago(20)))),
+        ]
+    }
+
+    #[test]
+    fn test_save_and_load_block_trees() {
+        let store = Store::open_in_memory().unwrap();
+        let blocks = flatten_trees(&page_tree());
+        assert_eq!(blocks.len(), 5);
+
+        assert_eq!(
+            store
+                .save_page("Test", &page("page", "Sprint 22", days_ago(1)), &blocks)
+                .unwrap(),
+            5
+        );
+        // saving the same blocks again doesn't change any of them
+        assert_eq!(
+            store
+                .save_page("Test", &page("page", "Sprint 22", days_ago(1)), &blocks)
+                .unwrap(),
+            0
+        );
+
+        let pages = store.pages_edited_since("Test", days_ago(7)).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].title, "Sprint 22");
+        assert!(store
+            .pages_edited_since("Test", Utc::now())
+            .unwrap()
+            .is_empty());
+
+        let trees = block_trees_edited_since(store.page_blocks(&pages[0].id).unwrap(), days_ago(7));
+        assert_eq!(
+            build_markdown_from_trees(trees),
+            "\t- Team availability\n\t\t- PTOs\n"
+        );
+
+        // a longer lookback includes the whole page, in its original order
+        let trees =
+            block_trees_edited_since(store.page_blocks(&pages[0].id).unwrap(), days_ago(60));
+        assert_eq!(
+            build_markdown_from_trees(trees),
+            "\t- Planning notes\n\t\t- Team availability\n\t\t\t- PTOs\n\t\t- Last sprint review\n\t- Retro\n"
+        );
+
+        let stats = store.stats(Utc::now()).unwrap();
+        assert_eq!(stats.pages, vec![("Test".to_string(), 1)]);
+        assert_eq!(stats.blocks, 5);
+        assert_eq!(stats.cards, 0);
+    }
+
+    /// A NoteSource with a single page, that counts how often its blocks are fetched
+    struct FakeSource {
+        page_update_date: Cell<DateTime<Utc>>,
+        trees: RefCell<Vec<Tree<Block>>>,
+        page_deleted: Cell<bool>,
+        block_fetches: Cell<usize>,
+    }
+
+    #[async_trait(?Send)]
+    impl NoteSource for FakeSource {
+        fn name(&self) -> &str {
+            "Fake"
+        }
+
+        async fn get_last_edited_pages(
+            &self,
+            cutoff: DateTime<Utc>,
+            _report: &mut IngestionReport,
+        ) -> Result<Vec<Page>> {
+            if !self.page_deleted.get() && self.page_update_date.get() >= cutoff {
+                Ok(vec![page("page", "Sprint 22", self.page_update_date.get())])
+            } else {
+                Ok(Vec::new())
+            }
+        }
+
+        async fn get_block_trees(
+            &self,
+            _page: &Page,
+            _cutoff: DateTime<Utc>,
+            _report: &mut IngestionReport,
+        ) -> Result<Vec<Tree<Block>>> {
+            self.block_fetches.set(self.block_fetches.get() + 1);
+            Ok(self.trees.borrow().clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_updates_only_what_changed() {
+        let store = Rc::new(Store::open_in_memory().unwrap());
+        let source = FakeSource {
+            page_update_date: Cell::new(days_ago(1)),
+            trees: RefCell::new(page_tree()),
+            page_deleted: Cell::new(false),
+            block_fetches: Cell::new(0),
+        };
+
+        let mut ingestion_report = IngestionReport::default();
+        let report = sync_source(&store, &source, days_ago(7), false, &mut ingestion_report)
+            .await
+            .unwrap();
+        assert_eq!(report.pages_updated, 1);
+        assert_eq!(report.blocks_written, 5);
+
+        // the page hasn't changed, so its blocks aren't fetched again
+        let report = sync_source(&store, &source, days_ago(7), false, &mut ingestion_report)
+            .await
+            .unwrap();
+        assert_eq!(report.pages_checked, 1);
+        assert_eq!(report.pages_updated, 0);
+        assert_eq!(source.block_fetches.get(), 1);
+
+        let state = store.sync_state("Fake").unwrap().unwrap();
+        assert!(state.synced_since <= days_ago(7));
+
+        let parsed_pages = StoredNotes::new(store.clone(), "Fake")
+            .parse_last_edited(days_ago(7), &mut ingestion_report)
+            .await
+            .unwrap();
+        assert_eq!(parsed_pages.len(), 1);
+        assert_eq!(parsed_pages[0].page_content.len(), 1);
+
+        // "PTOs" is reworded and "Retro" is deleted, so only "PTOs" is written, and "Retro" is removed
+        source.page_update_date.set(Utc::now());
+        source.trees.replace(vec![planning_tree(bullet(
+            "3",
+            "PTOs and holidays",
+            Utc::now(),
+        ))]);
+        let report = sync_source(&store, &source, days_ago(7), false, &mut ingestion_report)
+            .await
+            .unwrap();
+        assert_eq!(report.pages_updated, 1);
+        assert_eq!(report.blocks_written, 1);
+        let page_id = PageID::new("page".to_string());
+        assert_eq!(store.page_blocks(&page_id).unwrap().len(), 4);
+        assert!(store
+            .find_block(&BlockID::new("5".to_string()))
+            .unwrap()
+            .is_none());
+        let ptos = store
+            .find_block(&BlockID::new("3".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ptos.block.text, "PTOs and holidays");
+        assert_eq!(ptos.parent_id, Some(BlockID::new("2".to_string())));
+        assert!(!store.is_incomplete(&page_id).unwrap());
+
+        // once the page is deleted from the source, it's deleted from the store too, but only
+        // when the sync is asked to prune
+        source.page_deleted.set(true);
+        let report = sync_source(&store, &source, days_ago(7), false, &mut ingestion_report)
+            .await
+            .unwrap();
+        assert_eq!(report.pages_deleted, 0);
+        assert_eq!(store.all_pages().unwrap().len(), 1);
+        let report = sync_source(&store, &source, days_ago(7), true, &mut ingestion_report)
+            .await
+            .unwrap();
+        assert_eq!(report.pages_deleted, 1);
+        assert!(store.all_pages().unwrap().is_empty());
+        assert!(store.page_blocks(&page_id).unwrap().is_empty());
+        assert!(ingestion_report.is_empty());
+    }
+}
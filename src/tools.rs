@@ -0,0 +1,452 @@
+use crate::action_items::to_markdown;
+use crate::core::{
+    datatypes::{BlockID, PageID},
+    helpers::build_front_matter,
+};
+use crate::error::{NaviError, Result};
+use crate::llm::{parse_json, JsonSchema, LlmProvider, ToolCall};
+use crate::notion::Notion;
+use crate::search::search_notes;
+use crate::store::{block_line, page_nodes, render_node, Store};
+use crate::transcript::list_sessions;
+use chrono::{Duration, Local, NaiveDate, Utc};
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// The most characters of a Page that get_page returns, so that one long Page can't crowd the
+/// rest of the retro out of the context window
+const MAX_PAGE_CHARS: usize = 12_000;
+/// How many of the latest retros past_action_items returns the action items of
+const PAST_RETROS: usize = 5;
+
+/// The tools that the LLM can call during a retro, to look further into the user's notes than the
+/// notes it was given at the start
+pub struct Tools {
+    store: Rc<Store>,
+    /// Where the transcripts of past retros are, for their action items
+    transcripts_dir: PathBuf,
+    /// For fetching the children of Notion Blocks, when Notion is a source
+    notion: Option<Notion>,
+    /// The provider the notes were indexed with and how many results a search returns, when
+    /// search is enabled
+    search: Option<(Box<dyn LlmProvider>, usize)>,
+    /// The Pages of the Blocks that expand_block fetched from Notion, which aren't in the `Store`
+    fetched_blocks: RefCell<HashMap<BlockID, PageID>>,
+}
+
+#[derive(Deserialize)]
+struct PageArguments {
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct DayArguments {
+    date: String,
+}
+
+#[derive(Deserialize)]
+struct BlockArguments {
+    block_id: String,
+}
+
+#[derive(Deserialize)]
+struct SearchArguments {
+    query: String,
+}
+
+impl Tools {
+    /// The tools that read from the `store` and the transcripts in `transcripts_dir`
+    pub fn new(store: Rc<Store>, transcripts_dir: PathBuf) -> Self {
+        Tools {
+            store,
+            transcripts_dir,
+            notion: None,
+            search: None,
+            fetched_blocks: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Lets expand_block fetch the children of Notion Blocks from Notion, rather than reading
+    /// them from the `Store`
+    pub fn with_notion(mut self, notion: Notion) -> Self {
+        self.notion = Some(notion);
+        self
+    }
+
+    /// Adds the search_notes tool, which searches the index that `search::index_notes` built
+    /// with the same `embedder`, returning `results` passages of notes per search
+    pub fn with_search(mut self, embedder: Box<dyn LlmProvider>, results: usize) -> Self {
+        self.search = Some((embedder, results));
+        self
+    }
+
+    /// The schemas of the tools' arguments, for offering them to the LLM
+    pub fn definitions(&self) -> Vec<JsonSchema> {
+        let mut definitions = vec![
+            tool(
+                "get_page",
+                "Fetches one of the user's pages of notes by its title, with all of its blocks. Blocks that have children are followed by their block ID.",
+                json!({"title": {"type": "string", "description": "The page's title, or part of it"}}),
+            ),
+            tool(
+                "list_pages_edited_on",
+                "Lists the titles of the user's pages with notes that were edited on a given day, whether or not they have been edited since.",
+                json!({"date": {"type": "string", "description": "The day as YYYY-MM-DD"}}),
+            ),
+            tool(
+                "expand_block",
+                "Fetches the children of a block, given a block ID from get_page, search_notes or an earlier expand_block.",
+                json!({"block_id": {"type": "string", "description": "The ID of the block"}}),
+            ),
+            tool(
+                "past_action_items",
+                "Lists the action items the user agreed on in their latest retros.",
+                json!({}),
+            ),
+        ];
+        if self.search.is_some() {
+            definitions.push(tool(
+                "search_notes",
+                "Searches all of the user's notes, however old, for the passages closest in meaning to the query. Use it when the user mentions something that isn't in the notes you were given, or to see how something has come up before.",
+                json!({"query": {"type": "string", "description": "What to search for, e.g. a topic, project or person"}}),
+            ));
+        }
+        definitions
+    }
+
+    /// What the tool call does, in a few words for the user to see while Navi waits on it
+    pub fn describe(&self, call: &ToolCall) -> String {
+        let arguments =
+            parse_json::<Value>(&call.arguments, "the tool call's arguments").unwrap_or_default();
+        let argument = |name: &str| arguments.get(name).and_then(Value::as_str).unwrap_or("?");
+        match call.name.as_str() {
+            "get_page" => format!("reading \"{}\"", argument("title")),
+            "list_pages_edited_on" => format!("listing the pages edited on {}", argument("date")),
+            "expand_block" => format!("reading the children of block {}", argument("block_id")),
+            "past_action_items" => "looking up your past action items".to_string(),
+            "search_notes" => format!("searching your notes for \"{}\"", argument("query")),
+            name => format!("calling {}", name),
+        }
+    }
+
+    /// Runs the tool call and returns its result for the LLM. A call that fails gets the error as
+    /// its result, so that the LLM can carry on without it.
+    pub async fn call(&self, call: &ToolCall) -> String {
+        let result = match call.name.as_str() {
+            "get_page" => self.get_page(&call.arguments),
+            "list_pages_edited_on" => self.list_pages_edited_on(&call.arguments),
+            "expand_block" => self.expand_block(&call.arguments).await,
+            "past_action_items" => self.past_action_items(),
+            "search_notes" => self.search_notes(&call.arguments).await,
+            name => Err(NaviError::Llm(format!("there is no tool called {}", name))),
+        };
+        result.unwrap_or_else(|e| {
+            warn!(target: "intelligence", "the tool call {} failed: {}", call.name, e);
+            format!("The tool call failed: {}", e)
+        })
+    }
+
+    fn get_page(&self, arguments: &str) -> Result<String> {
+        let arguments: PageArguments = parse_json(arguments, "the get_page arguments")?;
+        let pages = self.store.find_pages(&arguments.title)?;
+        // an exact match beats the most recently edited page that contains the title
+        let page = match pages
+            .iter()
+            .find(|page| page.title.eq_ignore_ascii_case(&arguments.title))
+            .or(pages.first())
+        {
+            Some(page) => page,
+            None => return Ok(format!("There is no page titled \"{}\".", arguments.title)),
+        };
+
+        let mut text = format!(
+            "Page Title: {}\nLast edited: {}\n{}",
+            page.title,
+            page.update_date.with_timezone(&Local).format("%Y-%m-%d"),
+            build_front_matter(page.database.as_deref(), &page.properties)
+        );
+        for node in page_nodes(self.store.page_blocks(&page.id)?) {
+            render_node(&node, 0, &mut text, true);
+        }
+        if let Some((end, _)) = text.char_indices().nth(MAX_PAGE_CHARS) {
+            text.truncate(end);
+            text.push_str("\n(the rest of the page was cut)\n");
+        }
+
+        let others = pages
+            .iter()
+            .filter(|other| other.id != page.id)
+            .map(|other| format!("\"{}\"", other.title))
+            .collect::<Vec<_>>();
+        if !others.is_empty() {
+            text.push_str(&format!(
+                "\nOther pages with matching titles: {}",
+                others.join(", ")
+            ));
+        }
+        Ok(text)
+    }
+
+    fn list_pages_edited_on(&self, arguments: &str) -> Result<String> {
+        let arguments: DayArguments = parse_json(arguments, "the list_pages_edited_on arguments")?;
+        let day = NaiveDate::parse_from_str(&arguments.date, "%Y-%m-%d").map_err(|_| {
+            NaviError::Llm(format!("{} is not a date as YYYY-MM-DD", arguments.date))
+        })?;
+        let start_of = |day: NaiveDate| {
+            day.and_hms_opt(0, 0, 0)
+                .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+                .map(|midnight| midnight.with_timezone(&Utc))
+                .ok_or_else(|| NaviError::Llm(format!("{} has no midnight", day)))
+        };
+
+        let pages = self
+            .store
+            .pages_with_blocks_edited_between(start_of(day)?, start_of(day + Duration::days(1))?)?;
+        if pages.is_empty() {
+            return Ok(format!("No pages were edited on {}.", day));
+        }
+        Ok(pages
+            .iter()
+            .map(|(page, edited)| {
+                format!(
+                    "- {} (edited at {})",
+                    page.title,
+                    edited.with_timezone(&Local).format("%H:%M")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    async fn expand_block(&self, arguments: &str) -> Result<String> {
+        let arguments: BlockArguments = parse_json(arguments, "the expand_block arguments")?;
+        let block_id = BlockID::new(arguments.block_id);
+        let stored = self.store.find_block(&block_id)?;
+        let page_id = match &stored {
+            Some(stored) => Some(stored.block.page_id.clone()),
+            None => self.fetched_blocks.borrow().get(&block_id).cloned(),
+        };
+        let page_id = match page_id {
+            Some(page_id) => page_id,
+            None => return Ok(format!("There is no block with the ID {}.", block_id)),
+        };
+
+        // Notion Blocks' children are fetched fresh, since the Store may not have them all
+        let from_notion =
+            stored.is_none() || self.store.page_source(&page_id)?.as_deref() == Some(Notion::NAME);
+        let children = match &self.notion {
+            Some(notion) if from_notion => {
+                let children = notion
+                    .retrieve_all_block_children(&block_id, &page_id)
+                    .await?
+                    .blocks;
+                let mut fetched_blocks = self.fetched_blocks.borrow_mut();
+                for child in &children {
+                    fetched_blocks.insert(child.id.clone(), child.page_id.clone());
+                }
+                children
+            }
+            _ => {
+                let mut children = self
+                    .store
+                    .page_blocks(&page_id)?
+                    .into_iter()
+                    .filter(|stored| stored.parent_id.as_ref() == Some(&block_id))
+                    .collect::<Vec<_>>();
+                children.sort_by_key(|stored| stored.position);
+                children.into_iter().map(|stored| stored.block).collect()
+            }
+        };
+
+        let mut text = String::new();
+        for child in children.iter().filter(|child| !child.is_empty()) {
+            text.push_str(&block_line(child, 0, true));
+        }
+        if text.is_empty() {
+            return Ok(format!("Block {} has no children.", block_id));
+        }
+        Ok(text)
+    }
+
+    fn past_action_items(&self) -> Result<String> {
+        let retros = list_sessions(&self.transcripts_dir)?
+            .iter()
+            .rev()
+            .filter_map(|session| {
+                let action_items = session.action_items.as_ref()?;
+                Some(format!(
+                    "# Retro of {}\n{}",
+                    session
+                        .info
+                        .started_at
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d"),
+                    to_markdown(action_items)
+                ))
+            })
+            .take(PAST_RETROS)
+            .collect::<Vec<_>>();
+        if retros.is_empty() {
+            return Ok("No retros have agreed on action items yet.".to_string());
+        }
+        Ok(retros.join("\n"))
+    }
+
+    async fn search_notes(&self, arguments: &str) -> Result<String> {
+        let (embedder, limit) = self
+            .search
+            .as_ref()
+            .ok_or_else(|| NaviError::Config("search is not enabled".to_string()))?;
+        let arguments: SearchArguments = parse_json(arguments, "the search_notes arguments")?;
+
+        let results =
+            search_notes(&self.store, embedder.as_ref(), &arguments.query, *limit).await?;
+        if results.is_empty() {
+            return Ok("Nothing was found, the notes may not have been indexed yet.".to_string());
+        }
+        Ok(results
+            .iter()
+            .map(|result| {
+                format!(
+                    "Last edited {} (block {}):\n{}",
+                    result
+                        .chunk
+                        .update_date
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d"),
+                    result.chunk.block_id,
+                    result.chunk.text
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n---\n\n"))
+    }
+}
+
+/// The definition of a tool whose arguments are the `properties`, all of them required
+fn tool(name: &str, description: &str, properties: Value) -> JsonSchema {
+    let required = properties
+        .as_object()
+        .map(|properties| properties.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    JsonSchema {
+        name: name.to_string(),
+        description: description.to_string(),
+        schema: json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::datatypes::TextBlockKind;
+    use crate::fixtures::{block, page, stored};
+    use crate::llm::scripted::ScriptedLlm;
+    use crate::store::StoredBlock;
+    use chrono::DateTime;
+
+    fn call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    /// A bullet point of the page "Sprint 22", which has children if it's at the top level
+    fn bullet(
+        id: &str,
+        text: &str,
+        parent_id: Option<&str>,
+        update_date: DateTime<Utc>,
+    ) -> StoredBlock {
+        let mut block = block(
+            "sprint",
+            id,
+            TextBlockKind::BulletedListItem,
+            text,
+            update_date,
+        );
+        block.has_children = parent_id.is_none();
+        stored(block, parent_id, 0)
+    }
+
+    #[tokio::test]
+    async fn test_tools_read_the_store() {
+        let store = Rc::new(Store::open_in_memory().unwrap());
+        // the page was edited today, and its planning two days ago
+        let edited = Utc::now() - Duration::days(2);
+        let page = page("sprint", "Sprint 22", Utc::now());
+        let blocks = vec![
+            bullet("planning", "Planning", None, edited),
+            bullet("goal", "Ship the CLI", Some("planning"), page.update_date),
+        ];
+        store.save_page("Obsidian", &page, &blocks).unwrap();
+        let tools = Tools::new(store, PathBuf::from("no-such-transcripts"));
+        let edited = edited.with_timezone(&Local);
+
+        assert_eq!(
+            tools
+                .call(&call("get_page", r#"{"title": "sprint 22"}"#))
+                .await,
+            format!(
+                "Page Title: Sprint 22\nLast edited: {}\n- Planning [block planning]\n\t- Ship the CLI\n",
+                page.update_date.with_timezone(&Local).format("%Y-%m-%d")
+            )
+        );
+        let date = format!(r#"{{"date": "{}"}}"#, edited.format("%Y-%m-%d"));
+        assert_eq!(
+            tools.call(&call("list_pages_edited_on", &date)).await,
+            format!("- Sprint 22 (edited at {})", edited.format("%H:%M"))
+        );
+        assert_eq!(
+            tools
+                .call(&call("expand_block", r#"{"block_id": "planning"}"#))
+                .await,
+            "- Ship the CLI\n"
+        );
+        assert_eq!(
+            tools.call(&call("past_action_items", "{}")).await,
+            "No retros have agreed on action items yet."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_return_their_errors_as_results() {
+        let store = Rc::new(Store::open_in_memory().unwrap());
+        let search = call("search_notes", r#"{"query": "deploys"}"#);
+
+        let tools = Tools::new(store, PathBuf::from("no-such-transcripts"));
+        assert!(!tools
+            .definitions()
+            .iter()
+            .any(|definition| definition.name == "search_notes"));
+        assert!(tools.call(&search).await.ends_with("search is not enabled"));
+
+        let tools = tools.with_search(Box::new(ScriptedLlm::new(Vec::<String>::new())), 5);
+        assert_eq!(tools.definitions().last().unwrap().name, "search_notes");
+        assert_eq!(
+            tools.describe(&search),
+            "searching your notes for \"deploys\""
+        );
+        assert!(tools.call(&search).await.starts_with("Nothing was found"));
+        assert!(tools
+            .call(&call("list_pages_edited_on", r#"{"date": "last Friday"}"#))
+            .await
+            .starts_with("The tool call failed"));
+        assert!(tools
+            .call(&call("delete_notes", "{}"))
+            .await
+            .starts_with("The tool call failed"));
+    }
+}
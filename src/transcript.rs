@@ -0,0 +1,511 @@
+use crate::action_items::ActionItem;
+use crate::core::{datatypes::ParsedPage, helpers::hash_parts};
+use crate::error::{NaviError, Result};
+use crate::llm::{Message, Role, ToolCall};
+use crate::retro_format::DEFAULT_FORMAT;
+use chrono::{DateTime, Utc};
+use dendron::traverse::DftEvent;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// What a retro session was run with, which is the first line of its transcript
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// The ID that `navi retro --resume` takes, which is also the transcript's file name
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    /// The name of the format the retro is run in
+    #[serde(default = "default_format")]
+    pub format: String,
+    /// The provider and model the session was started with
+    pub provider: String,
+    pub model: String,
+    /// A hash of the messages the retro opened with, i.e. the instructions and the notes
+    pub prompt_hash: String,
+    /// Identifies the version of the notes the retro was about, see `snapshot_id`
+    pub snapshot_id: String,
+}
+
+impl SessionInfo {
+    /// Describes a new session in the format `format`, which starts now and opens with `messages`
+    pub fn new(
+        format: &str,
+        provider: &str,
+        model: &str,
+        messages: &[Message],
+        snapshot_id: String,
+    ) -> SessionInfo {
+        let started_at = Utc::now();
+        let contents = messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>();
+        SessionInfo {
+            // the random suffix keeps two retros started in the same second apart
+            id: format!(
+                "{}-{:04x}",
+                started_at.format("%Y-%m-%d-%H%M%S"),
+                fastrand::u16(..)
+            ),
+            started_at,
+            format: format.to_string(),
+            provider: provider.to_string(),
+            model: model.to_string(),
+            prompt_hash: hash_parts(&contents),
+            snapshot_id,
+        }
+    }
+}
+
+/// Transcripts from before there were formats were all of the default one
+fn default_format() -> String {
+    DEFAULT_FORMAT.to_string()
+}
+
+/// One line of a transcript file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Entry {
+    Session(SessionInfo),
+    Message {
+        at: DateTime<Utc>,
+        role: Role,
+        content: String,
+        /// The step of the retro that the message belongs to, which the messages the retro opens
+        /// with don't have
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        step: Option<usize>,
+        /// The tools Navi called in the message, see `Message::tool_calls`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
+        /// For the result of a tool call, the ID of the call
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tool_call_id: Option<String>,
+    },
+    ActionItems {
+        at: DateTime<Utc>,
+        items: Vec<ActionItem>,
+    },
+}
+
+/// A retro session that is saved to disk as it happens, as a file of JSON lines in the
+/// transcripts directory: one line describing the session, followed by one per message (along
+/// with the step of the retro it was sent in), and finally the action items agreed on in the
+/// session. The tools Navi calls and what they return are messages too.
+///
+/// Each message is written as soon as it is part of the conversation, so a retro that ends
+/// early (with exit() or because of a network error) can be picked up where it left off.
+pub struct Transcript {
+    info: SessionInfo,
+    file: File,
+}
+
+impl Transcript {
+    /// Starts the transcript of a new session, which opens with `messages`
+    pub fn create(dir: &Path, info: SessionInfo, messages: &[Message]) -> Result<Transcript> {
+        fs::create_dir_all(dir)
+            .map_err(|e| NaviError::io(format!("unable to create {}", dir.display()), e))?;
+        let path = transcript_path(dir, &info.id);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| NaviError::io(format!("unable to create {}", path.display()), e))?;
+        debug!(target: "transcript", "saving the retro to {}", path.display());
+
+        let mut transcript = Transcript { info, file };
+        transcript.write(&Entry::Session(transcript.info.clone()))?;
+        for message in messages {
+            transcript.write_message(message, None)?;
+        }
+        Ok(transcript)
+    }
+
+    /// Reopens the transcript of the session with the ID `id`, returning it along with the
+    /// session so far
+    pub fn resume(dir: &Path, id: &str) -> Result<(Transcript, SavedSession)> {
+        let session = load_session(dir, id)?;
+        let path = transcript_path(dir, id);
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| NaviError::io(format!("unable to open {}", path.display()), e))?;
+
+        let transcript = Transcript {
+            info: session.info.clone(),
+            file,
+        };
+        Ok((transcript, session))
+    }
+
+    pub fn info(&self) -> &SessionInfo {
+        &self.info
+    }
+
+    /// Adds a message that was sent in the step numbered `step` (counting from 1) to the
+    /// transcript
+    pub fn record(&mut self, message: &Message, step: usize) -> Result<()> {
+        self.write_message(message, Some(step))
+    }
+
+    /// Saves the action items agreed on in the session, replacing any that were saved before
+    /// the session was resumed
+    pub fn record_action_items(&mut self, items: &[ActionItem]) -> Result<()> {
+        self.write(&Entry::ActionItems {
+            at: Utc::now(),
+            items: items.to_vec(),
+        })
+    }
+
+    fn write_message(&mut self, message: &Message, step: Option<usize>) -> Result<()> {
+        self.write(&Entry::Message {
+            at: Utc::now(),
+            role: message.role,
+            content: message.content.clone(),
+            step,
+            tool_calls: message.tool_calls.clone(),
+            tool_call_id: message.tool_call_id.clone(),
+        })
+    }
+
+    fn write(&mut self, entry: &Entry) -> Result<()> {
+        let line = serde_json::to_string(entry).map_err(|source| NaviError::Deserialization {
+            what: "a line of the transcript".to_string(),
+            source,
+        })?;
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.flush())
+            .map_err(|e| NaviError::io(format!("unable to save the retro {}", self.info.id), e))
+    }
+}
+
+/// A message of a past session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedMessage {
+    pub at: DateTime<Utc>,
+    /// The step of the retro the message was sent in, or None for the messages the retro opened
+    /// with
+    pub step: Option<usize>,
+    pub message: Message,
+}
+
+/// A past session, as read back from its transcript
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedSession {
+    pub info: SessionInfo,
+    pub messages: Vec<SavedMessage>,
+    /// The action items agreed on in the session, if it got as far as agreeing on them
+    pub action_items: Option<Vec<ActionItem>>,
+}
+
+impl SavedSession {
+    /// How many responses Navi gave in the session, not counting its calls to tools
+    pub fn responses(&self) -> usize {
+        self.messages
+            .iter()
+            .filter(|saved| {
+                saved.message.role == Role::Assistant && saved.message.tool_calls.is_empty()
+            })
+            .count()
+    }
+
+    /// When the last message of the session was sent
+    pub fn last_active(&self) -> DateTime<Utc> {
+        self.messages
+            .last()
+            .map_or(self.info.started_at, |saved| saved.at)
+    }
+
+    /// The step the session had got to when it was last active, counting from 1
+    pub fn step(&self) -> usize {
+        self.messages
+            .iter()
+            .rev()
+            .find_map(|saved| saved.step)
+            .unwrap_or(1)
+    }
+
+    /// The session's messages, without when they were sent. Calls to tools that never returned
+    /// (e.g. because the session ended while Navi was calling them) are left out, along with any
+    /// results of the other calls made in the same message, so that the conversation can be sent
+    /// to the LLM again and the calls made again if they're still needed.
+    pub fn conversation(&self) -> Vec<Message> {
+        let messages = self
+            .messages
+            .iter()
+            .map(|saved| &saved.message)
+            .collect::<Vec<_>>();
+
+        let mut conversation = Vec::new();
+        // whether the results that follow are of calls that were kept
+        let mut keep_results = false;
+        for (i, message) in messages.iter().enumerate() {
+            if message.role == Role::Tool {
+                if keep_results {
+                    conversation.push((*message).clone());
+                }
+                continue;
+            }
+
+            // the results of a message's calls are recorded straight after it
+            let results = messages[i + 1..]
+                .iter()
+                .take_while(|message| message.role == Role::Tool)
+                .filter_map(|message| message.tool_call_id.as_deref())
+                .collect::<HashSet<_>>();
+            keep_results = message
+                .tool_calls
+                .iter()
+                .all(|call| results.contains(call.id.as_str()));
+            if keep_results {
+                conversation.push((*message).clone());
+            }
+        }
+        conversation
+    }
+}
+
+/// Reads the transcript of the session with the ID `id`
+pub fn load_session(dir: &Path, id: &str) -> Result<SavedSession> {
+    match read_transcript(&transcript_path(dir, id)) {
+        Err(NaviError::Io { source, .. }) if source.kind() == ErrorKind::NotFound => {
+            Err(NaviError::Config(format!(
+                "there is no retro with the ID {}, `navi history` lists them",
+                id
+            )))
+        }
+        other => other,
+    }
+}
+
+/// Lists the sessions that have transcripts in `dir`, oldest first. Files in `dir` that can't be
+/// read as transcripts are skipped.
+pub fn list_sessions(dir: &Path) -> Result<Vec<SavedSession>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(NaviError::io(
+                format!("unable to read {}", dir.display()),
+                e,
+            ))
+        }
+    };
+
+    let mut sessions = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| NaviError::io(format!("unable to read {}", dir.display()), e))?
+            .path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("jsonl") {
+            continue;
+        }
+        match read_transcript(&path) {
+            Ok(session) => sessions.push(session),
+            Err(e) => debug!(target: "transcript", "skipping {}: {}", path.display(), e),
+        }
+    }
+    sessions.sort_by_key(|session| session.info.started_at);
+
+    Ok(sessions)
+}
+
+/// A short ID for the version of the notes in `pages`, which changes whenever a Page is added,
+/// removed or edited. Two retros with the same snapshot ID were given the same notes.
+pub fn snapshot_id(pages: &[ParsedPage]) -> String {
+    let mut versions = pages
+        .iter()
+        .map(|page| {
+            let mut last_edited = None;
+            for tree in &page.page_content {
+                for event in tree.root().depth_first_traverse() {
+                    if let DftEvent::Open(node) = event {
+                        last_edited = last_edited.max(Some(node.borrow_data().update_date));
+                    }
+                }
+            }
+            format!(
+                "{}@{}",
+                page.page_id,
+                last_edited
+                    .map(|date| date.to_rfc3339())
+                    .unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>();
+    // the order the sources return Pages in doesn't change what the notes say
+    versions.sort();
+
+    let versions = versions.iter().map(String::as_str).collect::<Vec<_>>();
+    hash_parts(&versions)[..12].to_string()
+}
+
+fn transcript_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", id))
+}
+
+fn read_transcript(path: &Path) -> Result<SavedSession> {
+    let file = File::open(path)
+        .map_err(|e| NaviError::io(format!("unable to open {}", path.display()), e))?;
+    let lines = BufReader::new(file)
+        .lines()
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| NaviError::io(format!("unable to read {}", path.display()), e))?;
+
+    let mut info = None;
+    let mut messages = Vec::new();
+    let mut action_items = None;
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            // a line cut short by a crash can only be the last one, and only loses that entry
+            Err(e) if i == lines.len() - 1 => {
+                warn!(target: "transcript", "the last line of {} is incomplete, and was skipped: {}", path.display(), e);
+                continue;
+            }
+            Err(source) => {
+                return Err(NaviError::Deserialization {
+                    what: format!("line {} of {}", i + 1, path.display()),
+                    source,
+                })
+            }
+        };
+        match entry {
+            Entry::Session(session) => info = Some(session),
+            Entry::Message {
+                at,
+                role,
+                content,
+                step,
+                tool_calls,
+                tool_call_id,
+            } => messages.push(SavedMessage {
+                at,
+                step,
+                message: Message {
+                    tool_calls,
+                    tool_call_id,
+                    ..Message::new(role, content)
+                },
+            }),
+            Entry::ActionItems { items, .. } => action_items = Some(items),
+        }
+    }
+
+    let info = info.ok_or_else(|| {
+        NaviError::Config(format!("{} is not a retro transcript", path.display()))
+    })?;
+    Ok(SavedSession {
+        info,
+        messages,
+        action_items,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TempDir;
+
+    #[test]
+    fn test_resume_continues_the_transcript() {
+        let dir = TempDir::new("transcripts");
+        let opening = vec![
+            Message::system("Lead a retro"),
+            Message::user("Here are my weekly notes"),
+        ];
+        let info = SessionInfo::new(
+            "weekly",
+            "OpenAI",
+            "gpt-4o-mini",
+            &opening,
+            "abc".to_string(),
+        );
+        let id = info.id.clone();
+
+        let mut transcript = Transcript::create(dir.path(), info, &opening).unwrap();
+        assert_eq!(load_session(dir.path(), &id).unwrap().step(), 1);
+        transcript
+            .record(&Message::assistant("Let's begin!"), 1)
+            .unwrap();
+        transcript
+            .record(&Message::user("I'm ready to move on to step 2"), 2)
+            .unwrap();
+        drop(transcript);
+
+        let (mut transcript, session) = Transcript::resume(dir.path(), &id).unwrap();
+        assert_eq!(transcript.info().model, "gpt-4o-mini");
+        assert_eq!(session.step(), 2);
+        let messages = session.conversation();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[2], Message::assistant("Let's begin!"));
+        assert_eq!(session.messages[0].step, None);
+        transcript.record(&Message::user("Sounds good"), 2).unwrap();
+        let call = ToolCall {
+            id: "call_1".to_string(),
+            name: "get_page".to_string(),
+            arguments: r#"{"title": "Sprint 22"}"#.to_string(),
+        };
+        transcript
+            .record(&Message::tool_calls("", vec![call.clone()]), 2)
+            .unwrap();
+        transcript
+            .record(&Message::tool_result("call_1", "Page Title: Sprint 22"), 2)
+            .unwrap();
+        let messages = load_session(dir.path(), &id).unwrap().conversation();
+        assert_eq!(messages[5].tool_calls, vec![call.clone()]);
+        assert_eq!(messages[6].tool_call_id.as_deref(), Some("call_1"));
+        // calls that were cut off before they returned are left out, wherever they are
+        transcript
+            .record(&Message::tool_calls("", vec![call]), 2)
+            .unwrap();
+        assert_eq!(
+            load_session(dir.path(), &id).unwrap().conversation(),
+            messages
+        );
+        let unanswered = ToolCall {
+            id: "call_2".to_string(),
+            name: "search_notes".to_string(),
+            arguments: r#"{"query": "launch"}"#.to_string(),
+        };
+        let answered = ToolCall {
+            id: "call_3".to_string(),
+            ..unanswered.clone()
+        };
+        transcript
+            .record(&Message::tool_calls("", vec![answered, unanswered]), 2)
+            .unwrap();
+        transcript
+            .record(&Message::tool_result("call_3", "No notes matched"), 2)
+            .unwrap();
+        transcript
+            .record(&Message::user("Never mind, let's move on"), 2)
+            .unwrap();
+        let mut expected = messages.clone();
+        expected.push(Message::user("Never mind, let's move on"));
+        assert_eq!(
+            load_session(dir.path(), &id).unwrap().conversation(),
+            expected
+        );
+
+        transcript.record_action_items(&[]).unwrap();
+
+        let sessions = list_sessions(dir.path()).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].info.id, id);
+        assert_eq!(sessions[0].responses(), 1);
+        assert_eq!(sessions[0].action_items, Some(Vec::new()));
+
+        assert!(matches!(
+            Transcript::resume(dir.path(), "no-such-retro"),
+            Err(NaviError::Config(_))
+        ));
+    }
+}